@@ -0,0 +1,112 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Compares the cost of the TLS handshake of pull requests with and without session
+//! resumption, see tls_session_resumption. Both ends run on the current thread, so the elapsed
+//! time is the CPU time spent on both sides.
+//!
+//!     cargo bench --bench tls_handshake
+//!
+//! With the release profile on an x86-64 build host, a full handshake took about 2.7 ms and a
+//! resumed one about 0.47 ms, no matter if resumed from the cache or via a ticket (5.7x). Most
+//! of the difference is due to the RSA signatures of the server and of the client certificate,
+//! which a resumed handshake doesn't need.
+
+#[allow(dead_code)]
+#[path = "../tests/common/certs.rs"]
+mod test_certs;
+
+use cmk_agent_ctl::{certs, configuration::config, tls_server};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const HANDSHAKES: u32 = 200;
+
+async fn pull_request(
+    acceptor: &tls_server::PullTlsAcceptor,
+    connector: &tokio_rustls::TlsConnector,
+    server_name: &rustls::ServerName,
+) {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    // As the pull handler, st. the client gets the session ticket sent after the handshake
+    let serve = async {
+        let mut stream = acceptor.accept(server).await.unwrap();
+        stream.write_all(b"<<<check_mk>>>").await.unwrap();
+        stream.shutdown().await.unwrap();
+    };
+    let fetch = async {
+        let mut stream = connector
+            .connect(server_name.clone(), client)
+            .await
+            .unwrap();
+        stream.read_to_end(&mut vec![]).await.unwrap();
+    };
+    tokio::join!(serve, fetch);
+}
+
+async fn measure(session_resumption: certs::SessionResumption) -> Duration {
+    let uuid = uuid::Uuid::new_v4();
+    let x509_certs = test_certs::X509Certs::new("Test CA", "Test receiver", &uuid.to_string());
+    let ca_cert = String::from_utf8(x509_certs.ca_cert).unwrap();
+    let connection = config::TrustedConnection {
+        uuid,
+        private_key: String::from_utf8(x509_certs.controller_private_key).unwrap(),
+        certificate: String::from_utf8(x509_certs.controller_cert).unwrap(),
+        root_cert: ca_cert.clone(),
+        pinned_fingerprint: None,
+        pinned_client_fingerprints: vec![],
+        labels: config::ConnectionLabels::new(),
+        agent_output_disabled: false,
+        min_tls_version: None,
+    };
+    let tls_policy = certs::TlsPolicy {
+        session_resumption,
+        ..certs::TlsPolicy::default()
+    };
+    let acceptor = tls_server::tls_acceptor([&connection].into_iter(), &tls_policy).unwrap();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(
+        certs::pull_client_config(
+            &ca_cert,
+            certs::TLSIdentity {
+                cert_chain: vec![certs::rustls_certificate(
+                    &String::from_utf8(x509_certs.receiver_cert).unwrap(),
+                )
+                .unwrap()],
+                key_der: certs::rustls_private_key(
+                    &String::from_utf8(x509_certs.receiver_private_key).unwrap(),
+                )
+                .unwrap(),
+            },
+            &tls_policy,
+        )
+        .unwrap(),
+    ));
+    let server_name = rustls::ServerName::try_from(uuid.to_string().as_str()).unwrap();
+    // The first handshake is always a full one
+    pull_request(&acceptor, &connector, &server_name).await;
+    let started = Instant::now();
+    for _ in 0..HANDSHAKES {
+        pull_request(&acceptor, &connector, &server_name).await;
+    }
+    started.elapsed() / HANDSHAKES
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let full = measure(certs::SessionResumption::Off).await;
+    println!("off: {:?} per handshake", full);
+    for (name, session_resumption) in [
+        ("cache", certs::SessionResumption::Cache),
+        ("tickets", certs::SessionResumption::Tickets),
+    ] {
+        let resumed = measure(session_resumption).await;
+        println!(
+            "{}: {:?} per handshake, {:.1}x faster",
+            name,
+            resumed,
+            full.as_secs_f64() / resumed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! One JSON line per pull connection, like the access log of a web server. Unlike the operational
+//! log, every connection gets exactly one record once it is done, no matter the log level.
+//!
+//! Records are written straight from the connection handling, without flushing to disk, st. the
+//! log is cheap enough to keep enabled.
+
+use super::security_log;
+use anyhow::{Context, Result as AnyhowResult};
+use log::warn;
+use serde::Serialize;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Completed,
+    Failed,
+    TimedOut,
+    /// Turned down before handling the request, eg. by the allowlist
+    Rejected,
+    /// Still being handled when we shut down
+    Aborted,
+}
+
+#[derive(Serialize, Debug)]
+struct Record<'a> {
+    timestamp: String,
+    peer: String,
+    /// The UUID of the connection the peer selected, only known after the TLS handshake
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_version: Option<&'a str>,
+    bytes_sent: u64,
+    duration_ms: u128,
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+pub struct AccessLog {
+    path: PathBuf,
+    file: Mutex<std::fs::File>,
+}
+
+fn open(path: &Path) -> AnyhowResult<std::fs::File> {
+    let mut open_options = std::fs::OpenOptions::new();
+    open_options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o640);
+    open_options
+        .open(path)
+        .context(format!("Failed to open access log {}", path.display()))
+}
+
+impl AccessLog {
+    pub fn open(path: &Path) -> AnyhowResult<Self> {
+        Ok(Self {
+            path: PathBuf::from(path),
+            file: Mutex::new(open(path)?),
+        })
+    }
+
+    /// Continues in a new file if the current one was moved away, eg. by logrotate
+    pub fn reopen(&self) -> AnyhowResult<()> {
+        let file = open(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+
+    fn write(&self, record: &Record) {
+        let written = serde_json::to_vec(record)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                // A single write, st. concurrent records don't interleave
+                self.file.lock().unwrap().write_all(&line)
+            });
+        if let Err(err) = written {
+            warn!(
+                "Failed to write to access log {}. ({})",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// What we learn about a pull connection while handling it. Without an access log, nothing is
+/// ever written, which keeps the connection handling the same either way.
+pub struct Access {
+    log: Option<Arc<AccessLog>>,
+    security_log: Option<Arc<security_log::SecurityLog>>,
+    peer: SocketAddr,
+    timestamp: time::OffsetDateTime,
+    start: Instant,
+    uuid: OnceLock<String>,
+    tls_version: OnceLock<String>,
+    bytes_sent: AtomicU64,
+    finished: AtomicBool,
+}
+
+impl Access {
+    pub fn new(log: Option<Arc<AccessLog>>, peer: SocketAddr) -> Self {
+        Self {
+            log,
+            security_log: None,
+            peer,
+            timestamp: time::OffsetDateTime::now_utc(),
+            start: Instant::now(),
+            uuid: OnceLock::new(),
+            tls_version: OnceLock::new(),
+            bytes_sent: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    pub fn security_logged(mut self, security_log: Option<Arc<security_log::SecurityLog>>) -> Self {
+        self.security_log = security_log;
+        self
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// In addition to the record of the access log, which only has the final outcome
+    pub fn security_event(&self, event: security_log::Event, reason: &str) {
+        if let Some(security_log) = &self.security_log {
+            security_log.write(event, &self.peer, reason)
+        }
+    }
+
+    /// Only known once the TLS handshake is done
+    pub fn uuid(&self) -> Option<&str> {
+        self.uuid.get().map(String::as_str)
+    }
+
+    pub fn elapsed(&self) -> std::time::Duration {
+        self.start.elapsed()
+    }
+
+    pub fn set_handshake(&self, connection: &rustls::ServerConnection) {
+        if let Some(uuid) = connection.sni_hostname() {
+            let _ = self.uuid.set(String::from(uuid));
+        }
+        if let Some(version) = connection.protocol_version() {
+            let _ = self.tls_version.set(format!("{:?}", version));
+        }
+    }
+
+    pub fn count_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Only the first outcome is recorded
+    pub fn finish(&self, outcome: Outcome, error: Option<&str>) {
+        let Some(log) = &self.log else {
+            return;
+        };
+        if self.finished.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        log.write(&Record {
+            timestamp: self
+                .timestamp
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default(),
+            peer: self.peer.to_string(),
+            uuid: self.uuid.get().map(String::as_str),
+            tls_version: self.tls_version.get().map(String::as_str),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            duration_ms: self.start.elapsed().as_millis(),
+            outcome,
+            error,
+        });
+    }
+}
+
+impl Drop for Access {
+    fn drop(&mut self) {
+        self.finish(Outcome::Aborted, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_records(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.jsonl");
+        let log = Arc::new(AccessLog::open(&path).unwrap());
+        let peer = SocketAddr::from(([192, 168, 0, 1], 4242));
+
+        let access = Access::new(Some(log.clone()), peer);
+        access.count_bytes_sent(100);
+        access.count_bytes_sent(23);
+        access.finish(Outcome::Completed, None);
+        Access::new(Some(log), peer).finish(Outcome::Rejected, Some("IP is not allowed"));
+
+        let records = read_records(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["peer"], "192.168.0.1:4242");
+        assert_eq!(records[0]["bytes_sent"], 123);
+        assert_eq!(records[0]["outcome"], "completed");
+        assert!(records[0]["duration_ms"].is_u64());
+        assert!(records[0].get("uuid").is_none());
+        assert!(records[0].get("error").is_none());
+        assert_eq!(records[1]["outcome"], "rejected");
+        assert_eq!(records[1]["error"], "IP is not allowed");
+    }
+
+    #[test]
+    fn test_finish_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.jsonl");
+        let log = Arc::new(AccessLog::open(&path).unwrap());
+        let peer = SocketAddr::from(([127, 0, 0, 1], 4242));
+        let access = Access::new(Some(log.clone()), peer);
+        access.finish(Outcome::Failed, Some("Handshake failed"));
+        access.finish(Outcome::Completed, None);
+        drop(access);
+        // Dropped while being handled
+        drop(Access::new(Some(log), peer));
+        let records = read_records(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["outcome"], "failed");
+        assert_eq!(records[1]["outcome"], "aborted");
+    }
+
+    #[test]
+    fn test_finish_without_log() {
+        Access::new(None, SocketAddr::from(([127, 0, 0, 1], 4242))).finish(Outcome::Failed, None);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.jsonl");
+        let rotated = dir.path().join("access.jsonl.1");
+        let log = Arc::new(AccessLog::open(&path).unwrap());
+        let peer = SocketAddr::from(([127, 0, 0, 1], 4242));
+        Access::new(Some(log.clone()), peer).finish(Outcome::Completed, None);
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen().unwrap();
+        Access::new(Some(log), peer).finish(Outcome::TimedOut, None);
+        assert_eq!(read_records(&rotated).len(), 1);
+        assert_eq!(read_records(&path)[0]["outcome"], "timed-out");
+    }
+}
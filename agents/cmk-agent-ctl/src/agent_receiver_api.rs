@@ -2,9 +2,10 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use super::{certs, config, types};
+use super::{certs, clock_skew, config, proxy, types};
 use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
 use http::StatusCode;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use serde_with::DisplayFromStr;
 use string_enum::StringEnum;
@@ -64,6 +65,42 @@ struct ErrorResponse {
     pub detail: String,
 }
 
+/// The agent receiver answered with an unexpected status code. Kept as a type of its own, st.
+/// the exit code can tell eg. rejected credentials from other failures.
+#[derive(Debug)]
+pub struct ResponseError {
+    pub status: StatusCode,
+    pub description: String,
+}
+
+impl std::fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+/// Connecting to the agent receiver, including the TLS handshake, took too long. Most likely,
+/// the receiver is down or unreachable.
+#[derive(Debug)]
+pub struct ConnectTimeout {
+    pub timeout: std::time::Duration,
+    source: reqwest::Error,
+}
+
+impl std::fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Connect timed out after {}s", self.timeout.as_secs())
+    }
+}
+
+impl std::error::Error for ConnectTimeout {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
 pub trait Pairing {
     fn pair(
         &self,
@@ -112,11 +149,164 @@ pub trait Status {
     ) -> AnyhowResult<StatusResponse>;
 }
 
+trait SendWithin {
+    fn send_within(
+        self,
+        connect_timeout: std::time::Duration,
+        clock_skew_threshold: std::time::Duration,
+    ) -> AnyhowResult<reqwest::blocking::Response>;
+}
+
+impl SendWithin for reqwest::blocking::RequestBuilder {
+    /// The timeout is configured with the client already, this only tells it apart from others
+    fn send_within(
+        self,
+        connect_timeout: std::time::Duration,
+        clock_skew_threshold: std::time::Duration,
+    ) -> AnyhowResult<reqwest::blocking::Response> {
+        let response = self.send().map_err(|err| {
+            if err.is_connect() && err.is_timeout() {
+                anyhow!(ConnectTimeout {
+                    timeout: connect_timeout,
+                    source: err,
+                })
+            } else {
+                anyhow!(err)
+            }
+        })?;
+        // Helps to tell which receiver answered if the name resolves to several addresses
+        if let Some(address) = response.remote_addr() {
+            debug!(
+                "Connected to {} at {}",
+                response.url().host_str().unwrap_or_default(),
+                address
+            );
+        }
+        if let Some(skew) = clock_skew::date_header_skew(response.headers(), clock_skew_threshold) {
+            warn!(
+                "{}",
+                clock_skew::warning(
+                    &format!(
+                        "The Date header of {}",
+                        response.url().host_str().unwrap_or_default()
+                    ),
+                    &skew
+                )
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// Without a TLS server name, reqwest tries all addresses itself. With one, we have to pin a
+/// single address, so we take the first one which accepts connections, in the order of the
+/// resolver. If none does, the first one is as good as any for reporting the error.
+fn first_reachable(
+    addresses: Vec<std::net::SocketAddr>,
+    connect_timeout: std::time::Duration,
+) -> Option<std::net::SocketAddr> {
+    if addresses.len() > 1 {
+        for address in &addresses {
+            match std::net::TcpStream::connect_timeout(address, connect_timeout) {
+                Ok(_) => return Some(*address),
+                Err(err) => debug!(
+                    "Failed to connect to {}, trying the next address: {}",
+                    address, err
+                ),
+            }
+        }
+    }
+    addresses.into_iter().next()
+}
+
 pub struct Api {
     pub use_proxy: bool,
+    pub proxy: Option<reqwest::Url>,
+    pub tls_policy: certs::TlsPolicy,
+    pub extra_root_certs: Vec<rustls::Certificate>,
+    pub tls_servername: Option<String>,
+    pub connect_timeout: std::time::Duration,
+    /// Limits the whole request, including sending the body. None means the default of reqwest.
+    pub request_timeout: Option<std::time::Duration>,
+    /// Idle time before TCP keepalive probes are sent, None for no keepalive
+    pub tcp_keepalive: Option<std::time::Duration>,
+    /// Address to connect from, None lets the operating system choose
+    pub local_address: Option<std::net::IpAddr>,
+    /// We warn if the Date header of a response is further off than this
+    pub clock_skew_threshold: std::time::Duration,
+}
+
+/// For requests other than pushing, which override the timeouts and the local address
+impl From<&config::ClientConfig> for Api {
+    fn from(client_config: &config::ClientConfig) -> Self {
+        Self {
+            use_proxy: client_config.use_proxy,
+            proxy: client_config.proxy.clone(),
+            tls_policy: client_config.tls_policy.clone(),
+            extra_root_certs: client_config.extra_root_certs.clone(),
+            tls_servername: client_config.tls_servername.clone(),
+            connect_timeout: client_config.connect_timeout,
+            request_timeout: None,
+            tcp_keepalive: None,
+            local_address: None,
+            clock_skew_threshold: client_config.clock_skew_threshold,
+        }
+    }
 }
 
 impl Api {
+    /// With a TLS server name, the request URL carries this name instead of the server, st. it
+    /// is sent via SNI. We still connect to the address of the server from base_url.
+    fn request(
+        &self,
+        method: reqwest::Method,
+        base_url: &reqwest::Url,
+        endpoint_segments: &[&str],
+        handshake_credentials: Option<certs::HandshakeCredentials>,
+    ) -> AnyhowResult<reqwest::blocking::RequestBuilder> {
+        let mut url = Self::endpoint_url(base_url, endpoint_segments)?;
+        let (use_proxy, proxy) = proxy::for_host(
+            self.use_proxy,
+            self.proxy.as_ref(),
+            base_url.host_str().unwrap_or_default(),
+        );
+        let server_address = match &self.tls_servername {
+            None => None,
+            Some(tls_servername) => {
+                // Resolved for every request, st. we follow changes of the DNS records
+                let addresses = base_url
+                    .socket_addrs(|| None)
+                    .context(format!("Failed to resolve {}", base_url))?;
+                // Through a proxy, we can't tell which addresses are reachable from there
+                let address = match proxy.is_none() && !use_proxy {
+                    true => first_reachable(addresses, self.connect_timeout),
+                    false => addresses.into_iter().next(),
+                }
+                .context(format!("Failed to resolve {}", base_url))?;
+                url.set_host(Some(tls_servername))
+                    .context(format!("Invalid TLS server name '{}'", tls_servername))?;
+                Some((tls_servername.as_str(), address))
+            }
+        };
+        let request_builder = certs::client_builder(
+            handshake_credentials,
+            use_proxy,
+            proxy,
+            &self.tls_policy,
+            &self.extra_root_certs,
+            server_address,
+            self.connect_timeout,
+        )?
+        .tcp_keepalive(self.tcp_keepalive)
+        .local_address(self.local_address)
+        .build()?
+        .request(method, url);
+        Ok(match self.request_timeout {
+            Some(request_timeout) => request_builder.timeout(request_timeout),
+            None => request_builder,
+        })
+    }
+
     fn endpoint_url(
         base_url: &reqwest::Url,
         endpoint_segments: &[&str],
@@ -133,6 +323,13 @@ impl Api {
         ))
     }
 
+    fn error_response(status: StatusCode, body: Option<String>) -> ResponseError {
+        ResponseError {
+            status,
+            description: Api::error_response_description(status, body),
+        }
+    }
+
     fn error_response_description(status: StatusCode, body: Option<String>) -> String {
         match body {
             None => format!(
@@ -155,10 +352,7 @@ impl Api {
         if status == StatusCode::NO_CONTENT {
             Ok(())
         } else {
-            Err(anyhow!(Api::error_response_description(
-                status,
-                response.text().ok()
-            )))
+            Err(anyhow!(Api::error_response(status, response.text().ok())))
         }
     }
 }
@@ -171,17 +365,20 @@ impl Pairing for Api {
         csr: String,
         credentials: &types::Credentials,
     ) -> AnyhowResult<PairingResponse> {
-        let response = certs::client(
-            root_cert.map(|r| certs::HandshakeCredentials {
-                server_root_cert: r,
-                client_identity: None,
-            }),
-            self.use_proxy,
-        )?
-        .post(Self::endpoint_url(base_url, &["pairing"])?)
-        .basic_auth(&credentials.username, Some(&credentials.password))
-        .json(&PairingBody { csr })
-        .send()?;
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                base_url,
+                &["pairing"],
+                root_cert.map(|r| certs::HandshakeCredentials {
+                    server_root_cert: r,
+                    pinned_fingerprint: None,
+                    client_identity: None,
+                }),
+            )?
+            .basic_auth(&credentials.username, Some(&credentials.password))
+            .json(&PairingBody { csr })
+            .send_within(self.connect_timeout, self.clock_skew_threshold)?;
         let status = response.status();
 
         if status == StatusCode::OK {
@@ -189,10 +386,7 @@ impl Pairing for Api {
             serde_json::from_str::<PairingResponse>(&body)
                 .context(format!("Error parsing this response body: {}", body))
         } else {
-            Err(anyhow!(Api::error_response_description(
-                status,
-                response.text().ok()
-            )))
+            Err(anyhow!(Api::error_response(status, response.text().ok())))
         }
     }
 }
@@ -207,20 +401,22 @@ impl Registration for Api {
         host_name: &str,
     ) -> AnyhowResult<()> {
         Api::check_response_204(
-            certs::client(
+            self.request(
+                reqwest::Method::POST,
+                base_url,
+                &["register_with_hostname"],
                 Some(certs::HandshakeCredentials {
                     server_root_cert: root_cert,
+                    pinned_fingerprint: None,
                     client_identity: None,
                 }),
-                self.use_proxy,
             )?
-            .post(Self::endpoint_url(base_url, &["register_with_hostname"])?)
             .basic_auth(&credentials.username, Some(&credentials.password))
             .json(&RegistrationWithHNBody {
                 uuid: uuid.to_owned(),
                 host_name: String::from(host_name),
             })
-            .send()?,
+            .send_within(self.connect_timeout, self.clock_skew_threshold)?,
         )
     }
 
@@ -233,20 +429,22 @@ impl Registration for Api {
         agent_labels: &types::AgentLabels,
     ) -> AnyhowResult<()> {
         Api::check_response_204(
-            certs::client(
+            self.request(
+                reqwest::Method::POST,
+                base_url,
+                &["register_with_labels"],
                 Some(certs::HandshakeCredentials {
                     server_root_cert: root_cert,
+                    pinned_fingerprint: None,
                     client_identity: None,
                 }),
-                self.use_proxy,
             )?
-            .post(Self::endpoint_url(base_url, &["register_with_labels"])?)
             .basic_auth(&credentials.username, Some(&credentials.password))
             .json(&RegistrationWithALBody {
                 uuid: uuid.to_owned(),
                 agent_labels: agent_labels.clone(),
             })
-            .send()?,
+            .send_within(self.connect_timeout, self.clock_skew_threshold)?,
         )
     }
 }
@@ -260,14 +458,12 @@ impl AgentData for Api {
         monitoring_data: &[u8],
     ) -> AnyhowResult<()> {
         Api::check_response_204(
-            certs::client(
-                Some(connection.tls_handshake_credentials()?),
-                self.use_proxy,
-            )?
-            .post(Self::endpoint_url(
+            self.request(
+                reqwest::Method::POST,
                 base_url,
                 &["agent_data", &connection.uuid.to_string()],
-            )?)
+                Some(connection.tls_handshake_credentials()?),
+            )?
             .header("compression", compression_algorithm)
             .multipart(
                 reqwest::blocking::multipart::Form::new().part(
@@ -278,7 +474,7 @@ impl AgentData for Api {
                         .file_name("agent_data"),
                 ),
             )
-            .send()?,
+            .send_within(self.connect_timeout, self.clock_skew_threshold)?,
         )
     }
 }
@@ -289,15 +485,14 @@ impl Status for Api {
         base_url: &reqwest::Url,
         connection: &config::TrustedConnection,
     ) -> AnyhowResult<StatusResponse> {
-        let response = certs::client(
-            Some(connection.tls_handshake_credentials()?),
-            self.use_proxy,
-        )?
-        .get(Self::endpoint_url(
-            base_url,
-            &["registration_status", &connection.uuid.to_string()],
-        )?)
-        .send()?;
+        let response = self
+            .request(
+                reqwest::Method::GET,
+                base_url,
+                &["registration_status", &connection.uuid.to_string()],
+                Some(connection.tls_handshake_credentials()?),
+            )?
+            .send_within(self.connect_timeout, self.clock_skew_threshold)?;
 
         match response.status() {
             StatusCode::OK => {
@@ -305,10 +500,7 @@ impl Status for Api {
                 Ok(serde_json::from_str::<StatusResponse>(&body)
                     .context(format!("Failed to deserialize response body: {}", body))?)
             }
-            _ => bail!(Api::error_response_description(
-                response.status(),
-                response.text().ok()
-            )),
+            status => bail!(Api::error_response(status, response.text().ok())),
         }
     }
 }
@@ -330,6 +522,97 @@ mod test_api {
         );
     }
 
+    fn api(tls_servername: Option<&str>) -> Api {
+        Api {
+            use_proxy: false,
+            proxy: None,
+            tls_policy: certs::TlsPolicy::default(),
+            extra_root_certs: vec![],
+            tls_servername: tls_servername.map(String::from),
+            connect_timeout: std::time::Duration::from_secs(1),
+            request_timeout: None,
+            tcp_keepalive: None,
+            local_address: None,
+            clock_skew_threshold: std::time::Duration::from_secs(60),
+        }
+    }
+
+    fn request_url(api: &Api) -> reqwest::Url {
+        api.request(
+            reqwest::Method::GET,
+            &reqwest::Url::parse("https://127.0.0.1:7766/site2").unwrap(),
+            &["some", "endpoint"],
+            None,
+        )
+        .unwrap()
+        .build()
+        .unwrap()
+        .url()
+        .clone()
+    }
+
+    #[test]
+    fn test_request_tls_servername() {
+        assert_eq!(
+            request_url(&api(None)).to_string(),
+            "https://127.0.0.1:7766/site2/agent-receiver/some/endpoint"
+        );
+        assert_eq!(
+            request_url(&api(Some("receiver.example.com"))).to_string(),
+            "https://receiver.example.com:7766/site2/agent-receiver/some/endpoint"
+        );
+    }
+
+    #[test]
+    fn test_first_reachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let timeout = std::time::Duration::from_secs(1);
+        assert_eq!(
+            first_reachable(vec![unreachable, reachable], timeout),
+            Some(reachable)
+        );
+        assert_eq!(
+            first_reachable(vec![unreachable, unreachable], timeout),
+            Some(unreachable)
+        );
+        assert_eq!(
+            first_reachable(vec![unreachable], timeout),
+            Some(unreachable)
+        );
+        assert_eq!(first_reachable(vec![], timeout), None);
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        // The kernel completes the TCP handshake, but nobody ever answers the TLS handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let started = std::time::Instant::now();
+        let err = api(None)
+            .pair(
+                &reqwest::Url::parse(&format!("https://{}/site2", listener.local_addr().unwrap()))
+                    .unwrap(),
+                None,
+                String::from("csr"),
+                &types::Credentials {
+                    username: String::from("user"),
+                    password: String::from("password"),
+                },
+            )
+            .err()
+            .unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        assert!(err.to_string().starts_with("Connect timed out after 1s"));
+        assert_eq!(
+            crate::exit_codes::ExitCode::from(&err),
+            crate::exit_codes::ExitCode::Network
+        );
+    }
+
     #[test]
     fn test_error_response_description_body_missing() {
         assert_eq!(
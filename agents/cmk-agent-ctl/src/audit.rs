@@ -0,0 +1,440 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Append-only record of who changed the registered connections, as JSON lines. This is
+//! separate from the operational log, which may be filtered, rotated or turned off.
+//!
+//! The changes are determined by comparing the registry before the mode ran with what ended up
+//! on disk afterwards, st. we record what was actually persisted, also if a mode failed halfway.
+
+use crate::{config, site_spec};
+use anyhow::{Context, Result as AnyhowResult};
+use log::error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Register,
+    Import,
+    Delete,
+    Update,
+    TrustRoot,
+    Repair,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize, Debug)]
+struct Invoker {
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    /// Who ran us via sudo, since the user is root then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sudo_user: Option<String>,
+}
+
+static INVOKER: OnceLock<Invoker> = OnceLock::new();
+
+#[cfg(unix)]
+fn current_invoker() -> Invoker {
+    let uid = nix::unistd::getuid();
+    Invoker {
+        user: nix::unistd::User::from_uid(uid)
+            .ok()
+            .flatten()
+            .map(|user| user.name),
+        uid: Some(uid.as_raw()),
+        sudo_user: std::env::var("SUDO_USER").ok(),
+    }
+}
+
+#[cfg(windows)]
+fn current_invoker() -> Invoker {
+    Invoker {
+        user: std::env::var("USERNAME").ok(),
+        uid: None,
+        sudo_user: None,
+    }
+}
+
+/// Must be called before we switch to the agent user, otherwise we would always record the
+/// agent user as the invoking one.
+pub fn capture_invoker() {
+    INVOKER.get_or_init(current_invoker);
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct ConnectionState {
+    site_id: Option<site_spec::SiteID>,
+    certificate: String,
+    root_cert: String,
+    receiver_port: Option<u16>,
+}
+
+type Snapshot = BTreeMap<uuid::Uuid, ConnectionState>;
+
+fn snapshot(registry: &config::Registry) -> Snapshot {
+    registry
+        .push_connections()
+        .chain(registry.standard_pull_connections())
+        .map(|(site_id, connection)| {
+            (
+                connection.trust.uuid,
+                ConnectionState {
+                    site_id: Some(site_id.clone()),
+                    certificate: connection.trust.certificate.clone(),
+                    root_cert: connection.trust.root_cert.clone(),
+                    receiver_port: Some(connection.receiver_port),
+                },
+            )
+        })
+        .chain(registry.imported_pull_connections().map(|connection| {
+            (
+                connection.uuid,
+                ConnectionState {
+                    site_id: None,
+                    certificate: connection.certificate.clone(),
+                    root_cert: connection.root_cert.clone(),
+                    receiver_port: None,
+                },
+            )
+        }))
+        .collect()
+}
+
+fn changes<'a>(
+    before: &'a Snapshot,
+    after: &'a Snapshot,
+) -> Vec<(Change, &'a uuid::Uuid, &'a ConnectionState)> {
+    let mut changes = vec![];
+    for (uuid, state) in before {
+        match after.get(uuid) {
+            None => changes.push((Change::Removed, uuid, state)),
+            Some(new_state) if new_state != state => {
+                changes.push((Change::Changed, uuid, new_state))
+            }
+            Some(_) => {}
+        }
+    }
+    for (uuid, state) in after {
+        if !before.contains_key(uuid) {
+            changes.push((Change::Added, uuid, state));
+        }
+    }
+    changes
+}
+
+#[derive(Serialize, Debug)]
+struct Event<'a> {
+    timestamp: String,
+    action: Action,
+    result: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change: Option<Change>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    /// The connection as given on the command line, for failures which changed nothing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    invoker: &'a Invoker,
+}
+
+pub struct Audit {
+    path: PathBuf,
+    action: Action,
+    target: Option<String>,
+    before: Snapshot,
+}
+
+impl Audit {
+    pub fn start(
+        path: &Path,
+        action: Action,
+        target: Option<String>,
+        registry: &config::Registry,
+    ) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            action,
+            target,
+            before: snapshot(registry),
+        }
+    }
+
+    fn events<'a>(
+        &'a self,
+        after: &'a Snapshot,
+        result: &AnyhowResult<()>,
+        timestamp: &str,
+        invoker: &'a Invoker,
+    ) -> Vec<Event<'a>> {
+        let event = |result: Outcome| Event {
+            timestamp: String::from(timestamp),
+            action: self.action,
+            result,
+            change: None,
+            site_id: None,
+            uuid: None,
+            target: None,
+            error: None,
+            invoker,
+        };
+        let mut events: Vec<Event> = changes(&self.before, after)
+            .into_iter()
+            .map(|(change, uuid, state)| Event {
+                change: Some(change),
+                site_id: state.site_id.as_ref().map(site_spec::SiteID::to_string),
+                uuid: Some(uuid.to_string()),
+                ..event(Outcome::Success)
+            })
+            .collect();
+        match result {
+            Err(err) => events.push(Event {
+                target: self.target.as_deref(),
+                error: Some(format!("{:#}", err)),
+                ..event(Outcome::Failure)
+            }),
+            // Even if nothing changed, eg. when deleting all of no connections
+            Ok(()) if events.is_empty() => events.push(Event {
+                target: self.target.as_deref(),
+                ..event(Outcome::Success)
+            }),
+            Ok(()) => {}
+        }
+        events
+    }
+
+    fn write(&self, events: &[Event]) -> AnyhowResult<()> {
+        let mut lines = String::new();
+        for event in events {
+            lines.push_str(&serde_json::to_string(event)?);
+            lines.push('\n');
+        }
+        let mut open_options = std::fs::OpenOptions::new();
+        open_options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+        let mut file = open_options.open(&self.path)?;
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Records the outcome of the mode. If this fails, a successful mode fails nevertheless,
+    /// since its change would go unrecorded. A failing mode keeps its own error.
+    pub fn finish(self, registry_path: &Path, result: AnyhowResult<()>) -> AnyhowResult<()> {
+        let audited = config::Registry::from_file(registry_path)
+            .context("Failed to load the connection registry")
+            .and_then(|registry| {
+                let after = snapshot(&registry);
+                let timestamp = time::OffsetDateTime::now_utc()
+                    .format(&time::format_description::well_known::Rfc3339)?;
+                self.write(&self.events(
+                    &after,
+                    &result,
+                    &timestamp,
+                    INVOKER.get_or_init(current_invoker),
+                ))
+            })
+            .with_context(|| format!("Failed to write audit record to {}", self.path.display()));
+        match (result, audited) {
+            (result, Ok(())) => result,
+            (Ok(()), Err(err)) => Err(err),
+            (Err(err), Err(audit_err)) => {
+                error!("{:#}", audit_err);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::str::FromStr;
+
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_PULL: &str = "b1e0d4c4-1bb1-4a39-8bd6-8e5b1b7c3b6d";
+
+    fn registry(path: &Path) -> config::Registry {
+        let mut registry = config::Registry::new(path).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            config::TrustedConnectionWithRemote::from(UUID_PUSH),
+        );
+        registry
+    }
+
+    fn invoker() -> Invoker {
+        Invoker {
+            user: Some(String::from("root")),
+            uid: Some(0),
+            sudo_user: Some(String::from("admin")),
+        }
+    }
+
+    fn read_events(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_changes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(&tmp_dir.path().join("registry.json"));
+        let before = snapshot(&registry);
+        registry
+            .delete_standard_connection(&site_spec::SiteID::from_str("server/push-site").unwrap())
+            .unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Pull,
+            &site_spec::SiteID::from_str("server/pull-site").unwrap(),
+            config::TrustedConnectionWithRemote::from(UUID_PULL),
+        );
+        let after = snapshot(&registry);
+        assert_eq!(
+            changes(&before, &after)
+                .iter()
+                .map(|(change, uuid, _)| (*change, uuid.to_string()))
+                .collect::<Vec<(Change, String)>>(),
+            [
+                (Change::Removed, String::from(UUID_PUSH)),
+                (Change::Added, String::from(UUID_PULL))
+            ]
+        );
+        registry
+            .get_mutable(&site_spec::SiteID::from_str("server/pull-site").unwrap())
+            .unwrap()
+            .receiver_port = 8001;
+        let changed = snapshot(&registry);
+        assert_eq!(changes(&after, &changed)[0].0, Change::Changed);
+        assert!(changes(&changed, &changed).is_empty());
+    }
+
+    #[test]
+    fn test_events() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let registry = registry(&tmp_dir.path().join("registry.json"));
+        let audit = Audit::start(
+            &tmp_dir.path().join("audit.log"),
+            Action::Delete,
+            Some(String::from("server/push-site")),
+            &registry,
+        );
+        let invoker = invoker();
+        let deleted = Snapshot::new();
+        let events = audit.events(&deleted, &Ok(()), "2022-01-01T00:00:00Z", &invoker);
+        assert_eq!(
+            serde_json::to_value(&events).unwrap(),
+            serde_json::json!([{
+                "timestamp": "2022-01-01T00:00:00Z",
+                "action": "delete",
+                "result": "success",
+                "change": "removed",
+                "site_id": "server/push-site",
+                "uuid": UUID_PUSH,
+                "user": "root",
+                "uid": 0,
+                "sudo_user": "admin",
+            }])
+        );
+        let unchanged = snapshot(&registry);
+        let events = audit.events(
+            &unchanged,
+            &Err(anyhow!("Site refused")),
+            "2022-01-01T00:00:00Z",
+            &invoker,
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].result, Outcome::Failure);
+        assert_eq!(events[0].target, Some("server/push-site"));
+        assert_eq!(events[0].error.as_deref(), Some("Site refused"));
+    }
+
+    #[test]
+    fn test_finish_appends() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let registry_path = tmp_dir.path().join("registry.json");
+        let audit_path = tmp_dir.path().join("audit.log");
+        let registry = registry(&registry_path);
+        Audit::start(
+            &audit_path,
+            Action::Register,
+            None,
+            &config::Registry::new(&registry_path).unwrap(),
+        )
+        .finish(&registry_path, registry.save().map_err(anyhow::Error::from))
+        .unwrap();
+        Audit::start(
+            &audit_path,
+            Action::Delete,
+            Some(String::from("server/other")),
+            &registry,
+        )
+        .finish(
+            &registry_path,
+            Err(anyhow!("Connection 'server/other' not found")),
+        )
+        .unwrap_err();
+        let events = read_events(&audit_path);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["action"], "register");
+        assert_eq!(events[0]["change"], "added");
+        assert_eq!(events[0]["uuid"], UUID_PUSH);
+        assert_eq!(events[1]["action"], "delete");
+        assert_eq!(events[1]["result"], "failure");
+        assert_eq!(events[1]["target"], "server/other");
+    }
+
+    #[test]
+    fn test_finish_write_failure() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let registry_path = tmp_dir.path().join("registry.json");
+        let audit_path = tmp_dir.path().join("missing").join("audit.log");
+        let registry = registry(&registry_path);
+        // The change went through, but it must not go unnoticed that it was not recorded
+        assert!(Audit::start(&audit_path, Action::Update, None, &registry)
+            .finish(&registry_path, Ok(()))
+            .unwrap_err()
+            .to_string()
+            .starts_with("Failed to write audit record to"));
+        // The error of the mode itself takes precedence
+        assert_eq!(
+            Audit::start(&audit_path, Action::Update, None, &registry)
+                .finish(&registry_path, Err(anyhow!("Site refused")))
+                .unwrap_err()
+                .to_string(),
+            "Site refused"
+        );
+    }
+}
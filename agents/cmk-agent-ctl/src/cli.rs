@@ -4,7 +4,7 @@
 
 #[cfg(windows)]
 use super::types;
-use super::{constants, site_spec};
+use super::{certs, config, constants, logging, proxy, site_spec, tls_debug};
 use clap::Parser;
 
 #[derive(Parser)]
@@ -17,23 +17,84 @@ pub struct LoggingOpts {
     /// level DEBUG.
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
+
+    /// Filter log output per module, e.g. "cmk_agent_ctl::certs=debug,info". Comma-separated
+    /// directives of the form [module=]level, where level is one of off, error, warn, info, debug
+    /// or trace. Module directives are applied on top of the verbosity set by -v, a directive
+    /// without module replaces it. The environment variable RUST_LOG takes the same syntax and
+    /// overrides both.
+    #[arg(long, value_parser = logging::parse_log_filter)]
+    pub log_filter: Option<String>,
+
+    /// Log the details of TLS handshakes: protocol versions, cipher suites, peer certificates
+    /// and the reason of failed handshakes. Only public handshake metadata is logged.
+    #[arg(long)]
+    pub tls_debug: bool,
+
+    /// DEBUGGING ONLY, NEVER USE IN PRODUCTION. Write the TLS session secrets to the file named
+    /// by the environment variable SSLKEYLOGFILE, eg. to decrypt captured traffic with
+    /// Wireshark. Anyone with access to this file can decrypt the agent output, so it is made
+    /// readable for the owner only.
+    #[arg(long)]
+    pub insecure_keylog: bool,
+
+    /// Format of the log output. With json, every log event is written as one JSON object.
+    #[arg(long, value_enum, default_value_t = logging::LogFormat::Text)]
+    pub log_format: logging::LogFormat,
+
+    /// Where to send log output. With syslog, nothing is written to stderr, use both to get
+    /// the log on stderr as well.
+    #[cfg(unix)]
+    #[arg(long, value_enum, default_value_t = logging::LogTarget::Stderr)]
+    pub log_target: logging::LogTarget,
+
+    /// Syslog facility to log to, only relevant for --log-target syslog and both.
+    #[cfg(unix)]
+    #[arg(long, value_enum, default_value_t = logging::SyslogFacility::Daemon)]
+    pub syslog_facility: logging::SyslogFacility,
+
+    /// Use this file as connection registry instead of registered_connections.json in the home
+    /// directory, eg. for several independent instances on one host. The environment variable
+    /// CMK_AGENT_CTL_REGISTRY takes precedence over this option. Several instances sharing one
+    /// registry are unsupported.
+    #[arg(long, value_name = "PATH")]
+    pub registry: Option<std::path::PathBuf>,
+
+    /// Read the settings from this TOML file instead of cmk-agent-ctl.toml, eg. to keep the
+    /// pull settings out of the service definition. Command line options take precedence over
+    /// the settings in the file.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<std::path::PathBuf>,
+
+    /// Create the temporary files of atomic writes, eg. of the connection registry, in this
+    /// directory instead of next to the file they replace, eg. if that directory is read-only.
+    /// On another file system, the files are copied over instead, which is not atomic. The
+    /// environment variable CMK_AGENT_CTL_TMP_DIR takes precedence over this option.
+    #[arg(long, value_name = "PATH")]
+    pub tmp_dir: Option<std::path::PathBuf>,
 }
 
 impl LoggingOpts {
     fn logging_level(&self) -> String {
-        match self.verbose {
-            2.. => String::from("debug"),
-            1 => String::from("info"),
-            _ => String::from("warn"),
+        let level = match self.verbose {
+            2.. => "debug",
+            1 => "info",
+            _ => "warn",
+        };
+        let spec = logging::log_spec(level, self.log_filter.as_deref());
+        match self.tls_debug {
+            true => format!("{},{}", spec, tls_debug::LOG_DIRECTIVE),
+            false => spec,
         }
     }
 }
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct ClientOpts {
-    /// Detect and use proxy settings configured on this system for outgoing HTTPS connections.
-    /// The default is to ignore configured proxies and to connect directly.
+    /// Detect and use proxy settings configured on this system for outgoing HTTPS connections,
+    /// including the environment variables HTTPS_PROXY, ALL_PROXY and NO_PROXY. The default is to
+    /// ignore configured proxies and to connect directly.
     #[arg(short = 'd', long)]
     pub detect_proxy: bool,
 
@@ -42,32 +103,193 @@ pub struct ClientOpts {
     /// at this stage, see werk #14715.
     #[arg(long)]
     pub validate_api_cert: bool,
+
+    /// HTTP proxy to tunnel outgoing connections through, in the format
+    /// [http://][user:password@]host[:port]. If not given and --detect-proxy is set, the
+    /// environment variables HTTPS_PROXY and https_proxy are evaluated. With --detect-proxy, hosts
+    /// matched by NO_PROXY are connected to directly.
+    #[arg(long, value_parser = proxy::parse_proxy_url)]
+    pub proxy: Option<reqwest::Url>,
+
+    /// SOCKS5 proxy to tunnel outgoing connections through, in the format
+    /// [socks5://][user:password@]host[:port]. If neither this nor --proxy is given,
+    /// --detect-proxy is set and HTTPS_PROXY is not, a SOCKS5 proxy from ALL_PROXY or all_proxy
+    /// is used.
+    #[arg(long, value_parser = proxy::parse_socks_proxy_url, conflicts_with = "proxy")]
+    pub socks_proxy: Option<reqwest::Url>,
+
+    /// Where to resolve the host names of the sites when connecting through a SOCKS5 proxy
+    #[arg(long, value_enum, default_value_t = proxy::SocksDns::Remote)]
+    pub socks_dns: proxy::SocksDns,
+
+    /// PEM file with additional CA certificates to trust when connecting to agent receivers, on
+    /// top of the root certificate stored for each connection. Server certificates issued by
+    /// these have to match the host name of the receiver.
+    #[arg(long)]
+    pub ca_file: Option<std::path::PathBuf>,
+
+    /// How to treat OCSP responses stapled by the agent receiver. Overrides 'ocsp_stapling' from
+    /// the config file, the default is to ignore them.
+    #[arg(long, value_enum)]
+    pub ocsp_stapling: Option<certs::OcspStapling>,
+
+    /// Server name to send via SNI when connecting to agent receivers, instead of the server we
+    /// connect to. Use this when reaching the receiver via an IP address or a load balancer
+    /// which routes by server name. When tunneling through a proxy, the proxy resolves this name.
+    #[arg(long, value_name = "NAME", value_parser = certs::parse_tls_servername)]
+    pub tls_servername: Option<String>,
+
+    /// Seconds to wait for the connection to the agent receiver or the REST API to be
+    /// established, including the TLS handshake [default: 10]
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds a single push may take, including sending the agent output. Pushing to a
+    /// receiver which takes in the data slower than that fails and is retried later with the
+    /// then current agent output. [default: 30]
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub push_timeout: Option<u64>,
+
+    /// Seconds a push connection may be idle before TCP keepalive probes are sent, st. stateful
+    /// firewalls and NAT gateways don't drop it while the receiver processes the data. 0 turns
+    /// keepalive off. Interval and number of probes are the ones of the operating system.
+    /// [default: 60]
+    #[arg(long, value_name = "SECONDS")]
+    pub push_keepalive: Option<u64>,
+
+    /// Local address to open push connections from, eg. to match firewall rules keyed on the
+    /// source address. Overrides 'push_source_address' from the config file. The source port is
+    /// still chosen by the operating system, and every push opens a new connection. Behind NAT,
+    /// the receiver sees the translated address instead, so firewall rules between the NAT
+    /// gateway and the receiver have to match that one.
+    #[arg(long, value_name = "ADDRESS")]
+    pub push_source_address: Option<std::net::IpAddr>,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct RegistrationArgsConnection {
-    /// Address of the Checkmk site in the format "<server>" or "<server>:<port>"
-    #[arg(long = "server", short = 's', value_parser = clap::value_parser!(site_spec::ServerSpec))]
-    pub server_spec: site_spec::ServerSpec,
+    /// Address of the Checkmk site in the format "<server>" or "<server>:<port>".
+    /// Can be repeated together with --site to register with multiple sites at once.
+    #[arg(
+        long = "server",
+        short = 's',
+        required_unless_present = "from_file",
+        value_parser = clap::value_parser!(site_spec::ServerSpec)
+    )]
+    pub server_spec: Vec<site_spec::ServerSpec>,
+
+    /// Name of the Checkmk site, one per --server
+    #[arg(long, short = 'i', required_unless_present = "from_file")]
+    pub site: Vec<String>,
+
+    /// Path under which a reverse proxy exposes the sites, eg. "/monitoring/checkmk" for
+    /// https://<server>/monitoring/checkmk/<site>. It is stored with the connection and used for
+    /// all later requests to the site, such as pushing. Overrides 'path_prefix' from the
+    /// config file, "" or "/" selects the root path.
+    #[arg(long, value_parser = site_spec::parse_path_prefix)]
+    pub path_prefix: Option<String>,
 
-    /// Name of the Checkmk site
-    #[arg(long, short = 'i')]
-    pub site: String,
+    /// Read the sites to register with from a JSON file instead of --server and --site. The file
+    /// contains a list of objects with the keys "server", "site" and optionally "port".
+    #[arg(long, conflicts_with_all = ["server_spec", "site"])]
+    pub from_file: Option<std::path::PathBuf>,
 
     /// API user to use for registration
-    #[arg(long, short = 'U')]
-    pub user: String,
+    #[arg(long, short = 'U', required_unless_present = "credential_helper")]
+    pub user: Option<String>,
 
     /// Password for API user. Can also be entered interactively.
     #[arg(long, short = 'P')]
     pub password: Option<String>,
 
+    /// Read the password for the API user from the first line of stdin, st. it neither shows up
+    /// in the process list nor in the shell history
+    #[arg(long, requires = "user", conflicts_with = "password")]
+    pub password_stdin: bool,
+
+    /// Get the credentials from the output of this command instead, e.g. a helper querying a
+    /// vault. Like git credential helpers, the command writes lines of the form key=value:
+    /// password, along with username unless --user is given. Other keys are ignored. The command
+    /// is run by the shell, its stderr is passed through. The credentials are neither logged nor
+    /// stored. If the command fails, we exit with code 8.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["password", "password_stdin"]
+    )]
+    pub credential_helper: Option<String>,
+
     /// Blindly trust the server certificate of the Checkmk site
     // We are consistent with agent updater, which uses "trust-cert"
     #[arg(long = "trust-cert")]
     pub trust_server_cert: bool,
 
+    /// Proceed even if the agent receiver presents a self-signed certificate, which is not issued
+    /// by a separate CA. Required together with --trust-cert or --trusted-fingerprint, in
+    /// interactive mode you are asked instead.
+    #[arg(long)]
+    pub accept_self_signed: bool,
+
+    /// Pin the SHA-256 fingerprint of the certificate the agent receiver presents right now.
+    /// Later connections are aborted if the receiver presents a different certificate, even if
+    /// it is signed by the site CA.
+    #[arg(long)]
+    pub pin_fingerprint: bool,
+
+    /// Only register if the SHA-256 fingerprint of the server certificate matches, instead of
+    /// asking interactively. Colons are optional, case does not matter.
+    #[arg(long, conflicts_with = "trust_server_cert")]
+    pub trusted_fingerprint: Option<String>,
+
+    /// Use the private key and certificate from this PKCS#12 bundle instead of requesting a
+    /// certificate from the site. The CN of the certificate has to be a UUID. Requires
+    /// --site-ca.
+    #[arg(long, requires = "site_ca")]
+    pub pkcs12: Option<std::path::PathBuf>,
+
+    /// Passphrase of the PKCS#12 bundle
+    #[arg(long, requires = "pkcs12")]
+    pub pkcs12_passphrase: Option<String>,
+
+    /// PEM file with the CA certificate of the site, which is used to verify the agent receiver.
+    /// Without pairing, the site doesn't send it.
+    #[arg(long, requires = "pkcs12")]
+    pub site_ca: Option<std::path::PathBuf>,
+
+    /// Type of the key pair to generate for the connection. The site has to be able to issue
+    /// certificates for this type of key.
+    #[arg(long, value_enum, default_value_t, conflicts_with = "pkcs12")]
+    pub key_type: certs::KeyType,
+
+    /// Organization (O) in the subject of the certificate signing request, eg. if a CA in front
+    /// of the site enforces naming conventions. The CN is always the UUID of the connection.
+    #[arg(long, conflicts_with = "pkcs12")]
+    pub csr_organization: Option<String>,
+
+    /// Organizational unit (OU) in the subject of the certificate signing request
+    #[arg(long, conflicts_with = "pkcs12")]
+    pub csr_organizational_unit: Option<String>,
+
+    /// DNS name to request as subject alternative name. Can be repeated.
+    #[arg(long = "csr-dns-name", value_name = "NAME", conflicts_with = "pkcs12")]
+    pub csr_dns_names: Vec<String>,
+
+    /// IP address to request as subject alternative name. Can be repeated.
+    #[arg(long = "csr-ip-address", value_name = "IP", conflicts_with = "pkcs12")]
+    pub csr_ip_addresses: Vec<std::net::IpAddr>,
+
+    /// Label to store with the connection in the form KEY=VALUE, e.g. env=prod. Can be repeated.
+    /// Labels only serve to group connections, e.g. in the status output, they are not sent to
+    /// the site.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_agent_labels)]
+    pub labels: Vec<(String, String)>,
+
+    /// Register without ever serving agent output for the connection, eg. for hosts which only
+    /// need to be known to the site. Pull requests get empty output, pushes are skipped.
+    #[arg(long)]
+    pub disable_agent_output: bool,
+
     #[clap(flatten)]
     pub client_opts: ClientOpts,
 }
@@ -81,10 +303,71 @@ pub struct RegistrationArgsHostName {
     #[clap(flatten)]
     pub logging_opts: LoggingOpts,
 
-    /// Name of this host in the monitoring site
+    /// Name of this host in the monitoring site. Defaults to the host name of this system when
+    /// registering, but is mandatory for registrations by proxy.
     // We are consistent with agent updater, which uses "hostname", not "host-name".
     #[arg(long, short = 'H', long = "hostname", value_parser = clap::value_parser!(String))]
-    pub host_name: String,
+    pub host_name: Option<String>,
+
+    /// Read the host name from this file instead, e.g. /etc/cmk-hostname or an instance-id file
+    #[arg(long, conflicts_with = "host_name")]
+    pub hostname_file: Option<std::path::PathBuf>,
+
+    /// Take the host name from the output of this command instead, e.g. a query of the cloud
+    /// metadata service. The command is run by the shell.
+    #[arg(long, conflicts_with_all = ["host_name", "hostname_file"])]
+    pub hostname_command: Option<String>,
+
+    /// Only check that the site is reachable and accepts the credentials, without registering.
+    /// The registered connections are left untouched. Note that the credentials are checked by
+    /// pairing with the site, which thus issues a client certificate that is thrown away.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Register even if there is a connection to the site already, replacing it. Without this,
+    /// registering again fails, since it would leave the previous registration behind.
+    #[arg(long, visible_alias = "reregister")]
+    pub force: bool,
+
+    /// Number of times to retry registering after transient errors, e.g. if the site is not
+    /// reachable. Rejected credentials are not retried.
+    #[arg(long, default_value_t = 0)]
+    pub register_retries: u32,
+
+    /// Seconds to wait between two registration attempts
+    #[arg(long, default_value_t = 10)]
+    pub register_retry_delay: u64,
+
+    /// Seconds to wait for the site to come online before registering, e.g. while it is still
+    /// starting up. Only an unreachable site is waited for, rejected credentials fail right away.
+    #[arg(long, value_name = "SECONDS")]
+    pub wait: Option<u64>,
+
+    /// Output format of the registration result. With JSON, errors are reported as JSON object
+    /// on stdout as well. Only supported when registering with a single site.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with = "dry_run")]
+    pub output_format: OutputFormat,
+
+    /// Run this command after each successful registration, e.g. to tag the host in a CMDB. The
+    /// command is run by the shell and gets CMK_SITE_ID, CMK_CONNECTION_UUID and
+    /// CMK_CONNECTION_TYPE in its environment. Its stdout is discarded. A failing hook is logged,
+    /// the registration is kept either way.
+    #[arg(long, value_name = "COMMAND", conflicts_with = "dry_run")]
+    pub post_register_hook: Option<String>,
+
+    /// Seconds after which the post-register hook is killed and considered failed
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "post_register_hook"
+    )]
+    pub post_register_hook_timeout: u64,
+
+    /// Exit non-zero if the post-register hook fails. The registration is kept nonetheless.
+    #[arg(long, requires = "post_register_hook")]
+    pub fail_on_hook_error: bool,
 }
 
 #[derive(Parser)]
@@ -109,17 +392,82 @@ fn parse_agent_labels(s: &str) -> Result<(String, String), String> {
     }
 }
 
+fn parse_connection_type(s: &str) -> Result<config::ConnectionType, String> {
+    match s {
+        "push" | "push-agent" => Ok(config::ConnectionType::Push),
+        "pull" | "pull-agent" => Ok(config::ConnectionType::Pull),
+        _ => Err(format!(
+            "invalid connection type `{}`, expected `push` or `pull`",
+            s
+        )),
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct StatusArgs {
-    /// Write output in JSON format
-    #[arg(long)]
+    /// Write output in JSON format (same as '--output-format json')
+    #[arg(long, conflicts_with = "output_format")]
     pub json: bool,
 
+    /// Output format. The JSON schema is versioned via its top-level 'format_version' field.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
     /// Do not query the remote about our status
     #[arg(long)]
     pub no_query_remote: bool,
 
+    /// Show the connection counters of the running pull listener
+    #[arg(long)]
+    pub counters: bool,
+
+    /// When checking whether the agent channel is reachable, also wait for the first bytes of
+    /// agent output and report the agent version found there. Note that this runs the agent
+    /// once.
+    #[arg(long)]
+    pub probe_agent_output: bool,
+
+    /// Only show connections of this type (push or pull)
+    #[arg(long, value_parser = parse_connection_type)]
+    pub connection_type: Option<config::ConnectionType>,
+
+    /// Only show the connection to this site, specified as '<servername>/<site>'
+    #[arg(long, value_parser = clap::value_parser!(site_spec::SiteID))]
+    pub site: Option<site_spec::SiteID>,
+
+    /// Only show connections with this label, in the form KEY=VALUE. Can be repeated, all of
+    /// the labels have to match.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_agent_labels)]
+    pub labels: Vec<(String, String)>,
+
+    /// Warn about connection certificates expiring within this number of days.
+    /// Expired certificates result in exit code 7.
+    #[arg(long, value_name = "DAYS", default_value_t = constants::CERT_EXPIRY_WARNING_DAYS)]
+    pub cert_expiry_warning_days: u32,
+
+    /// Keep running and write the status as one JSON object per line at every interval, until
+    /// interrupted. Always uses the JSON format. Expired certificates are reported in the
+    /// output, but don't end the loop.
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Seconds between two status objects with --watch
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        requires = "watch",
+        default_value_t = constants::DEFAULT_STATUS_WATCH_INTERVAL,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub interval: u64,
+
     #[clap(flatten)]
     pub client_opts: ClientOpts,
 
@@ -129,18 +477,78 @@ pub struct StatusArgs {
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("selection").required(true).multiple(true).args(["CONNECTION", "labels", "site_glob", "site"])))]
+#[command(group(clap::ArgGroup::new("forceable").multiple(true).args(["labels", "site_glob"])))]
 pub struct DeleteArgs {
     /// The connection to delete
+    #[arg(name = "CONNECTION", conflicts_with_all = ["labels", "site_glob"])]
+    pub connection: Option<String>,
+
+    /// Delete all connections with this label, in the form KEY=VALUE. Can be repeated, all of
+    /// the labels have to match. Requires --force.
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_agent_labels, requires = "force")]
+    pub labels: Vec<(String, String)>,
+
+    /// Delete all connections to sites matching this pattern, eg. 'staging-*/*'. '*' matches
+    /// any number of characters, '?' a single one. Imported connections have no site and never
+    /// match. Requires --force.
+    #[arg(long, value_name = "PATTERN", requires = "force")]
+    pub site_glob: Option<String>,
+
+    /// Delete all connections to this site, in the form SERVER/SITE, eg. when decommissioning
+    /// it. This includes connections registered with the server spelled differently, like in
+    /// upper case. Requires --all, succeeds if there are none.
+    #[arg(long, value_name = "SERVER/SITE", value_parser = clap::value_parser!(site_spec::SiteID), requires = "all", conflicts_with_all = ["CONNECTION", "labels", "site_glob"])]
+    pub site: Option<site_spec::SiteID>,
+
+    /// Confirms deleting all connections to the site given by --site
+    #[arg(long, requires = "site")]
+    pub all: bool,
+
+    /// Confirms deleting all connections matching --label or --site-glob
+    #[arg(long, requires = "forceable")]
+    pub force: bool,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct PushArgs {
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct VerifyArgs {
+    /// The connection to verify
     #[arg(name = "CONNECTION")]
     pub connection: String,
 
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
     #[clap(flatten)]
     pub logging_opts: LoggingOpts,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
-pub struct PushArgs {
+pub struct UpdateConnectionArgs {
+    /// The connection to update
+    #[arg(name = "CONNECTION")]
+    pub connection: String,
+
+    /// The new port of the agent receiver of the site. The site has to answer on this port
+    /// before the connection is changed.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub receiver_port: u16,
+
     #[clap(flatten)]
     pub client_opts: ClientOpts,
 
@@ -148,6 +556,141 @@ pub struct PushArgs {
     pub logging_opts: LoggingOpts,
 }
 
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct DumpCertsArgs {
+    /// Only show the certificates of this connection, specified by its site address or UUID
+    #[arg(name = "CONNECTION")]
+    pub connection: Option<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct HealthcheckArgs {
+    /// Give up on each check after this number of seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = constants::AGENT_CHANNEL_PROBE_TIMEOUT, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: u64,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct ShowConfigArgs {
+    #[clap(flatten)]
+    pub pull_opts: PullOpts,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct TestPullArgs {
+    /// The pull connection to use, specified by its site address or UUID. Can be omitted if
+    /// there is only one.
+    #[arg(name = "CONNECTION")]
+    pub connection: Option<String>,
+
+    /// Print the complete agent output instead of a summary
+    #[arg(long)]
+    pub full: bool,
+
+    /// Number of lines of the agent output shown in the summary
+    #[arg(
+        long,
+        value_name = "LINES",
+        default_value_t = 10,
+        conflicts_with = "full"
+    )]
+    pub lines: usize,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct SelfTestArgs {
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[cfg(feature = "dev")]
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct GenTestCertsArgs {
+    /// Directory to write the certificates and keys to, created if missing. Existing files are
+    /// never overwritten.
+    #[arg(name = "DIRECTORY")]
+    pub directory: std::path::PathBuf,
+
+    /// Common name of the CA
+    #[arg(long, default_value = "Test CA")]
+    pub ca_name: String,
+
+    /// Common name and DNS name of the receiver certificate
+    #[arg(long, default_value = "localhost")]
+    pub receiver_name: String,
+
+    /// UUID the controller certificate is issued for, a random one if omitted
+    #[arg(long, value_name = "UUID")]
+    pub controller_uuid: Option<uuid::Uuid>,
+
+    /// Validity of the certificates
+    #[arg(long, value_name = "DAYS", default_value_t = 365, value_parser = clap::value_parser!(u32).range(1..))]
+    pub valid_days: u32,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct TrustRootArgs {
+    /// The connection to manage the trusted root certificates of
+    #[arg(name = "CONNECTION")]
+    pub connection: String,
+
+    /// Additionally trust the root certificate(s) from this PEM file
+    #[arg(long, conflicts_with = "remove")]
+    pub add: Option<std::path::PathBuf>,
+
+    /// Stop trusting the root certificate with this SHA-256 fingerprint
+    #[arg(long)]
+    pub remove: Option<String>,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct RepairArgs {
+    /// Disable broken connections instead of removing them, st. they can still be inspected
+    #[arg(long)]
+    pub disable: bool,
+
+    /// Only report the broken connections, without changing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct DeleteAllArgs {
@@ -155,6 +698,10 @@ pub struct DeleteAllArgs {
     #[arg(long)]
     pub enable_insecure_connections: bool,
 
+    /// Do not ask for confirmation before deleting
+    #[arg(long)]
+    pub force: bool,
+
     #[clap(flatten)]
     pub logging_opts: LoggingOpts,
 }
@@ -163,9 +710,107 @@ pub struct DeleteAllArgs {
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct PullOpts {
-    /// TCP port to listen on for incoming pull connections
-    #[arg(long, short = 'P', value_parser = site_spec::parse_port)]
-    pub port: Option<u16>,
+    /// TCP port to listen on for incoming pull connections. Repeat the option or separate the
+    /// ports by commas to listen on several ports.
+    #[arg(long, short = 'P', value_parser = site_spec::parse_port, value_delimiter = ',')]
+    pub port: Vec<u16>,
+
+    /// Minimum TLS protocol version to accept for incoming pull connections
+    #[arg(long, value_enum)]
+    pub tls_min_version: Option<certs::TlsVersion>,
+
+    /// Serve the agent output from a cache for this many seconds after collecting it,
+    /// instead of querying the agent for every pull request
+    #[arg(long)]
+    pub cache_ttl: Option<u64>,
+
+    /// Address to serve metrics in Prometheus text format on, eg. 127.0.0.1:9999. Metrics are
+    /// not exposed unless this is given.
+    #[arg(long)]
+    pub metrics_listen: Option<std::net::SocketAddr>,
+
+    /// Address to answer plain HTTP health probes of load balancers on, eg. 0.0.0.0:8080, see
+    /// GET /health. Must not be a pull port. Not served unless this is given.
+    #[arg(long)]
+    pub health_listen: Option<std::net::SocketAddr>,
+
+    /// Maximum number of concurrent pull connections per source IP [default: 3]
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// Number of threads handling pull connections. The default of 1 suffices unless many
+    /// sites poll at once; 0 starts one thread per CPU core. [default: 1]
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+
+    /// Length of the queue of pull connections not accepted yet, raise it if connections are
+    /// refused when many sites poll at once. The OS may cap it further, on Linux at
+    /// net.core.somaxconn. [default: 4096]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=65535))]
+    pub listen_backlog: Option<u32>,
+
+    /// If a port is still in use, eg. by a previous instance which is shutting down, keep
+    /// trying for up to this many seconds before giving up [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    pub retry_bind: Option<u64>,
+
+    /// Limit the bytes per second sent to each single pull connection, eg. 1MiB/s. Overall
+    /// throughput still grows with the number of concurrent connections. [default: unlimited]
+    #[arg(long, value_name = "RATE", value_parser = config::parse_byte_rate)]
+    pub pull_bandwidth_limit: Option<u64>,
+
+    /// Size in bytes of the chunks in which the agent output is forwarded to pull connections,
+    /// at least 4096. Values above 1 MiB are capped. [default: 65536]
+    #[arg(long, value_name = "BYTES")]
+    pub io_chunk_size: Option<usize>,
+
+    /// Never write the connection registry, e.g. for containers which were registered when
+    /// building the image. This is also the case if the registry is on a read-only file system.
+    #[arg(long)]
+    pub registry_readonly: bool,
+
+    /// If loading the connection registry fails with an I/O error, eg. because it is on network
+    /// storage which is not mounted yet, keep trying for up to this many seconds. A registry
+    /// which can't be parsed fails right away. [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    pub registry_load_retry: Option<u64>,
+
+    /// Refuse to start if the connection registry does not exist. By default, a missing
+    /// registry is reported and we don't listen for pull requests until connections are
+    /// registered. An existing registry without connections is a valid state, in which we
+    /// listen, but trust nobody.
+    #[arg(long)]
+    pub require_registry: bool,
+
+    /// Accept pull connections from any address, ignoring the configured IP allowlist. Only meant
+    /// for isolated networks. Without this, a configured but empty allowlist denies everybody,
+    /// while no allowlist configured at all accepts everybody, as before allow_any existed.
+    #[arg(long)]
+    pub allow_any: bool,
+
+    /// INSECURE, only for lab setups: Serve pull connections without requiring client
+    /// certificates, anybody passing the IP allowlist gets the agent output. Refused unless an
+    /// IP allowlist is configured and allow_any is not set. There is no config file setting
+    /// for this, it has to be given on the command line every time.
+    #[arg(long)]
+    pub no_client_auth: bool,
+
+    /// Log why each rejected or failed pull connection was turned down, naming the counter it is
+    /// counted in along with the rule or phase which failed. Meant for troubleshooting.
+    #[arg(long)]
+    pub explain_rejections: bool,
+
+    /// Refuse to start if the certificate, private key or root certificate of any pull
+    /// connection can't be decoded or the certificate has expired. By default, such connections
+    /// are reported and the broken ones are not served, while the others are.
+    #[arg(long)]
+    pub strict_startup: bool,
+
+    /// What pull requests get if the agent channel can't be reached: "fail" aborts the
+    /// connection, "report" sends a minimal agent output telling the site what went wrong.
+    /// Overrides 'on_agent_unavailable' from the config file. [default: fail]
+    #[arg(long, value_enum)]
+    pub on_agent_unavailable: Option<config::AgentUnavailablePolicy>,
 
     /// Connection in format "type/peer"
     /// where
@@ -183,9 +828,114 @@ pub struct PullOpts {
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct PullOpts {
-    /// TCP port to listen on for incoming pull connections
-    #[arg(long, short = 'P', value_parser = site_spec::parse_port)]
-    pub port: Option<u16>,
+    /// TCP port to listen on for incoming pull connections. Repeat the option or separate the
+    /// ports by commas to listen on several ports.
+    #[arg(long, short = 'P', value_parser = site_spec::parse_port, value_delimiter = ',')]
+    pub port: Vec<u16>,
+
+    /// Minimum TLS protocol version to accept for incoming pull connections
+    #[arg(long, value_enum)]
+    pub tls_min_version: Option<certs::TlsVersion>,
+
+    /// Serve the agent output from a cache for this many seconds after collecting it,
+    /// instead of querying the agent for every pull request
+    #[arg(long)]
+    pub cache_ttl: Option<u64>,
+
+    /// Address to serve metrics in Prometheus text format on, eg. 127.0.0.1:9999. Metrics are
+    /// not exposed unless this is given.
+    #[arg(long)]
+    pub metrics_listen: Option<std::net::SocketAddr>,
+
+    /// Address to answer plain HTTP health probes of load balancers on, eg. 0.0.0.0:8080, see
+    /// GET /health. Must not be a pull port. Not served unless this is given.
+    #[arg(long)]
+    pub health_listen: Option<std::net::SocketAddr>,
+
+    /// Maximum number of concurrent pull connections per source IP [default: 3]
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// Number of threads handling pull connections. The default of 1 suffices unless many
+    /// sites poll at once; 0 starts one thread per CPU core. [default: 1]
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+
+    /// Length of the queue of pull connections not accepted yet, raise it if connections are
+    /// refused when many sites poll at once. The OS may cap it further, on Linux at
+    /// net.core.somaxconn. [default: 4096]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=65535))]
+    pub listen_backlog: Option<u32>,
+
+    /// If a port is still in use, eg. by a previous instance which is shutting down, keep
+    /// trying for up to this many seconds before giving up [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    pub retry_bind: Option<u64>,
+
+    /// Limit the bytes per second sent to each single pull connection, eg. 1MiB/s. Overall
+    /// throughput still grows with the number of concurrent connections. [default: unlimited]
+    #[arg(long, value_name = "RATE", value_parser = config::parse_byte_rate)]
+    pub pull_bandwidth_limit: Option<u64>,
+
+    /// Size in bytes of the chunks in which the agent output is forwarded to pull connections,
+    /// at least 4096. Values above 1 MiB are capped. [default: 65536]
+    #[arg(long, value_name = "BYTES")]
+    pub io_chunk_size: Option<usize>,
+
+    /// Never write the connection registry, e.g. for containers which were registered when
+    /// building the image. This is also the case if the registry is on a read-only file system.
+    #[arg(long)]
+    pub registry_readonly: bool,
+
+    /// If loading the connection registry fails with an I/O error, eg. because it is on network
+    /// storage which is not mounted yet, keep trying for up to this many seconds. A registry
+    /// which can't be parsed fails right away. [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    pub registry_load_retry: Option<u64>,
+
+    /// Refuse to start if the connection registry does not exist. By default, a missing
+    /// registry is reported and we don't listen for pull requests until connections are
+    /// registered. An existing registry without connections is a valid state, in which we
+    /// listen, but trust nobody.
+    #[arg(long)]
+    pub require_registry: bool,
+
+    /// Accept pull connections from any address, ignoring the configured IP allowlist. Only meant
+    /// for isolated networks. Without this, a configured but empty allowlist denies everybody,
+    /// while no allowlist configured at all accepts everybody, as before allow_any existed.
+    #[arg(long)]
+    pub allow_any: bool,
+
+    /// INSECURE, only for lab setups: Serve pull connections without requiring client
+    /// certificates, anybody passing the IP allowlist gets the agent output. Refused unless an
+    /// IP allowlist is configured and allow_any is not set. There is no config file setting
+    /// for this, it has to be given on the command line every time.
+    #[arg(long)]
+    pub no_client_auth: bool,
+
+    /// Log why each rejected or failed pull connection was turned down, naming the counter it is
+    /// counted in along with the rule or phase which failed. Meant for troubleshooting.
+    #[arg(long)]
+    pub explain_rejections: bool,
+
+    /// Refuse to start if the certificate, private key or root certificate of any pull
+    /// connection can't be decoded or the certificate has expired. By default, such connections
+    /// are reported and the broken ones are not served, while the others are.
+    #[arg(long)]
+    pub strict_startup: bool,
+
+    /// What pull requests get if the agent channel can't be reached: "fail" aborts the
+    /// connection, "report" sends a minimal agent output telling the site what went wrong.
+    /// Overrides 'on_agent_unavailable' from the config file. [default: fail]
+    #[arg(long, value_enum)]
+    pub on_agent_unavailable: Option<config::AgentUnavailablePolicy>,
+
+    /// Additionally serve the agent output on a Unix socket at this path, eg. for a scraper in
+    /// the same pod. Access is controlled by the permissions of the socket instead of the IP
+    /// allowlist, and the output is sent unencrypted and uncompressed. Local peers count as one
+    /// more peer towards max_connections.
+    #[arg(long)]
+    pub pull_unix_socket: Option<std::path::PathBuf>,
 }
 
 #[derive(Parser)]
@@ -198,15 +948,62 @@ pub struct PullArgs {
     pub logging_opts: LoggingOpts,
 }
 
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct PushRetryOpts {
+    /// Delay in seconds before retrying after a failed push. The delay is doubled with every
+    /// further failure and randomized to avoid reconnecting in lockstep with other hosts. Every
+    /// site is retried on its own, the others are pushed to on schedule meanwhile.
+    #[arg(long, default_value_t = constants::DEFAULT_PUSH_RETRY_BASE, value_parser = clap::value_parser!(u64).range(1..))]
+    pub push_retry_base: u64,
+
+    /// Maximum delay in seconds before retrying after failed pushes
+    #[arg(long, default_value_t = constants::DEFAULT_PUSH_RETRY_MAX, value_parser = clap::value_parser!(u64).range(1..))]
+    pub push_retry_max: u64,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct PushScheduleOpts {
+    /// Seconds between the starts of two pushes. If a push takes longer, the next one is
+    /// skipped instead of being started late. [default: 60]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub push_interval: Option<u64>,
+
+    /// Push at the times matching this cron-like expression (UTC) instead of a fixed interval,
+    /// eg. "*/5 * * * *" for every five minutes, aligned to the full hour
+    #[arg(long, conflicts_with = "push_interval")]
+    pub push_schedule: Option<String>,
+}
+
+/// Which parts the daemon runs
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DaemonMode {
+    Push,
+    Pull,
+    Both,
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct DaemonArgs {
+    /// Only run the push loop (push) or the pull listener (pull) instead of both. A push-only
+    /// daemon never binds a port, a pull-only daemon never contacts any site.
+    #[arg(long, value_enum, default_value_t = DaemonMode::Both)]
+    pub mode: DaemonMode,
+
     #[clap(flatten)]
     pub pull_opts: PullOpts,
 
     #[clap(flatten)]
     pub client_opts: ClientOpts,
 
+    #[clap(flatten)]
+    pub push_retry_opts: PushRetryOpts,
+
+    #[clap(flatten)]
+    pub push_schedule_opts: PushScheduleOpts,
+
     #[clap(flatten)]
     pub logging_opts: LoggingOpts,
 }
@@ -218,6 +1015,37 @@ pub struct ImportArgs {
     #[arg(name = "CONNECTION_FILE")]
     pub conn_file: Option<std::path::PathBuf>,
 
+    /// Import a bundle created by the 'export' command and add its connections to the existing
+    /// ones. Existing connections to the same sites are overwritten.
+    #[arg(long, conflicts_with = "replace")]
+    pub merge: bool,
+
+    /// Import a bundle created by the 'export' command and replace all existing connections
+    #[arg(long)]
+    pub replace: bool,
+
+    #[clap(flatten)]
+    pub logging_opts: LoggingOpts,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("content").required(true).args(["public_only", "include_secrets"])))]
+pub struct ExportArgs {
+    /// The file to export to. If not provided, data is written to standard output.
+    #[arg(name = "BUNDLE_FILE")]
+    pub bundle_file: Option<std::path::PathBuf>,
+
+    /// Only export what identifies the connections, eg. for an inventory, without private
+    /// keys and certificates. The result cannot be imported.
+    #[arg(long)]
+    pub public_only: bool,
+
+    /// Export everything needed to import the connections elsewhere, including their private
+    /// keys
+    #[arg(long)]
+    pub include_secrets: bool,
+
     #[clap(flatten)]
     pub logging_opts: LoggingOpts,
 }
@@ -230,13 +1058,17 @@ pub struct SharedArgsOnly {
 }
 
 #[derive(Parser)]
-#[command(about = "Checkmk agent controller.", version = constants::VERSION)]
+#[command(
+    about = "Checkmk agent controller.",
+    version = constants::VERSION,
+    after_help = constants::EXIT_CODES_HELP
+)]
 pub enum Args {
     /// Register with a Checkmk site
     ///
     /// Register with a Checkmk instance for monitoring. The required information
     /// can be read from a config file or must be passed via command line.
-    #[command(name = "register")]
+    #[command(name = "register", after_help = constants::EXIT_CODES_HELP)]
     RegisterHostName(RegistrationArgsHostName),
 
     /// Register with a Checkmk site, automatically creating a new host.
@@ -244,7 +1076,7 @@ pub enum Args {
     /// Register with a Checkmk instance for monitoring. A new host will be created
     /// in the target Checkmk instance. This mode is only available if the target
     /// is an Enterprise Plus edition.
-    #[command(name = "register-new")]
+    #[command(name = "register-new", after_help = constants::EXIT_CODES_HELP)]
     RegisterAgentLabels(RegistrationArgsAgentLabels),
 
     /// Register with a Checkmk site on behalf of another host
@@ -258,13 +1090,13 @@ pub enum Args {
     ///
     /// This command will collect monitoring data, send them to all
     /// Checkmk site configured for 'push' and exit.
-    #[command()]
+    #[command(after_help = constants::EXIT_CODES_HELP)]
     Push(PushArgs),
 
     /// Handle incoming connections from Checkmk sites collecting monitoring data
     ///
     /// This command will listen for incoming connections
-    #[command()]
+    #[command(after_long_help = constants::PULL_ENV_HELP)]
     Pull(PullArgs),
 
     /// Run as daemon and handle all pull and push connections
@@ -272,22 +1104,82 @@ pub enum Args {
     /// Listen for incoming connections (as the 'pull' command does),
     /// and send data to all Checkmk sites configured for 'push'
     /// (as the 'push' command does) once a minute.
-    #[command()]
+    #[command(after_long_help = constants::PULL_ENV_HELP)]
     Daemon(DaemonArgs),
 
     /// Collect monitoring data and write it to standard output
     #[command()]
     Dump(SharedArgsOnly),
 
-    /// Query the registration status of this host
+    /// Show the certificates stored for the registered connections
+    ///
+    /// For the client certificate and the trusted root certificates of each connection,
+    /// subject, issuer, serial, validity, fingerprint and key type are shown.
+    /// Private keys are never shown.
     #[command()]
+    DumpCerts(DumpCertsArgs),
+
+    /// Query the registration status of this host
+    #[command(after_help = constants::EXIT_CODES_HELP)]
     Status(StatusArgs),
 
+    /// Check whether the running pull daemon is able to serve requests
+    ///
+    /// Checks locally that the pull listener accepts connections and that the agent
+    /// channel is reachable, without contacting any site. The exit code is non-zero
+    /// if any check fails, making this suitable for container health probes.
+    #[command()]
+    Healthcheck(HealthcheckArgs),
+
+    /// Show the pull settings in effect after merging all configuration sources
+    ///
+    /// The environment, the command line and the config file are merged exactly as the pull
+    /// daemon would, which takes the same options. Nothing is started and no connection is
+    /// opened. The registered connections are only counted, their keys are never shown.
+    #[command(after_long_help = constants::PULL_ENV_HELP)]
+    ShowConfig(ShowConfigArgs),
+
+    /// Fetch the agent output via pull from this host, the way a site would
+    ///
+    /// The request is handled by the same code as requests of the pull daemon, using the
+    /// TLS certificate of the connection. The site's client certificate is not available
+    /// locally, so a temporary one is used instead. The agent output is decrypted and
+    /// either summarized or printed completely.
+    #[command()]
+    TestPull(TestPullArgs),
+
+    /// Check that pull works on this host, without any site or registered connection
+    ///
+    /// A temporary connection with throwaway certificates is served by the code of the pull
+    /// daemon, with a dummy agent behind it. The agent output is fetched via TLS and compared
+    /// to what the dummy agent sent. Neither the registry nor the config file are used, and
+    /// nothing is left behind.
+    #[command()]
+    SelfTest(SelfTestArgs),
+
+    /// Show what this build supports, as JSON
+    ///
+    /// Lists the modes, key types, TLS versions, transports and so on, along with their
+    /// defaults, st. tooling can adapt to the controller at hand. Neither the registry nor
+    /// the config file are used.
+    #[command()]
+    Capabilities(SharedArgsOnly),
+
+    /// [Testing only] Write a CA and controller and receiver certificates to a directory
+    ///
+    /// The CA, the controller certificate (issued for a UUID) and the receiver certificate are
+    /// written as PEM files, along with their private keys, for trying out the pull and push
+    /// paths by hand. Only available in builds with the dev feature. These certificates must
+    /// never be used for real registrations.
+    #[cfg(feature = "dev")]
+    #[command()]
+    GenTestCerts(GenTestCertsArgs),
+
     /// Delete a connection to a Checkmk instance
     ///
     /// Connections can be specified either by their site address or their UUID.
     /// The site address is '<servername>/<site>', see the output of the
-    /// status command.
+    /// status command. With --label or --site-glob, all matching connections are deleted.
     #[command()]
     Delete(DeleteArgs),
 
@@ -299,37 +1191,159 @@ pub enum Args {
     ///
     /// A connection is imported from the JSON-encoded connection information.
     /// A compatible dataset can be created using the 'proxy-register' command.
+    /// With --merge or --replace, a bundle created by the 'export' command is imported.
     #[command()]
     Import(ImportArgs),
+
+    /// Export all connections to a single file, which can be imported elsewhere
+    ///
+    /// With --include-secrets, the bundle contains the private keys of all connections, so
+    /// handle it with care and delete it once it is not needed anymore. With --public-only,
+    /// only the connection metadata is exported, which is safe to collect, eg. for an inventory.
+    #[command()]
+    Export(ExportArgs),
+
+    /// Change the stored settings of a connection to a Checkmk instance
+    ///
+    /// Connections can be specified either by their site address or their UUID.
+    /// The certificates and the UUID of the connection are kept, no new registration is needed.
+    #[command()]
+    UpdateConnection(UpdateConnectionArgs),
+
+    /// Manage the root certificates trusted for a connection to a Checkmk instance
+    ///
+    /// While a site rotates its CA, its new root certificate can be trusted in addition
+    /// to the old one, st. the connection keeps working without registering again.
+    /// Without --add or --remove, the trusted root certificates are listed.
+    #[command()]
+    TrustRoot(TrustRootArgs),
+
+    /// Remove the registered connections which can't be used anymore
+    ///
+    /// Connections whose certificate or key doesn't parse, whose key doesn't belong to the
+    /// certificate or whose certificate was issued for another UUID are removed, or disabled
+    /// with --disable. Each action taken is reported, use --dry-run to only see them.
+    #[command()]
+    Repair(RepairArgs),
+
+    /// Check the configuration and the registered connections for problems
+    ///
+    /// All problems found are reported, the exit code is non-zero if there are any.
+    /// Use this to make sure that a reload or restart will succeed.
+    #[command()]
+    Validate(SharedArgsOnly),
+
+    /// Check a connection to a Checkmk instance against the site
+    ///
+    /// The site is contacted with the stored certificates, and it has to recognize the
+    /// registration. Nothing is changed. The exit code tells which step failed.
+    #[command()]
+    Verify(VerifyArgs),
 }
 
 impl Args {
-    pub fn logging_level(&self) -> String {
+    fn logging_opts(&self) -> &LoggingOpts {
         match self {
-            Args::RegisterHostName(args) => args.logging_opts.logging_level(),
-            Args::RegisterAgentLabels(args) => args.logging_opts.logging_level(),
-            Args::ProxyRegister(args) => args.logging_opts.logging_level(),
-            Args::Push(args) => args.logging_opts.logging_level(),
-            Args::Pull(args) => args.logging_opts.logging_level(),
-            Args::Daemon(args) => args.logging_opts.logging_level(),
-            Args::Dump(args) => args.logging_opts.logging_level(),
-            Args::Status(args) => args.logging_opts.logging_level(),
-            Args::Delete(args) => args.logging_opts.logging_level(),
-            Args::DeleteAll(args) => args.logging_opts.logging_level(),
-            Args::Import(args) => args.logging_opts.logging_level(),
+            Args::RegisterHostName(args) => &args.logging_opts,
+            Args::RegisterAgentLabels(args) => &args.logging_opts,
+            Args::ProxyRegister(args) => &args.logging_opts,
+            Args::Push(args) => &args.logging_opts,
+            Args::Pull(args) => &args.logging_opts,
+            Args::Daemon(args) => &args.logging_opts,
+            Args::Dump(args) => &args.logging_opts,
+            Args::DumpCerts(args) => &args.logging_opts,
+            Args::Status(args) => &args.logging_opts,
+            Args::Healthcheck(args) => &args.logging_opts,
+            Args::ShowConfig(args) => &args.logging_opts,
+            Args::TestPull(args) => &args.logging_opts,
+            Args::SelfTest(args) => &args.logging_opts,
+            Args::Capabilities(args) => &args.logging_opts,
+            #[cfg(feature = "dev")]
+            Args::GenTestCerts(args) => &args.logging_opts,
+            Args::Delete(args) => &args.logging_opts,
+            Args::DeleteAll(args) => &args.logging_opts,
+            Args::Import(args) => &args.logging_opts,
+            Args::Export(args) => &args.logging_opts,
+            Args::UpdateConnection(args) => &args.logging_opts,
+            Args::TrustRoot(args) => &args.logging_opts,
+            Args::Repair(args) => &args.logging_opts,
+            Args::Validate(args) => &args.logging_opts,
+            Args::Verify(args) => &args.logging_opts,
         }
     }
+
+    pub fn logging_level(&self) -> String {
+        self.logging_opts().logging_level()
+    }
+
+    pub fn tls_debug(&self) -> bool {
+        self.logging_opts().tls_debug
+    }
+
+    pub fn insecure_keylog(&self) -> bool {
+        self.logging_opts().insecure_keylog
+    }
+
+    pub fn log_format(&self) -> logging::LogFormat {
+        self.logging_opts().log_format
+    }
+
+    #[cfg(unix)]
+    pub fn log_target(&self) -> logging::LogTarget {
+        self.logging_opts().log_target
+    }
+
+    #[cfg(unix)]
+    pub fn syslog_facility(&self) -> logging::SyslogFacility {
+        self.logging_opts().syslog_facility
+    }
+
+    pub fn registry(&self) -> Option<&std::path::Path> {
+        self.logging_opts().registry.as_deref()
+    }
+
+    pub fn config(&self) -> Option<&std::path::Path> {
+        self.logging_opts().config.as_deref()
+    }
+
+    pub fn tmp_dir(&self) -> Option<&std::path::Path> {
+        self.logging_opts().tmp_dir.as_deref()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn logging_opts(verbose: u8, log_filter: Option<&str>) -> LoggingOpts {
+        LoggingOpts {
+            verbose,
+            log_filter: log_filter.map(String::from),
+            tls_debug: false,
+            insecure_keylog: false,
+            log_format: logging::LogFormat::Text,
+            #[cfg(unix)]
+            log_target: logging::LogTarget::Stderr,
+            #[cfg(unix)]
+            syslog_facility: logging::SyslogFacility::Daemon,
+            registry: None,
+            config: None,
+            tmp_dir: None,
+        }
+    }
+
     #[test]
     fn test_logging_level() {
-        assert_eq!((LoggingOpts { verbose: 0 }).logging_level(), "warn");
-        assert_eq!((LoggingOpts { verbose: 1 }).logging_level(), "info");
-        assert_eq!((LoggingOpts { verbose: 2 }).logging_level(), "debug");
+        assert_eq!(logging_opts(0, None).logging_level(), "warn");
+        assert_eq!(logging_opts(1, None).logging_level(), "info");
+        assert_eq!(logging_opts(2, None).logging_level(), "debug");
+        assert_eq!(
+            logging_opts(1, Some("cmk_agent_ctl::certs=debug")).logging_level(),
+            "info,cmk_agent_ctl::certs=debug"
+        );
+        let mut opts = logging_opts(0, None);
+        opts.tls_debug = true;
+        assert_eq!(opts.logging_level(), "warn,cmk_agent_ctl::tls_debug=info");
     }
 
     #[test]
@@ -348,6 +1362,13 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_connection_type() {
+        assert!(parse_connection_type("push").unwrap() == config::ConnectionType::Push);
+        assert!(parse_connection_type("pull-agent").unwrap() == config::ConnectionType::Pull);
+        assert!(parse_connection_type("legacy").is_err());
+    }
+
     #[test]
     fn test_parse_agent_labels_error() {
         assert!(parse_agent_labels("missing-equal-sign").is_err(),);
@@ -0,0 +1,239 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Detects a local clock which is off, eg. because NTP stopped working. TLS handshakes fail if
+//! the clock is outside of the validity period of a certificate, which is reported as a cryptic
+//! "CertNotValidYet". We compare against the validity of freshly issued certificates and
+//! against the Date header of the agent receiver, st. we can point at the clock instead.
+
+use super::certs;
+use anyhow::Result as AnyhowResult;
+use log::warn;
+use rustls::Error as RustlsError;
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Skew {
+    /// Seconds the local clock is behind
+    Behind(u64),
+    /// Seconds the local clock is ahead
+    Ahead(u64),
+}
+
+impl std::fmt::Display for Skew {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Behind(seconds) => write!(f, "the local clock is {}s behind", seconds),
+            Self::Ahead(seconds) => write!(f, "the local clock is {}s ahead", seconds),
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    x509_parser::time::ASN1Time::now().timestamp()
+}
+
+fn skew(local: i64, reference: i64, threshold: std::time::Duration) -> Option<Skew> {
+    let difference = reference - local;
+    if difference.unsigned_abs() <= threshold.as_secs() {
+        return None;
+    }
+    match difference > 0 {
+        true => Some(Skew::Behind(difference.unsigned_abs())),
+        false => Some(Skew::Ahead(difference.unsigned_abs())),
+    }
+}
+
+/// A certificate can't be valid before it was issued, so a start of validity in the future
+/// means that our clock is behind. The end of validity tells nothing, the certificate may just
+/// have expired.
+fn certificate_skew_at(
+    cert_pem: &str,
+    now: i64,
+    threshold: std::time::Duration,
+) -> AnyhowResult<Option<Skew>> {
+    let pem = certs::parse_pem(cert_pem)?;
+    let not_before = pem.parse_x509()?.validity().not_before.timestamp();
+    Ok(match skew(now, not_before, threshold) {
+        Some(Skew::Behind(seconds)) => Some(Skew::Behind(seconds)),
+        _ => None,
+    })
+}
+
+pub fn certificate_skew(
+    cert_pem: &str,
+    threshold: std::time::Duration,
+) -> AnyhowResult<Option<Skew>> {
+    certificate_skew_at(cert_pem, unix_now(), threshold)
+}
+
+fn date_header_skew_at(
+    headers: &reqwest::header::HeaderMap,
+    now: i64,
+    threshold: std::time::Duration,
+) -> Option<Skew> {
+    let date = headers.get(reqwest::header::DATE)?.to_str().ok()?;
+    let date = httpdate::parse_http_date(date).ok()?;
+    let date = date.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    skew(now, i64::try_from(date).ok()?, threshold)
+}
+
+/// The Date header only has a resolution of seconds and is taken by the server some time
+/// before we receive it, so small thresholds make little sense here.
+pub fn date_header_skew(
+    headers: &reqwest::header::HeaderMap,
+    threshold: std::time::Duration,
+) -> Option<Skew> {
+    date_header_skew_at(headers, unix_now(), threshold)
+}
+
+pub fn warning(source: &str, skew: &Skew) -> String {
+    format!(
+        "{} indicates that {}. Please check the system time and its synchronization (NTP), \
+         TLS connections fail if the clock is off.",
+        source, skew
+    )
+}
+
+/// Broken certificates are reported where they are actually used, not here
+pub fn warn_certificate_skew(description: &str, cert_pem: &str, threshold: std::time::Duration) {
+    if let Ok(Some(skew)) = certificate_skew(cert_pem, threshold) {
+        warn!("{}", warning(description, &skew))
+    }
+}
+
+/// Adds a hint at the local clock to the errors rustls reports for certificates outside of
+/// their validity period
+pub fn explain_validity_error(err: RustlsError) -> RustlsError {
+    let RustlsError::InvalidCertificateData(description) = err else {
+        return err;
+    };
+    let hint = if description.contains("CertNotValidYet") {
+        "the certificate is not valid yet, is the local clock behind?"
+    } else if description.contains("CertExpired") {
+        "the certificate has expired, or the local clock is ahead"
+    } else {
+        return RustlsError::InvalidCertificateData(description);
+    };
+    RustlsError::InvalidCertificateData(format!(
+        "{} ({}, local time is {})",
+        description,
+        hint,
+        x509_parser::time::ASN1Time::now().to_rfc2822()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: std::time::Duration = std::time::Duration::from_secs(60);
+
+    fn cert_valid_from(now: i64) -> String {
+        let key =
+            openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = openssl::x509::X509::builder().unwrap();
+        builder
+            .set_not_before(&openssl::asn1::Asn1Time::from_unix(now).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&openssl::asn1::Asn1Time::from_unix(now + 86400).unwrap())
+            .unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .sign(&key, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_skew() {
+        assert_eq!(skew(1000, 1060, THRESHOLD), None);
+        assert_eq!(skew(1000, 940, THRESHOLD), None);
+        assert_eq!(skew(1000, 1061, THRESHOLD), Some(Skew::Behind(61)));
+        assert_eq!(skew(1000, 900, THRESHOLD), Some(Skew::Ahead(100)));
+    }
+
+    #[test]
+    fn test_certificate_skew() {
+        let now = 1_700_000_000;
+        let cert = cert_valid_from(now + 3600);
+        assert_eq!(
+            certificate_skew_at(&cert, now, THRESHOLD).unwrap(),
+            Some(Skew::Behind(3600))
+        );
+        assert_eq!(
+            certificate_skew_at(&cert, now + 3590, THRESHOLD).unwrap(),
+            None
+        );
+        // Long after the start of validity is just fine
+        assert_eq!(
+            certificate_skew_at(&cert, now + 10 * 86400, THRESHOLD).unwrap(),
+            None
+        );
+        assert!(certificate_skew_at("no certificate", now, THRESHOLD).is_err());
+    }
+
+    #[test]
+    fn test_date_header_skew() {
+        let headers = |date: &str| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert(
+                reqwest::header::DATE,
+                reqwest::header::HeaderValue::from_str(date).unwrap(),
+            );
+            headers
+        };
+        // Tue, 14 Nov 2023 22:13:20 GMT
+        let now = 1_700_000_000;
+        let date = headers("Tue, 14 Nov 2023 22:13:20 GMT");
+        assert_eq!(date_header_skew_at(&date, now, THRESHOLD), None);
+        assert_eq!(
+            date_header_skew_at(&date, now + 7200, THRESHOLD),
+            Some(Skew::Ahead(7200))
+        );
+        assert_eq!(
+            date_header_skew_at(&date, now - 120, THRESHOLD),
+            Some(Skew::Behind(120))
+        );
+        assert_eq!(
+            date_header_skew_at(&headers("yesterday"), now, THRESHOLD),
+            None
+        );
+        assert_eq!(
+            date_header_skew_at(&reqwest::header::HeaderMap::new(), now, THRESHOLD),
+            None
+        );
+    }
+
+    #[test]
+    fn test_warning() {
+        assert_eq!(
+            warning("The Date header of the agent receiver", &Skew::Ahead(7200)),
+            "The Date header of the agent receiver indicates that the local clock is 7200s ahead. \
+             Please check the system time and its synchronization (NTP), TLS connections fail if \
+             the clock is off."
+        );
+    }
+
+    #[test]
+    fn test_explain_validity_error() {
+        let explained = explain_validity_error(RustlsError::InvalidCertificateData(String::from(
+            "invalid peer certificate: CertNotValidYet",
+        )))
+        .to_string();
+        assert!(explained.contains("CertNotValidYet (the certificate is not valid yet, is the local clock behind?, local time is "));
+        assert_eq!(
+            explain_validity_error(RustlsError::InvalidCertificateData(String::from(
+                "invalid peer certificate: UnknownIssuer"
+            ))),
+            RustlsError::InvalidCertificateData(String::from(
+                "invalid peer certificate: UnknownIssuer"
+            ))
+        );
+        assert_eq!(
+            explain_validity_error(RustlsError::InvalidCertificateEncoding),
+            RustlsError::InvalidCertificateEncoding
+        );
+    }
+}
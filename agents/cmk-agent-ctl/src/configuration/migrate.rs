@@ -2,11 +2,13 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+use crate::constants;
 use crate::site_spec::SiteID;
 
 use super::config;
 use anyhow::{anyhow, Context, Error as AnyhowError, Result as AnyhowResult};
 use config::JSONLoaderMissingSafe;
+use log::info;
 use serde::Deserialize;
 use serde_with::DisplayFromStr;
 use std::collections::{HashMap, HashSet};
@@ -16,8 +18,32 @@ use std::path::Path;
 use std::str::FromStr;
 
 pub fn migrate_registered_connections(path: impl AsRef<Path>) -> AnyhowResult<()> {
-    if config::Registry::from_file(path.as_ref()).is_ok() {
-        return Ok(());
+    // Usually, there is nothing to migrate, and then we don't wait for the lock
+    if let Ok(registry) = config::Registry::from_file(path.as_ref()) {
+        if registry.upgraded_from().is_none() {
+            return Ok(());
+        }
+    }
+    // The other modes modifying the registry must not overwrite the migrated one with what they
+    // loaded before, nor the other way round
+    let _registry_lock = config::RegistryLock::acquire(
+        path.as_ref(),
+        std::time::Duration::from_secs(constants::REGISTRY_LOCK_TIMEOUT),
+    )?;
+    migrate_locked(path.as_ref())
+}
+
+fn migrate_locked(path: impl AsRef<Path>) -> AnyhowResult<()> {
+    match config::Registry::from_file(path.as_ref()) {
+        Ok(registry) => return save_upgraded(&registry),
+        // Don't mistake a registry written by a newer agent controller for the legacy format
+        Err(err) if err.is::<config::UnsupportedRegistryVersion>() => {
+            return Err(err.context(format!(
+                "Failed to load registered connections from {:?}",
+                path.as_ref()
+            )))
+        }
+        Err(_) => {}
     }
 
     let registered_connections_legacy = RegisteredConnections::load_missing_safe(path.as_ref())
@@ -58,6 +84,21 @@ pub fn migrate_registered_connections(path: impl AsRef<Path>) -> AnyhowResult<()
         .context("Failed to save migrated connection registry")
 }
 
+/// Older versions of the current format are upgraded on loading anyway, we only persist this
+fn save_upgraded(registry: &config::Registry) -> AnyhowResult<()> {
+    let Some(upgraded_from) = registry.upgraded_from() else {
+        return Ok(());
+    };
+    info!(
+        "Upgrading connection registry from version {} to {}",
+        upgraded_from,
+        config::REGISTRY_VERSION
+    );
+    registry
+        .save()
+        .context("Failed to save upgraded connection registry")
+}
+
 #[derive(Deserialize, Default)]
 struct RegisteredConnections {
     #[serde(default)]
@@ -85,6 +126,8 @@ fn migrate_standard_connection(
         config::TrustedConnectionWithRemote {
             trust: connection.into(),
             receiver_port: coordinates.port,
+            host_name: None,
+            path_prefix: None,
         },
     )
 }
@@ -150,6 +193,11 @@ impl Into<config::TrustedConnection> for Connection {
             private_key: self.private_key,
             certificate: self.certificate,
             root_cert: self.root_cert,
+            pinned_fingerprint: None,
+            pinned_client_fingerprints: vec![],
+            labels: config::ConnectionLabels::new(),
+            agent_output_disabled: false,
+            min_tls_version: None,
         }
     }
 }
@@ -293,6 +341,54 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_unversioned_registry_upgrade() {
+        let tmp_path = tmp_path();
+        // Current format except for the version, without the optional fields added later
+        std::fs::write(
+            &tmp_path,
+            r#"{
+            "push": {
+              "server/push-site": {
+                "uuid": "ca30e826-cf0e-4a7a-9f9d-84b304d61ccb",
+                "private_key": "private_key_push",
+                "certificate": "certificate_push",
+                "root_cert": "root_cert_push",
+                "receiver_port": 8000
+              }
+            },
+            "pull": {},
+            "pull_imported": []
+          }"#,
+        )
+        .unwrap();
+        let registry = config::Registry::from_file(&tmp_path).unwrap();
+        assert_eq!(registry.upgraded_from(), Some(0));
+        let (_, connection) = registry.push_connections().next().unwrap();
+        assert!(connection.host_name.is_none());
+        assert!(connection.trust.pinned_fingerprint.is_none());
+
+        assert!(migrate_registered_connections(&tmp_path).is_ok());
+        let registry = config::Registry::from_file(&tmp_path).unwrap();
+        assert!(registry.upgraded_from().is_none());
+        assert_eq!(registry.push_connections().count(), 1);
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&tmp_path).unwrap()).unwrap();
+        assert_eq!(written["version"], config::REGISTRY_VERSION);
+    }
+
+    #[test]
+    fn test_future_registry_version() {
+        let tmp_path = tmp_path();
+        let registry = r#"{"version": 1000, "push": {}, "pull": {}, "pull_imported": []}"#;
+        std::fs::write(&tmp_path, registry).unwrap();
+        let err = migrate_registered_connections(&tmp_path).unwrap_err();
+        assert!(format!("{:#}", err).contains("Registry version 1000 is not supported"));
+        // The file is left alone
+        assert_eq!(std::fs::read_to_string(&tmp_path).unwrap(), registry);
+        assert!(config::Registry::from_file(&tmp_path).is_err());
+    }
+
     #[test]
     fn test_crash_upon_corrupt_registry() {
         let tmp_path = tmp_path();
@@ -7,9 +7,69 @@ pub const VERSION: &str = "2.2.0i1";
 // CONFIGURATION
 pub const DEFAULT_PULL_PORT: u16 = 6556;
 pub const MAX_CONNECTIONS: usize = 3;
+// Per source IP, more than this is almost certainly a typo
+pub const MAX_CONNECTIONS_WARN_THRESHOLD: usize = 1000;
+pub const DEFAULT_WORKER_THREADS: usize = 1;
+// The OS caps the backlog of a listening socket anyway, eg. Linux at net.core.somaxconn
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 4096;
+pub const MAX_LISTEN_BACKLOG: u32 = 65535;
+// Only with on_overload = "queue"
+pub const DEFAULT_OVERLOAD_QUEUE: usize = 16;
 pub const CONNECTION_TIMEOUT: u64 = 20;
+pub const AGENT_CHANNEL_PROBE_TIMEOUT: u64 = 5;
+pub const REGISTRY_LOCK_TIMEOUT: u64 = 10;
+pub const CERT_EXPIRY_WARNING_DAYS: u32 = 30;
+// A safety valve against runaway registration loops, no sane host has that many sites
+pub const DEFAULT_MAX_REGISTERED_CONNECTIONS: usize = 256;
+// Tolerated clock skew in seconds when checking the validity period of OCSP responses
+pub const OCSP_MAX_CLOCK_SKEW: u32 = 300;
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 64 * 1024 * 1024;
+// A site sends the PROXY header and the TLS handshake only, both of which take a few KiB at most
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 16 * 1024;
+pub const MIN_MAX_REQUEST_BYTES: usize = 1024;
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 10;
+// Generous, some agents only start sending once all of their plugins have run
+pub const DEFAULT_AGENT_CHANNEL_TIMEOUT: u64 = 60;
+// Hooks only massage output which is already there, so they get less time than the agent
+pub const DEFAULT_OUTPUT_HOOK_TIMEOUT: u64 = 30;
+// Both the Linux and the Windows agent start their output with this section
+pub const DEFAULT_AGENT_OUTPUT_MARKER: &str = "<<<check_mk>>>";
+// Long-term accounting only, losing a few minutes of it on a crash doesn't matter
+pub const DEFAULT_CONNECTION_TOTALS_INTERVAL: u64 = 300;
+// Short, a peer which is not done with the handshake by then only ties up a connection slot
+pub const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 5;
+// For connecting to agent receivers, which would otherwise take the OS default of a minute or more
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+// As the implicit default of reqwest, which applied before this was configurable
+pub const DEFAULT_PUSH_TIMEOUT: u64 = 30;
+// Well below the idle timeouts of common firewalls and NAT gateways, which start at 5 minutes
+pub const DEFAULT_PUSH_KEEPALIVE: u64 = 60;
+// Well above the drift NTP lets through, but below what breaks freshly issued certificates
+pub const DEFAULT_CLOCK_SKEW_THRESHOLD: u64 = 60;
+// How often to check whether the site is up when registering with --wait
+pub const REGISTRATION_WAIT_INTERVAL: u64 = 5;
+pub const DEFAULT_STATUS_WATCH_INTERVAL: u64 = 30;
+// Between two attempts to load the registry with --registry-load-retry
+pub const REGISTRY_LOAD_RETRY_INTERVAL: u64 = 2;
+// As rustls, one per polling site is plenty
+pub const DEFAULT_TLS_SESSION_CACHE_SIZE: usize = 256;
+pub const DEFAULT_PULL_RATE_LIMIT_BURST: u32 = 5;
+// Chunk size when forwarding the agent output to pull connections, see io_chunk_size. Forwarding
+// 17 MB of agent output in memory with the release profile, the plain copy took 26 ms with 16 KiB
+// chunks, 21 ms with 64 KiB and 23-25 ms with 256 KiB or 1 MiB. With zlib, as for all TLS
+// connections, compressing takes about 0.5 s and the chunk size is within the noise. So larger
+// chunks only cost memory per connection, while tiny ones multiply syscalls and TLS records.
+pub const DEFAULT_IO_CHUNK_SIZE: usize = 64 * 1024;
+pub const MIN_IO_CHUNK_SIZE: usize = 4 * 1024;
+pub const MAX_IO_CHUNK_SIZE: usize = 1024 * 1024;
+pub const PUSH_INTERVAL: u64 = 60;
+pub const DEFAULT_PUSH_RETRY_BASE: u64 = 60;
+pub const DEFAULT_PUSH_RETRY_MAX: u64 = 900;
 #[cfg(unix)]
 pub const CMK_AGENT_USER: &str = "cmk-agent";
+// We only ever connect to this socket, it is created by systemd. Its mode and ownership are
+// thus configured in the socket unit (SocketUser, SocketGroup, SocketMode), see
+// agents/scripts/super-server/0_systemd/check-mk-agent.socket.
 #[cfg(unix)]
 pub const UNIX_AGENT_SOCKET: &str = "/run/check-mk-agent.socket";
 
@@ -17,6 +77,24 @@ pub const UNIX_AGENT_SOCKET: &str = "/run/check-mk-agent.socket";
 pub const PRE_CONFIGURED_CONNECTIONS_FILE: &str = "pre_configured_connections.json";
 pub const REGISTRY_FILE: &str = "registered_connections.json";
 pub const CONFIG_FILE: &str = "cmk-agent-ctl.toml";
+pub const PULL_COUNTERS_FILE: &str = "pull_counters.json";
+pub const PUSH_RESULTS_FILE: &str = "push_results.json";
+pub const CONNECTION_TOTALS_FILE: &str = "connection_totals.json";
+
+// Exit codes, see exit_codes::ExitCode
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  Success
+  1  Any other error
+  2  Invalid command line usage
+  3  Network error, the site could not be reached
+  4  Authentication failed, the site rejected the credentials
+  5  Already exists, eg. the host is already registered
+  6  Invalid configuration
+  7  A connection certificate has expired (status and verify)
+  8  The credential helper failed (register only)
+  9  The site declined the registration (verify only)
+ 10  The site expects another connection type (verify only)";
 
 // ENVIRONMENT
 #[cfg(windows)]
@@ -34,6 +112,26 @@ pub const WIN_AGENT_HOME_DIR: &str = "\\checkmk\\agent";
 pub const ENV_HOME_DIR: &str = "DEBUG_HOME_DIR";
 pub const ENV_MAX_CONNECTIONS: &str = "DEBUG_MAX_CONNECTIONS";
 pub const ENV_CONNECTION_TIMEOUT: &str = "DEBUG_CONNECTION_TIMEOUT";
+pub const ENV_PULL_PORT: &str = "CMK_AGENT_CTL_PORT";
+pub const ENV_PULL_ALLOWED_IP: &str = "CMK_AGENT_CTL_ALLOWED_IP";
+pub const ENV_PULL_TLS_MIN_VERSION: &str = "CMK_AGENT_CTL_TLS_MIN_VERSION";
+pub const ENV_PULL_AGENT_CHANNEL: &str = "CMK_AGENT_CTL_AGENT_CHANNEL";
+pub const ENV_PULL_MAX_CONNECTIONS: &str = "CMK_AGENT_CTL_MAX_CONNECTIONS";
+pub const ENV_PULL_WORKER_THREADS: &str = "CMK_AGENT_CTL_WORKER_THREADS";
+pub const ENV_REGISTRY: &str = "CMK_AGENT_CTL_REGISTRY";
+pub const ENV_TMP_DIR: &str = "CMK_AGENT_CTL_TMP_DIR";
+pub const ENV_SSLKEYLOGFILE: &str = "SSLKEYLOGFILE";
+pub const PULL_ENV_HELP: &str = "\
+Environment variables:
+  CMK_AGENT_CTL_PORT             Comma- or space-separated TCP ports to listen on (pull_port)
+  CMK_AGENT_CTL_ALLOWED_IP       Comma- or space-separated allowed addresses/networks (allowed_ip)
+  CMK_AGENT_CTL_TLS_MIN_VERSION  Minimum TLS protocol version, 1.2 or 1.3 (tls_min_version)
+  CMK_AGENT_CTL_AGENT_CHANNEL    Where to get the agent output from (agent_channel)
+  CMK_AGENT_CTL_MAX_CONNECTIONS  Concurrent pull connections per source IP (max_connections)
+  CMK_AGENT_CTL_WORKER_THREADS   Threads handling pull connections, 0 for one per CPU (worker_threads)
+
+Precedence: environment variable > command line option > config file.
+Unset or empty variables are ignored.";
 #[cfg(windows)]
 pub const ENV_PROGRAM_DATA: &str = "ProgramData";
 
@@ -0,0 +1,291 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Cron-like expressions with the five classic fields: minute, hour, day of month, month and
+//! day of week. Each field is '*', a number, a range 'a-b', optionally followed by a step '/n',
+//! or a comma-separated list of those. As in cron, a day matches if either the day of month or
+//! the day of week matches, provided both are restricted. All times are in UTC.
+
+use anyhow::{anyhow, bail, Context, Error as AnyhowError, Result as AnyhowResult};
+use std::str::FromStr;
+use time::{Date, OffsetDateTime, Time};
+
+// Searching further ahead is pointless, the calendar repeats after at most eight years
+// (February 29th, taking skipped leap years into account).
+const MAX_DAYS_AHEAD: u32 = 8 * 366;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Field {
+    allowed: u64,
+    restricted: bool,
+}
+
+impl Field {
+    fn parse(text: &str, min: u8, max: u8) -> AnyhowResult<Self> {
+        let mut allowed = 0;
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, Some(step)),
+                None => (part, None),
+            };
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((start, end)) => {
+                        (Self::value(start, min, max)?, Self::value(end, min, max)?)
+                    }
+                    // 'a/n' means from a to the maximum, a single value just that
+                    None => {
+                        let start = Self::value(range, min, max)?;
+                        (start, if step.is_some() { max } else { start })
+                    }
+                },
+            };
+            if start > end {
+                bail!("Invalid range '{}'", range)
+            }
+            let step = match step {
+                Some(step) => step
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| anyhow!("Invalid step '{}'", step))?,
+                None => 1,
+            };
+            for value in (start..=end).step_by(step.into()) {
+                allowed |= 1 << value;
+            }
+        }
+        Ok(Self {
+            allowed,
+            // As in cron, '*/n' and the like still count as unrestricted for the day matching
+            restricted: !text.starts_with('*'),
+        })
+    }
+
+    fn value(text: &str, min: u8, max: u8) -> AnyhowResult<u8> {
+        text.parse::<u8>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(|| anyhow!("Invalid value '{}', expected {} to {}", text, min, max))
+    }
+
+    fn contains(&self, value: u8) -> bool {
+        self.allowed & (1 << value) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: Field,
+    hours: Field,
+    days_of_month: Field,
+    months: Field,
+    days_of_week: Field,
+}
+
+impl FromStr for CronSchedule {
+    type Err = AnyhowError;
+
+    fn from_str(s: &str) -> AnyhowResult<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            bail!(
+                "Invalid cron expression '{}', expected five fields (minute hour day-of-month month day-of-week)",
+                s
+            )
+        }
+        let context = || format!("Invalid cron expression '{}'", s);
+        let mut days_of_week = Field::parse(fields[4], 0, 7).with_context(context)?;
+        // Both 0 and 7 are Sunday
+        if days_of_week.contains(7) {
+            days_of_week.allowed = (days_of_week.allowed | 1) & !(1 << 7);
+        }
+        let schedule = Self {
+            expression: fields.join(" "),
+            minutes: Field::parse(fields[0], 0, 59).with_context(context)?,
+            hours: Field::parse(fields[1], 0, 23).with_context(context)?,
+            days_of_month: Field::parse(fields[2], 1, 31).with_context(context)?,
+            months: Field::parse(fields[3], 1, 12).with_context(context)?,
+            days_of_week,
+        };
+        if schedule.next_after(OffsetDateTime::now_utc()).is_none() {
+            bail!("Cron expression '{}' never matches", s)
+        }
+        Ok(schedule)
+    }
+}
+
+impl std::fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl CronSchedule {
+    fn day_matches(&self, date: Date) -> bool {
+        let day_of_month = self.days_of_month.contains(date.day());
+        let day_of_week = self
+            .days_of_week
+            .contains(date.weekday().number_days_from_sunday());
+        match (self.days_of_month.restricted, self.days_of_week.restricted) {
+            (true, true) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+
+    /// The first matching minute strictly after the given time, if any
+    pub fn next_after(&self, after: OffsetDateTime) -> Option<OffsetDateTime> {
+        let after = after.to_offset(time::UtcOffset::UTC);
+        let mut date = after.date();
+        let mut first_minute = Some((after.hour(), after.minute() + 1));
+        for _ in 0..MAX_DAYS_AHEAD {
+            if self.months.contains(date.month() as u8) && self.day_matches(date) {
+                if let Some(time) = self.first_time_at_or_after(first_minute) {
+                    return Some(date.with_time(time).assume_utc());
+                }
+            }
+            date = date.next_day()?;
+            first_minute = None;
+        }
+        None
+    }
+
+    fn first_time_at_or_after(&self, from: Option<(u8, u8)>) -> Option<Time> {
+        let (from_hour, from_minute) = from.unwrap_or((0, 0));
+        for hour in (from_hour..24).filter(|hour| self.hours.contains(*hour)) {
+            let start = if hour == from_hour { from_minute } else { 0 };
+            if let Some(minute) = (start..60).find(|minute| self.minutes.contains(*minute)) {
+                return Time::from_hms(hour, minute, 0).ok();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+
+    fn date(year: i32, month: Month, day: u8) -> Date {
+        Date::from_calendar_date(year, month, day).unwrap()
+    }
+
+    fn at(date: Date, hour: u8, minute: u8, second: u8) -> OffsetDateTime {
+        date.with_hms(hour, minute, second).unwrap().assume_utc()
+    }
+
+    fn next(expression: &str, after: OffsetDateTime) -> OffsetDateTime {
+        CronSchedule::from_str(expression)
+            .unwrap()
+            .next_after(after)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_every_minute() {
+        let now = at(date(2022, Month::June, 1), 10, 15, 30);
+        assert_eq!(
+            next("* * * * *", now),
+            at(date(2022, Month::June, 1), 10, 16, 0)
+        );
+        // Strictly after, even if we are exactly on a matching minute
+        assert_eq!(
+            next("* * * * *", at(date(2022, Month::June, 1), 10, 16, 0)),
+            at(date(2022, Month::June, 1), 10, 17, 0)
+        );
+    }
+
+    #[test]
+    fn test_steps_and_lists() {
+        let now = at(date(2022, Month::June, 1), 10, 15, 30);
+        assert_eq!(
+            next("*/5 * * * *", now),
+            at(date(2022, Month::June, 1), 10, 20, 0)
+        );
+        assert_eq!(
+            next("0,30 * * * *", now),
+            at(date(2022, Month::June, 1), 10, 30, 0)
+        );
+        assert_eq!(
+            next("10-12 * * * *", now),
+            at(date(2022, Month::June, 1), 11, 10, 0)
+        );
+        assert_eq!(
+            next("5/20 * * * *", now),
+            at(date(2022, Month::June, 1), 10, 25, 0)
+        );
+    }
+
+    #[test]
+    fn test_rollover() {
+        let now = at(date(2022, Month::December, 31), 23, 59, 0);
+        assert_eq!(
+            next("* * * * *", now),
+            at(date(2023, Month::January, 1), 0, 0, 0)
+        );
+        assert_eq!(
+            next("0 12 29 2 *", now),
+            at(date(2024, Month::February, 29), 12, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_days() {
+        // 2022-06-01 is a Wednesday
+        let now = at(date(2022, Month::June, 1), 10, 0, 0);
+        assert_eq!(
+            next("0 8 * * 0", now),
+            at(date(2022, Month::June, 5), 8, 0, 0)
+        );
+        assert_eq!(
+            next("0 8 * * 7", now),
+            at(date(2022, Month::June, 5), 8, 0, 0)
+        );
+        assert_eq!(
+            next("0 8 15 * *", now),
+            at(date(2022, Month::June, 15), 8, 0, 0)
+        );
+        // Either day of month or day of week
+        assert_eq!(
+            next("0 8 15 * 5", now),
+            at(date(2022, Month::June, 3), 8, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        for expression in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "0 0 31 2 *",
+        ] {
+            assert!(
+                CronSchedule::from_str(expression).is_err(),
+                "{} should be invalid",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            CronSchedule::from_str(" */5  *  * * 1-5")
+                .unwrap()
+                .to_string(),
+            "*/5 * * * 1-5"
+        );
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Exit codes of the controller, st. automation can tell the different kinds of failures apart.
+//! Errors are categorized at the top level: Either they were tagged explicitly via
+//! `.context(ExitCode::...)`, or we recognize the underlying error type.
+
+use crate::{agent_receiver_api, certs, modes};
+use http::StatusCode;
+
+/// Keep in sync with constants::EXIT_CODES_HELP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success,
+    Failure,
+    // Used by clap when parsing the command line fails
+    Usage,
+    Network,
+    Authentication,
+    AlreadyExists,
+    ConfigInvalid,
+    CertificateExpired,
+    CredentialHelper,
+    RegistrationDeclined,
+    ConnectionTypeMismatch,
+}
+
+impl std::fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Success => "Success",
+                Self::Failure => "Failure",
+                Self::Usage => "Invalid usage",
+                Self::Network => "Network error",
+                Self::Authentication => "Authentication failed",
+                Self::AlreadyExists => "Already exists",
+                Self::ConfigInvalid => "Invalid configuration",
+                Self::CertificateExpired => "Certificate expired",
+                Self::CredentialHelper => "Credential helper failed",
+                Self::RegistrationDeclined => "Registration declined",
+                Self::ConnectionTypeMismatch => "Connection type mismatch",
+            }
+        )
+    }
+}
+
+impl ExitCode {
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::Success => 0,
+            Self::Failure => 1,
+            Self::Usage => 2,
+            Self::Network => 3,
+            Self::Authentication => 4,
+            Self::AlreadyExists => 5,
+            Self::ConfigInvalid => 6,
+            Self::CertificateExpired => 7,
+            Self::CredentialHelper => 8,
+            Self::RegistrationDeclined => 9,
+            Self::ConnectionTypeMismatch => 10,
+        }
+    }
+
+    /// Machine-readable name of the category, as reported in JSON error objects
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+            Self::Usage => "usage",
+            Self::Network => "network",
+            Self::Authentication => "authentication",
+            Self::AlreadyExists => "already_exists",
+            Self::ConfigInvalid => "config_invalid",
+            Self::CertificateExpired => "certificate_expired",
+            Self::CredentialHelper => "credential_helper",
+            Self::RegistrationDeclined => "registration_declined",
+            Self::ConnectionTypeMismatch => "connection_type_mismatch",
+        }
+    }
+}
+
+/// The error object printed on stdout for modes with JSON output
+pub fn error_json(err: &anyhow::Error) -> serde_json::Value {
+    let exit_code = ExitCode::from(err);
+    serde_json::json!({
+        "error": {
+            "category": exit_code.category(),
+            "exit_code": exit_code.code(),
+            "message": format!("{:#}", err),
+        }
+    })
+}
+
+impl From<&anyhow::Error> for ExitCode {
+    fn from(err: &anyhow::Error) -> Self {
+        if err.is::<modes::status::CertificateExpired>() {
+            return Self::CertificateExpired;
+        }
+        // Also finds explicit tags further down the chain of contexts
+        if let Some(exit_code) = err.downcast_ref::<ExitCode>() {
+            return *exit_code;
+        }
+        err.chain().find_map(categorize).unwrap_or(Self::Failure)
+    }
+}
+
+fn categorize(err: &(dyn std::error::Error + 'static)) -> Option<ExitCode> {
+    if let Some(response_error) = err.downcast_ref::<agent_receiver_api::ResponseError>() {
+        return match response_error.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(ExitCode::Authentication),
+            StatusCode::CONFLICT => Some(ExitCode::AlreadyExists),
+            StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => Some(ExitCode::Network),
+            _ => None,
+        };
+    }
+    if err.is::<agent_receiver_api::ConnectTimeout>() {
+        return Some(ExitCode::Network);
+    }
+    // Certificates and keys we fail to parse come from the registry or from the user. Where they
+    // come from the site, the caller tags them explicitly.
+    if err.is::<modes::pull::PortInUse>() || err.is::<certs::PemError>() {
+        return Some(ExitCode::ConfigInvalid);
+    }
+    if let Some(reqwest_error) = err.downcast_ref::<reqwest::Error>() {
+        if reqwest_error.is_connect() || reqwest_error.is_timeout() {
+            return Some(ExitCode::Network);
+        }
+    }
+    if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
+        use std::io::ErrorKind;
+        if matches!(
+            io_error.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::AddrNotAvailable
+                | ErrorKind::TimedOut
+        ) {
+            return Some(ExitCode::Network);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    fn response_error(status: StatusCode) -> anyhow::Error {
+        anyhow!(agent_receiver_api::ResponseError {
+            status,
+            description: String::from("Request failed"),
+        })
+    }
+
+    #[test]
+    fn test_generic_error() {
+        assert_eq!(ExitCode::from(&anyhow!("some error")), ExitCode::Failure);
+        assert_eq!(
+            ExitCode::from(&response_error(StatusCode::INTERNAL_SERVER_ERROR)),
+            ExitCode::Failure
+        );
+    }
+
+    #[test]
+    fn test_certificate_expired() {
+        assert_eq!(
+            ExitCode::from(&anyhow!(modes::status::CertificateExpired)),
+            ExitCode::CertificateExpired
+        );
+        assert_eq!(ExitCode::CertificateExpired.code(), 7);
+    }
+
+    #[test]
+    fn test_codes_distinct_and_documented() {
+        let all = [
+            ExitCode::Success,
+            ExitCode::Failure,
+            ExitCode::Usage,
+            ExitCode::Network,
+            ExitCode::Authentication,
+            ExitCode::AlreadyExists,
+            ExitCode::ConfigInvalid,
+            ExitCode::CertificateExpired,
+            ExitCode::CredentialHelper,
+            ExitCode::RegistrationDeclined,
+            ExitCode::ConnectionTypeMismatch,
+        ];
+        let codes: std::collections::HashSet<i32> = all.iter().map(ExitCode::code).collect();
+        assert_eq!(codes.len(), all.len());
+        for code in codes {
+            assert!(
+                crate::constants::EXIT_CODES_HELP
+                    .lines()
+                    .any(|line| line.trim_start().starts_with(&format!("{}  ", code))),
+                "Exit code {} is not documented",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_explicit_tag() {
+        assert_eq!(
+            ExitCode::from(
+                &Err::<(), _>(anyhow!("bad value"))
+                    .context(ExitCode::ConfigInvalid)
+                    .context("Loading the config failed")
+                    .unwrap_err()
+            ),
+            ExitCode::ConfigInvalid
+        );
+    }
+
+    #[test]
+    fn test_response_error() {
+        for (status, expected) in [
+            (StatusCode::UNAUTHORIZED, ExitCode::Authentication),
+            (StatusCode::FORBIDDEN, ExitCode::Authentication),
+            (StatusCode::CONFLICT, ExitCode::AlreadyExists),
+            (StatusCode::BAD_GATEWAY, ExitCode::Network),
+        ] {
+            assert_eq!(
+                ExitCode::from(&response_error(status).context("Registration failed")),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_error_json() {
+        assert_eq!(
+            error_json(&response_error(StatusCode::CONFLICT).context("Registration failed")),
+            serde_json::json!({
+                "error": {
+                    "category": "already_exists",
+                    "exit_code": 5,
+                    "message": "Registration failed: Request failed",
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_io_error() {
+        assert_eq!(
+            ExitCode::from(
+                &anyhow!(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+                    .context("Connecting failed")
+            ),
+            ExitCode::Network
+        );
+        assert_eq!(
+            ExitCode::from(&anyhow!(std::io::Error::from(
+                std::io::ErrorKind::PermissionDenied
+            ))),
+            ExitCode::Failure
+        );
+    }
+
+    #[test]
+    fn test_pem_error() {
+        assert_eq!(
+            ExitCode::from(&anyhow!(certs::PemError::Empty).context("Invalid private key")),
+            ExitCode::ConfigInvalid
+        );
+        assert_eq!(
+            ExitCode::from(
+                &anyhow!(certs::PemError::Empty)
+                    .context(ExitCode::Failure)
+                    .context("Received invalid certificate")
+            ),
+            ExitCode::Failure
+        );
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Plain HTTP health probe of the pull daemon, for load balancers which can't check a TLS
+//! service. GET (or HEAD) /health answers 200 while we listen for pull requests and 503
+//! otherwise, eg. while retrying to bind the pull port, while the registry holds no pull
+//! connections and while shutting down. The answer is a fixed text, nothing from the registry or
+//! the agent is exposed.
+
+use super::metrics;
+use anyhow::{Context, Result as AnyhowResult};
+use log::{debug, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct Health {
+    listening: AtomicBool,
+}
+
+/// Healthy as long as this is alive
+pub struct Listening(Arc<Health>);
+
+impl Health {
+    pub fn listening(self: &Arc<Self>) -> Listening {
+        self.listening.store(true, Ordering::SeqCst);
+        Listening(self.clone())
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        self.0.listening.store(false, Ordering::SeqCst);
+    }
+}
+
+fn http_response(status: &str, body: &str, with_body: bool) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        if with_body { body } else { "" }
+    )
+}
+
+fn response_for(request_head: &str, healthy: bool) -> String {
+    let mut request_line = request_head.lines().next().unwrap_or("").split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+    let with_body = method != Some("HEAD");
+    match (method, path) {
+        (Some("GET" | "HEAD"), Some("/health")) => match healthy {
+            true => http_response("200 OK", "ok\n", with_body),
+            false => http_response(
+                "503 Service Unavailable",
+                "not listening for pull requests\n",
+                with_body,
+            ),
+        },
+        (Some("GET" | "HEAD"), Some(_)) => {
+            http_response("404 Not Found", "Not found, try /health\n", with_body)
+        }
+        _ => http_response(
+            "405 Method Not Allowed",
+            "Only GET and HEAD are supported\n",
+            true,
+        ),
+    }
+}
+
+async fn handle_probe(mut stream: TcpStream, health: &Health) -> AnyhowResult<()> {
+    let request_head = metrics::read_request_head(&mut stream).await?;
+    let response = response_for(&request_head, health.is_healthy());
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+pub async fn bind(address: std::net::SocketAddr) -> AnyhowResult<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .context(format!("Failed to listen for health probes on {}", address))
+}
+
+/// Answers health probes forever
+pub async fn serve(listener: TcpListener, health: Arc<Health>) {
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed accepting health probe connection. ({})", err);
+                continue;
+            }
+        };
+        let health = health.clone();
+        tokio::spawn(async move {
+            match timeout(PROBE_TIMEOUT, handle_probe(stream, &health)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("{}: Health probe failed. ({})", remote, err),
+                Err(_) => debug!("{}: Health probe timed out.", remote),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_listening() {
+        let health = Arc::new(Health::default());
+        assert!(!health.is_healthy());
+        let listening = health.listening();
+        assert!(health.is_healthy());
+        drop(listening);
+        assert!(!health.is_healthy());
+    }
+
+    #[test]
+    fn test_response_for() {
+        let head = |request_line: &str| format!("{}\r\nHost: lb\r\n\r\n", request_line);
+        assert!(
+            response_for(&head("GET /health HTTP/1.1"), true).starts_with("HTTP/1.1 200 OK\r\n")
+        );
+        assert!(response_for(&head("GET /health HTTP/1.1"), true).ends_with("\r\n\r\nok\n"));
+        assert!(response_for(&head("GET /health HTTP/1.1"), false)
+            .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        let head_response = response_for(&head("HEAD /health HTTP/1.1"), true);
+        assert!(head_response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head_response.contains("Content-Length: 3\r\n"));
+        assert!(head_response.ends_with("\r\n\r\n"));
+        assert!(response_for(&head("GET /metrics HTTP/1.1"), true)
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response_for(&head("POST /health HTTP/1.1"), true)
+            .starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let health = Arc::new(Health::default());
+        let _listening = health.listening();
+        tokio::spawn(serve(listener, health.clone()));
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: lb\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok\n"));
+    }
+}
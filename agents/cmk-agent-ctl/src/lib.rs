@@ -2,113 +2,421 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+mod access_log;
 mod agent_receiver_api;
+mod audit;
 pub mod certs;
 mod cli;
+mod clock_skew;
 pub mod configuration;
 mod constants;
+mod cron;
+mod exit_codes;
+mod health;
 #[cfg(windows)]
 mod log_ext;
+#[cfg(unix)]
+mod log_syslog;
+mod logging;
 #[cfg(windows)]
 pub mod mailslot_transport;
+mod metrics;
 mod misc;
 pub mod modes;
 mod monitoring_data;
+mod output_hooks;
+mod proxy;
+mod proxy_protocol;
+mod pull_admin;
+mod request_limit;
+#[cfg(unix)]
+mod sd_notify;
+mod security_log;
 mod setup;
 pub mod site_spec;
-mod tls_server;
+mod tls_debug;
+mod tls_keylog;
+pub mod tls_server;
+mod tmp_dir;
 pub mod types;
-use anyhow::{anyhow, Context, Result as AnyhowResult};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
 use configuration::config;
 use configuration::config::TOMLLoaderMissingSafe;
+use exit_codes::ExitCode::ConfigInvalid;
 use log::info;
+use modes::capabilities::show_capabilities;
 use modes::daemon::daemon;
 use modes::delete_connection::{delete, delete_all};
 use modes::dump::dump;
+use modes::dump_certs::dump_certs;
+use modes::export::export;
+#[cfg(feature = "dev")]
+use modes::gen_test_certs::gen_test_certs;
+use modes::healthcheck::healthcheck;
 use modes::import_connection::import;
 use modes::pull::pull;
 use modes::push::handle_push_cycle as push;
 use modes::registration;
+use modes::repair::repair;
+use modes::self_test::self_test;
+use modes::show_config::show_config;
 use modes::status::status;
+use modes::test_pull::test_pull;
+use modes::trust_root::trust_root;
+use modes::update_connection::update_connection;
+use modes::validate::validate;
+use modes::verify::verify;
 pub use setup::init;
 
 #[cfg(windows)]
 pub use misc::validate_elevation;
 
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    exit_codes::ExitCode::from(err).code()
+}
+
 pub fn run_requested_mode(args: cli::Args, paths: setup::PathResolver) -> AnyhowResult<()> {
-    configuration::migrate::migrate_registered_connections(&paths.registry_path)?;
+    // With JSON output, automation gets the error on stdout as well, including its category
+    let json_errors = matches!(&args, cli::Args::RegisterHostName(reg_args)
+        if reg_args.output_format == cli::OutputFormat::Json);
+    let result = _run_requested_mode(args, paths);
+    if json_errors {
+        if let Err(err) = &result {
+            println!("{}", exit_codes::error_json(err));
+        }
+    }
+    result
+}
+
+fn _run_requested_mode(args: cli::Args, paths: setup::PathResolver) -> AnyhowResult<()> {
+    // Works without registry and config file, st. it can run anywhere
+    if let cli::Args::SelfTest(..) = args {
+        return self_test(pull_opts_from_config());
+    }
+    if let cli::Args::Capabilities(..) = args {
+        return show_capabilities();
+    }
+    #[cfg(feature = "dev")]
+    if let cli::Args::GenTestCerts(gen_test_certs_args) = &args {
+        return gen_test_certs(gen_test_certs_args);
+    }
+    wait_for_registry(&args, &paths.registry_path);
+    let registry_read_only = registry_read_only(&args, &paths.registry_path);
+    let migration_result = match registry_read_only {
+        true => Ok(()),
+        false => configuration::migrate::migrate_registered_connections(&paths.registry_path),
+    };
+    // Validation must not stop at the first file which fails to load. A registry which can't be
+    // migrated fails to load as well and is reported as such.
+    if let cli::Args::Validate(..) = args {
+        return validate(&paths.config_path, &paths.registry_path).context(ConfigInvalid);
+    }
+    migration_result.context(ConfigInvalid)?;
+    if registry_read_only && modifies_registry(&args) {
+        bail!(
+            "Connection registry {} is read-only, it cannot be modified",
+            paths.registry_path.display()
+        )
+    }
     agent_socket_operational(&args)?;
+    // Held until the mode has finished, st. loading, modifying and saving the registry is atomic
+    let _registry_lock = registry_lock(&args, &paths.registry_path)?;
 
-    let runtime_config = config::RuntimeConfig::load_missing_safe(&paths.config_path)?;
-    let mut registry = config::Registry::from_file(&paths.registry_path).with_context(|| {
+    let runtime_config =
+        config::RuntimeConfig::load_missing_safe(&paths.config_path).context(ConfigInvalid)?;
+    let mut registry = config::Registry::from_file_with_limit(
+        &paths.registry_path,
+        runtime_config.max_registered_connections(),
+        duplicate_uuids(&args, &runtime_config),
+    )
+    .context(ConfigInvalid)
+    .with_context(|| {
         format!(
             "Error while loading registered connections from {:?}.",
             &paths.registry_path
         )
     })?;
+    if registry_read_only {
+        info!("Connection registry is read-only, it will not be written");
+        registry.set_read_only();
+    }
     info!(
         "Loaded config from '{:?}', connection registry from '{:?}'",
         &paths.config_path, &paths.registry_path
     );
-    match args {
-        cli::Args::RegisterHostName(reg_args) => registration::register_host_name(
-            &config::RegistrationConfigHostName::new(runtime_config, reg_args)?,
-            &mut registry,
-        ),
+    let audit = match (runtime_config.audit_log(), audit_action(&args)) {
+        (Some(path), Some((action, target))) => {
+            Some(audit::Audit::start(path, action, target, &registry))
+        }
+        _ => None,
+    };
+    let result = match args {
+        cli::Args::RegisterHostName(reg_args) => {
+            let output_format = reg_args.output_format.clone();
+            registration::register_host_names(
+                config::RegistrationConfigHostName::new_multiple(runtime_config, reg_args)
+                    .context(ConfigInvalid)?,
+                &mut registry,
+                &output_format,
+            )
+        }
         cli::Args::RegisterAgentLabels(reg_args) => registration::register_agent_labels(
             &config::RegistrationConfigAgentLabels::new(
-                config::RegistrationConnectionConfig::new(
-                    runtime_config,
-                    reg_args.connection_args,
-                )?,
+                config::RegistrationConnectionConfig::new(runtime_config, reg_args.connection_args)
+                    .context(ConfigInvalid)?,
                 reg_args.agent_labels_raw.into_iter().collect(),
-            )?,
+            )
+            .context(ConfigInvalid)?,
             &mut registry,
         ),
         cli::Args::ProxyRegister(proxy_reg_args) => registration::proxy_register(
             &config::RegistrationConfigHostName::new(runtime_config, proxy_reg_args)?,
         ),
         cli::Args::Import(import_args) => import(&mut registry, &import_args),
+        cli::Args::Export(export_args) => export(&registry, &export_args),
         cli::Args::Push(push_args) => push(
             &registry,
-            &config::ClientConfig::new(runtime_config, push_args.client_opts),
+            &config::ClientConfig::new(runtime_config, push_args.client_opts)
+                .context(ConfigInvalid)?,
             &setup::agent_channel(),
+            &paths.push_results_path,
+        ),
+        cli::Args::Pull(pull_args) => pull(
+            config::PullConfig::new(
+                runtime_config,
+                pull_args.pull_opts,
+                registry,
+                &paths.pull_counters_path,
+            )?
+            .reloadable(&paths.config_path),
         ),
-        cli::Args::Pull(pull_args) => pull(config::PullConfig::new(
-            runtime_config,
-            pull_args.pull_opts,
-            registry,
-        )?),
         cli::Args::Daemon(daemon_args) => daemon(
             &paths.pre_configured_connections_path,
-            registry.clone(),
-            config::PullConfig::new(runtime_config.clone(), daemon_args.pull_opts, registry)?,
-            config::ClientConfig::new(runtime_config, daemon_args.client_opts),
+            config::PullConfig::new(
+                runtime_config.clone(),
+                daemon_args.pull_opts,
+                registry,
+                &paths.pull_counters_path,
+            )?
+            .reloadable(&paths.config_path),
+            config::ClientConfig::new(runtime_config, daemon_args.client_opts)?,
+            config::PushRetryConfig::new(daemon_args.push_retry_opts)?,
+            config::PushScheduleConfig::new(daemon_args.push_schedule_opts)?,
+            paths.push_results_path,
+            daemon_args.mode,
         ),
         cli::Args::Dump { .. } => dump(),
+        cli::Args::DumpCerts(dump_certs_args) => dump_certs(&registry, &dump_certs_args),
         cli::Args::Status(status_args) => status(
             &registry,
             &config::PullConfig::new(
                 runtime_config.clone(),
                 // this will vanish once the Windows agent also uses the toml config
-                cli::PullOpts {
-                    port: None,
-                    #[cfg(windows)]
-                    agent_channel: None,
-                },
+                pull_opts_from_config(),
                 registry.clone(),
-            )?,
-            config::ClientConfig::new(runtime_config, status_args.client_opts),
-            status_args.json,
-            !status_args.no_query_remote,
+                &paths.pull_counters_path,
+            )
+            .context(ConfigInvalid)?,
+            config::ClientConfig::new(runtime_config, status_args.client_opts.clone())
+                .context(ConfigInvalid)?,
+            &status_args,
+            &paths.push_results_path,
+        ),
+        cli::Args::Healthcheck(healthcheck_args) => healthcheck(
+            &config::PullConfig::new(
+                runtime_config,
+                pull_opts_from_config(),
+                registry,
+                &paths.pull_counters_path,
+            )
+            .context(ConfigInvalid)?,
+            &healthcheck_args,
+        ),
+        cli::Args::ShowConfig(show_config_args) => show_config(
+            &config::PullConfig::new(
+                runtime_config,
+                show_config_args.pull_opts,
+                registry,
+                &paths.pull_counters_path,
+            )
+            .context(ConfigInvalid)?
+            .reloadable(&paths.config_path),
+            &show_config_args.output_format,
+        ),
+        cli::Args::TestPull(test_pull_args) => test_pull(
+            &config::PullConfig::new(
+                runtime_config,
+                pull_opts_from_config(),
+                registry,
+                &paths.pull_counters_path,
+            )
+            .context(ConfigInvalid)?,
+            &test_pull_args,
+        ),
+        cli::Args::Delete(delete_args) => delete(&mut registry, &delete_args),
+        cli::Args::UpdateConnection(update_args) => update_connection(
+            &mut registry,
+            &update_args,
+            &config::ClientConfig::new(runtime_config, update_args.client_opts.clone())?,
+        ),
+        cli::Args::TrustRoot(trust_root_args) => trust_root(&mut registry, &trust_root_args),
+        cli::Args::Repair(repair_args) => repair(&mut registry, &repair_args),
+        cli::Args::Verify(verify_args) => verify(
+            &registry,
+            &verify_args.connection,
+            &config::ClientConfig::new(runtime_config, verify_args.client_opts)?,
+        ),
+        cli::Args::DeleteAll(delete_all_args) => delete_all(
+            &mut registry,
+            delete_all_args.enable_insecure_connections,
+            delete_all_args.force,
         ),
-        cli::Args::Delete(delete_args) => delete(&mut registry, &delete_args.connection),
-        cli::Args::DeleteAll(delete_all_args) => {
-            delete_all(&mut registry, delete_all_args.enable_insecure_connections)
+        cli::Args::Validate(..) | cli::Args::SelfTest(..) | cli::Args::Capabilities(..) => {
+            unreachable!("handled above")
         }
+        #[cfg(feature = "dev")]
+        cli::Args::GenTestCerts(..) => unreachable!("handled above"),
+    };
+    match audit {
+        Some(audit) => audit.finish(&paths.registry_path, result),
+        None => result,
     }
 }
 
+/// For modes which need the pull settings without having pull command line options
+fn pull_opts_from_config() -> cli::PullOpts {
+    cli::PullOpts {
+        port: vec![],
+        tls_min_version: None,
+        cache_ttl: None,
+        metrics_listen: None,
+        health_listen: None,
+        max_connections: None,
+        worker_threads: None,
+        listen_backlog: None,
+        retry_bind: None,
+        pull_bandwidth_limit: None,
+        io_chunk_size: None,
+        registry_readonly: false,
+        registry_load_retry: None,
+        require_registry: false,
+        allow_any: false,
+        no_client_auth: false,
+        explain_rejections: false,
+        strict_startup: false,
+        on_agent_unavailable: None,
+        #[cfg(unix)]
+        pull_unix_socket: None,
+        #[cfg(windows)]
+        agent_channel: None,
+    }
+}
+
+/// Repairing removes duplicate UUIDs, so it has to be able to load them in the first place
+fn duplicate_uuids(
+    args: &cli::Args,
+    runtime_config: &config::RuntimeConfig,
+) -> config::DuplicateUuidPolicy {
+    match args {
+        cli::Args::Repair(..) => config::DuplicateUuidPolicy::KeepFirst,
+        _ => runtime_config.duplicate_uuids(),
+    }
+}
+
+/// The short-lived modes which modify the registry. The daemon only does so when processing
+/// pre-configured connections at startup.
+fn modifies_registry(args: &cli::Args) -> bool {
+    match args {
+        // Without changes requested, the trusted roots are only listed
+        cli::Args::TrustRoot(trust_root_args) => {
+            trust_root_args.add.is_some() || trust_root_args.remove.is_some()
+        }
+        cli::Args::Repair(repair_args) => !repair_args.dry_run,
+        _ => matches!(
+            args,
+            cli::Args::RegisterHostName { .. }
+                | cli::Args::RegisterAgentLabels { .. }
+                | cli::Args::Import { .. }
+                | cli::Args::Delete { .. }
+                | cli::Args::DeleteAll { .. }
+                | cli::Args::UpdateConnection { .. }
+        ),
+    }
+}
+
+/// What to record in the audit log for the modes which modify the registry, along with the
+/// connection given on the command line, if any
+fn audit_action(args: &cli::Args) -> Option<(audit::Action, Option<String>)> {
+    match args {
+        cli::Args::RegisterHostName(..) | cli::Args::RegisterAgentLabels(..) => {
+            Some((audit::Action::Register, None))
+        }
+        cli::Args::Import(..) => Some((audit::Action::Import, None)),
+        cli::Args::Delete(delete_args) => Some((
+            audit::Action::Delete,
+            delete_args
+                .connection
+                .clone()
+                .or(delete_args.site.as_ref().map(|site_id| site_id.to_string())),
+        )),
+        cli::Args::DeleteAll(..) => Some((audit::Action::Delete, None)),
+        cli::Args::UpdateConnection(update_args) => {
+            Some((audit::Action::Update, Some(update_args.connection.clone())))
+        }
+        cli::Args::TrustRoot(trust_root_args) if modifies_registry(args) => Some((
+            audit::Action::TrustRoot,
+            Some(trust_root_args.connection.clone()),
+        )),
+        cli::Args::Repair(..) if modifies_registry(args) => Some((audit::Action::Repair, None)),
+        _ => None,
+    }
+}
+
+/// Only modes which modify the registry take the lock. Read-only modes don't need it, and the
+/// daemon must not block the other modes for its whole lifetime.
+fn registry_lock(
+    args: &cli::Args,
+    registry_path: &std::path::Path,
+) -> AnyhowResult<Option<config::RegistryLock>> {
+    match modifies_registry(args) {
+        true => Ok(Some(config::RegistryLock::acquire(
+            registry_path,
+            std::time::Duration::from_secs(constants::REGISTRY_LOCK_TIMEOUT),
+        )?)),
+        false => Ok(None),
+    }
+}
+
+/// Storage which is mounted late, eg. network-mounted home directories at boot, fails to read
+/// for a while. Only waits until the registry can be read, st. migrating and loading it below
+/// see the actual content and report on it.
+fn wait_for_registry(args: &cli::Args, registry_path: &std::path::Path) {
+    let retry_for = match args {
+        cli::Args::Pull(pull_args) => pull_args.pull_opts.registry_load_retry,
+        cli::Args::Daemon(daemon_args) => daemon_args.pull_opts.registry_load_retry,
+        _ => None,
+    };
+    if let Some(retry_for) = retry_for.filter(|retry_for| *retry_for > 0) {
+        let _ = config::Registry::from_file_retrying(
+            registry_path,
+            std::time::Duration::from_secs(retry_for),
+            std::time::Duration::from_secs(constants::REGISTRY_LOAD_RETRY_INTERVAL),
+        );
+    }
+}
+
+/// Pull-only containers may have been registered when building the image and run with a
+/// read-only file system. In this case, we must neither migrate nor save the registry.
+fn registry_read_only(args: &cli::Args, registry_path: &std::path::Path) -> bool {
+    let requested = match args {
+        cli::Args::Pull(pull_args) => pull_args.pull_opts.registry_readonly,
+        cli::Args::Daemon(daemon_args) => daemon_args.pull_opts.registry_readonly,
+        cli::Args::ShowConfig(show_config_args) => show_config_args.pull_opts.registry_readonly,
+        _ => false,
+    };
+    requested || registry_path.parent().is_some_and(misc::is_on_read_only_fs)
+}
+
 // This check is currently only useful on Unix. On Windows, the internal agent address can be passed
 // on the command line, so we cannot easily check this for any mode.
 fn agent_socket_operational(args: &cli::Args) -> AnyhowResult<()> {
@@ -126,3 +434,18 @@ fn agent_socket_operational(args: &cli::Args) -> AnyhowResult<()> {
         _ => Ok(()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&anyhow!("some error")), 1);
+        assert_eq!(exit_code(&anyhow!(modes::status::CertificateExpired)), 7);
+        assert_eq!(
+            exit_code(&anyhow!("bad value").context(exit_codes::ExitCode::ConfigInvalid)),
+            6
+        );
+    }
+}
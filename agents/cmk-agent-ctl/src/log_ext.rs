@@ -2,6 +2,7 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+use super::logging;
 use super::mailslot_transport::{send_to_mailslot, service_mailslot_name, DataType};
 use flexi_logger::writers::LogWriter;
 use flexi_logger::DeferredNow;
@@ -46,38 +47,6 @@ impl LogWriter for MailSlotLogWriter {
     }
 }
 
-pub fn to_log_level(level: &str) -> log::LevelFilter {
-    let result = flexi_logger::LogSpecification::env_or_parse(level);
-    match result {
-        Ok(spec) => spec
-            .module_filters()
-            .iter()
-            .map(|d| d.level_filter)
-            .max()
-            .unwrap_or(log::LevelFilter::Trace),
-        Err(_) => log::LevelFilter::Trace,
-    }
-}
-
 pub fn make_mailslot_logger(level: &str) -> Box<MailSlotLogWriter> {
-    Box::new(MailSlotLogWriter::new(to_log_level(level)))
-}
-
-#[cfg(test)]
-#[cfg(windows)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_log_levels() {
-        let tests = [
-            ("warn", log::LevelFilter::Warn),
-            ("debug", log::LevelFilter::Debug),
-            ("info", log::LevelFilter::Info),
-            ("bad", log::LevelFilter::Trace),
-        ];
-        for &(s, expected) in &tests {
-            assert_eq!(to_log_level(s), expected);
-        }
-    }
+    Box::new(MailSlotLogWriter::new(logging::to_log_level(level)))
 }
@@ -0,0 +1,130 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::logging;
+use flexi_logger::writers::LogWriter;
+use flexi_logger::{DeferredNow, FormatFunction};
+use log::Record;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const SYSLOG_IDENTITY: &str = "cmk-agent-ctl";
+
+fn severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+/// Writes log events to the local syslog daemon, in the format understood by syslog(3)
+pub struct SyslogLogWriter {
+    socket: UnixDatagram,
+    facility: logging::SyslogFacility,
+    format: FormatFunction,
+    max_log_level: log::LevelFilter,
+}
+
+impl SyslogLogWriter {
+    fn new(
+        socket_path: &Path,
+        facility: logging::SyslogFacility,
+        max_log_level: log::LevelFilter,
+    ) -> std::io::Result<SyslogLogWriter> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(socket_path)?;
+        Ok(SyslogLogWriter {
+            socket,
+            facility,
+            format: flexi_logger::default_format,
+            max_log_level,
+        })
+    }
+
+    fn message(&self, now: &mut DeferredNow, record: &Record) -> std::io::Result<Vec<u8>> {
+        let mut message = format!(
+            "<{}>{}[{}]: ",
+            self.facility.code() * 8 + severity(record.level()),
+            SYSLOG_IDENTITY,
+            std::process::id()
+        )
+        .into_bytes();
+        (self.format)(&mut message, now, record)?;
+        Ok(message)
+    }
+}
+
+impl LogWriter for SyslogLogWriter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        self.socket.send(&self.message(now, record)?)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn max_log_level(&self) -> log::LevelFilter {
+        self.max_log_level
+    }
+
+    fn format(&mut self, format: FormatFunction) {
+        self.format = format;
+    }
+}
+
+pub fn make_syslog_writer(
+    level: &str,
+    facility: logging::SyslogFacility,
+) -> std::io::Result<Box<SyslogLogWriter>> {
+    Ok(Box::new(SyslogLogWriter::new(
+        Path::new(SYSLOG_SOCKET),
+        facility,
+        logging::to_log_level(level),
+    )?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("log");
+        let syslog = UnixDatagram::bind(&socket_path).unwrap();
+        let writer = SyslogLogWriter::new(
+            &socket_path,
+            logging::SyslogFacility::Local3,
+            log::LevelFilter::Trace,
+        )
+        .unwrap();
+        for (level, priority) in [(log::Level::Error, 155), (log::Level::Warn, 156)] {
+            writer
+                .write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .args(format_args!("Handling pull request."))
+                        .level(level)
+                        .module_path(Some("cmk_agent_ctl::modes::pull"))
+                        .build(),
+                )
+                .unwrap();
+            let mut buffer = [0u8; 256];
+            let received = syslog.recv(&mut buffer).unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&buffer[..received]),
+                format!(
+                    "<{}>cmk-agent-ctl[{}]: {} [cmk_agent_ctl::modes::pull] Handling pull request.",
+                    priority,
+                    std::process::id(),
+                    level
+                )
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use flexi_logger::{DeferredNow, FormatFunction, LogSpecification};
+use log::kv;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Where log events go. Only on unix, on Windows, the log target depends on the mode.
+#[cfg(unix)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Stderr,
+    Syslog,
+    Both,
+}
+
+#[cfg(unix)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyslogFacility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+#[cfg(unix)]
+impl SyslogFacility {
+    /// Numerical code as defined in RFC 5424
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+pub fn to_log_level(level: &str) -> log::LevelFilter {
+    let result = LogSpecification::env_or_parse(level);
+    match result {
+        Ok(spec) => spec
+            .module_filters()
+            .iter()
+            .map(|d| d.level_filter)
+            .max()
+            .unwrap_or(log::LevelFilter::Trace),
+        Err(_) => log::LevelFilter::Trace,
+    }
+}
+
+pub fn parse_log_filter(filter: &str) -> Result<String, String> {
+    LogSpecification::parse(filter)
+        .map(|_| filter.to_owned())
+        .map_err(|err| err.to_string())
+}
+
+/// Combines the level derived from the verbosity flags with a user-provided filter. Module
+/// directives are layered on top of the verbosity level, a global level in the filter replaces
+/// it.
+pub fn log_spec(level: &str, log_filter: Option<&str>) -> String {
+    let log_filter = match log_filter {
+        Some(log_filter) => log_filter,
+        None => return level.to_owned(),
+    };
+    let sets_global_level = LogSpecification::parse(log_filter)
+        .map(|spec| {
+            spec.module_filters()
+                .iter()
+                .any(|filter| filter.module_name.is_none())
+        })
+        .unwrap_or(false);
+    if sets_global_level {
+        log_filter.to_owned()
+    } else {
+        format!("{},{}", level, log_filter)
+    }
+}
+
+struct JSONFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> kv::Visitor<'kvs> for JSONFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(
+            key.as_str().to_owned(),
+            serde_json::Value::String(value.to_string()),
+        );
+        Ok(())
+    }
+}
+
+/// One JSON object per log event. Connection context passed as key-value pairs to the log
+/// macros (uuid, site, peer, ...) ends up in dedicated fields.
+pub fn json_format(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &log::Record,
+) -> Result<(), std::io::Error> {
+    let mut event = serde_json::Map::new();
+    event.insert(
+        String::from("timestamp"),
+        serde_json::Value::String(now.format(&time::format_description::well_known::Rfc3339)),
+    );
+    event.insert(
+        String::from("level"),
+        serde_json::Value::String(record.level().to_string()),
+    );
+    event.insert(
+        String::from("target"),
+        serde_json::Value::String(record.target().to_owned()),
+    );
+    event.insert(
+        String::from("module"),
+        serde_json::Value::String(record.module_path().unwrap_or("<unnamed>").to_owned()),
+    );
+    event.insert(
+        String::from("message"),
+        serde_json::Value::String(record.args().to_string()),
+    );
+    record
+        .key_values()
+        .visit(&mut JSONFields(&mut event))
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+    serde_json::to_writer(w, &event)?;
+    Ok(())
+}
+
+pub fn format_function(log_format: LogFormat, text_format: FormatFunction) -> FormatFunction {
+    match log_format {
+        LogFormat::Text => text_format,
+        LogFormat::Json => json_format,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_levels() {
+        let tests = [
+            ("warn", log::LevelFilter::Warn),
+            ("debug", log::LevelFilter::Debug),
+            ("info", log::LevelFilter::Info),
+            ("bad", log::LevelFilter::Trace),
+        ];
+        for &(s, expected) in &tests {
+            assert_eq!(to_log_level(s), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_log_filter() {
+        assert_eq!(
+            parse_log_filter("cmk_agent_ctl::certs=debug,info").unwrap(),
+            "cmk_agent_ctl::certs=debug,info"
+        );
+        assert!(parse_log_filter("cmk_agent_ctl::certs=loud").is_err());
+    }
+
+    #[test]
+    fn test_log_spec() {
+        assert_eq!(log_spec("warn", None), "warn");
+        assert_eq!(
+            log_spec("warn", Some("cmk_agent_ctl::certs=debug")),
+            "warn,cmk_agent_ctl::certs=debug"
+        );
+        assert_eq!(
+            log_spec("warn", Some("cmk_agent_ctl::certs=debug,info")),
+            "cmk_agent_ctl::certs=debug,info"
+        );
+    }
+
+    fn format(record: &log::Record) -> serde_json::Value {
+        let mut buffer = vec![];
+        json_format(&mut buffer, &mut DeferredNow::new(), record).unwrap();
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    #[test]
+    fn test_json_format() {
+        let event = format(
+            &log::Record::builder()
+                .args(format_args!("Handling pull request."))
+                .level(log::Level::Info)
+                .target("cmk_agent_ctl::modes::pull")
+                .module_path(Some("cmk_agent_ctl::modes::pull"))
+                .build(),
+        );
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["target"], "cmk_agent_ctl::modes::pull");
+        assert_eq!(event["module"], "cmk_agent_ctl::modes::pull");
+        assert_eq!(event["message"], "Handling pull request.");
+        assert!(event["timestamp"].is_string());
+        assert!(event.get("peer").is_none());
+    }
+
+    #[test]
+    fn test_json_format_connection_context() {
+        let context: &[(&str, &dyn kv::ToValue)] = &[("site", &"server/site"), ("peer", &"::1")];
+        let event = format(
+            &log::Record::builder()
+                .args(format_args!("Pushing agent output"))
+                .level(log::Level::Warn)
+                .key_values(&context)
+                .build(),
+        );
+        assert_eq!(event["site"], "server/site");
+        assert_eq!(event["peer"], "::1");
+        assert_eq!(event["level"], "WARN");
+    }
+}
@@ -14,12 +14,12 @@ fn main() {
     info!("starting");
     let result = cmk_agent_ctl::run_requested_mode(args, paths);
 
-    if let Err(error) = &result {
+    if let Err(error) = result {
         exit_with_error(error)
     }
 }
 
-fn exit_with_error(err: impl std::fmt::Debug) {
+fn exit_with_error(err: anyhow::Error) {
     // In case of an error, we want a non-zero exit code and log the error, which
     // goes to stderr under Unix and to stderr and logfile under Windows.
 
@@ -27,5 +27,5 @@ fn exit_with_error(err: impl std::fmt::Debug) {
     // However, this trait is still experimental at the moment. See also
     // https://www.joshmcguigan.com/blog/custom-exit-status-codes-rust/
     error!("{:?}", err);
-    std::process::exit(1);
+    std::process::exit(cmk_agent_ctl::exit_code(&err));
 }
@@ -0,0 +1,987 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{certs, config, exit_codes, tmp_dir};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_HEAD_BYTES: usize = 8 * 1024;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Counters of the pull listener. They live in memory only and start from zero whenever the
+/// listener is (re)started. Snapshots are written to disk periodically, such that other
+/// processes (status command) can access them.
+#[derive(Default)]
+pub struct PullCounters {
+    accepted: AtomicU64,
+    rejected_ip: AtomicU64,
+    handshake_failed: AtomicU64,
+    completed: AtomicU64,
+    timed_out: AtomicU64,
+    rejected_rate_limit: AtomicU64,
+    rejected_max_connections: AtomicU64,
+    rejected_max_connections_total: AtomicU64,
+    rejected_denylist: AtomicU64,
+    failed: AtomicU64,
+    bytes_served: AtomicU64,
+    active: AtomicU64,
+    explain_rejections: bool,
+    /// Unlike the other counters, these resume from disk, see ConnectionTotals
+    connection_totals: std::sync::Mutex<ConnectionTotals>,
+}
+
+/// Why we turned down a pull connection, named like the counter it's counted in
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Rejection {
+    /// Not on the allowlist
+    Ip,
+    Denylist,
+    RateLimit,
+    MaxConnections,
+    MaxConnectionsTotal,
+    HandshakeFailed,
+    TimedOut,
+    Failed,
+}
+
+impl Rejection {
+    pub fn counter_name(&self) -> &'static str {
+        match self {
+            Self::Ip => "rejected_ip",
+            Self::Denylist => "rejected_denylist",
+            Self::RateLimit => "rejected_rate_limit",
+            Self::MaxConnections => "rejected_max_connections",
+            Self::MaxConnectionsTotal => "rejected_max_connections_total",
+            Self::HandshakeFailed => "handshake_failed",
+            Self::TimedOut => "timed_out",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct PullCountersSnapshot {
+    pub accepted: u64,
+    pub rejected_ip: u64,
+    pub handshake_failed: u64,
+    pub completed: u64,
+    pub timed_out: u64,
+    // Added later, snapshots written by older versions lack them
+    #[serde(default)]
+    pub rejected_rate_limit: u64,
+    #[serde(default)]
+    pub rejected_max_connections: u64,
+    #[serde(default)]
+    pub rejected_max_connections_total: u64,
+    #[serde(default)]
+    pub rejected_denylist: u64,
+    #[serde(default)]
+    pub failed: u64,
+    #[serde(default)]
+    pub bytes_served: u64,
+    #[serde(default)]
+    pub active: u64,
+}
+
+impl config::JSONLoader for PullCountersSnapshot {}
+
+/// Like the registry, the files written here are replaced atomically, st. status never reads
+/// them half-written
+fn save_json(value: &impl Serialize, path: &Path) -> io::Result<()> {
+    let path_tmp = tmp_dir::tmp_path(path);
+    let mut file = tmp_dir::create(&path_tmp)?;
+    #[cfg(unix)]
+    config::take_over_permissions(path, &file)?;
+    io::Write::write_all(&mut file, serde_json::to_string(value)?.as_bytes())?;
+    tmp_dir::replace(&path_tmp, path)
+}
+
+fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl PullCounters {
+    /// With explain_rejections, every rejection counted via count_rejection is also logged along
+    /// with the rule or phase which turned the peer down.
+    pub fn new(explain_rejections: bool) -> Self {
+        PullCounters {
+            explain_rejections,
+            ..PullCounters::default()
+        }
+    }
+
+    pub fn count_rejection(
+        &self,
+        rejection: Rejection,
+        peer: &impl std::fmt::Display,
+        reason: impl std::fmt::Display,
+    ) {
+        match rejection {
+            Rejection::Ip => self.count_rejected_ip(),
+            Rejection::Denylist => self.count_rejected_denylist(),
+            Rejection::RateLimit => self.count_rejected_rate_limit(),
+            Rejection::MaxConnections => self.count_rejected_max_connections(),
+            Rejection::MaxConnectionsTotal => self.count_rejected_max_connections_total(),
+            Rejection::HandshakeFailed => self.count_handshake_failed(),
+            Rejection::TimedOut => self.count_timed_out(),
+            Rejection::Failed => self.count_failed(),
+        }
+        if self.explain_rejections {
+            warn!(
+                peer = peer.to_string();
+                "{}: Rejection explained: {} - {}",
+                peer,
+                rejection.counter_name(),
+                reason
+            );
+        }
+    }
+
+    pub fn count_accepted(&self) {
+        increment(&self.accepted)
+    }
+
+    pub fn count_rejected_ip(&self) {
+        increment(&self.rejected_ip)
+    }
+
+    pub fn count_handshake_failed(&self) {
+        increment(&self.handshake_failed)
+    }
+
+    pub fn count_completed(&self) {
+        increment(&self.completed)
+    }
+
+    pub fn count_timed_out(&self) {
+        increment(&self.timed_out)
+    }
+
+    pub fn count_rejected_denylist(&self) {
+        increment(&self.rejected_denylist)
+    }
+
+    pub fn count_rejected_rate_limit(&self) {
+        increment(&self.rejected_rate_limit)
+    }
+
+    pub fn count_rejected_max_connections(&self) {
+        increment(&self.rejected_max_connections)
+    }
+
+    pub fn count_rejected_max_connections_total(&self) {
+        increment(&self.rejected_max_connections_total)
+    }
+
+    /// Requests which failed for other reasons than a timeout
+    pub fn count_failed(&self) {
+        increment(&self.failed)
+    }
+
+    pub fn count_bytes_served(&self, bytes: usize) {
+        self.bytes_served.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_active_started(&self) {
+        increment(&self.active)
+    }
+
+    pub fn count_active_finished(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A pull request of the connection with the given UUID, no matter if it completed
+    pub fn count_served(&self, uuid: &str, bytes: u64) {
+        self.connection_totals.lock().unwrap().record(
+            uuid,
+            bytes,
+            time::OffsetDateTime::now_utc().unix_timestamp(),
+        );
+    }
+
+    /// Continue counting from the totals written by an earlier run
+    pub fn resume_connection_totals(&self, totals: ConnectionTotals) {
+        *self.connection_totals.lock().unwrap() = totals;
+    }
+
+    pub fn connection_totals(&self) -> ConnectionTotals {
+        self.connection_totals.lock().unwrap().clone()
+    }
+
+    pub fn snapshot(&self) -> PullCountersSnapshot {
+        PullCountersSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected_ip: self.rejected_ip.load(Ordering::Relaxed),
+            handshake_failed: self.handshake_failed.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            rejected_rate_limit: self.rejected_rate_limit.load(Ordering::Relaxed),
+            rejected_max_connections: self.rejected_max_connections.load(Ordering::Relaxed),
+            rejected_max_connections_total: self
+                .rejected_max_connections_total
+                .load(Ordering::Relaxed),
+            rejected_denylist: self.rejected_denylist.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl PullCountersSnapshot {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        save_json(self, path)
+    }
+}
+
+impl std::fmt::Display for PullCountersSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "accepted: {}, rejected (IP): {}, TLS handshake failed: {}, completed: {}, timed out: {}",
+            self.accepted, self.rejected_ip, self.handshake_failed, self.completed, self.timed_out
+        )
+    }
+}
+
+/// What a single pull connection served so far. Timestamps are seconds since the epoch.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct ConnectionUsage {
+    pub pulls: u64,
+    pub bytes_served: u64,
+    pub last_served: Option<i64>,
+}
+
+/// Usage of the pull connections by connection UUID, for long-term accounting. Unlike the pull
+/// counters, they add up over restarts: the pull daemon resumes from the totals it wrote last.
+/// Written periodically only, st. a restart loses at most the requests of one interval.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct ConnectionTotals(BTreeMap<String, ConnectionUsage>);
+
+impl config::JSONLoader for ConnectionTotals {}
+impl config::JSONLoaderMissingSafe for ConnectionTotals {}
+
+impl ConnectionTotals {
+    pub fn record(&mut self, uuid: &str, bytes: u64, timestamp: i64) {
+        let usage = self.0.entry(String::from(uuid)).or_default();
+        usage.pulls += 1;
+        usage.bytes_served += bytes;
+        usage.last_served = Some(timestamp);
+    }
+
+    pub fn get(&self, uuid: &uuid::Uuid) -> Option<&ConnectionUsage> {
+        self.0.get(&uuid.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        save_json(self, path)
+    }
+}
+
+impl std::fmt::Display for ConnectionUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} pull(s), {} bytes served, last: {}",
+            self.pulls,
+            self.bytes_served,
+            self.last_served
+                .map(format_timestamp)
+                .unwrap_or_else(|| String::from("never")),
+        )
+    }
+}
+
+/// Outcome of the most recent push to a connection. Timestamps are seconds since the epoch.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct PushResult {
+    pub timestamp: i64,
+    pub success: bool,
+    /// Category of the error, as used for the exit codes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    #[serde(default)]
+    pub last_success: Option<i64>,
+}
+
+/// Results of the most recent push attempts by connection UUID. Like the pull counters, they
+/// are kept in memory by the pushing process and written to disk after every push cycle.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct PushResults(BTreeMap<String, PushResult>);
+
+impl config::JSONLoader for PushResults {}
+impl config::JSONLoaderMissingSafe for PushResults {}
+
+impl PushResults {
+    pub fn record(&mut self, uuid: &uuid::Uuid, timestamp: i64, error: Option<&anyhow::Error>) {
+        let last_success = match error {
+            None => Some(timestamp),
+            Some(_) => self.get(uuid).and_then(|previous| previous.last_success),
+        };
+        self.0.insert(
+            uuid.to_string(),
+            PushResult {
+                timestamp,
+                success: error.is_none(),
+                error_kind: error
+                    .map(|error| String::from(exit_codes::ExitCode::from(error).category())),
+                last_success,
+            },
+        );
+    }
+
+    pub fn get(&self, uuid: &uuid::Uuid) -> Option<&PushResult> {
+        self.0.get(&uuid.to_string())
+    }
+
+    /// Forget about connections which are not registered anymore
+    pub fn retain_registered(&mut self, registry: &config::Registry) {
+        let registered: Vec<String> = registry
+            .push_connections()
+            .map(|(_, connection)| connection.trust.uuid.to_string())
+            .collect();
+        self.0.retain(|uuid, _| registered.contains(uuid));
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        save_json(self, path)
+    }
+}
+
+impl std::fmt::Display for PushResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.success {
+            return write!(f, "{}, succeeded", format_timestamp(self.timestamp));
+        }
+        write!(
+            f,
+            "{}, failed ({}), last success: {}",
+            format_timestamp(self.timestamp),
+            self.error_kind.as_deref().unwrap_or("unknown"),
+            self.last_success
+                .map(format_timestamp)
+                .unwrap_or_else(|| String::from("never")),
+        )
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()
+        .and_then(|date_time| {
+            date_time
+                .format(&time::format_description::well_known::Rfc3339)
+                .ok()
+        })
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Expiry of a connection certificate, labelled st. it can be matched with the status output
+pub struct CertificateExpiry {
+    pub site_id: Option<String>,
+    pub uuid: String,
+    pub days_until_expiry: i64,
+}
+
+impl CertificateExpiry {
+    fn from_certificate(
+        site_id: Option<String>,
+        uuid: String,
+        certificate: &str,
+    ) -> AnyhowResult<Self> {
+        let pem = certs::parse_pem(certificate)?;
+        let x509 = pem.parse_x509()?;
+        let seconds_until_expiry =
+            x509.validity().not_after.timestamp() - x509_parser::time::ASN1Time::now().timestamp();
+        Ok(Self {
+            site_id,
+            uuid,
+            days_until_expiry: seconds_until_expiry.div_euclid(SECONDS_PER_DAY),
+        })
+    }
+
+    fn from_registry(registry: &config::Registry) -> Vec<Self> {
+        let standard = registry
+            .standard_pull_connections()
+            .chain(registry.push_connections())
+            .map(|(site_id, connection)| (Some(site_id.to_string()), &connection.trust));
+        let imported = registry
+            .imported_pull_connections()
+            .map(|connection| (None, connection));
+        standard
+            .chain(imported)
+            .filter_map(|(site_id, connection)| {
+                Self::from_certificate(
+                    site_id,
+                    connection.uuid.to_string(),
+                    &connection.certificate,
+                )
+                .map_err(|err| {
+                    warn!(
+                        "Not exporting expiry of certificate of connection {}. ({})",
+                        connection.uuid, err
+                    )
+                })
+                .ok()
+            })
+            .collect()
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    metric_type: &str,
+    help: &str,
+    samples: &[(String, i64)],
+) {
+    // Writing to a String never fails
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+fn unlabelled(value: u64) -> Vec<(String, i64)> {
+    vec![(String::new(), value as i64)]
+}
+
+fn labelled(label: &str, samples: &[(&str, u64)]) -> Vec<(String, i64)> {
+    samples
+        .iter()
+        .map(|(label_value, value)| {
+            (
+                format!("{{{}=\"{}\"}}", label, escape_label_value(label_value)),
+                *value as i64,
+            )
+        })
+        .collect()
+}
+
+/// Renders the metrics in the Prometheus text exposition format
+pub fn prometheus_text(
+    snapshot: &PullCountersSnapshot,
+    certificate_expiries: &[CertificateExpiry],
+) -> String {
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_pull_connections_accepted_total",
+        "counter",
+        "Pull connections accepted from peers the IP allowlist and denylist let through",
+        &unlabelled(snapshot.accepted),
+    );
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_pull_connections_rejected_total",
+        "counter",
+        "Pull connections rejected before handling the request",
+        &labelled(
+            "reason",
+            &[
+                ("ip", snapshot.rejected_ip),
+                ("denylist", snapshot.rejected_denylist),
+                ("rate_limit", snapshot.rejected_rate_limit),
+                ("max_connections", snapshot.rejected_max_connections),
+                (
+                    "max_connections_total",
+                    snapshot.rejected_max_connections_total,
+                ),
+            ],
+        ),
+    );
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_pull_connections_failed_total",
+        "counter",
+        "Pull requests which failed while being handled",
+        &labelled(
+            "reason",
+            &[("timeout", snapshot.timed_out), ("error", snapshot.failed)],
+        ),
+    );
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_pull_handshakes_failed_total",
+        "counter",
+        "Pull requests which failed during the TLS handshake, also counted as failed",
+        &unlabelled(snapshot.handshake_failed),
+    );
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_pull_connections_completed_total",
+        "counter",
+        "Pull requests which were served completely",
+        &unlabelled(snapshot.completed),
+    );
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_pull_bytes_served_total",
+        "counter",
+        "Bytes sent to pulling peers",
+        &unlabelled(snapshot.bytes_served),
+    );
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_pull_connections_active",
+        "gauge",
+        "Pull requests currently being handled",
+        &unlabelled(snapshot.active),
+    );
+    write_metric(
+        &mut out,
+        "cmk_agent_ctl_certificate_expiry_days",
+        "gauge",
+        "Days until the certificate of a connection expires",
+        &certificate_expiries
+            .iter()
+            .map(|expiry| {
+                let mut labels = format!("uuid=\"{}\"", escape_label_value(&expiry.uuid));
+                if let Some(site_id) = &expiry.site_id {
+                    let _ = write!(labels, ",site=\"{}\"", escape_label_value(site_id));
+                }
+                (format!("{{{}}}", labels), expiry.days_until_expiry)
+            })
+            .collect::<Vec<_>>(),
+    );
+    out
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+pub async fn read_request_head(stream: &mut TcpStream) -> AnyhowResult<String> {
+    let mut head = vec![];
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read_bytes = stream.read(&mut buffer).await?;
+        if read_bytes == 0 {
+            bail!("Connection closed before the request was complete")
+        }
+        head.extend_from_slice(&buffer[..read_bytes]);
+        if head.len() > MAX_REQUEST_HEAD_BYTES {
+            bail!("Request head exceeds {} bytes", MAX_REQUEST_HEAD_BYTES)
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn response_for(request_head: &str, counters: &PullCounters, registry_path: &Path) -> String {
+    let mut request_line = request_head.lines().next().unwrap_or("").split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let certificate_expiries = match config::Registry::from_file(registry_path) {
+                Ok(registry) => CertificateExpiry::from_registry(&registry),
+                Err(err) => {
+                    warn!("Not exporting certificate expiries. ({})", err);
+                    vec![]
+                }
+            };
+            http_response(
+                "200 OK",
+                &prometheus_text(&counters.snapshot(), &certificate_expiries),
+            )
+        }
+        (Some("GET"), Some(_)) => http_response("404 Not Found", "Not found, try /metrics\n"),
+        _ => http_response("405 Method Not Allowed", "Only GET is supported\n"),
+    }
+}
+
+async fn handle_scrape(
+    mut stream: TcpStream,
+    counters: &PullCounters,
+    registry_path: &Path,
+) -> AnyhowResult<()> {
+    let request_head = read_request_head(&mut stream).await?;
+    let response = response_for(&request_head, counters, registry_path);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+pub async fn bind(address: std::net::SocketAddr) -> AnyhowResult<TcpListener> {
+    TcpListener::bind(address).await.context(format!(
+        "Failed to listen for metrics scrapes on {}",
+        address
+    ))
+}
+
+/// Answers Prometheus scrapes forever. The certificate expiries are read from the registry
+/// with every scrape, st. they reflect registrations done after startup.
+pub async fn serve(listener: TcpListener, counters: Arc<PullCounters>, registry_path: PathBuf) {
+    let registry_path = Arc::new(registry_path);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed accepting metrics connection. ({})", err);
+                continue;
+            }
+        };
+        debug!("{}: Handling metrics scrape.", remote);
+        let counters = counters.clone();
+        let registry_path = registry_path.clone();
+        tokio::spawn(async move {
+            match timeout(
+                SCRAPE_TIMEOUT,
+                handle_scrape(stream, &counters, &registry_path),
+            )
+            .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("{}: Metrics scrape failed. ({})", remote, err),
+                Err(_) => debug!("{}: Metrics scrape timed out.", remote),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_pull_counters {
+    use super::*;
+    use config::JSONLoader;
+
+    #[test]
+    fn test_snapshot() {
+        let counters = PullCounters::default();
+        counters.count_accepted();
+        counters.count_accepted();
+        counters.count_rejected_ip();
+        counters.count_handshake_failed();
+        counters.count_completed();
+        counters.count_timed_out();
+        counters.count_rejected_rate_limit();
+        counters.count_rejected_max_connections();
+        counters.count_rejected_max_connections_total();
+        counters.count_rejected_denylist();
+        counters.count_failed();
+        counters.count_bytes_served(10);
+        counters.count_bytes_served(5);
+        counters.count_active_started();
+        counters.count_active_started();
+        counters.count_active_finished();
+        assert_eq!(
+            counters.snapshot(),
+            PullCountersSnapshot {
+                accepted: 2,
+                rejected_ip: 1,
+                handshake_failed: 1,
+                completed: 1,
+                timed_out: 1,
+                rejected_rate_limit: 1,
+                rejected_max_connections: 1,
+                rejected_max_connections_total: 1,
+                rejected_denylist: 1,
+                failed: 1,
+                bytes_served: 15,
+                active: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_rejection() {
+        let rejections = [
+            Rejection::Ip,
+            Rejection::Denylist,
+            Rejection::RateLimit,
+            Rejection::MaxConnections,
+            Rejection::MaxConnectionsTotal,
+            Rejection::HandshakeFailed,
+            Rejection::TimedOut,
+            Rejection::Failed,
+        ];
+        let counters = PullCounters::new(true);
+        for rejection in rejections {
+            counters.count_rejection(rejection, &"10.0.0.1:4242", "some reason");
+        }
+        // The explanations name the counters as they show up in the snapshots
+        let snapshot = serde_json::to_value(counters.snapshot()).unwrap();
+        for rejection in rejections {
+            assert_eq!(snapshot[rejection.counter_name()], 1);
+        }
+        assert_eq!(snapshot["accepted"], 0);
+    }
+
+    #[test]
+    fn test_io() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let snapshot = PullCountersSnapshot {
+            accepted: 5,
+            rejected_ip: 4,
+            handshake_failed: 3,
+            completed: 2,
+            timed_out: 1,
+            rejected_rate_limit: 6,
+            rejected_max_connections: 10,
+            rejected_max_connections_total: 11,
+            rejected_denylist: 12,
+            failed: 7,
+            bytes_served: 8,
+            active: 9,
+        };
+        snapshot.save(&path).unwrap();
+        assert_eq!(PullCountersSnapshot::load(&path).unwrap(), snapshot);
+        assert!(!tmp_dir::tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn test_load_old_snapshot() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        std::fs::write(
+            &path,
+            r#"{"accepted":5,"rejected_ip":4,"handshake_failed":3,"completed":2,"timed_out":1}"#,
+        )
+        .unwrap();
+        let snapshot = PullCountersSnapshot::load(&path).unwrap();
+        assert_eq!(snapshot.accepted, 5);
+        assert_eq!(snapshot.bytes_served, 0);
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let text = prometheus_text(
+            &PullCountersSnapshot {
+                accepted: 5,
+                rejected_ip: 4,
+                rejected_rate_limit: 3,
+                rejected_max_connections: 2,
+                rejected_max_connections_total: 1,
+                rejected_denylist: 6,
+                bytes_served: 1024,
+                active: 2,
+                ..PullCountersSnapshot::default()
+            },
+            &[
+                CertificateExpiry {
+                    site_id: Some(String::from("server/site")),
+                    uuid: String::from("uuid-1"),
+                    days_until_expiry: 42,
+                },
+                CertificateExpiry {
+                    site_id: None,
+                    uuid: String::from("uuid-2"),
+                    days_until_expiry: -1,
+                },
+            ],
+        );
+        for expected in [
+            "# TYPE cmk_agent_ctl_pull_connections_accepted_total counter\n",
+            "cmk_agent_ctl_pull_connections_accepted_total 5\n",
+            "cmk_agent_ctl_pull_connections_rejected_total{reason=\"ip\"} 4\n",
+            "cmk_agent_ctl_pull_connections_rejected_total{reason=\"denylist\"} 6\n",
+            "cmk_agent_ctl_pull_connections_rejected_total{reason=\"rate_limit\"} 3\n",
+            "cmk_agent_ctl_pull_connections_rejected_total{reason=\"max_connections\"} 2\n",
+            "cmk_agent_ctl_pull_connections_rejected_total{reason=\"max_connections_total\"} 1\n",
+            "cmk_agent_ctl_pull_connections_failed_total{reason=\"error\"} 0\n",
+            "cmk_agent_ctl_pull_bytes_served_total 1024\n",
+            "# TYPE cmk_agent_ctl_pull_connections_active gauge\n",
+            "cmk_agent_ctl_pull_connections_active 2\n",
+            "cmk_agent_ctl_certificate_expiry_days{uuid=\"uuid-1\",site=\"server/site\"} 42\n",
+            "cmk_agent_ctl_certificate_expiry_days{uuid=\"uuid-2\"} -1\n",
+        ] {
+            assert!(text.contains(expected), "{} not in {}", expected, text);
+        }
+    }
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_response_for() {
+        let counters = PullCounters::default();
+        counters.count_accepted();
+        let registry_path = tempfile::tempdir()
+            .unwrap()
+            .path()
+            .join("registered_connections.json");
+        let response = response_for(
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            &counters,
+            &registry_path,
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("cmk_agent_ctl_pull_connections_active 0\n# HELP cmk_agent_ctl_certificate_expiry_days Days until the certificate of a connection expires\n# TYPE cmk_agent_ctl_certificate_expiry_days gauge\n"));
+        assert!(response.contains("cmk_agent_ctl_pull_connections_accepted_total 1\n"));
+        assert!(
+            response_for("GET / HTTP/1.1\r\n\r\n", &counters, &registry_path)
+                .starts_with("HTTP/1.1 404 Not Found\r\n")
+        );
+        assert!(
+            response_for("POST /metrics HTTP/1.1\r\n\r\n", &counters, &registry_path)
+                .starts_with("HTTP/1.1 405 Method Not Allowed\r\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let counters = Arc::new(PullCounters::default());
+        counters.count_completed();
+        tokio::spawn(serve(
+            listener,
+            counters,
+            PathBuf::from("/non/existing/registry.json"),
+        ));
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("cmk_agent_ctl_pull_connections_completed_total 1\n"));
+    }
+}
+
+#[cfg(test)]
+mod test_push_results {
+    use super::*;
+    use config::JSONLoaderMissingSafe;
+
+    fn network_error() -> anyhow::Error {
+        anyhow::anyhow!(io::Error::from(io::ErrorKind::ConnectionRefused))
+    }
+
+    #[test]
+    fn test_record() {
+        let uuid = uuid::Uuid::new_v4();
+        let mut results = PushResults::default();
+        assert!(results.get(&uuid).is_none());
+        results.record(&uuid, 100, Some(&network_error()));
+        assert_eq!(
+            results.get(&uuid).unwrap(),
+            &PushResult {
+                timestamp: 100,
+                success: false,
+                error_kind: Some(String::from("network")),
+                last_success: None,
+            }
+        );
+        results.record(&uuid, 200, None);
+        results.record(&uuid, 300, Some(&anyhow::anyhow!("some error")));
+        assert_eq!(
+            results.get(&uuid).unwrap(),
+            &PushResult {
+                timestamp: 300,
+                success: false,
+                error_kind: Some(String::from("failure")),
+                last_success: Some(200),
+            }
+        );
+    }
+
+    #[test]
+    fn test_io() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            PushResults::load_missing_safe(&path).unwrap(),
+            PushResults::default()
+        );
+        let mut results = PushResults::default();
+        results.record(&uuid::Uuid::new_v4(), 100, None);
+        results.save(&path).unwrap();
+        assert_eq!(PushResults::load_missing_safe(&path).unwrap(), results);
+        assert!(!tmp_dir::tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn test_display() {
+        let mut result = PushResult {
+            timestamp: 0,
+            success: true,
+            error_kind: None,
+            last_success: Some(0),
+        };
+        assert_eq!(format!("{}", result), "1970-01-01T00:00:00Z, succeeded");
+        result.success = false;
+        result.error_kind = Some(String::from("network"));
+        assert_eq!(
+            format!("{}", result),
+            "1970-01-01T00:00:00Z, failed (network), last success: 1970-01-01T00:00:00Z"
+        );
+        result.last_success = None;
+        assert!(format!("{}", result).ends_with("last success: never"));
+    }
+}
+
+#[cfg(test)]
+mod test_connection_totals {
+    use super::*;
+    use config::JSONLoaderMissingSafe;
+    use std::str::FromStr;
+
+    const UUID: &str = "50611369-7a42-4c0b-927e-9a14330401fe";
+
+    #[test]
+    fn test_record() {
+        let mut totals = ConnectionTotals::default();
+        let uuid = uuid::Uuid::from_str(UUID).unwrap();
+        assert!(totals.get(&uuid).is_none());
+        totals.record(UUID, 100, 60);
+        totals.record(UUID, 23, 120);
+        assert_eq!(
+            totals.get(&uuid).unwrap(),
+            &ConnectionUsage {
+                pulls: 2,
+                bytes_served: 123,
+                last_served: Some(120),
+            }
+        );
+        assert_eq!(
+            format!("{}", totals.get(&uuid).unwrap()),
+            "2 pull(s), 123 bytes served, last: 1970-01-01T00:02:00Z"
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        std::fs::remove_file(&path).unwrap();
+        let counters = PullCounters::default();
+        counters.resume_connection_totals(ConnectionTotals::load_missing_safe(&path).unwrap());
+        counters.count_served(UUID, 100);
+        counters.connection_totals().save(&path).unwrap();
+        assert!(!tmp_dir::tmp_path(&path).exists());
+
+        // As after a restart
+        let counters = PullCounters::default();
+        counters.resume_connection_totals(ConnectionTotals::load_missing_safe(&path).unwrap());
+        counters.count_served(UUID, 50);
+        let totals = counters.connection_totals();
+        let usage = totals.get(&uuid::Uuid::from_str(UUID).unwrap()).unwrap();
+        assert_eq!((usage.pulls, usage.bytes_served), (2, 150));
+        assert!(usage.last_served.is_some());
+    }
+}
@@ -8,6 +8,9 @@ use is_elevated::is_elevated;
 use anyhow::Error as AnyhowError;
 #[cfg(windows)]
 use anyhow::{anyhow, Result as AnyhowResult};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 pub fn anyhow_error_to_human_readable(err: &AnyhowError) -> String {
     err.chain()
@@ -32,6 +35,75 @@ pub fn validate_elevation() -> AnyhowResult<()> {
     }
 }
 
+/// Whether the file system holding the given path is mounted read-only. Not detected on Windows.
+#[cfg(unix)]
+pub fn is_on_read_only_fs(path: &std::path::Path) -> bool {
+    nix::sys::statvfs::statvfs(path)
+        .map(|stat| stat.flags().contains(nix::sys::statvfs::FsFlags::ST_RDONLY))
+        .unwrap_or(false)
+}
+
+#[cfg(windows)]
+pub fn is_on_read_only_fs(_path: &std::path::Path) -> bool {
+    false
+}
+
+/// Resolves on SIGINT or SIGTERM (Ctrl+C on Windows).
+#[cfg(unix)]
+pub async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = sigterm.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            }
+        }
+        Err(error) => {
+            log::warn!("Failed to listen for SIGTERM. ({})", error);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+/// Resolves on Ctrl+C.
+#[cfg(windows)]
+pub async fn wait_for_shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Notifies on SIGINT and SIGTERM (Ctrl+C on Windows), st. we can stop waiting immediately.
+pub fn shutdown_signal() -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(error) => {
+                log::warn!("Failed to set up handling of shutdown signals. ({})", error);
+                // Keep the sender alive, otherwise waiting would end right away
+                loop {
+                    thread::park();
+                }
+            }
+        };
+        runtime.block_on(wait_for_shutdown_signal());
+        log::info!("Received shutdown signal");
+        let _ = tx.send(());
+    });
+    rx
+}
+
+/// Wait for the given time. Returns false if we were interrupted by a shutdown signal.
+pub fn wait(shutdown: &mpsc::Receiver<()>, duration: Duration) -> bool {
+    match shutdown.recv_timeout(duration) {
+        Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => false,
+        Err(mpsc::RecvTimeoutError::Timeout) => true,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -46,4 +118,25 @@ mod test {
             "some context\nsomething went wrong"
         )
     }
+
+    #[test]
+    fn test_is_on_read_only_fs() {
+        assert!(!is_on_read_only_fs(&std::env::temp_dir()));
+        assert!(!is_on_read_only_fs(std::path::Path::new("/does/not/exist")));
+    }
+
+    #[test]
+    fn test_wait_interrupted() {
+        let (tx, rx) = mpsc::channel();
+        let begin = std::time::Instant::now();
+        tx.send(()).unwrap();
+        assert!(!wait(&rx, Duration::from_secs(60)));
+        assert!(begin.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let (_tx, rx) = mpsc::channel();
+        assert!(wait(&rx, Duration::from_millis(10)));
+    }
 }
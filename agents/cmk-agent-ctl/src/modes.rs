@@ -2,11 +2,25 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+pub mod capabilities;
 pub mod daemon;
 pub mod delete_connection;
 pub mod dump;
+pub mod dump_certs;
+pub mod export;
+#[cfg(feature = "dev")]
+pub mod gen_test_certs;
+pub mod healthcheck;
 pub mod import_connection;
 pub mod pull;
 pub mod push;
 pub mod registration;
+pub mod repair;
+pub mod self_test;
+pub mod show_config;
 pub mod status;
+pub mod test_pull;
+pub mod trust_root;
+pub mod update_connection;
+pub mod validate;
+pub mod verify;
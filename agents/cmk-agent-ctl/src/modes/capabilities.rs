@@ -0,0 +1,112 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Describes what this build supports, st. tooling can adapt the configuration it generates to
+//! the controller at hand instead of gating on its version. Only what is compiled in is shown,
+//! along with the built-in defaults: neither the config file nor the registry is read, and
+//! nothing is contacted.
+
+use crate::{certs, cli, constants, logging};
+use anyhow::Result as AnyhowResult;
+use serde_json::{json, Value};
+
+fn value_name<T: clap::ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|value| String::from(value.get_name()))
+        .unwrap_or_default()
+}
+
+fn value_names<T: clap::ValueEnum>() -> Vec<String> {
+    T::value_variants().iter().map(value_name).collect()
+}
+
+fn cipher_suite_names(suites: &[rustls::SupportedCipherSuite]) -> Vec<String> {
+    suites
+        .iter()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .collect()
+}
+
+fn modes() -> Vec<String> {
+    <cli::Args as clap::CommandFactory>::command()
+        .get_subcommands()
+        .map(|mode| String::from(mode.get_name()))
+        .collect()
+}
+
+fn agent_channels() -> &'static [&'static str] {
+    match cfg!(unix) {
+        true => &["socket", "tcp", "command"],
+        false => &["mailslot", "tcp"],
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "version": constants::VERSION,
+        "features": {
+            "dev": cfg!(feature = "dev"),
+        },
+        "modes": modes(),
+        "key_types": {
+            "supported": value_names::<certs::KeyType>(),
+            "default": value_name(&certs::KeyType::default()),
+        },
+        "tls_versions": {
+            "supported": value_names::<certs::TlsVersion>(),
+            // Without tls_min_version, we go with the defaults of rustls
+            "default_min": value_name(&certs::TlsVersion::Tls12),
+        },
+        "cipher_suites": {
+            "supported": cipher_suite_names(rustls::ALL_CIPHER_SUITES),
+            "default": cipher_suite_names(rustls::DEFAULT_CIPHER_SUITES),
+        },
+        "pull": {
+            "default_port": constants::DEFAULT_PULL_PORT,
+            "compression": ["zlib"],
+            "proxy_protocol": ["v1", "v2"],
+            "unix_socket": cfg!(unix),
+            "admin_socket": cfg!(unix),
+            "metrics_endpoint": true,
+            "health_endpoint": true,
+            "agent_channels": agent_channels(),
+        },
+        "push": {
+            "proxies": ["http", "socks5", "socks5h"],
+        },
+        "log_formats": value_names::<logging::LogFormat>(),
+    })
+}
+
+pub fn show_capabilities() -> AnyhowResult<()> {
+    println!("{}", serde_json::to_string_pretty(&capabilities())?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities["version"], json!(constants::VERSION));
+        assert_eq!(
+            capabilities["key_types"],
+            json!({"supported": ["rsa", "ec", "ec-p384"], "default": "rsa"})
+        );
+        assert_eq!(
+            capabilities["tls_versions"],
+            json!({"supported": ["1.2", "1.3"], "default_min": "1.2"})
+        );
+        let modes = capabilities["modes"].as_array().unwrap();
+        assert!(modes.contains(&json!("capabilities")));
+        assert!(modes.contains(&json!("pull")));
+        assert_eq!(
+            capabilities["features"]["dev"],
+            json!(modes.contains(&json!("gen-test-certs")))
+        );
+    }
+}
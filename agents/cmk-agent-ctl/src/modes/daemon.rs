@@ -2,13 +2,16 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+use crate::cli;
+use crate::clock_skew;
 use crate::config;
 use crate::config::JSONLoader;
+use crate::constants;
 use crate::misc;
 use crate::modes::registration;
 use crate::modes::{pull, push};
 use anyhow::Result as AnyhowResult;
-use log::{error, info};
+use log::{error, info, warn};
 use std::sync::mpsc;
 use std::thread;
 
@@ -23,36 +26,119 @@ fn register_panic_handler() {
     }));
 }
 
+/// The pull config always comes along, since pushing collects from its agent channel as well
 pub fn daemon(
     path_pre_configured_connections: &std::path::Path,
-    mut registry: config::Registry,
     pull_config: config::PullConfig,
     client_config: config::ClientConfig,
+    push_retry_config: config::PushRetryConfig,
+    push_schedule_config: config::PushScheduleConfig,
+    path_push_results: std::path::PathBuf,
+    mode: cli::DaemonMode,
 ) -> AnyhowResult<()> {
     register_panic_handler();
-    process_pre_configured_connections(
-        path_pre_configured_connections,
-        &mut registry,
-        &client_config,
-    );
+    let mut registry = pull_config.registry.clone();
+    if registry.is_read_only() {
+        info!("Not processing pre-configured connections, the connection registry is read-only");
+    } else {
+        process_pre_configured_connections(
+            path_pre_configured_connections,
+            &mut registry,
+            &client_config,
+        );
+    }
+    warn_clock_skew(&registry, client_config.clock_skew_threshold);
+    warn_incomplete_chains(&registry);
 
     let (tx_push, rx) = mpsc::channel();
     let tx_pull = tx_push.clone();
-    let agent_channel = pull_config.agent_channel.clone();
-    thread::spawn(move || {
-        tx_push
-            .send(push::push(registry, client_config, agent_channel))
-            .unwrap();
-    });
-    thread::spawn(move || {
-        tx_pull.send(pull::pull(pull_config)).unwrap();
-    });
+    let mut running = 0;
+    if mode != cli::DaemonMode::Pull {
+        let agent_channel = pull_config.agent_channel.clone();
+        thread::spawn(move || {
+            tx_push
+                .send(push::push(
+                    registry,
+                    client_config,
+                    agent_channel,
+                    push_retry_config,
+                    push_schedule_config,
+                    path_push_results,
+                ))
+                .unwrap();
+        });
+        running += 1;
+    } else {
+        info!("Running pull only, not pushing to any site");
+    }
+    if mode != cli::DaemonMode::Push {
+        thread::spawn(move || {
+            tx_pull.send(pull::pull(pull_config)).unwrap();
+        });
+        running += 1;
+    } else {
+        info!("Running push only, not listening for pull connections");
+    }
 
-    // We should never receive anything here, unless one of the threads crashed.
-    // In that case, this will contain an error that should be propagated.
+    // We should never receive anything here, unless one of the threads crashed or all threads
+    // stopped due to a shutdown signal. In the former case, this will contain an error that
+    // should be propagated. In the latter case, we wait for the other thread as well, since the
+    // pull thread may still be draining connections.
+    for _ in 1..running {
+        rx.recv().unwrap()?;
+    }
     rx.recv().unwrap()
 }
 
+/// Certificates which are not valid yet make all connections with them fail. Right after
+/// registering, that's most likely because our clock is behind.
+fn warn_clock_skew(registry: &config::Registry, threshold: std::time::Duration) {
+    for (site_id, connection) in registry
+        .push_connections()
+        .chain(registry.standard_pull_connections())
+    {
+        clock_skew::warn_certificate_skew(
+            &format!("The certificate of connection {}", site_id),
+            &connection.trust.certificate,
+            threshold,
+        );
+    }
+    for connection in registry.imported_pull_connections() {
+        clock_skew::warn_certificate_skew(
+            &format!("The certificate of imported connection {}", connection.uuid),
+            &connection.certificate,
+            threshold,
+        );
+    }
+}
+
+/// Pull connections are checked along with the rest of their trust material once pull starts
+fn warn_incomplete_chains(registry: &config::Registry) {
+    for (site_id, connection) in registry.push_connections() {
+        if let Some(issuer) = connection.trust.chain_gap() {
+            warn!(
+                "Certificate chain of connection {} is incomplete, the certificate of its issuer {} is missing. The site can only verify it if it has that certificate itself.",
+                site_id, issuer
+            );
+        }
+    }
+}
+
+/// Like the modes modifying the registry, we hold the lock from loading the registry until saving
+/// it, st. we don't overwrite what they registered meanwhile.
+fn register_pre_configured_locked(
+    pre_configured: &config::PreConfiguredConnections,
+    registry: &mut config::Registry,
+    client_config: &config::ClientConfig,
+) -> AnyhowResult<()> {
+    let _registry_lock = config::RegistryLock::acquire(
+        registry.path(),
+        std::time::Duration::from_secs(constants::REGISTRY_LOCK_TIMEOUT),
+    )?;
+    registry.refresh()?;
+    registration::register_pre_configured(pre_configured, client_config, registry)
+}
+
 fn process_pre_configured_connections(
     path_pre_configured_connections: &std::path::Path,
     registry: &mut config::Registry,
@@ -61,7 +147,7 @@ fn process_pre_configured_connections(
     match config::PreConfiguredConnections::load(path_pre_configured_connections) {
         Ok(pre_configured) => {
             if let Err(err) =
-                registration::register_pre_configured(&pre_configured, client_config, registry)
+                register_pre_configured_locked(&pre_configured, registry, client_config)
             {
                 error!(
                     "Error while processing pre-configured connections: {}",
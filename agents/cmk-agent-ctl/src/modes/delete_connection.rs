@@ -4,10 +4,70 @@
 
 use std::str::FromStr;
 
-use crate::{config, site_spec};
-use anyhow::{Context, Result as AnyhowResult};
+use crate::{cli, config, site_spec};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
 
-fn retrieve_standard_connection_by_uuid(
+trait Confirming {
+    fn confirm(&self, question: &str) -> AnyhowResult<bool>;
+}
+
+struct InteractiveConfirmation {}
+
+impl Confirming for InteractiveConfirmation {
+    fn confirm(&self, question: &str) -> AnyhowResult<bool> {
+        eprintln!("{} [y/N]", question);
+        eprint!("> ");
+        loop {
+            let mut answer = String::new();
+            std::io::stdin()
+                .read_line(&mut answer)
+                .context("Failed to read answer from standard input")?;
+            match answer.to_lowercase().trim() {
+                "y" => return Ok(true),
+                // An empty answer also covers EOF, i.e. non-interactive usage without '--force'
+                "n" | "" => return Ok(false),
+                _ => {
+                    eprintln!("Please answer 'y' or 'n'");
+                    eprint!("> ");
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct DeletionSummary {
+    push: usize,
+    pull: usize,
+}
+
+impl DeletionSummary {
+    fn from(registry: &config::Registry) -> Self {
+        Self {
+            push: registry.push_connections().count(),
+            pull: registry.pull_connections().count(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.push == 0 && self.pull == 0
+    }
+}
+
+impl std::fmt::Display for DeletionSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} connection(s) of type {}, {} connection(s) of type {}",
+            self.push,
+            config::ConnectionType::Push,
+            self.pull,
+            config::ConnectionType::Pull,
+        )
+    }
+}
+
+pub fn retrieve_standard_connection_by_uuid(
     uuid: &uuid::Uuid,
     registry: &config::Registry,
 ) -> Option<site_spec::SiteID> {
@@ -22,6 +82,29 @@ fn retrieve_standard_connection_by_uuid(
     None
 }
 
+/// Resolves the site of a standard connection, given either the site ID or the UUID.
+/// Imported connections have no site, `imported` says what this means for the caller.
+pub fn standard_connection_site_id(
+    registry: &config::Registry,
+    connection_id: &str,
+    imported: &str,
+) -> AnyhowResult<site_spec::SiteID> {
+    if let Ok(site_id) = site_spec::SiteID::from_str(connection_id) {
+        return Ok(site_id);
+    }
+    let uuid = uuid::Uuid::from_str(connection_id)
+        .context("Provided connection identifier is neither a valid site ID nor a valid UUID")?;
+    retrieve_standard_connection_by_uuid(&uuid, registry).ok_or_else(|| {
+        match registry
+            .imported_pull_connections()
+            .any(|conn| conn.uuid == uuid)
+        {
+            true => anyhow!("Connection with UUID '{}' is imported, {}", uuid, imported),
+            false => anyhow!("No connection with UUID '{}'", uuid),
+        }
+    })
+}
+
 fn delete_by_uuid(uuid: &uuid::Uuid, registry: &mut config::Registry) -> AnyhowResult<()> {
     match retrieve_standard_connection_by_uuid(uuid, registry) {
         Some(site_id) => registry.delete_standard_connection(&site_id),
@@ -31,7 +114,7 @@ fn delete_by_uuid(uuid: &uuid::Uuid, registry: &mut config::Registry) -> AnyhowR
     }
 }
 
-pub fn delete(registry: &mut config::Registry, connection_id: &str) -> AnyhowResult<()> {
+fn delete_locally(registry: &mut config::Registry, connection_id: &str) -> AnyhowResult<()> {
     match site_spec::SiteID::from_str(connection_id) {
         Ok(site_id) => registry.delete_standard_connection(&site_id),
         Err(_) => delete_by_uuid(
@@ -46,20 +129,165 @@ pub fn delete(registry: &mut config::Registry, connection_id: &str) -> AnyhowRes
     Ok(())
 }
 
-pub fn delete_all(registry: &mut config::Registry, enable_legacy_mode: bool) -> AnyhowResult<()> {
-    registry.clear();
-    registry.save()?;
+/// '*' matches any number of characters, including none and '/', '?' matches a single one
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len())
+            .filter(|index| text.is_char_boundary(*index))
+            .any(|index| glob_matches(&pattern[1..], &text[index..])),
+        Some(pattern_char) => text.chars().next().is_some_and(|text_char| {
+            (pattern_char == '?' || pattern_char == text_char)
+                && glob_matches(
+                    &pattern[pattern_char.len_utf8()..],
+                    &text[text_char.len_utf8()..],
+                )
+        }),
+    }
+}
+
+/// The connections selected by '--label' and '--site-glob', all given criteria have to match.
+/// Imported connections have no site and are thus only selected by their labels.
+fn matching_connections(
+    registry: &config::Registry,
+    labels: &[(String, String)],
+    site_glob: Option<&str>,
+) -> Vec<String> {
+    let has_labels = |connection_labels: &config::ConnectionLabels| {
+        labels
+            .iter()
+            .all(|(key, value)| connection_labels.get(key) == Some(value))
+    };
+    registry
+        .push_connections()
+        .chain(registry.standard_pull_connections())
+        .filter(|(site_id, connection)| {
+            has_labels(&connection.trust.labels)
+                && site_glob.is_none_or(|pattern| glob_matches(pattern, &site_id.to_string()))
+        })
+        .map(|(site_id, _)| site_id.to_string())
+        .chain(
+            registry
+                .imported_pull_connections()
+                .filter(|connection| site_glob.is_none() && has_labels(&connection.labels))
+                .map(|connection| connection.uuid.to_string()),
+        )
+        .collect()
+}
+
+fn _delete_matching(
+    registry: &mut config::Registry,
+    labels: &[(String, String)],
+    site_glob: Option<&str>,
+) -> AnyhowResult<usize> {
+    let connection_ids = matching_connections(registry, labels, site_glob);
+    for connection_id in &connection_ids {
+        delete_locally(registry, connection_id)?;
+    }
+    Ok(connection_ids.len())
+}
+
+/// Host names are case-insensitive, so registering again with the server spelled differently
+/// leaves a second connection to the same site behind. The site name is case-sensitive.
+fn connections_to_site(
+    registry: &config::Registry,
+    site_id: &site_spec::SiteID,
+) -> Vec<(site_spec::SiteID, config::TrustedConnectionWithRemote)> {
+    registry
+        .push_connections()
+        .chain(registry.standard_pull_connections())
+        .filter(|(id, _)| {
+            id.server.eq_ignore_ascii_case(&site_id.server) && id.site == site_id.site
+        })
+        .map(|(id, connection)| (id.clone(), connection.clone()))
+        .collect()
+}
+
+/// Deletes all connections to the site, without asking
+fn _delete_site(
+    registry: &mut config::Registry,
+    site_id: &site_spec::SiteID,
+) -> AnyhowResult<usize> {
+    let connections = connections_to_site(registry, site_id);
+    for (id, _) in &connections {
+        registry.delete_standard_connection(id)?;
+    }
+    if !connections.is_empty() {
+        registry.save()?;
+    }
+    Ok(connections.len())
+}
+
+pub fn delete(registry: &mut config::Registry, delete_args: &cli::DeleteArgs) -> AnyhowResult<()> {
+    if let Some(site_id) = &delete_args.site {
+        match _delete_site(registry, site_id)? {
+            0 => println!("No connections to site '{}', nothing to delete", site_id),
+            deleted => println!("Deleted {} connection(s) to site '{}'", deleted, site_id),
+        }
+        return Ok(());
+    }
+    if let Some(connection_id) = &delete_args.connection {
+        return delete_locally(registry, connection_id);
+    }
+    match _delete_matching(
+        registry,
+        &delete_args.labels,
+        delete_args.site_glob.as_deref(),
+    )? {
+        0 => println!("No connections match, nothing to delete"),
+        deleted => println!("Deleted {} connection(s)", deleted),
+    }
+    Ok(())
+}
+
+fn _delete_all(
+    registry: &mut config::Registry,
+    enable_legacy_mode: bool,
+    force: bool,
+    confirmation: &impl Confirming,
+) -> AnyhowResult<DeletionSummary> {
+    let summary = DeletionSummary::from(registry);
+    if !summary.is_empty() {
+        if !force && !confirmation.confirm(&format!("Delete {}?", summary))? {
+            bail!("Aborted, no connections were deleted. Use '--force' to skip the confirmation.")
+        }
+        registry.clear();
+        registry.save()?;
+    }
     if enable_legacy_mode {
         registry.activate_legacy_pull()?;
     }
+    Ok(summary)
+}
+
+pub fn delete_all(
+    registry: &mut config::Registry,
+    enable_legacy_mode: bool,
+    force: bool,
+) -> AnyhowResult<()> {
+    let summary = _delete_all(
+        registry,
+        enable_legacy_mode,
+        force,
+        &InteractiveConfirmation {},
+    )?;
+    if summary.is_empty() {
+        println!("No connections registered, nothing to delete");
+    } else {
+        println!("Deleted {}", summary);
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::modes::delete_connection::{delete, delete_all};
+    use crate::modes::delete_connection::{
+        _delete_all, _delete_matching, _delete_site, delete_locally, glob_matches, Confirming,
+        DeletionSummary,
+    };
     use crate::site_spec;
     use crate::*;
+    use anyhow::Result as AnyhowResult;
     use std::str::FromStr;
     const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
     const UUID_PULL: &str = "b3501e4d-2820-433c-8e9c-38c69ac20faa";
@@ -90,7 +318,7 @@ mod tests {
     fn test_delete_by_site_id_ok() {
         let mut reg = registry(None);
         assert!(!reg.path().exists());
-        assert!(delete(&mut reg, "server/push-site").is_ok());
+        assert!(delete_locally(&mut reg, "server/push-site").is_ok());
         assert!(reg.path().exists());
     }
 
@@ -99,7 +327,7 @@ mod tests {
         assert_eq!(
             format!(
                 "{}",
-                delete(&mut registry(None), "someserver/site").unwrap_err()
+                delete_locally(&mut registry(None), "someserver/site").unwrap_err()
             ),
             "Connection 'someserver/site' not found"
         );
@@ -109,7 +337,7 @@ mod tests {
     fn test_delete_pull_by_uuid_ok() {
         let mut reg = registry(None);
         assert!(!reg.path().exists());
-        assert!(delete(&mut reg, UUID_PULL).is_ok());
+        assert!(delete_locally(&mut reg, UUID_PULL).is_ok());
         assert!(reg.pull_standard_is_empty());
         assert!(reg.path().exists());
     }
@@ -118,7 +346,7 @@ mod tests {
     fn test_delete_push_by_uuid_ok() {
         let mut reg = registry(None);
         assert!(!reg.path().exists());
-        assert!(delete(&mut reg, UUID_PUSH).is_ok());
+        assert!(delete_locally(&mut reg, UUID_PUSH).is_ok());
         assert!(reg.push_is_empty());
         assert!(reg.path().exists());
     }
@@ -127,7 +355,7 @@ mod tests {
     fn test_delete_pull_imported_ok() {
         let mut reg = registry(None);
         assert!(!reg.path().exists());
-        assert!(delete(&mut reg, UUID_PULL_IMP1).is_ok());
+        assert!(delete_locally(&mut reg, UUID_PULL_IMP1).is_ok());
         assert!(reg.path().exists());
     }
 
@@ -137,18 +365,170 @@ mod tests {
         assert_eq!(
             format!(
                 "{}",
-                delete(&mut registry(None), &uuid.to_string()).unwrap_err()
+                delete_locally(&mut registry(None), &uuid.to_string()).unwrap_err()
             ),
             format!("No connection with UUID '{}'", &uuid),
         );
     }
 
+    fn labeled_registry() -> config::Registry {
+        let mut reg = registry(None);
+        let labels =
+            config::ConnectionLabels::from([(String::from("env"), String::from("staging"))]);
+        reg.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("staging-1/site").unwrap(),
+            config::TrustedConnectionWithRemote::from("62734b3c-28bb-4863-8ba4-a1b3e1e50b11"),
+        );
+        let mut pull =
+            config::TrustedConnectionWithRemote::from("f8a1e5f2-7b1c-4bd6-9e47-ff57dba4e9a6");
+        pull.trust.labels = labels.clone();
+        reg.register_connection(
+            &config::ConnectionType::Pull,
+            &site_spec::SiteID::from_str("staging-2/site").unwrap(),
+            pull,
+        );
+        let mut imported = config::TrustedConnection::from("6fdb3e48-cc8c-4b7a-9b59-4f2aafcf0e0f");
+        imported.labels = labels;
+        reg.register_imported_connection(imported);
+        reg
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+        labels
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("staging-*/*", "staging-1/site"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("server/?ite", "server/site"));
+        assert!(glob_matches("*-site", "server/pull-site"));
+        assert!(!glob_matches("staging-*/*", "server/staging-site"));
+        assert!(!glob_matches("server/?", "server/site"));
+        assert!(!glob_matches("server", "server/site"));
+    }
+
+    #[test]
+    fn test_delete_matching_label() {
+        let mut reg = labeled_registry();
+        assert_eq!(
+            _delete_matching(&mut reg, &labels(&[("env", "staging")]), None,).unwrap(),
+            2
+        );
+        assert!(reg.path().exists());
+        assert_eq!(reg.pull_connections().count(), 3);
+        assert_eq!(reg.push_connections().count(), 2);
+    }
+
+    #[test]
+    fn test_delete_matching_site_glob() {
+        let mut reg = labeled_registry();
+        assert_eq!(
+            _delete_matching(&mut reg, &[], Some("staging-*/*"),).unwrap(),
+            2
+        );
+        // Imported connections have no site to match
+        assert_eq!(reg.imported_pull_connections().count(), 3);
+        assert_eq!(reg.registered_site_ids().count(), 2);
+    }
+
+    #[test]
+    fn test_delete_matching_all_criteria() {
+        let mut reg = labeled_registry();
+        assert_eq!(
+            _delete_matching(
+                &mut reg,
+                &labels(&[("env", "staging")]),
+                Some("staging-*/*"),
+            )
+            .unwrap(),
+            1
+        );
+        assert!(reg
+            .get_mutable(&site_spec::SiteID::from_str("staging-1/site").unwrap())
+            .is_some());
+    }
+
+    #[test]
+    fn test_delete_matching_nothing() {
+        let mut reg = labeled_registry();
+        assert_eq!(
+            _delete_matching(&mut reg, &labels(&[("env", "prod")]), None,).unwrap(),
+            0
+        );
+        assert!(!reg.path().exists());
+    }
+
+    /// Two connections to the same site, registered with the server spelled differently
+    fn duplicated_registry() -> config::Registry {
+        let mut reg = registry(None);
+        reg.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("Server/pull-site").unwrap(),
+            config::TrustedConnectionWithRemote::from("62734b3c-28bb-4863-8ba4-a1b3e1e50b11"),
+        );
+        reg
+    }
+
+    #[test]
+    fn test_delete_site() {
+        let mut reg = duplicated_registry();
+        let site_id = site_spec::SiteID::from_str("server/pull-site").unwrap();
+        assert_eq!(_delete_site(&mut reg, &site_id).unwrap(), 2);
+        assert!(reg.path().exists());
+        assert_eq!(
+            reg.registered_site_ids()
+                .map(|site_id| site_id.to_string())
+                .collect::<Vec<String>>(),
+            ["server/push-site"]
+        );
+        assert_eq!(reg.imported_pull_connections().count(), 2);
+        // Nothing left to do
+        assert_eq!(_delete_site(&mut reg, &site_id).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_delete_site_nothing() {
+        let mut reg = registry(None);
+        assert_eq!(
+            _delete_site(
+                &mut reg,
+                &site_spec::SiteID::from_str("server/other-site").unwrap(),
+            )
+            .unwrap(),
+            0
+        );
+        // Not even touched
+        assert!(!reg.path().exists());
+    }
+
+    struct MockConfirmation {
+        answer: bool,
+    }
+
+    impl Confirming for MockConfirmation {
+        fn confirm(&self, _question: &str) -> AnyhowResult<bool> {
+            Ok(self.answer)
+        }
+    }
+
+    const CONFIRM: MockConfirmation = MockConfirmation { answer: true };
+    const DECLINE: MockConfirmation = MockConfirmation { answer: false };
+
     #[test]
     fn test_delete_all_no_legacy_pull() {
         let mut reg = registry(None);
         assert!(!reg.path().exists());
-        assert!(delete_all(&mut reg, false).is_ok());
+        assert_eq!(
+            _delete_all(&mut reg, false, false, &CONFIRM).unwrap(),
+            DeletionSummary { push: 1, pull: 3 }
+        );
         assert!(reg.path().exists());
+        assert!(reg.is_empty());
         assert!(!reg.legacy_pull_active());
     }
 
@@ -157,9 +537,42 @@ mod tests {
         let tmp_dir = tempfile::tempdir().unwrap();
         let mut reg = registry(Some(tmp_dir.path().join("registry.json")));
         assert!(!reg.path().exists());
-        assert!(delete_all(&mut reg, true).is_ok());
+        assert!(_delete_all(&mut reg, true, false, &CONFIRM).is_ok());
         assert!(reg.path().exists());
         assert!(reg.legacy_pull_active());
         tmp_dir.close().unwrap();
     }
+
+    #[test]
+    fn test_delete_all_declined() {
+        let mut reg = registry(None);
+        assert!(_delete_all(&mut reg, false, false, &DECLINE).is_err());
+        assert!(!reg.path().exists());
+        assert!(!reg.is_empty());
+    }
+
+    #[test]
+    fn test_delete_all_forced() {
+        let mut reg = registry(None);
+        assert!(_delete_all(&mut reg, false, true, &DECLINE).is_ok());
+        assert!(reg.is_empty());
+    }
+
+    #[test]
+    fn test_delete_all_empty_registry() {
+        let mut reg =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        assert!(_delete_all(&mut reg, false, false, &DECLINE)
+            .unwrap()
+            .is_empty());
+        assert!(!reg.path().exists());
+    }
+
+    #[test]
+    fn test_deletion_summary_display() {
+        assert_eq!(
+            DeletionSummary { push: 1, pull: 3 }.to_string(),
+            "1 connection(s) of type push-agent, 3 connection(s) of type pull-agent"
+        );
+    }
 }
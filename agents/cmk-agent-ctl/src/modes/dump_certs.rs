@@ -0,0 +1,289 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Shows the certificates stored in the registry. Private keys are never part of the output.
+
+use crate::{certs, cli, config, site_spec};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use serde_with::DisplayFromStr;
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum CertificateResult {
+    Success(certs::CertificateDetails),
+    Error { error: String },
+}
+
+impl CertificateResult {
+    fn from_der(der: &[u8]) -> Self {
+        match certs::CertificateDetails::from_der(der) {
+            Ok(details) => Self::Success(details),
+            Err(err) => Self::Error {
+                error: format!("{:#}", err),
+            },
+        }
+    }
+
+    fn from_pem(cert_pem: &str) -> Self {
+        match certs::parse_pem(cert_pem) {
+            Ok(pem) => Self::from_der(&pem.contents),
+            Err(err) => Self::Error {
+                error: format!("{:#}", err),
+            },
+        }
+    }
+
+    fn readable_lines(&self) -> Vec<String> {
+        match self {
+            Self::Success(details) => vec![
+                format!("Subject: {}", details.subject),
+                format!("Issuer: {}", details.issuer),
+                format!("Serial: {}", details.serial),
+                format!("Validity: {} - {}", details.not_before, details.not_after),
+                format!("Fingerprint (SHA-256): {}", details.fingerprint),
+                format!("Key type: {}", details.key_type),
+            ],
+            Self::Error { error } => vec![format!("Error: {}", error)],
+        }
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(serde::Serialize)]
+struct ConnectionCertificates {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site_id: Option<site_spec::SiteID>,
+    connection_type: config::ConnectionType,
+    #[serde_as(as = "DisplayFromStr")]
+    uuid: uuid::Uuid,
+    certificate: CertificateResult,
+    root_certificates: Vec<CertificateResult>,
+}
+
+impl ConnectionCertificates {
+    fn new(
+        site_id: Option<&site_spec::SiteID>,
+        connection_type: config::ConnectionType,
+        connection: &config::TrustedConnection,
+    ) -> Self {
+        Self {
+            site_id: site_id.cloned(),
+            connection_type,
+            uuid: connection.uuid,
+            certificate: CertificateResult::from_pem(&connection.certificate),
+            root_certificates: match certs::root_certificates(&connection.root_cert) {
+                Ok(roots) => roots
+                    .iter()
+                    .map(|root| CertificateResult::from_der(root.as_ref()))
+                    .collect(),
+                Err(err) => vec![CertificateResult::Error {
+                    error: format!("{:#}", err),
+                }],
+            },
+        }
+    }
+
+    fn matches(&self, connection_id: &str) -> bool {
+        self.uuid.to_string() == connection_id
+            || self
+                .site_id
+                .as_ref()
+                .is_some_and(|site_id| site_id.to_string() == connection_id)
+    }
+
+    fn to_human_readable(&self) -> String {
+        let mut lines = vec![
+            match &self.site_id {
+                Some(site_id) => format!("Connection: {}", site_id),
+                None => String::from("Imported connection:"),
+            },
+            format!("\tUUID: {}", self.uuid),
+            format!("\tConnection type: {}", self.connection_type),
+            String::from("\tCertificate:"),
+        ];
+        lines.extend(
+            self.certificate
+                .readable_lines()
+                .iter()
+                .map(|line| format!("\t\t{}", line)),
+        );
+        for root in &self.root_certificates {
+            lines.push(String::from("\tRoot certificate:"));
+            lines.extend(
+                root.readable_lines()
+                    .iter()
+                    .map(|line| format!("\t\t{}", line)),
+            );
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(serde::Serialize)]
+struct CertificatesDump {
+    connections: Vec<ConnectionCertificates>,
+}
+
+impl CertificatesDump {
+    fn new(registry: &config::Registry, connection_id: Option<&str>) -> AnyhowResult<Self> {
+        let connections: Vec<ConnectionCertificates> =
+            registry
+                .push_connections()
+                .map(|(site_id, conn)| {
+                    ConnectionCertificates::new(
+                        Some(site_id),
+                        config::ConnectionType::Push,
+                        &conn.trust,
+                    )
+                })
+                .chain(registry.standard_pull_connections().map(|(site_id, conn)| {
+                    ConnectionCertificates::new(
+                        Some(site_id),
+                        config::ConnectionType::Pull,
+                        &conn.trust,
+                    )
+                }))
+                .chain(registry.imported_pull_connections().map(|conn| {
+                    ConnectionCertificates::new(None, config::ConnectionType::Pull, conn)
+                }))
+                .filter(|conn| connection_id.is_none_or(|id| conn.matches(id)))
+                .collect();
+        if let Some(connection_id) = connection_id {
+            if connections.is_empty() {
+                bail!("No connection '{}'", connection_id)
+            }
+        }
+        Ok(Self { connections })
+    }
+
+    fn to_string(&self, output_format: &cli::OutputFormat) -> AnyhowResult<String> {
+        match output_format {
+            cli::OutputFormat::Json => {
+                serde_json::to_string(self).context("Failed to serialize certificates to JSON")
+            }
+            cli::OutputFormat::Text => Ok(match self.connections.is_empty() {
+                true => String::from("No connections"),
+                false => self
+                    .connections
+                    .iter()
+                    .map(ConnectionCertificates::to_human_readable)
+                    .collect::<Vec<String>>()
+                    .join("\n\n"),
+            }),
+        }
+    }
+}
+
+pub fn dump_certs(
+    registry: &config::Registry,
+    dump_certs_args: &cli::DumpCertsArgs,
+) -> AnyhowResult<()> {
+    println!(
+        "{}",
+        CertificatesDump::new(registry, dump_certs_args.connection.as_deref())?
+            .to_string(&dump_certs_args.output_format)?
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use std::str::FromStr;
+
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_PULL: &str = "b1e0d4c4-1bb1-4a39-8bd6-8e5b1b7c3b6d";
+    const UUID_PULL_IMP: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+
+    fn connection(uuid: &str) -> config::TrustedConnectionWithRemote {
+        let mut connection = config::TrustedConnectionWithRemote::from(uuid);
+        connection.trust.certificate = String::from(constants::TEST_CERT_OK);
+        connection.trust.root_cert = String::from(constants::TEST_ROOT_CERT);
+        connection
+    }
+
+    fn registry() -> config::Registry {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            connection(UUID_PUSH),
+        );
+        registry.register_connection(
+            &config::ConnectionType::Pull,
+            &site_spec::SiteID::from_str("server/pull-site").unwrap(),
+            connection(UUID_PULL),
+        );
+        registry.register_imported_connection(config::TrustedConnection::from(UUID_PULL_IMP));
+        registry
+    }
+
+    #[test]
+    fn test_dump_all() {
+        let registry = registry();
+        let dump = CertificatesDump::new(&registry, None).unwrap();
+        assert_eq!(dump.connections.len(), 3);
+        let text = dump.to_string(&cli::OutputFormat::Text).unwrap();
+        assert!(text.starts_with(&format!(
+            "Connection: server/push-site\n\tUUID: {}\n\tConnection type: push-agent\n\
+             \tCertificate:\n\t\tSubject: CN=heute\n\t\tIssuer: CN=Site 'heute' local CA\n",
+            UUID_PUSH
+        )));
+        assert!(text.contains("Imported connection:"));
+        assert!(text.contains("\tRoot certificate:\n\t\tSubject: CN=Site 'heute' local CA\n"));
+        // Neither the key of the connection nor any other PEM data ends up in the output
+        assert!(!text.contains("PRIVATE KEY"));
+        assert!(!text.contains("-----BEGIN"));
+    }
+
+    #[test]
+    fn test_dump_single_connection_json() {
+        let registry = registry();
+        let json: serde_json::Value = serde_json::from_str(
+            &CertificatesDump::new(&registry, Some("server/pull-site"))
+                .unwrap()
+                .to_string(&cli::OutputFormat::Json)
+                .unwrap(),
+        )
+        .unwrap();
+        let connections = json["connections"].as_array().unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0]["uuid"], UUID_PULL);
+        assert_eq!(connections[0]["connection_type"], "pull-agent");
+        assert_eq!(connections[0]["certificate"]["subject"], "CN=heute");
+        assert!(connections[0]["certificate"]["key_type"]
+            .as_str()
+            .unwrap()
+            .starts_with("RSA"));
+        assert_eq!(
+            connections[0]["root_certificates"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(connections[0].get("private_key").is_none());
+    }
+
+    #[test]
+    fn test_dump_by_uuid() {
+        let registry = registry();
+        let dump = CertificatesDump::new(&registry, Some(UUID_PULL_IMP)).unwrap();
+        assert_eq!(dump.connections.len(), 1);
+        assert!(dump.connections[0].site_id.is_none());
+    }
+
+    #[test]
+    fn test_dump_unknown_connection() {
+        assert_eq!(
+            CertificatesDump::new(&registry(), Some("server/unknown"))
+                .err()
+                .unwrap()
+                .to_string(),
+            "No connection 'server/unknown'"
+        );
+    }
+}
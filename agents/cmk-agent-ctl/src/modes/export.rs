@@ -0,0 +1,243 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::{certs, cli, config, site_spec};
+use anyhow::{Context, Result as AnyhowResult};
+use serde::Serialize;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+pub const PRIVATE_KEY_WARNING: &str =
+    "WARNING: The bundle contains the private keys of all connections. Anyone who obtains it \
+     can impersonate this host towards the Checkmk sites. Keep it safe and delete it once it is \
+     not needed anymore.";
+
+fn write_bundle(path: &Path, bundle: &str) -> AnyhowResult<()> {
+    let mut open_options = std::fs::OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    open_options.mode(0o600);
+    let mut file = open_options
+        .open(path)
+        .context(format!("Failed to open {}", path.display()))?;
+    // The file may have existed before with broader permissions
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(bundle.as_bytes())
+        .context(format!("Failed to write to {}", path.display()))
+}
+
+/// What we may tell about a connection without enabling anyone to use it
+#[derive(Serialize, PartialEq, Eq, Debug)]
+struct PublicConnection<'a> {
+    /// None for imported connections, which don't belong to a site
+    site_id: Option<String>,
+    uuid: String,
+    connection_type: String,
+    receiver_port: Option<u16>,
+    /// SHA-256 of the client certificate, None if it can't be decoded
+    certificate_fingerprint: Option<String>,
+    labels: &'a config::ConnectionLabels,
+}
+
+impl<'a> PublicConnection<'a> {
+    fn new(
+        site_id: Option<String>,
+        connection_type: &config::ConnectionType,
+        receiver_port: Option<u16>,
+        connection: &'a config::TrustedConnection,
+    ) -> Self {
+        Self {
+            site_id,
+            uuid: connection.uuid.to_string(),
+            connection_type: connection_type.to_string(),
+            receiver_port,
+            certificate_fingerprint: certs::parse_pem(&connection.certificate)
+                .and_then(|pem| certs::fingerprint_sha256(&pem.contents))
+                .ok(),
+            labels: &connection.labels,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PublicBundle<'a> {
+    connections: Vec<PublicConnection<'a>>,
+}
+
+fn public_standard_connections<'a>(
+    connection_type: config::ConnectionType,
+    connections: impl Iterator<
+        Item = (
+            &'a site_spec::SiteID,
+            &'a config::TrustedConnectionWithRemote,
+        ),
+    >,
+) -> impl Iterator<Item = PublicConnection<'a>> {
+    connections.map(move |(site_id, connection)| {
+        PublicConnection::new(
+            Some(site_id.to_string()),
+            &connection_type,
+            Some(connection.receiver_port),
+            &connection.trust,
+        )
+    })
+}
+
+fn public_bundle(registry: &config::Registry) -> PublicBundle<'_> {
+    PublicBundle {
+        connections: public_standard_connections(
+            config::ConnectionType::Push,
+            registry.push_connections(),
+        )
+        .chain(public_standard_connections(
+            config::ConnectionType::Pull,
+            registry.standard_pull_connections(),
+        ))
+        .chain(registry.imported_pull_connections().map(|connection| {
+            PublicConnection::new(None, &config::ConnectionType::Pull, None, connection)
+        }))
+        .collect(),
+    }
+}
+
+fn _export(
+    registry: &config::Registry,
+    path: Option<&Path>,
+    public_only: bool,
+) -> AnyhowResult<()> {
+    let bundle = match public_only {
+        true => serde_json::to_string_pretty(&public_bundle(registry))?,
+        false => serde_json::to_string_pretty(&registry.bundle())?,
+    };
+    match path {
+        Some(path) => write_bundle(path, &bundle)?,
+        None => println!("{}", bundle),
+    }
+    Ok(())
+}
+
+pub fn export(registry: &config::Registry, export_args: &cli::ExportArgs) -> AnyhowResult<()> {
+    if !export_args.public_only {
+        eprintln!("{}", PRIVATE_KEY_WARNING);
+    }
+    _export(
+        registry,
+        export_args.bundle_file.as_deref(),
+        export_args.public_only,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::JSONLoader;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_export() {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &crate::site_spec::SiteID::from_str("server/site").unwrap(),
+            config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.json");
+
+        _export(&registry, Some(&path), false).unwrap();
+
+        assert_eq!(
+            config::RegistryBundle::load(&path)
+                .unwrap()
+                .connection_count(),
+            1
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_keeps_labels() {
+        let site_id = crate::site_spec::SiteID::from_str("server/site").unwrap();
+        let mut connection = config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4());
+        connection.trust.labels =
+            config::ConnectionLabels::from([(String::from("env"), String::from("prod"))]);
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        registry.register_connection(&config::ConnectionType::Push, &site_id, connection);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.json");
+
+        _export(&registry, Some(&path), false).unwrap();
+
+        let mut imported =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        imported.import_bundle(config::RegistryBundle::load(&path).unwrap(), true);
+        assert_eq!(
+            imported.get_mutable(&site_id).unwrap().trust.labels["env"],
+            "prod"
+        );
+    }
+
+    #[test]
+    fn test_export_public_only() {
+        let mut push = config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4());
+        push.trust.certificate = String::from(crate::constants::TEST_CERT_OK);
+        push.trust.labels =
+            config::ConnectionLabels::from([(String::from("env"), String::from("prod"))]);
+        let imported = config::TrustedConnection::from(uuid::Uuid::new_v4());
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &crate::site_spec::SiteID::from_str("server/site").unwrap(),
+            push.clone(),
+        );
+        registry.register_imported_connection(imported.clone());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inventory.json");
+
+        _export(&registry, Some(&path), true).unwrap();
+
+        let exported = std::fs::read_to_string(&path).unwrap();
+        assert!(!exported.contains("private_key"));
+        assert!(!exported.contains("BEGIN CERTIFICATE"));
+        let exported: serde_json::Value = serde_json::from_str(&exported).unwrap();
+        assert_eq!(
+            exported["connections"][0],
+            serde_json::json!({
+                "site_id": "server/site",
+                "uuid": push.trust.uuid.to_string(),
+                "connection_type": "push-agent",
+                "receiver_port": 8000,
+                "certificate_fingerprint": certs::fingerprint_sha256(
+                    &certs::parse_pem(crate::constants::TEST_CERT_OK).unwrap().contents
+                )
+                .unwrap(),
+                "labels": {"env": "prod"},
+            })
+        );
+        assert_eq!(
+            exported["connections"][1],
+            serde_json::json!({
+                "site_id": null,
+                "uuid": imported.uuid.to_string(),
+                "connection_type": "pull-agent",
+                "receiver_port": null,
+                "certificate_fingerprint": null,
+                "labels": {},
+            })
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Writes a CA and a controller and a receiver certificate signed by it to a directory, for
+//! trying out the pull and push paths by hand. Only built with the dev feature. The
+//! certificates are for testing only and must never be used for real registrations.
+
+use crate::{certs, cli};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub const TESTING_ONLY_WARNING: &str =
+    "WARNING: These certificates are for testing only. Never use them for real registrations.";
+
+/// Never overwrites anything, st. an existing set of certificates can't get lost by accident
+fn write_new(path: &Path, content: &str, private: bool) -> AnyhowResult<()> {
+    let mut open_options = std::fs::OpenOptions::new();
+    open_options.write(true).create_new(true);
+    #[cfg(unix)]
+    open_options.mode(if private { 0o600 } else { 0o644 });
+    #[cfg(windows)]
+    let _ = private;
+    open_options
+        .open(path)
+        .context(format!("Failed to create {}", path.display()))?
+        .write_all(content.as_bytes())
+        .context(format!("Failed to write to {}", path.display()))
+}
+
+fn files(test_certs: &certs::TestCerts) -> [(&'static str, &str, bool); 6] {
+    [
+        ("ca.pem", &test_certs.ca_cert, false),
+        ("ca.key", &test_certs.ca_private_key, true),
+        ("controller.pem", &test_certs.controller_cert, false),
+        ("controller.key", &test_certs.controller_private_key, true),
+        ("receiver.pem", &test_certs.receiver_cert, false),
+        ("receiver.key", &test_certs.receiver_private_key, true),
+    ]
+}
+
+fn _gen_test_certs(args: &cli::GenTestCertsArgs) -> AnyhowResult<Vec<PathBuf>> {
+    let controller_uuid = args.controller_uuid.unwrap_or_else(uuid::Uuid::new_v4);
+    let test_certs = certs::make_test_certs(
+        &args.ca_name,
+        &args.receiver_name,
+        &controller_uuid,
+        args.valid_days,
+    )
+    .context("Failed to generate test certificates")?;
+    std::fs::create_dir_all(&args.directory)
+        .context(format!("Failed to create {}", args.directory.display()))?;
+    let files = files(&test_certs);
+    for (name, _, _) in &files {
+        let path = args.directory.join(name);
+        if path.exists() {
+            bail!("{} already exists, not overwriting it", path.display())
+        }
+    }
+    let mut written = vec![];
+    for (name, content, private) in files {
+        let path = args.directory.join(name);
+        write_new(&path, content, private)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+pub fn gen_test_certs(args: &cli::GenTestCertsArgs) -> AnyhowResult<()> {
+    eprintln!("{}", TESTING_ONLY_WARNING);
+    for path in _gen_test_certs(args)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn args(directory: &Path) -> cli::GenTestCertsArgs {
+        clap::Parser::parse_from([
+            std::ffi::OsStr::new("gen-test-certs"),
+            directory.as_os_str(),
+            std::ffi::OsStr::new("--controller-uuid"),
+            std::ffi::OsStr::new("00c21714-5086-46d7-848e-5be72c715cfd"),
+        ])
+    }
+
+    #[test]
+    fn test_gen_test_certs() {
+        let dir = tempfile::tempdir().unwrap();
+        let written = _gen_test_certs(&args(dir.path())).unwrap();
+        assert_eq!(written.len(), 6);
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        // Usable as a pull connection as is
+        config::TrustedConnection {
+            uuid: uuid::Uuid::parse_str("00c21714-5086-46d7-848e-5be72c715cfd").unwrap(),
+            private_key: read("controller.key"),
+            certificate: read("controller.pem"),
+            root_cert: read("ca.pem"),
+            pinned_fingerprint: None,
+            pinned_client_fingerprints: vec![],
+            labels: config::ConnectionLabels::new(),
+            agent_output_disabled: false,
+            min_tls_version: None,
+        }
+        .validate()
+        .unwrap();
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(
+                &std::fs::metadata(dir.path().join("receiver.key"))
+                    .unwrap()
+                    .permissions()
+            ) & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn test_gen_test_certs_no_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("receiver.key"), "precious").unwrap();
+        assert!(_gen_test_certs(&args(dir.path())).is_err());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("receiver.key")).unwrap(),
+            "precious"
+        );
+        // Nothing is written if anything is in the way
+        assert!(!dir.path().join("ca.pem").exists());
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Local liveness check of a running pull daemon, eg. for container health probes. Unlike
+//! status, this never contacts a site and only answers whether we are able to serve pull
+//! requests right now.
+
+use crate::{cli, config, monitoring_data, types};
+use anyhow::{bail, Result as AnyhowResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+enum CheckResult {
+    Passed(String),
+    Skipped(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Passed(detail) => write!(f, "ok ({})", detail),
+            Self::Skipped(reason) => write!(f, "skipped ({})", reason),
+            Self::Failed(error) => write!(f, "FAILED ({})", error),
+        }
+    }
+}
+
+/// Where we can reach the listeners locally. When listening on all interfaces, the loopback
+/// address is the one which is always there.
+fn listener_addresses(pull_config: &config::PullConfig) -> Vec<SocketAddr> {
+    let address = match pull_config.listen_address {
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        Some(IpAddr::V4(address)) if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        Some(IpAddr::V6(address)) if address.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        Some(address) => address,
+    };
+    pull_config
+        .ports
+        .iter()
+        .map(|port| SocketAddr::new(address, *port))
+        .collect()
+}
+
+fn check_listener(pull_config: &config::PullConfig, timeout: Duration) -> CheckResult {
+    // Without a registry, the daemon does not open the listener at all
+    if !pull_config.listens() {
+        return CheckResult::Skipped(String::from("no connection registry"));
+    }
+    let addresses = listener_addresses(pull_config);
+    for address in &addresses {
+        if let Err(err) = TcpStream::connect_timeout(address, timeout) {
+            return CheckResult::Failed(format!("{}: {}", address, err));
+        }
+    }
+    CheckResult::Passed(
+        addresses
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect::<Vec<String>>()
+            .join(", "),
+    )
+}
+
+fn check_agent_channel(agent_channel: &types::AgentChannel, timeout: Duration) -> CheckResult {
+    match monitoring_data::probe(agent_channel, false, timeout) {
+        Ok(_) => CheckResult::Passed(agent_channel.to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+            CheckResult::Skipped(err.to_string())
+        }
+        Err(err) => CheckResult::Failed(format!("{}: {}", agent_channel, err)),
+    }
+}
+
+fn _healthcheck(pull_config: &config::PullConfig, timeout: Duration) -> Vec<(&str, CheckResult)> {
+    vec![
+        ("Pull listener", check_listener(pull_config, timeout)),
+        (
+            "Agent channel",
+            check_agent_channel(&pull_config.agent_channel, timeout),
+        ),
+    ]
+}
+
+pub fn healthcheck(
+    pull_config: &config::PullConfig,
+    healthcheck_args: &cli::HealthcheckArgs,
+) -> AnyhowResult<()> {
+    let results = _healthcheck(pull_config, Duration::from_secs(healthcheck_args.timeout));
+    for (check, result) in &results {
+        println!("{}: {}", check, result);
+    }
+    if results
+        .iter()
+        .any(|(_, result)| matches!(result, CheckResult::Failed(_)))
+    {
+        bail!("Health check failed")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pull_config(ports: &[u16], listen_address: Option<IpAddr>) -> config::PullConfig {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        registry.register_imported_connection(config::TrustedConnection::from(
+            "00c21714-5086-46d7-848e-5be72c715cfd",
+        ));
+        let mut pull_config = config::PullConfig::new(
+            config::RuntimeConfig::default(),
+            cli::PullOpts {
+                port: ports.to_vec(),
+                tls_min_version: None,
+                cache_ttl: None,
+                metrics_listen: None,
+                health_listen: None,
+                max_connections: None,
+                worker_threads: None,
+                listen_backlog: None,
+                retry_bind: None,
+                pull_bandwidth_limit: None,
+                io_chunk_size: None,
+                registry_readonly: false,
+                registry_load_retry: None,
+                require_registry: false,
+                allow_any: false,
+                no_client_auth: false,
+                explain_rejections: false,
+                strict_startup: false,
+                on_agent_unavailable: None,
+                #[cfg(unix)]
+                pull_unix_socket: None,
+                #[cfg(windows)]
+                agent_channel: None,
+            },
+            registry,
+            tempfile::NamedTempFile::new().unwrap().path(),
+        )
+        .unwrap();
+        pull_config.listen_address = listen_address;
+        pull_config
+    }
+
+    #[test]
+    fn test_listener_addresses() {
+        assert_eq!(
+            listener_addresses(&pull_config(&[6556], None)),
+            [SocketAddr::from((Ipv4Addr::LOCALHOST, 6556))]
+        );
+        assert_eq!(
+            listener_addresses(&pull_config(
+                &[6556],
+                Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+            )),
+            [SocketAddr::from((Ipv6Addr::LOCALHOST, 6556))]
+        );
+        let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(
+            listener_addresses(&pull_config(&[6556, 6557], Some(address))),
+            [
+                SocketAddr::from((address, 6556)),
+                SocketAddr::from((address, 6557))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_listener() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(matches!(
+            check_listener(&pull_config(&[port], None), Duration::from_secs(1)),
+            CheckResult::Passed(_)
+        ));
+        let other = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let other_port = other.local_addr().unwrap().port();
+        drop(other);
+        match check_listener(
+            &pull_config(&[port, other_port], None),
+            Duration::from_secs(1),
+        ) {
+            CheckResult::Failed(error) => {
+                assert!(error.starts_with(&format!("127.0.0.1:{}", other_port)))
+            }
+            _ => panic!("Expected the check to fail"),
+        }
+        drop(listener);
+        assert!(matches!(
+            check_listener(&pull_config(&[port], None), Duration::from_secs(1)),
+            CheckResult::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn test_check_listener_pull_inactive() {
+        let mut pull_config = pull_config(&[6556], None);
+        pull_config.registry.clear();
+        assert!(matches!(
+            check_listener(&pull_config, Duration::from_secs(1)),
+            CheckResult::Skipped(_)
+        ));
+    }
+
+    #[test]
+    fn test_check_result_display() {
+        assert_eq!(
+            format!("{}", CheckResult::Failed(String::from("refused"))),
+            "FAILED (refused)"
+        );
+        assert_eq!(
+            format!("{}", CheckResult::Passed(String::from("[::1]:6556"))),
+            "ok ([::1]:6556)"
+        );
+    }
+}
@@ -2,13 +2,16 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+use crate::modes::export::PRIVATE_KEY_WARNING;
 use crate::modes::registration::ProxyPullData;
 use crate::{cli, config};
 use anyhow::{Context, Result as AnyhowResult};
 use config::JSONLoader;
+use std::io::Read;
 
 trait ImportDataProvider {
     fn provide(&self) -> AnyhowResult<ProxyPullData>;
+    fn provide_bundle(&self) -> AnyhowResult<config::RegistryBundle>;
 }
 
 struct ImportDataFromFile {
@@ -20,6 +23,11 @@ impl ImportDataProvider for ImportDataFromFile {
         ProxyPullData::load(&self.path)
             .context(format!("Failed to read file {}", &self.path.display()))
     }
+
+    fn provide_bundle(&self) -> AnyhowResult<config::RegistryBundle> {
+        config::RegistryBundle::load(&self.path)
+            .context(format!("Failed to read file {}", &self.path.display()))
+    }
 }
 
 struct ImportDataFromStdin {}
@@ -33,6 +41,15 @@ impl ImportDataProvider for ImportDataFromStdin {
         serde_json::from_str(&buffer)
             .context(format!("Failed to deserialize JSON data:\n{}", &buffer))
     }
+
+    fn provide_bundle(&self) -> AnyhowResult<config::RegistryBundle> {
+        let mut buffer = String::new();
+        std::io::stdin()
+            .read_to_string(&mut buffer)
+            .context("Failed to read from stdin")?;
+        // Don't include the data in the error message, it contains private keys
+        serde_json::from_str(&buffer).context("Failed to deserialize JSON data")
+    }
 }
 
 fn _import(
@@ -44,15 +61,55 @@ fn _import(
     Ok(())
 }
 
+fn _import_bundle(
+    registry: &mut config::Registry,
+    import_data_provider: &impl ImportDataProvider,
+    replace: bool,
+) -> AnyhowResult<usize> {
+    let bundle = import_data_provider.provide_bundle()?;
+    // Validate everything up front, st. we never end up with a partially imported bundle
+    bundle.validate()?;
+    let imported = bundle.connection_count();
+    registry.import_bundle(bundle, replace);
+    registry.save()?;
+    Ok(imported)
+}
+
+fn import_bundle(
+    registry: &mut config::Registry,
+    import_data_provider: &impl ImportDataProvider,
+    replace: bool,
+) -> AnyhowResult<()> {
+    eprintln!("{}", PRIVATE_KEY_WARNING);
+    let imported = _import_bundle(registry, import_data_provider, replace)?;
+    println!(
+        "Imported {} connection(s), {}",
+        imported,
+        if replace {
+            "replacing all existing connections"
+        } else {
+            "merged with existing connections"
+        }
+    );
+    Ok(())
+}
+
 pub fn import(registry: &mut config::Registry, import_args: &cli::ImportArgs) -> AnyhowResult<()> {
+    let bundle = import_args.merge || import_args.replace;
     match &import_args.conn_file {
-        Some(path) => _import(
-            registry,
-            &ImportDataFromFile {
+        Some(path) => {
+            let provider = ImportDataFromFile {
                 path: std::path::PathBuf::from(path),
-            },
-        ),
-        None => _import(registry, &ImportDataFromStdin {}),
+            };
+            match bundle {
+                true => import_bundle(registry, &provider, import_args.replace),
+                false => _import(registry, &provider),
+            }
+        }
+        None => match bundle {
+            true => import_bundle(registry, &ImportDataFromStdin {}, import_args.replace),
+            false => _import(registry, &ImportDataFromStdin {}),
+        },
     }
 }
 
@@ -61,8 +118,23 @@ mod tests {
     use std::str::FromStr;
 
     use super::*;
+    use anyhow::anyhow;
     struct MockImportDataProvider {}
 
+    struct MockBundleProvider {
+        bundle: &'static str,
+    }
+
+    impl ImportDataProvider for MockBundleProvider {
+        fn provide(&self) -> AnyhowResult<ProxyPullData> {
+            Err(anyhow!("not used in this test"))
+        }
+
+        fn provide_bundle(&self) -> AnyhowResult<config::RegistryBundle> {
+            Ok(serde_json::from_str(self.bundle)?)
+        }
+    }
+
     impl ImportDataProvider for MockImportDataProvider {
         fn provide(&self) -> AnyhowResult<ProxyPullData> {
             Ok(ProxyPullData {
@@ -72,9 +144,18 @@ mod tests {
                     private_key: String::from("fake private key"),
                     certificate: String::from("fake cert"),
                     root_cert: String::from("fake root cert"),
+                    pinned_fingerprint: None,
+                    pinned_client_fingerprints: vec![],
+                    labels: config::ConnectionLabels::new(),
+                    agent_output_disabled: false,
+                    min_tls_version: None,
                 },
             })
         }
+
+        fn provide_bundle(&self) -> AnyhowResult<config::RegistryBundle> {
+            Err(anyhow!("not used in this test"))
+        }
     }
 
     #[test]
@@ -87,4 +168,81 @@ mod tests {
         assert!(!reg.is_empty());
         assert!(reg.path().exists());
     }
+
+    #[test]
+    fn test_import_bundle_invalid() {
+        let mut reg =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        let err = _import_bundle(
+            &mut reg,
+            &MockBundleProvider {
+                bundle: r#"{
+                    "agent_controller_version": "2.2.0i1",
+                    "connections": {
+                        "pull": {
+                            "server/site": {
+                                "uuid": "2da53af5-5c06-4195-ab6f-668875710bec",
+                                "private_key": "fake private key",
+                                "certificate": "fake cert",
+                                "root_cert": "fake root cert",
+                                "receiver_port": 8000
+                            }
+                        }
+                    }
+                }"#,
+            },
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(format!("{}", err), "Invalid connection server/site");
+        assert!(reg.is_empty());
+        assert!(!reg.path().exists());
+    }
+
+    #[test]
+    fn test_import_bundle_invalid_uuid() {
+        let mut reg =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        assert!(_import_bundle(
+            &mut reg,
+            &MockBundleProvider {
+                bundle: r#"{
+                    "agent_controller_version": "2.2.0i1",
+                    "connections": {
+                        "pull_imported": [{
+                            "uuid": "not-a-uuid",
+                            "private_key": "fake private key",
+                            "certificate": "fake cert",
+                            "root_cert": "fake root cert"
+                        }]
+                    }
+                }"#,
+            },
+            false,
+        )
+        .is_err());
+        assert!(!reg.path().exists());
+    }
+
+    #[test]
+    fn test_import_bundle_future_version() {
+        let mut reg =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        let err = _import_bundle(
+            &mut reg,
+            &MockBundleProvider {
+                bundle: r#"{
+                    "agent_controller_version": "9.9.0",
+                    "connections": {"version": 1000}
+                }"#,
+            },
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "Bundle was created by agent controller 9.9.0"
+        );
+        assert!(!reg.path().exists());
+    }
 }
@@ -3,45 +3,111 @@
 // conditions defined in the file COPYING, which is part of this source code package.
 
 use core::future::Future;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
-use crate::{config, misc::anyhow_error_to_human_readable, monitoring_data, tls_server, types};
+#[cfg(unix)]
+use crate::sd_notify;
+use crate::{
+    access_log, certs, config, constants, health, metrics,
+    misc::{anyhow_error_to_human_readable, wait_for_shutdown_signal},
+    monitoring_data, output_hooks, proxy_protocol, pull_admin, request_limit, security_log,
+    tls_debug, tls_server, types,
+};
 use anyhow::{anyhow, bail, Context, Error as AnyhowError, Result as AnyhowResult};
 use async_trait::async_trait;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use socket2::{Domain, SockAddr, Socket, Type};
+use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as TcpListenerStd};
-use tokio::io::AsyncWriteExt;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
+use tokio::sync::{mpsc, Notify, Semaphore};
 use tokio::time::{timeout, Duration};
-use tokio_rustls::TlsAcceptor;
 
-const TLS_ID: &[u8] = b"16";
-const HEADER_VERSION: &[u8] = b"\x00\x00";
+pub(crate) const TLS_ID: &[u8] = b"16";
+pub(crate) const HEADER_VERSION: &[u8] = b"\x00\x00";
 const ONE_MINUTE: u64 = 60;
 const FIVE_MINUTES: u64 = 300;
+const PROXIED_QUEUE_SIZE: usize = 64;
+const CLOSE_TIMEOUT: u64 = 1;
+const RETRY_BIND_INTERVAL: u64 = 1;
 
+#[derive(Clone, PartialEq, Eq, Debug)]
 struct ListeningConfig {
-    pub addr_v4: Ipv4Addr,
-    pub addr_v6: Ipv6Addr,
-    pub port: u16,
+    /// None means all interfaces, via IPv6 (dual stack) if possible and IPv4 otherwise
+    pub address: Option<IpAddr>,
+    pub ports: Vec<u16>,
+    pub backlog: u32,
+    /// Seconds to wait for ports in use to become free
+    pub retry_bind: u64,
 }
 
+impl std::fmt::Display for ListeningConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "port(s) {} on {}",
+            self.ports
+                .iter()
+                .map(u16::to_string)
+                .collect::<Vec<String>>()
+                .join(", "),
+            self.address
+                .map(|address| address.to_string())
+                .unwrap_or_else(|| String::from("all interfaces"))
+        )
+    }
+}
+
+/// Someone else listens on the port already. This is rather a problem of the setup than one
+/// of ours, so it maps to the exit code of an invalid configuration.
+#[derive(Debug)]
+pub struct PortInUse(pub u16);
+
+impl std::fmt::Display for PortInUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Port {} is already in use. Another agent controller may still be running, or another \
+             service listens on it, eg. a legacy agent setup via xinetd or check-mk-agent.socket.",
+            self.0
+        )
+    }
+}
+
+impl Error for PortInUse {}
+
 trait PullState {
     fn refresh(&mut self) -> AnyhowResult<()>;
-    fn tls_acceptor(&self) -> TlsAcceptor;
+    fn reload(&mut self) -> AnyhowResult<()>;
+    fn tls_acceptor(&self) -> tls_server::PullTlsAcceptor;
     fn allow_legacy_pull(&self) -> bool;
     fn is_active(&self) -> bool;
-    fn ip_allowlist(&self) -> &[String];
+    fn ip_allowlist(&self) -> Option<&[config::IpEntry]>;
+    fn ip_denylist(&self) -> &[config::IpEntry];
+    fn trusted_proxies(&self) -> &[config::IpEntry];
     fn listening_config(&self) -> ListeningConfig;
-    fn connection_timeout(&self) -> u64;
+    /// Keep listening as given, eg. if the settings of a reload could not be bound
+    fn keep_listening(&mut self, listening_config: &ListeningConfig);
+    fn timeouts(&self) -> config::Timeouts;
+    fn max_request_bytes(&self) -> usize;
+    fn connection_timeout_overrides(&self) -> HashMap<String, u64>;
+    fn agent_channel(&self) -> &types::AgentChannel;
+    fn agent_channel_overrides(&self) -> HashMap<String, types::AgentChannel>;
+    fn agent_output_disabled(&self) -> HashSet<String>;
+    fn access_log(&self) -> Option<Arc<access_log::AccessLog>>;
+    fn security_log(&self) -> Option<Arc<security_log::SecurityLog>>;
 }
 struct PullStateImpl {
     allow_legacy_pull: bool,
-    tls_acceptor: TlsAcceptor,
+    tls_acceptor: tls_server::PullTlsAcceptor,
+    access_log: Option<Arc<access_log::AccessLog>>,
+    security_log: Option<Arc<security_log::SecurityLog>>,
     config: config::PullConfig,
 }
 
@@ -51,8 +117,20 @@ impl std::convert::TryFrom<config::PullConfig> for PullStateImpl {
     fn try_from(config: config::PullConfig) -> AnyhowResult<Self> {
         Ok(Self {
             allow_legacy_pull: config.allow_legacy_pull(),
-            tls_acceptor: tls_server::tls_acceptor(config.connections())
+            tls_acceptor: tls_server::tls_acceptor(config.connections(), &config.tls_policy)
                 .context("Could not initialize TLS.")?,
+            access_log: config
+                .access_log
+                .as_deref()
+                .map(access_log::AccessLog::open)
+                .transpose()?
+                .map(Arc::new),
+            security_log: config
+                .security_log
+                .as_deref()
+                .map(security_log::SecurityLog::open)
+                .transpose()?
+                .map(Arc::new),
             config,
         })
     }
@@ -61,14 +139,63 @@ impl std::convert::TryFrom<config::PullConfig> for PullStateImpl {
 impl PullState for PullStateImpl {
     fn refresh(&mut self) -> AnyhowResult<()> {
         if self.config.refresh()? {
-            self.tls_acceptor = tls_server::tls_acceptor(self.config.connections())
-                .context("Could not initialize TLS.")?;
+            self.tls_acceptor =
+                tls_server::tls_acceptor(self.config.connections(), &self.config.tls_policy)
+                    .context("Could not initialize TLS.")?;
         };
         self.allow_legacy_pull = self.config.allow_legacy_pull();
         Ok(())
     }
 
-    fn tls_acceptor(&self) -> TlsAcceptor {
+    fn reload(&mut self) -> AnyhowResult<()> {
+        // Independent of the rest, if this fails, we keep writing to the current file
+        if let Some(Err(err)) = self.access_log.as_ref().map(|log| log.reopen()) {
+            warn!("Failed to reopen access log. ({:#})", err);
+        }
+        if let Some(Err(err)) = self.security_log.as_ref().map(|log| log.reopen()) {
+            warn!("Failed to reopen security log. ({:#})", err);
+        }
+        // Set up everything from the new registry, allowlist and agent channel before swapping,
+        // st. we keep serving the current connections if anything fails. Requests which are
+        // already being handled hold their own clone of the old TLS acceptor.
+        let registry = config::Registry::from_file_with_limit(
+            self.config.registry.path(),
+            self.config.registry.max_connections(),
+            self.config.registry.duplicate_uuids(),
+        )
+        .context("Could not load registry.")?;
+        let tls_acceptor =
+            tls_server::tls_acceptor(registry.pull_connections(), &self.config.tls_policy)
+                .context("Could not initialize TLS.")?;
+        let allowed_ip = self
+            .config
+            .load_allowed_ip()
+            .context("Could not load allowlist.")?;
+        let agent_channel = self
+            .config
+            .reloaded_agent_channel()
+            .context("Could not load agent channel.")?;
+        let (ports, listen_address) = self
+            .config
+            .reloaded_listening()
+            .context("Could not load listener settings.")?;
+        if agent_channel != self.config.agent_channel {
+            info!(
+                "Agent channel changed from {} to {}, using it for new connections.",
+                self.config.agent_channel, agent_channel
+            );
+        }
+        self.config.registry = registry;
+        self.config.allowed_ip = allowed_ip;
+        self.config.agent_channel = agent_channel;
+        self.config.ports = ports;
+        self.config.listen_address = listen_address;
+        self.tls_acceptor = tls_acceptor;
+        self.allow_legacy_pull = self.config.allow_legacy_pull();
+        Ok(())
+    }
+
+    fn tls_acceptor(&self) -> tls_server::PullTlsAcceptor {
         self.tls_acceptor.clone()
     }
 
@@ -77,144 +204,1762 @@ impl PullState for PullStateImpl {
     }
 
     fn is_active(&self) -> bool {
-        self.allow_legacy_pull || self.config.has_connections()
+        self.allow_legacy_pull
+            || self.config.has_connections()
+            || self.config.registry.file_present()
+    }
+
+    fn ip_allowlist(&self) -> Option<&[config::IpEntry]> {
+        self.config.ip_allowlist()
+    }
+
+    fn ip_denylist(&self) -> &[config::IpEntry] {
+        &self.config.denied_ip
     }
 
-    fn ip_allowlist(&self) -> &[String] {
-        &self.config.allowed_ip
+    fn trusted_proxies(&self) -> &[config::IpEntry] {
+        &self.config.trusted_proxies
     }
 
     fn listening_config(&self) -> ListeningConfig {
         ListeningConfig {
-            addr_v4: Ipv4Addr::UNSPECIFIED,
-            addr_v6: Ipv6Addr::UNSPECIFIED,
-            port: self.config.port,
+            address: self.config.listen_address,
+            ports: self.config.ports.clone(),
+            backlog: self.config.listen_backlog,
+            retry_bind: self.config.retry_bind,
         }
     }
 
-    fn connection_timeout(&self) -> u64 {
-        self.config.connection_timeout
+    fn keep_listening(&mut self, listening_config: &ListeningConfig) {
+        self.config.listen_address = listening_config.address;
+        self.config.ports = listening_config.ports.clone();
+    }
+
+    fn timeouts(&self) -> config::Timeouts {
+        self.config.timeouts
+    }
+
+    fn max_request_bytes(&self) -> usize {
+        self.config.max_request_bytes
+    }
+
+    fn connection_timeout_overrides(&self) -> HashMap<String, u64> {
+        self.config.connection_timeouts_by_uuid()
+    }
+
+    fn agent_channel(&self) -> &types::AgentChannel {
+        &self.config.agent_channel
+    }
+
+    fn agent_channel_overrides(&self) -> HashMap<String, types::AgentChannel> {
+        self.config.agent_channels_by_uuid()
+    }
+
+    fn agent_output_disabled(&self) -> HashSet<String> {
+        self.config.agent_output_disabled_uuids()
+    }
+
+    fn access_log(&self) -> Option<Arc<access_log::AccessLog>> {
+        self.access_log.clone()
+    }
+
+    fn security_log(&self) -> Option<Arc<security_log::SecurityLog>> {
+        self.security_log.clone()
     }
 }
 
 #[async_trait]
 trait AgentOutputCollector: std::clone::Clone + Sync + Send + 'static {
-    async fn plain_output(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<Vec<u8>>;
-    async fn encoded_output(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<Vec<u8>>;
+    async fn connect(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<AgentOutput>;
+
+    /// Forget about output collected earlier, eg. because the configuration was reloaded
+    fn invalidate(&self) {}
+
+    /// Connect to the given agent channel from now on. Requests which are already being handled
+    /// hold their own clone and keep using the channel they started with.
+    fn use_agent_channel(&mut self, _agent_channel: &types::AgentChannel) {}
+
+    /// A clone which connects to the given agent channels for the connections with the given
+    /// UUIDs, see connect_for
+    fn with_connection_channels(&self, _channels: HashMap<String, types::AgentChannel>) -> Self {
+        self.clone()
+    }
+
+    /// A clone which serves empty output for the connections with the given UUIDs, without
+    /// touching the agent channel, see connect_for
+    fn with_agent_output_disabled(&self, _uuids: HashSet<String>) -> Self {
+        self.clone()
+    }
+
+    /// Whether the agent channel depends on the connection the peer selects
+    fn selects_by_connection(&self) -> bool {
+        false
+    }
+
+    /// Like connect, but from the agent channel of the connection with the given UUID, if it
+    /// has one of its own
+    async fn connect_for(
+        &self,
+        remote_ip: std::net::IpAddr,
+        _uuid: Option<&str>,
+    ) -> AnyhowResult<AgentOutput> {
+        self.connect(remote_ip).await
+    }
 }
 
-#[derive(Clone)]
-struct AgentOutputCollectorImpl {
-    agent_channel: types::AgentChannel,
+/// The agent accepted the connection, but did not start sending its output in time, eg.
+/// because a plugin hangs. Not to be confused with a slow peer, which hits the idle timeout.
+#[derive(Debug)]
+struct AgentChannelTimeout(u64);
+
+impl std::fmt::Display for AgentChannelTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Agent did not respond within {} seconds", self.0)
+    }
 }
 
-impl AgentOutputCollectorImpl {
-    fn encode(&self, raw_agent_output: &[u8]) -> AnyhowResult<Vec<u8>> {
-        let mut encoded_data = HEADER_VERSION.to_vec();
-        encoded_data.append(&mut monitoring_data::compression_header_info().pull);
-        encoded_data.append(
-            &mut monitoring_data::compress(raw_agent_output)
-                .context("Error compressing monitoring data")?,
-        );
-        Ok(encoded_data)
+impl Error for AgentChannelTimeout {}
+
+/// The agent channel answered, but not like an agent, eg. a web server behind a misconfigured
+/// TCP channel. See config::PullConfig::agent_output_marker.
+#[derive(Debug)]
+struct UnexpectedAgentOutput {
+    marker: Vec<u8>,
+    received: Vec<u8>,
+}
+
+impl std::fmt::Display for UnexpectedAgentOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Agent output does not start with {:?} but with {:?}, is the agent channel misconfigured?",
+            String::from_utf8_lossy(&self.marker),
+            String::from_utf8_lossy(&self.received)
+        )
     }
 }
 
-impl std::convert::From<types::AgentChannel> for AgentOutputCollectorImpl {
-    fn from(agent_channel: types::AgentChannel) -> Self {
-        AgentOutputCollectorImpl { agent_channel }
+impl Error for UnexpectedAgentOutput {}
+
+/// The peer did not complete the TLS handshake in time. Unlike hitting the idle timeout, this
+/// counts as a failed handshake, we never got to sending any data.
+#[derive(Debug)]
+struct HandshakeTimeout(u64);
+
+impl std::fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "TLS handshake not completed within {} seconds", self.0)
     }
 }
 
-impl std::convert::From<&types::AgentChannel> for AgentOutputCollectorImpl {
-    fn from(agent_channel: &types::AgentChannel) -> Self {
+impl Error for HandshakeTimeout {}
+
+#[derive(Clone)]
+struct AgentOutputCollectorImpl {
+    agent_channel: types::AgentChannel,
+    max_output_bytes: usize,
+    agent_channel_timeout: u64,
+    bandwidth_limit: Option<u64>,
+    io_chunk_size: usize,
+    cache: Option<AgentOutputCache>,
+    /// By the UUID of the connection, see config::PullConfig::site_agent_channels
+    connection_channels: Arc<HashMap<String, types::AgentChannel>>,
+    /// By the UUID of the connection, see config::TrustedConnection::agent_output_disabled
+    agent_output_disabled: Arc<HashSet<String>>,
+    on_agent_unavailable: config::AgentUnavailablePolicy,
+    /// See config::PullConfig::output_hooks
+    output_hooks: Arc<Vec<Vec<String>>>,
+    output_hook_timeout: u64,
+    /// See config::PullConfig::agent_output_marker
+    agent_output_marker: Option<Arc<[u8]>>,
+    // Each of these channels has its own output, so it's cached separately, by channel
+    channel_caches: Arc<std::sync::Mutex<HashMap<String, AgentOutputCache>>>,
+}
+
+impl AgentOutputCollectorImpl {
+    fn new(
+        agent_channel: &types::AgentChannel,
+        max_output_bytes: usize,
+        agent_channel_timeout: u64,
+        bandwidth_limit: Option<u64>,
+        io_chunk_size: usize,
+        cache_ttl: Option<u64>,
+    ) -> Self {
         AgentOutputCollectorImpl {
             agent_channel: agent_channel.clone(),
+            max_output_bytes,
+            agent_channel_timeout,
+            bandwidth_limit,
+            io_chunk_size,
+            cache: cache_ttl
+                .map(|ttl| AgentOutputCache::new(Duration::from_secs(ttl), max_output_bytes)),
+            connection_channels: Arc::new(HashMap::new()),
+            agent_output_disabled: Arc::new(HashSet::new()),
+            on_agent_unavailable: config::AgentUnavailablePolicy::default(),
+            output_hooks: Arc::new(vec![]),
+            output_hook_timeout: constants::DEFAULT_OUTPUT_HOOK_TIMEOUT,
+            agent_output_marker: None,
+            channel_caches: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn on_agent_unavailable(mut self, policy: config::AgentUnavailablePolicy) -> Self {
+        self.on_agent_unavailable = policy;
+        self
+    }
+
+    fn output_hooks(mut self, hooks: &[Vec<String>], hook_timeout: u64) -> Self {
+        self.output_hooks = Arc::new(hooks.to_vec());
+        self.output_hook_timeout = hook_timeout;
+        self
+    }
+
+    fn agent_output_marker(mut self, marker: Option<&[u8]>) -> Self {
+        self.agent_output_marker = marker.map(Arc::from);
+        self
+    }
+
+    /// Connects and waits for the first bytes of output, the rest is read on forwarding. With a
+    /// marker, we wait until we got enough of the output to compare it.
+    async fn connect_agent(
+        &self,
+        agent_channel: &types::AgentChannel,
+        remote_ip: std::net::IpAddr,
+    ) -> AnyhowResult<Box<dyn AsyncRead + Unpin + Send>> {
+        let agent_stream =
+            monitoring_data::async_connect(agent_channel, remote_ip, self.max_output_bytes).await?;
+        let mut agent_stream = tokio::io::BufReader::new(agent_stream);
+        let Some(marker) = &self.agent_output_marker else {
+            agent_stream.fill_buf().await?;
+            return Ok(Box::new(agent_stream));
+        };
+        let mut received = vec![];
+        (&mut agent_stream)
+            .take(marker.len() as u64)
+            .read_to_end(&mut received)
+            .await?;
+        if received != marker.as_ref() {
+            bail!(UnexpectedAgentOutput {
+                marker: marker.to_vec(),
+                received,
+            })
+        }
+        Ok(Box::new(std::io::Cursor::new(received).chain(agent_stream)))
+    }
+
+    async fn collect(
+        &self,
+        agent_channel: &types::AgentChannel,
+        remote_ip: std::net::IpAddr,
+    ) -> AnyhowResult<AgentOutput> {
+        let agent_stream = timeout(
+            Duration::from_secs(self.agent_channel_timeout),
+            self.connect_agent(agent_channel, remote_ip),
+        )
+        .await
+        .map_err(|_| anyhow!(AgentChannelTimeout(self.agent_channel_timeout)))
+        .and_then(|connected| connected)
+        .context("Error collecting monitoring data.")?;
+        Ok(AgentOutput::new(agent_stream, self.max_output_bytes))
+    }
+
+    /// The hooks get to see the peer, so they run for every request, also on cached output.
+    /// Otherwise, whatever they made of the output for one site would be served to the others.
+    async fn run_output_hooks(
+        &self,
+        output: AgentOutput,
+        remote_ip: std::net::IpAddr,
+    ) -> AnyhowResult<AgentOutput> {
+        if self.output_hooks.is_empty() {
+            return Ok(output);
         }
+        // The hooks need the complete output, so it can't be forwarded while it's being read
+        let transformed = output_hooks::run(
+            &self.output_hooks,
+            output.read_all().await?,
+            remote_ip,
+            self.max_output_bytes,
+            self.output_hook_timeout,
+        )
+        .await?;
+        Ok(AgentOutput::new(
+            Box::new(std::io::Cursor::new(transformed)),
+            self.max_output_bytes,
+        ))
+    }
+
+    async fn connect_to(
+        &self,
+        agent_channel: &types::AgentChannel,
+        cache: Option<&AgentOutputCache>,
+        remote_ip: std::net::IpAddr,
+    ) -> AnyhowResult<AgentOutput> {
+        let collected = match cache {
+            Some(cache) => {
+                cache
+                    .get_or_collect(self.collect(agent_channel, remote_ip))
+                    .await
+            }
+            None => self.collect(agent_channel, remote_ip).await,
+        };
+        let collected = match collected {
+            Ok(output) => self.run_output_hooks(output, remote_ip).await,
+            Err(err) => Err(err),
+        };
+        // Not cached, the agent may well be back for the next request
+        let output = match (collected, self.on_agent_unavailable) {
+            (Err(err), config::AgentUnavailablePolicy::Report)
+                if !err.is::<output_hooks::HookFailed>() =>
+            {
+                warn!(
+                    "{}: Agent is unavailable, reporting it to the peer. ({:#})",
+                    remote_ip, err
+                );
+                agent_unavailable_output(&err, self.max_output_bytes)
+            }
+            (collected, _) => collected?,
+        };
+        Ok(output
+            .throttled(self.bandwidth_limit)
+            .chunked(self.io_chunk_size))
     }
 }
 
 #[async_trait]
 impl AgentOutputCollector for AgentOutputCollectorImpl {
-    async fn plain_output(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<Vec<u8>> {
-        Ok(monitoring_data::async_collect(&self.agent_channel, remote_ip).await?)
+    async fn connect(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<AgentOutput> {
+        self.connect_to(&self.agent_channel, self.cache.as_ref(), remote_ip)
+            .await
+    }
+
+    fn invalidate(&self) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate();
+        }
+        for cache in self.channel_caches.lock().unwrap().values() {
+            cache.invalidate();
+        }
+    }
+
+    fn use_agent_channel(&mut self, agent_channel: &types::AgentChannel) {
+        self.agent_channel = agent_channel.clone();
+    }
+
+    fn with_connection_channels(&self, channels: HashMap<String, types::AgentChannel>) -> Self {
+        Self {
+            connection_channels: Arc::new(channels),
+            ..self.clone()
+        }
+    }
+
+    fn with_agent_output_disabled(&self, uuids: HashSet<String>) -> Self {
+        Self {
+            agent_output_disabled: Arc::new(uuids),
+            ..self.clone()
+        }
+    }
+
+    fn selects_by_connection(&self) -> bool {
+        !self.connection_channels.is_empty() || !self.agent_output_disabled.is_empty()
+    }
+
+    async fn connect_for(
+        &self,
+        remote_ip: std::net::IpAddr,
+        uuid: Option<&str>,
+    ) -> AnyhowResult<AgentOutput> {
+        if uuid.is_some_and(|uuid| self.agent_output_disabled.contains(uuid)) {
+            debug!(
+                "{}: Agent output is disabled for this connection.",
+                remote_ip
+            );
+            return Ok(AgentOutput::new(
+                Box::new(tokio::io::empty()),
+                self.max_output_bytes,
+            ));
+        }
+        let Some(agent_channel) = uuid.and_then(|uuid| self.connection_channels.get(uuid)) else {
+            return self.connect(remote_ip).await;
+        };
+        debug!("{}: Using agent channel {}.", remote_ip, agent_channel);
+        let cache = self.cache.as_ref().map(|cache| {
+            self.channel_caches
+                .lock()
+                .unwrap()
+                .entry(agent_channel.to_string())
+                .or_insert_with(|| AgentOutputCache::new(cache.ttl, cache.max_output_bytes))
+                .clone()
+        });
+        self.connect_to(agent_channel, cache.as_ref(), remote_ip)
+            .await
+    }
+}
+
+/// Stands in for the agent output we failed to collect. The check_mk section makes it valid
+/// agent output, the error gets a section of its own.
+fn agent_unavailable_output(err: &AnyhowError, max_output_bytes: usize) -> AgentOutput {
+    let output = format!(
+        "<<<check_mk>>>\nAgentController: cmk-agent-ctl {}\n<<<cmk_agent_ctl_agent_unavailable:sep(0)>>>\n{}\n",
+        constants::VERSION,
+        format!("{:#}", err).replace('\n', " ")
+    );
+    AgentOutput::new(
+        Box::new(std::io::Cursor::new(output.into_bytes())),
+        max_output_bytes,
+    )
+}
+
+#[derive(Default)]
+struct CacheEntry {
+    // Bumped on invalidation, st. a collection running meanwhile doesn't store outdated output
+    generation: u64,
+    output: Option<(Instant, Arc<[u8]>)>,
+}
+
+/// Keeps the agent output in memory for a while, st. several sites polling the same host
+/// shortly after each other don't trigger an agent run each. Note that the agent only gets to
+/// see the IP of the peer which caused the collection. The output hooks run afterwards, per
+/// peer.
+#[derive(Clone)]
+struct AgentOutputCache {
+    ttl: Duration,
+    max_output_bytes: usize,
+    entry: Arc<std::sync::Mutex<CacheEntry>>,
+    // Concurrent cache misses wait for a single collection instead of running the agent again
+    collecting: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl AgentOutputCache {
+    fn new(ttl: Duration, max_output_bytes: usize) -> Self {
+        AgentOutputCache {
+            ttl,
+            max_output_bytes,
+            entry: Arc::new(std::sync::Mutex::new(CacheEntry::default())),
+            collecting: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    fn lookup(&self, now: Instant) -> Option<Arc<[u8]>> {
+        let entry = self.entry.lock().unwrap();
+        let (collected_at, data) = entry.output.as_ref()?;
+        let age = now.saturating_duration_since(*collected_at);
+        if age >= self.ttl {
+            debug!(
+                "Cached agent output expired (age {:.1}s), collecting again.",
+                age.as_secs_f64()
+            );
+            return None;
+        }
+        debug!(
+            "Serving cached agent output (age {:.1}s).",
+            age.as_secs_f64()
+        );
+        Some(data.clone())
+    }
+
+    fn serve(&self, data: Arc<[u8]>) -> AgentOutput {
+        AgentOutput::new(Box::new(std::io::Cursor::new(data)), self.max_output_bytes)
+    }
+
+    async fn get_or_collect(
+        &self,
+        collect: impl Future<Output = AnyhowResult<AgentOutput>>,
+    ) -> AnyhowResult<AgentOutput> {
+        if let Some(data) = self.lookup(Instant::now()) {
+            return Ok(self.serve(data));
+        }
+        let _collecting = self.collecting.lock().await;
+        // Another request might have collected the output while we were waiting
+        if let Some(data) = self.lookup(Instant::now()) {
+            return Ok(self.serve(data));
+        }
+        debug!("No cached agent output, collecting.");
+        let generation = self.entry.lock().unwrap().generation;
+        let data: Arc<[u8]> = collect.await?.read_all().await?.into();
+        let mut entry = self.entry.lock().unwrap();
+        if entry.generation == generation {
+            entry.output = Some((Instant::now(), data.clone()));
+        }
+        drop(entry);
+        Ok(self.serve(data))
+    }
+
+    fn invalidate(&self) {
+        let mut entry = self.entry.lock().unwrap();
+        entry.generation += 1;
+        entry.output = None;
+    }
+}
+
+/// Token bucket limiting the bytes per second written to a single peer. The bucket starts
+/// empty and holds at most one second worth of bytes.
+struct Throttle {
+    bytes_per_second: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Throttle {
+    fn new(bytes_per_second: u64) -> Self {
+        Throttle {
+            bytes_per_second,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Waits until sending the given number of bytes keeps us within the rate
+    async fn consume(&mut self, bytes: usize) {
+        let rate = self.bytes_per_second as f64;
+        let now = Instant::now();
+        self.tokens = (self.tokens
+            + now
+                .saturating_duration_since(self.last_refill)
+                .as_secs_f64()
+                * rate)
+            .min(rate);
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / rate)).await;
+        }
+    }
+}
+
+/// Where the time of serving a pull request goes. Reading from the agent and writing to the
+/// peer alternate chunk by chunk, so both are summed up over all chunks. Waiting for the
+/// throttle counts towards neither.
+#[derive(Default)]
+struct ServeTiming {
+    forwarding: bool,
+    agent_bytes: usize,
+    agent_read: Duration,
+    peer_write: Duration,
+}
+
+/// Agent output which is forwarded to the peer in chunks while it is read, st. memory usage
+/// does not depend on the size of the output.
+struct AgentOutput {
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    max_output_bytes: usize,
+    chunk_size: usize,
+    counters: Option<Arc<metrics::PullCounters>>,
+    access: Option<Arc<access_log::Access>>,
+    throttle: Option<Throttle>,
+    timing: ServeTiming,
+}
+
+impl AgentOutput {
+    fn new(reader: Box<dyn AsyncRead + Unpin + Send>, max_output_bytes: usize) -> Self {
+        AgentOutput {
+            reader,
+            max_output_bytes,
+            chunk_size: constants::DEFAULT_IO_CHUNK_SIZE,
+            counters: None,
+            access: None,
+            throttle: None,
+            timing: ServeTiming::default(),
+        }
+    }
+
+    /// Limit the bytes per second sent to the peer
+    fn throttled(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.throttle = bytes_per_second.map(Throttle::new);
+        self
+    }
+
+    /// Read and forward the output in chunks of the given size
+    fn chunked(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Count the bytes sent to the peer
+    fn counted(mut self, counters: Arc<metrics::PullCounters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Count the bytes sent to the peer for the access log
+    fn logged(mut self, access: Arc<access_log::Access>) -> Self {
+        self.access = Some(access);
+        self
+    }
+
+    /// Reads whatever the agent sent so far, which may be less than fits. Only 0 bytes mean that
+    /// the agent is done, a slow agent just makes us wait for the next burst of output.
+    async fn read_chunk(&mut self, buffer: &mut [u8]) -> AnyhowResult<usize> {
+        let start = Instant::now();
+        loop {
+            match self.reader.read(buffer).await {
+                // Nothing was read, so we can simply try again
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                read => {
+                    self.timing.agent_read += start.elapsed();
+                    if let Ok(read_bytes) = read {
+                        self.timing.agent_bytes += read_bytes;
+                    }
+                    return read.context("Error collecting monitoring data.");
+                }
+            }
+        }
+    }
+
+    fn check_size(&self, total_bytes: usize) -> AnyhowResult<()> {
+        if total_bytes > self.max_output_bytes {
+            error!(
+                "Agent output exceeds the maximum size of {} bytes, aborting.",
+                self.max_output_bytes
+            );
+            bail!(
+                "Agent output exceeds the maximum size of {} bytes",
+                self.max_output_bytes
+            )
+        }
+        Ok(())
     }
 
-    async fn encoded_output(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<Vec<u8>> {
-        let mon_data = monitoring_data::async_collect(&self.agent_channel, remote_ip)
+    /// Reads the whole output into memory, which is only bounded by the maximum output size
+    async fn read_all(mut self) -> AnyhowResult<Vec<u8>> {
+        let mut data = vec![];
+        let mut buffer = vec![0; self.chunk_size];
+        loop {
+            let read_bytes = self.read_chunk(&mut buffer).await?;
+            if read_bytes == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buffer[..read_bytes]);
+            self.check_size(data.len())?;
+        }
+    }
+
+    async fn forward_plain(
+        self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        idle_timeout: u64,
+    ) -> AnyhowResult<()> {
+        self.forward(writer, None, idle_timeout).await
+    }
+
+    /// Output for TLS connections, which is always zlib-compressed. There is nothing to
+    /// negotiate: the fetcher does not advertise any capabilities, but reads the compression
+    /// from the header we send.
+    async fn forward_encoded(
+        mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        idle_timeout: u64,
+    ) -> AnyhowResult<()> {
+        let mut header = HEADER_VERSION.to_vec();
+        header.append(&mut monitoring_data::compression_header_info().pull);
+        self.write_counted(writer, &header, idle_timeout).await?;
+        self.forward(writer, Some(monitoring_data::compressor()), idle_timeout)
             .await
-            .context("Error collecting monitoring data.")?;
-        self.encode(&mon_data)
+    }
+
+    async fn forward(
+        mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        mut compressor: Option<flate2::write::ZlibEncoder<Vec<u8>>>,
+        idle_timeout: u64,
+    ) -> AnyhowResult<()> {
+        let mut buffer = vec![0; self.chunk_size];
+        let mut total_bytes: usize = 0;
+        loop {
+            let read_bytes = self.read_chunk(&mut buffer).await?;
+            if read_bytes == 0 {
+                break;
+            }
+            total_bytes += read_bytes;
+            self.check_size(total_bytes)?;
+            match compressor.as_mut() {
+                Some(compressor) => {
+                    compressor
+                        .write_all(&buffer[..read_bytes])
+                        .context("Error compressing monitoring data")?;
+                    let compressed = std::mem::take(compressor.get_mut());
+                    self.write_counted(writer, &compressed, idle_timeout)
+                        .await?;
+                }
+                None => {
+                    self.write_counted(writer, &buffer[..read_bytes], idle_timeout)
+                        .await?
+                }
+            }
+        }
+        if let Some(compressor) = compressor {
+            let compressed = compressor
+                .finish()
+                .context("Error compressing monitoring data")?;
+            self.write_counted(writer, &compressed, idle_timeout)
+                .await?;
+        }
+        let start = Instant::now();
+        let flushed = with_timeout(writer.flush(), idle_timeout).await;
+        self.timing.peer_write += start.elapsed();
+        flushed
+    }
+
+    async fn write_counted(
+        &mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        data: &[u8],
+        idle_timeout: u64,
+    ) -> AnyhowResult<()> {
+        // Waiting for the throttle does not count towards the timeout, only the peer being slow
+        // does
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.consume(data.len()).await;
+        }
+        self.timing.forwarding = true;
+        let start = Instant::now();
+        let written = write_with_timeout(writer, data, idle_timeout).await;
+        self.timing.peer_write += start.elapsed();
+        written?;
+        if let Some(counters) = &self.counters {
+            counters.count_bytes_served(data.len());
+        }
+        if let Some(access) = &self.access {
+            access.count_bytes_sent(data.len());
+        }
+        Ok(())
+    }
+}
+
+/// Also when forwarding is aborted, eg. by a timeout, which is when the breakdown matters most
+impl Drop for AgentOutput {
+    fn drop(&mut self) {
+        if !self.timing.forwarding {
+            return;
+        }
+        let (peer, uuid) = match &self.access {
+            Some(access) => (
+                access.peer().to_string(),
+                access.uuid().unwrap_or("-").to_owned(),
+            ),
+            None => (String::from("-"), String::from("-")),
+        };
+        debug!(
+            peer = peer, uuid = uuid, agent_bytes = self.timing.agent_bytes,
+            agent_read_ms = self.timing.agent_read.as_millis() as u64,
+            write_ms = self.timing.peer_write.as_millis() as u64;
+            "{}: Forwarded {} bytes of agent output for connection {}, reading from the agent took \
+             {}ms, writing to the peer {}ms.",
+            peer,
+            self.timing.agent_bytes,
+            uuid,
+            self.timing.agent_read.as_millis(),
+            self.timing.peer_write.as_millis()
+        );
+    }
+}
+
+/// The timeout limits how long the peer may take nothing, not writing all of the data. A slow
+/// peer which keeps taking some of it never times out.
+async fn write_with_timeout(
+    writer: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+    idle_timeout: u64,
+) -> AnyhowResult<()> {
+    let mut written = 0;
+    while written < data.len() {
+        match with_timeout(writer.write(&data[written..]), idle_timeout).await? {
+            0 => return Err(anyhow!(std::io::Error::from(std::io::ErrorKind::WriteZero))),
+            bytes => written += bytes,
+        }
+    }
+    Ok(())
+}
+
+/// Requests an immediate reload of the registry, the agent channel and the listener settings.
+/// On unix, this is SIGHUP.
+/// There is no equivalent on Windows yet, there we rely on the periodic refresh only.
+struct ReloadTrigger {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+    /// The agent channel after the last reload, for the listeners apart from _pull
+    agent_channel: tokio::sync::watch::Sender<types::AgentChannel>,
+}
+
+impl ReloadTrigger {
+    fn new(agent_channel: &types::AgentChannel) -> AnyhowResult<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .context("Failed to install SIGHUP handler.")?,
+            agent_channel: tokio::sync::watch::channel(agent_channel.clone()).0,
+        })
+    }
+
+    fn agent_channel_updates(&self) -> tokio::sync::watch::Receiver<types::AgentChannel> {
+        self.agent_channel.subscribe()
+    }
+
+    async fn triggered(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(windows)]
+        std::future::pending::<()>().await;
+    }
+}
+
+/// The limit a connection exceeded, see MaxConnectionsGuard
+#[derive(Debug, PartialEq, Eq)]
+enum ConnectionLimit {
+    PerIp,
+    Total,
+}
+
+impl std::fmt::Display for ConnectionLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::PerIp => write!(f, "Too many active connections from IP"),
+            Self::Total => write!(f, "Too many active connections in total"),
+        }
     }
 }
+
+/// Limits the concurrent connections per source IP and, optionally, in total. A connection
+/// holds a permit of both until it is done. Peers without active connections are forgotten, st.
+/// we only keep track of as many peers as there are active connections.
+///
+/// With a queue (on_overload = "queue"), connections beyond the limits wait for their permits
+/// instead, as long as there is room in the queue. A queued connection keeps the permit it
+/// already got, the per-IP one is always taken first.
 struct MaxConnectionsGuard {
     max_connections: usize,
+    max_connections_total: Option<usize>,
     active_connections: HashMap<IpAddr, Arc<Semaphore>>,
+    active_total: Option<Arc<Semaphore>>,
+    overload_queue: Option<usize>,
+    queue: Option<Arc<Semaphore>>,
 }
 
 impl MaxConnectionsGuard {
-    pub fn new(max_connections: usize) -> Self {
+    pub fn new(
+        max_connections: usize,
+        max_connections_total: Option<usize>,
+        overload_queue: Option<usize>,
+    ) -> Self {
         MaxConnectionsGuard {
             max_connections,
+            max_connections_total,
             active_connections: HashMap::new(),
+            active_total: max_connections_total.map(|max| Arc::new(Semaphore::new(max))),
+            overload_queue,
+            queue: overload_queue.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    fn from_config(pull_config: &config::PullConfig) -> Self {
+        Self::new(
+            pull_config.max_connections,
+            pull_config.max_connections_total,
+            match pull_config.on_overload {
+                config::OverloadPolicy::Reject => None,
+                config::OverloadPolicy::Queue => Some(pull_config.overload_queue),
+            },
+        )
+    }
+
+    /// How to count and explain a connection which exceeded the limit
+    fn rejection(&self, limit: &ConnectionLimit) -> (metrics::Rejection, String) {
+        let queue_full = match self.overload_queue {
+            Some(overload_queue) => {
+                format!(", {} more are queued (overload_queue)", overload_queue)
+            }
+            None => String::new(),
+        };
+        match limit {
+            ConnectionLimit::PerIp => (
+                metrics::Rejection::MaxConnections,
+                format!(
+                    "{} connections from IP are active (max_connections){}",
+                    self.max_connections, queue_full
+                ),
+            ),
+            ConnectionLimit::Total => (
+                metrics::Rejection::MaxConnectionsTotal,
+                format!(
+                    "{} connections are active in total (max_connections_total){}",
+                    self.max_connections_total.unwrap_or_default(),
+                    queue_full
+                ),
+            ),
         }
     }
 
+    fn forget_idle_peers(&mut self) {
+        let max_connections = self.max_connections;
+        self.active_connections
+            .retain(|_, sem| sem.available_permits() < max_connections);
+    }
+
     pub fn try_make_task_for_addr(
         &mut self,
         addr: SocketAddr,
         fut: impl Future<Output = AnyhowResult<()>>,
-    ) -> AnyhowResult<impl Future<Output = AnyhowResult<()>>> {
+    ) -> Result<impl Future<Output = AnyhowResult<()>>, ConnectionLimit> {
+        self.forget_idle_peers();
         let ip_addr = addr.ip();
         let sem = self
             .active_connections
             .entry(ip_addr)
             .or_insert_with(|| Arc::new(Semaphore::new(self.max_connections)));
-        if let Ok(permit) = sem.clone().try_acquire_owned() {
-            Ok(async move {
-                let res = fut.await;
-                drop(permit);
-                debug!("processed task!");
-                res
-            })
-        } else {
-            debug!("Too many active connections");
-            Err(anyhow!("Too many active connections"))
-        }
+        let per_ip = sem.clone();
+        let permit = per_ip.clone().try_acquire_owned().ok();
+        // None means that we have to wait for it, Some(None) that there is no limit in total.
+        // Waiting for the per-IP permit must not block the connections of other peers.
+        let total_permit = match (&permit, &self.active_total) {
+            (None, Some(_)) => None,
+            (Some(_), Some(active_total)) => {
+                active_total.clone().try_acquire_owned().ok().map(Some)
+            }
+            (_, None) => Some(None),
+        };
+        let queued = match (&permit, &total_permit) {
+            (Some(_), Some(_)) => None,
+            _ => {
+                let limit = match permit {
+                    Some(_) => ConnectionLimit::Total,
+                    None => ConnectionLimit::PerIp,
+                };
+                match self
+                    .queue
+                    .as_ref()
+                    .and_then(|queue| queue.clone().try_acquire_owned().ok())
+                {
+                    Some(place) => {
+                        debug!("{} ({}), queueing the connection", limit, ip_addr);
+                        Some(place)
+                    }
+                    None => {
+                        debug!("{} ({})", limit, ip_addr);
+                        return Err(limit);
+                    }
+                }
+            }
+        };
+        let active_total = self.active_total.clone();
+        Ok(async move {
+            let permit = match permit {
+                Some(permit) => permit,
+                None => per_ip.acquire_owned().await?,
+            };
+            let total_permit = match (total_permit, active_total) {
+                (Some(total_permit), _) => total_permit,
+                (None, Some(active_total)) => Some(active_total.acquire_owned().await?),
+                (None, None) => None,
+            };
+            drop(queued);
+            let res = fut.await;
+            drop(permit);
+            drop(total_permit);
+            debug!("processed task!");
+            res
+        })
     }
 }
 
-pub fn pull(pull_config: config::PullConfig) -> AnyhowResult<()> {
-    pull_runtime_wrapper(pull_config)
+/// Token bucket per peer address, against peers opening connections faster than any sane poller.
+/// The state is bounded: buckets which have refilled completely carry no information and are
+/// dropped, and if there are still too many peers, the one seen least recently is forgotten.
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, TokenBucket>,
 }
 
-pub async fn async_pull(pull_config: config::PullConfig) -> AnyhowResult<()> {
-    let guard = MaxConnectionsGuard::new(pull_config.max_connections);
-    let agent_output_collector = AgentOutputCollectorImpl::from(&pull_config.agent_channel);
-    let pull_state = PullStateImpl::try_from(pull_config)?;
-    _pull(pull_state, guard, agent_output_collector).await
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn pull_runtime_wrapper(pull_config: config::PullConfig) -> AnyhowResult<()> {
-    async_pull(pull_config).await
-}
+impl RateLimiter {
+    const MAX_TRACKED_PEERS: usize = 4096;
 
-async fn _pull(
-    mut pull_state: impl PullState,
-    mut guard: MaxConnectionsGuard,
-    agent_output_collector: impl AgentOutputCollector,
+    fn new(rate: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate: f64::from(rate),
+            burst: f64::from(burst.max(1)),
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn refilled(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last_update);
+        (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst)
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens
+                + now
+                    .saturating_duration_since(bucket.last_update)
+                    .as_secs_f64()
+                    * rate
+                < burst
+        });
+        if self.buckets.len() >= Self::MAX_TRACKED_PEERS {
+            if let Some(oldest) = self
+                .buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.last_update)
+                .map(|(ip_addr, _)| *ip_addr)
+            {
+                self.buckets.remove(&oldest);
+            }
+        }
+    }
+
+    fn allow(&mut self, ip_addr: IpAddr, now: Instant) -> bool {
+        if !self.buckets.contains_key(&ip_addr) && self.buckets.len() >= Self::MAX_TRACKED_PEERS {
+            self.make_room(now);
+        }
+        let tokens = match self.buckets.get(&ip_addr) {
+            Some(bucket) => self.refilled(bucket, now),
+            None => self.burst,
+        };
+        let allowed = tokens >= 1.0;
+        self.buckets.insert(
+            ip_addr,
+            TokenBucket {
+                tokens: if allowed { tokens - 1.0 } else { tokens },
+                last_update: now,
+            },
+        );
+        allowed
+    }
+}
+
+/// Keeps track of the requests currently being handled, st. we can wait for them on shutdown.
+/// The TCP connections among them are also listed for the admin socket. Whether we are listening
+/// for them at all is reported to health probes.
+#[derive(Clone, Default)]
+struct InFlight {
+    count: Arc<AtomicUsize>,
+    done: Arc<Notify>,
+    connections: Arc<pull_admin::ActiveConnections>,
+    health: Arc<health::Health>,
+}
+
+struct InFlightGuard(InFlight);
+
+impl InFlight {
+    fn track(&self) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    async fn drained(&self) {
+        loop {
+            // Register before checking, otherwise we might miss the last guard being dropped
+            let notified = self.done.notified();
+            if self.count() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+        self.0.done.notify_waiters();
+    }
+}
+
+/// Waits up to grace_period for the requests in flight to finish. Returns the number of requests
+/// that finished in time and the number of requests that are still running and will be closed.
+async fn drain(in_flight: &InFlight, grace_period: Duration) -> (usize, usize) {
+    let total = in_flight.count();
+    if total > 0 {
+        info!(
+            "Waiting up to {}s for {} pull request(s) in flight to finish.",
+            grace_period.as_secs(),
+            total
+        );
+    }
+    let _ = timeout(grace_period, in_flight.drained()).await;
+    let forced = in_flight.count();
+    (total.saturating_sub(forced), forced)
+}
+
+pub fn pull(pull_config: config::PullConfig) -> AnyhowResult<()> {
+    pull_runtime_wrapper(pull_config)
+}
+
+/// An empty registry on a pull host just means that nobody is trusted yet. A missing one may
+/// also mean that provisioning the host failed, which we want to notice.
+fn check_registry(pull_config: &mut config::PullConfig) -> AnyhowResult<()> {
+    // The daemon may just have created the registry from pre-configured connections
+    pull_config.refresh()?;
+    if pull_config.registry.file_present() {
+        return Ok(());
+    }
+    if pull_config.require_registry {
+        bail!(
+            "Connection registry {} does not exist, refusing to start since it is required",
+            pull_config.registry.path().display()
+        )
+    }
+    if !pull_config.listens() {
+        warn!(
+            "Connection registry {} does not exist, not listening for pull requests until connections are registered.",
+            pull_config.registry.path().display()
+        );
+    }
+    Ok(())
+}
+
+/// A site without a pull connection may just not be registered yet, but it may also be a typo
+fn check_site_agent_channels(pull_config: &config::PullConfig) {
+    for (site_id, agent_channel) in &pull_config.site_agent_channels {
+        match pull_config
+            .registry
+            .standard_pull_connections()
+            .any(|(registered, _)| registered == site_id)
+        {
+            true => info!(
+                "Serving pull requests of {} from agent channel {}.",
+                site_id, agent_channel
+            ),
+            false => warn!(
+                "Agent channel {} is configured for {}, which has no pull connection.",
+                agent_channel, site_id
+            ),
+        }
+    }
+}
+
+/// Otherwise, broken or expired trust material only shows once a site connects. Connections
+/// which can't be decoded are skipped when building the TLS config, expired ones are still
+/// served, the site will refuse them anyway.
+fn check_trust_material<'a>(
+    connections: impl Iterator<Item = &'a config::TrustedConnection>,
+    strict: bool,
+) -> AnyhowResult<()> {
+    let mut problems = vec![];
+    for connection in connections {
+        // Reported once the connection is skipped
+        if let Err(err) = tls_server::check_trust_material(connection) {
+            problems.push(format!("{}: {:#}", connection.uuid, err));
+            continue;
+        }
+        if let Some(issuer) = connection.chain_gap() {
+            warn!(
+                uuid = connection.uuid.to_string();
+                "Certificate chain of connection {} is incomplete, the certificate of its issuer {} is missing. Sites can only verify it if they have that certificate themselves.",
+                connection.uuid,
+                issuer
+            );
+        }
+        match certs::seconds_until_expiry(&connection.certificate) {
+            Ok(seconds) if seconds < 0 => {
+                warn!(
+                    uuid = connection.uuid.to_string();
+                    "Certificate of connection {} has expired", connection.uuid
+                );
+                problems.push(format!("{}: Certificate has expired", connection.uuid));
+            }
+            Ok(seconds)
+                if seconds < i64::from(constants::CERT_EXPIRY_WARNING_DAYS) * 24 * 60 * 60 =>
+            {
+                warn!(
+                    uuid = connection.uuid.to_string();
+                    "Certificate of connection {} expires in {} day(s), register the connection again to renew it",
+                    connection.uuid,
+                    seconds / (24 * 60 * 60)
+                );
+            }
+            Ok(_) => {}
+            Err(err) => problems.push(format!("{}: {:#}", connection.uuid, err)),
+        }
+    }
+    if strict && !problems.is_empty() {
+        bail!(
+            "Refusing to start with unusable connections since strict startup is enabled: {}",
+            problems.join(", ")
+        )
+    }
+    Ok(())
+}
+
+/// Without client certificates, the IP allowlist is all that stands between anybody and the
+/// agent output
+fn check_client_auth(
+    no_client_auth: bool,
+    ip_allowlist: Option<&[config::IpEntry]>,
+) -> AnyhowResult<()> {
+    if no_client_auth && ip_allowlist.is_none() {
+        bail!("Refusing to start without client authentication since no IP allowlist is in effect, configure allowed_ip and unset allow_any")
+    }
+    Ok(())
+}
+
+pub async fn async_pull(mut pull_config: config::PullConfig) -> AnyhowResult<()> {
+    check_client_auth(
+        pull_config.tls_policy.no_client_auth,
+        pull_config.ip_allowlist(),
+    )?;
+    check_registry(&mut pull_config)?;
+    check_trust_material(pull_config.connections(), pull_config.strict_startup)?;
+    check_site_agent_channels(&pull_config);
+    // Unlike a configured empty allowlist, no allowlist at all keeps accepting everybody as it
+    // always did, so setting allow_any is not the only way to get an unrestricted listener
+    match (pull_config.ip_allowlist(), pull_config.allow_any) {
+        (Some(_), _) => {}
+        (None, true) => warn!("Accepting pull connections from any address, the IP allowlist is ignored since allow_any is set."),
+        (None, false) => warn!("Accepting pull connections from any address since no IP allowlist is configured, set allowed_ip or allowed_ip_file to restrict them."),
+    }
+    if pull_config.tls_policy.no_client_auth {
+        warn!("Client authentication is DISABLED since --no-client-auth is set: Anybody passing the IP allowlist gets the agent output. Never use this outside of a lab.");
+    }
+    let guard = MaxConnectionsGuard::from_config(&pull_config);
+    let agent_output_collector = AgentOutputCollectorImpl::new(
+        &pull_config.agent_channel,
+        pull_config.max_output_bytes,
+        pull_config.timeouts.connect,
+        pull_config.pull_bandwidth_limit,
+        pull_config.io_chunk_size,
+        pull_config.cache_ttl,
+    )
+    .on_agent_unavailable(pull_config.on_agent_unavailable)
+    .output_hooks(&pull_config.output_hooks, pull_config.output_hook_timeout)
+    .agent_output_marker(pull_config.agent_output_marker.as_deref());
+    // The counters live next to the registry, which may be on a read-only file system
+    let (counters_path, connection_totals_path) = match pull_config.registry.is_read_only() {
+        true => (None, None),
+        false => (
+            Some(pull_config.counters_path.clone()),
+            Some(pull_config.connection_totals_path.clone()),
+        ),
+    };
+    let connection_totals_interval = Duration::from_secs(pull_config.connection_totals_interval);
+    let shutdown_grace_period = Duration::from_secs(pull_config.shutdown_grace_period);
+    let rate_limiter = pull_config
+        .pull_rate_limit
+        .map(|rate| RateLimiter::new(rate, pull_config.pull_rate_limit_burst));
+    let metrics_listener = match pull_config.metrics_listen {
+        Some(address) => Some(metrics::bind(address).await?),
+        None => None,
+    };
+    let health_listener = match pull_config.health_listen {
+        Some(address) => Some(health::bind(address).await?),
+        None => None,
+    };
+    #[cfg(unix)]
+    let unix_listener = pull_config
+        .pull_unix_socket
+        .as_deref()
+        .map(|path| unix_pull_listener(path, 0o660))
+        .transpose()?;
+    #[cfg(windows)]
+    let unix_listener: Option<UnixPullListener> = None;
+    #[cfg(unix)]
+    let admin_listener = pull_config
+        .pull_admin_socket
+        .as_deref()
+        .map(|path| unix_pull_listener(path, 0o600))
+        .transpose()?;
+    #[cfg(windows)]
+    let admin_listener: Option<UnixPullListener> = None;
+    let unix_timeouts = pull_config.timeouts;
+    let registry_path = pull_config.registry.path().to_path_buf();
+    let counters = Arc::new(metrics::PullCounters::new(pull_config.explain_rejections));
+    counters.resume_connection_totals(load_connection_totals(&pull_config.connection_totals_path));
+    // Local peers count against the limits as one more peer of their own
+    let unix_guard = MaxConnectionsGuard::from_config(&pull_config);
+    let reload_trigger = ReloadTrigger::new(&pull_config.agent_channel)?;
+    let agent_channel_updates = reload_trigger.agent_channel_updates();
+    let pull_state = PullStateImpl::try_from(pull_config)?;
+    let in_flight = InFlight::default();
+    tokio::select! {
+        res = _pull(
+            pull_state,
+            guard,
+            rate_limiter,
+            agent_output_collector.clone(),
+            counters.clone(),
+            reload_trigger,
+            in_flight.clone(),
+        ) => res,
+        _ = wait_for_shutdown_signal() => {
+            // Dropping _pull above already closed the listener, so no new requests come in.
+            info!("Received shutdown signal, stop listening for pull requests.");
+            let (drained, forced) = drain(&in_flight, shutdown_grace_period).await;
+            info!(
+                "Pull shutdown complete: {} request(s) drained, {} request(s) force-closed.",
+                drained, forced
+            );
+            if let Some(path) = &connection_totals_path {
+                save_connection_totals(&counters, path);
+            }
+            Ok(())
+        }
+        _ = persist_counters(counters.clone(), counters_path) => unreachable!(),
+        _ = persist_connection_totals(
+            counters.clone(),
+            connection_totals_path.clone(),
+            connection_totals_interval,
+        ) => unreachable!(),
+        _ = serve_unix_socket(
+            unix_listener,
+            agent_output_collector,
+            unix_timeouts,
+            counters.clone(),
+            in_flight.clone(),
+            unix_guard,
+            agent_channel_updates,
+        ) => unreachable!(),
+        _ = serve_admin_socket(admin_listener, in_flight.connections.clone()) => unreachable!(),
+        _ = serve_metrics(metrics_listener, counters.clone(), registry_path) => unreachable!(),
+        _ = serve_health(health_listener, in_flight.health.clone()) => unreachable!(),
+        _ = watchdog() => unreachable!(),
+    }
+}
+
+/// Handles a single pull request on the given listener like the pull daemon would, except that
+/// the client certificate has to be issued by client_root_cert instead of the root of the
+/// connection. This allows fetching the agent output from ourselves for testing, without the
+/// key of the site.
+pub async fn serve_single_request(
+    pull_config: &config::PullConfig,
+    connection: &config::TrustedConnection,
+    client_root_cert: &str,
+    listener: TcpListener,
+) -> AnyhowResult<()> {
+    let mut connection = connection.clone();
+    connection.root_cert = String::from(client_root_cert);
+    let tls_acceptor = tls_server::tls_acceptor([&connection].into_iter(), &pull_config.tls_policy)
+        .context("Could not initialize TLS.")?;
+    let agent_output_collector = AgentOutputCollectorImpl::new(
+        &pull_config.agent_channel,
+        pull_config.max_output_bytes,
+        pull_config.timeouts.connect,
+        None,
+        pull_config.io_chunk_size,
+        None,
+    )
+    .on_agent_unavailable(pull_config.on_agent_unavailable)
+    .output_hooks(&pull_config.output_hooks, pull_config.output_hook_timeout)
+    .agent_output_marker(pull_config.agent_output_marker.as_deref());
+    let (stream, remote) = listener.accept().await?;
+    handle_request(
+        request_limit::Limited::new(stream, pull_config.max_request_bytes),
+        agent_output_collector,
+        Arc::new(access_log::Access::new(None, remote)),
+        false,
+        tls_acceptor,
+        ConnectionTimeouts {
+            default: pull_config.timeouts,
+            overrides: HashMap::new(),
+        },
+        Arc::new(metrics::PullCounters::default()),
+    )
+    .await
+}
+
+/// Tells the service manager we are ready, st. dependent units don't start too early. On
+/// Windows, there is nothing to do.
+fn notify_ready() {
+    #[cfg(unix)]
+    sd_notify::ready();
+}
+
+async fn watchdog() {
+    #[cfg(unix)]
+    sd_notify::watchdog().await;
+    #[cfg(windows)]
+    std::future::pending::<()>().await;
+}
+
+async fn serve_metrics(
+    listener: Option<TcpListener>,
+    counters: Arc<metrics::PullCounters>,
+    registry_path: PathBuf,
+) {
+    match listener {
+        Some(listener) => {
+            info!(
+                "Serving metrics on {}.",
+                listener
+                    .local_addr()
+                    .map(|address| address.to_string())
+                    .unwrap_or_default()
+            );
+            metrics::serve(listener, counters, registry_path).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn serve_health(listener: Option<TcpListener>, health: Arc<health::Health>) {
+    match listener {
+        Some(listener) => {
+            info!(
+                "Answering health probes on {}.",
+                listener
+                    .local_addr()
+                    .map(|address| address.to_string())
+                    .unwrap_or_default()
+            );
+            health::serve(listener, health).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The socket file is removed again once we stop serving, st. the next start doesn't find a
+/// stale one. Unix sockets do not exist on Windows, there we never have a listener.
+#[cfg(unix)]
+struct UnixPullListener {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(windows)]
+enum UnixPullListener {}
+
+#[cfg(unix)]
+impl Drop for UnixPullListener {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            debug!(
+                "Failed to remove pull socket {}. ({})",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Socket permissions are the access control of this transport, so it's only accessible to
+/// our own user and, depending on mode, our group.
+#[cfg(unix)]
+fn unix_pull_listener(path: &std::path::Path, mode: u32) -> AnyhowResult<UnixPullListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!(
+                "Refusing to replace {} with the pull socket, it is not a socket",
+                path.display()
+            )
+        }
+        // Only a socket nobody accepts on anymore was left over by an instance which did not
+        // shut down cleanly
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => bail!(
+                "Refusing to replace {}, another process is serving on it",
+                path.display()
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {}
+            Err(err) => {
+                return Err(err).context(format!(
+                    "Failed to check whether {} is still in use",
+                    path.display()
+                ))
+            }
+        }
+    }
+    // A socket is created with the permissions of the umask, so we bind in a directory nobody
+    // else can enter and restrict the permissions before moving the socket into place. Moving
+    // also replaces a stale socket.
+    let mut private_dir_name = std::ffi::OsString::from(".");
+    private_dir_name.push(path.file_name().unwrap_or_default());
+    private_dir_name.push(format!(".{}", std::process::id()));
+    let private_dir = path.with_file_name(private_dir_name);
+    // Left over by an earlier process with our PID
+    let _ = std::fs::remove_dir_all(&private_dir);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .context(format!("Failed to create {}", private_dir.display()))?;
+    let private_path = private_dir.join("socket");
+    let listener = tokio::net::UnixListener::bind(&private_path)
+        .context(format!("Failed to listen on {}", path.display()))
+        .and_then(|listener| {
+            std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))
+                .context(format!("Failed to set permissions of {}", path.display()))?;
+            std::fs::rename(&private_path, path).context(format!(
+                "Failed to move socket into place at {}",
+                path.display()
+            ))?;
+            Ok(listener)
+        });
+    if let Err(err) = std::fs::remove_dir_all(&private_dir) {
+        debug!("Failed to remove {}. ({})", private_dir.display(), err);
+    }
+    Ok(UnixPullListener {
+        listener: listener?,
+        path: PathBuf::from(path),
+    })
+}
+
+/// Local peers get the plain agent output from the default agent channel, like legacy pull.
+/// There is no TLS handshake, hence no connection to select and nothing to encrypt. The agent
+/// channel follows reloads, and the connection limits apply as for any other peer.
+#[cfg(unix)]
+async fn serve_unix_socket(
+    listener: Option<UnixPullListener>,
+    mut agent_output_collector: impl AgentOutputCollector,
+    timeouts: config::Timeouts,
+    counters: Arc<metrics::PullCounters>,
+    in_flight: InFlight,
+    mut guard: MaxConnectionsGuard,
+    mut agent_channel_updates: tokio::sync::watch::Receiver<types::AgentChannel>,
+) {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+    info!("Serving pull requests on {}.", listener.path.display());
+    let peer = listener.path.display().to_string();
+    loop {
+        let mut stream = tokio::select! {
+            // A connection accepted after a reload must be served from the new agent channel
+            biased;
+            Ok(()) = agent_channel_updates.changed() => {
+                let agent_channel = agent_channel_updates.borrow().clone();
+                agent_output_collector.use_agent_channel(&agent_channel);
+                continue;
+            }
+            accepted = listener.listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    warn!("Failed accepting pull connection on {}. ({})", peer, error);
+                    continue;
+                }
+            },
+        };
+        counters.count_accepted();
+        debug!("{}: Handling pull request.", peer);
+        let agent_output_collector = agent_output_collector.clone();
+        let request_counters = counters.clone();
+        let request = async move {
+            until_deadline(
+                async {
+                    agent_output_collector
+                        .connect(IpAddr::V4(Ipv4Addr::LOCALHOST))
+                        .await?
+                        .counted(request_counters)
+                        .forward_plain(&mut stream, timeouts.idle)
+                        .await
+                },
+                deadline(timeouts.total),
+            )
+            .await
+        };
+        let request = match guard
+            .try_make_task_for_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), request)
+        {
+            Ok(request) => request,
+            Err(limit) => {
+                warn!("{}: Request failed. ({})", peer, limit);
+                let (rejection, reason) = guard.rejection(&limit);
+                counters.count_rejection(rejection, &peer, reason);
+                continue;
+            }
+        };
+        let counters = counters.clone();
+        let in_flight_guard = in_flight.track();
+        let peer = peer.clone();
+        tokio::spawn(async move {
+            let _in_flight_guard = in_flight_guard;
+            counters.count_active_started();
+            match request.await {
+                Ok(()) => counters.count_completed(),
+                Err(err) => {
+                    warn!("{}: Request failed. ({})", peer, err);
+                    counters.count_rejection(
+                        if is_timeout(&err) {
+                            metrics::Rejection::TimedOut
+                        } else {
+                            metrics::Rejection::Failed
+                        },
+                        &peer,
+                        format!("{:#}", err),
+                    );
+                }
+            }
+            counters.count_active_finished();
+        });
+    }
+}
+
+#[cfg(windows)]
+async fn serve_unix_socket(
+    _listener: Option<UnixPullListener>,
+    _agent_output_collector: impl AgentOutputCollector,
+    _timeouts: config::Timeouts,
+    _counters: Arc<metrics::PullCounters>,
+    _in_flight: InFlight,
+    _guard: MaxConnectionsGuard,
+    _agent_channel_updates: tokio::sync::watch::Receiver<types::AgentChannel>,
+) {
+    std::future::pending().await
+}
+
+/// Every admin session is handled by a task of its own, st. an idle session doesn't lock out
+/// the others. Sessions which stay idle are closed, see pull_admin::handle.
+#[cfg(unix)]
+async fn serve_admin_socket(
+    listener: Option<UnixPullListener>,
+    connections: Arc<pull_admin::ActiveConnections>,
+) {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+    info!(
+        "Serving the admin interface on {}.",
+        listener.path.display()
+    );
+    loop {
+        let stream = match listener.listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                warn!(
+                    "Failed accepting admin connection on {}. ({})",
+                    listener.path.display(),
+                    error
+                );
+                continue;
+            }
+        };
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            if let Err(error) =
+                pull_admin::handle(stream, &connections, pull_admin::IDLE_TIMEOUT).await
+            {
+                debug!("Admin connection failed. ({})", error);
+            }
+        });
+    }
+}
+
+#[cfg(windows)]
+async fn serve_admin_socket(
+    _listener: Option<UnixPullListener>,
+    _connections: Arc<pull_admin::ActiveConnections>,
+) {
+    std::future::pending().await
+}
+
+async fn persist_counters(counters: Arc<metrics::PullCounters>, path: Option<PathBuf>) {
+    let Some(path) = path else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(ONE_MINUTE));
+    loop {
+        interval.tick().await;
+        if let Err(error) = counters.snapshot().save(&path) {
+            warn!(
+                "Failed to write pull counters to {}. ({})",
+                path.display(),
+                error
+            );
+        }
+    }
+}
+
+/// Starting from zero is better than not starting at all
+fn load_connection_totals(path: &std::path::Path) -> metrics::ConnectionTotals {
+    <metrics::ConnectionTotals as config::JSONLoaderMissingSafe>::load_missing_safe(path)
+        .unwrap_or_else(|error| {
+            warn!(
+                "Failed to load connection totals from {}, starting from zero. ({})",
+                path.display(),
+                error
+            );
+            metrics::ConnectionTotals::default()
+        })
+}
+
+fn save_connection_totals(counters: &metrics::PullCounters, path: &std::path::Path) {
+    if let Err(error) = counters.connection_totals().save(path) {
+        warn!(
+            "Failed to write connection totals to {}. ({})",
+            path.display(),
+            error
+        );
+    }
+}
+
+async fn persist_connection_totals(
+    counters: Arc<metrics::PullCounters>,
+    path: Option<PathBuf>,
+    interval: Duration,
+) {
+    let Some(path) = path else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(interval);
+    // The first tick is immediate, there is nothing new to write yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        save_connection_totals(&counters, &path);
+    }
+}
+
+fn pull_runtime_wrapper(pull_config: config::PullConfig) -> AnyhowResult<()> {
+    runtime(pull_config.worker_threads)
+        .context("Failed to start the async runtime for pull.")?
+        .block_on(async_pull(pull_config))
+}
+
+/// The accept loop always runs on the calling thread. With more than one thread, the pull
+/// requests themselves are handled by a pool of workers.
+fn runtime(worker_threads: usize) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = match worker_threads {
+        1 => tokio::runtime::Builder::new_current_thread(),
+        _ => tokio::runtime::Builder::new_multi_thread(),
+    };
+    // Otherwise, tokio defaults to one thread per CPU core
+    if worker_threads > 1 {
+        builder.worker_threads(worker_threads);
+    }
+    builder.enable_all().build()
+}
+
+async fn _pull(
+    mut pull_state: impl PullState,
+    mut guard: MaxConnectionsGuard,
+    mut rate_limiter: Option<RateLimiter>,
+    mut agent_output_collector: impl AgentOutputCollector,
+    counters: Arc<metrics::PullCounters>,
+    mut reload_trigger: ReloadTrigger,
+    in_flight: InFlight,
 ) -> AnyhowResult<()> {
     loop {
         if !pull_state.is_active() {
-            tokio::time::sleep(Duration::from_secs(ONE_MINUTE)).await;
-            // Allow a crash due to a failing registry reload. It's not likely to recover
-            // here without action taken, and it's vital for all connections.
-            pull_state.refresh()?;
+            // Without a registry, there is nothing to listen on. Still, we are up and running.
+            notify_ready();
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(ONE_MINUTE)) => {
+                    // Allow a crash due to a failing registry reload. It's not likely to recover
+                    // here without action taken, and it's vital for all connections.
+                    pull_state.refresh()?;
+                }
+                _ = reload_trigger.triggered() => reload(&mut pull_state, &mut agent_output_collector, &reload_trigger),
+            }
             continue;
         }
         info!("Start listening for incoming pull requests");
-        _pull_cycle(&mut pull_state, &mut guard, agent_output_collector.clone()).await?;
+        _pull_cycle(
+            &mut pull_state,
+            &mut guard,
+            &mut rate_limiter,
+            &mut agent_output_collector,
+            &counters,
+            &mut reload_trigger,
+            &in_flight,
+        )
+        .await?;
     }
 }
 
@@ -229,23 +1974,214 @@ fn configure_socket(socket: Socket) -> AnyhowResult<Socket> {
     Ok(socket)
 }
 
-fn tcp_listener_v4(address: Ipv4Addr, port: u16) -> AnyhowResult<TcpListenerStd> {
+fn bind(socket: &Socket, address: SocketAddr) -> AnyhowResult<()> {
+    socket
+        .bind(&SockAddr::from(address))
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::AddrInUse => anyhow!(PortInUse(address.port())),
+            _ => anyhow!(err),
+        })
+}
+
+fn tcp_listener_v4(address: Ipv4Addr, port: u16, backlog: u32) -> AnyhowResult<TcpListenerStd> {
     let socket = configure_socket(Socket::new(Domain::IPV4, Type::STREAM, None)?)?;
-    socket.bind(&SockAddr::from(SocketAddr::new(IpAddr::V4(address), port)))?;
-    socket.listen(4096)?;
+    bind(&socket, SocketAddr::new(IpAddr::V4(address), port))?;
+    socket.listen(i32::try_from(backlog)?)?;
     Ok(socket.into())
 }
 
-fn tcp_listener_v6(address: Ipv6Addr, port: u16) -> AnyhowResult<TcpListenerStd> {
+fn tcp_listener_v6(address: Ipv6Addr, port: u16, backlog: u32) -> AnyhowResult<TcpListenerStd> {
     let socket = configure_socket(Socket::new(Domain::IPV6, Type::STREAM, None)?)?;
     socket.set_only_v6(false)?;
-    socket.bind(&SockAddr::from(SocketAddr::new(IpAddr::V6(address), port)))?;
-    socket.listen(4096)?;
+    bind(&socket, SocketAddr::new(IpAddr::V6(address), port))?;
+    socket.listen(i32::try_from(backlog)?)?;
     Ok(socket.into())
 }
 
-fn tcp_listener(listening_config: ListeningConfig) -> AnyhowResult<TcpListenerStd> {
-    let err_v6 = match tcp_listener_v6(listening_config.addr_v6, listening_config.port) {
+/// First file descriptor passed by systemd, see sd_listen_fds(3)
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+/// Returns the listening socket passed in by systemd, if we were socket-activated.
+#[cfg(unix)]
+fn socket_activation_fd(
+    listen_pid: Option<String>,
+    listen_fds: Option<String>,
+) -> AnyhowResult<Option<std::os::unix::io::RawFd>> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(None),
+    };
+    // The variables are inherited by child processes, they are only meant for us if the PID
+    // matches.
+    if listen_pid
+        .parse::<u32>()
+        .context(format!("Invalid LISTEN_PID '{}'", listen_pid))?
+        != std::process::id()
+    {
+        return Ok(None);
+    }
+    let listen_fds = listen_fds
+        .parse::<u32>()
+        .context(format!("Invalid LISTEN_FDS '{}'", listen_fds))?;
+    if listen_fds == 0 {
+        return Ok(None);
+    }
+    if listen_fds > 1 {
+        warn!(
+            "Received {} sockets from systemd, using the first one for pull connections",
+            listen_fds
+        );
+    }
+    Ok(Some(SD_LISTEN_FDS_START))
+}
+
+#[cfg(unix)]
+fn activated_tcp_listener(fd: std::os::unix::io::RawFd) -> AnyhowResult<TcpListenerStd> {
+    use std::os::unix::io::FromRawFd;
+    // The listener is dropped whenever the pull cycle restarts. Work on a duplicate, st. the
+    // socket owned by systemd stays open.
+    let fd = nix::fcntl::fcntl(
+        fd,
+        nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(SD_LISTEN_FDS_START),
+    )
+    .context("Failed to take over socket passed by systemd")?;
+    let listener = unsafe { TcpListenerStd::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Opens one listener per configured port. With socket activation, systemd decides where we
+/// listen and the configured ports don't matter.
+fn tcp_listeners(listening_config: ListeningConfig) -> AnyhowResult<Vec<TcpListenerStd>> {
+    #[cfg(unix)]
+    if let Some(fd) = socket_activation_fd(
+        std::env::var("LISTEN_PID").ok(),
+        std::env::var("LISTEN_FDS").ok(),
+    )? {
+        let listener = activated_tcp_listener(fd)?;
+        info!(
+            "Listening on {} for incoming pull connections (socket passed by systemd)",
+            listener.local_addr()?
+        );
+        return Ok(vec![listener]);
+    }
+    listening_config
+        .ports
+        .iter()
+        .map(|port| tcp_listener(listening_config.address, *port, listening_config.backlog))
+        .collect()
+}
+
+#[cfg(unix)]
+fn socket_activated() -> bool {
+    matches!(
+        socket_activation_fd(
+            std::env::var("LISTEN_PID").ok(),
+            std::env::var("LISTEN_FDS").ok(),
+        ),
+        Ok(Some(_))
+    )
+}
+
+#[cfg(windows)]
+fn socket_activated() -> bool {
+    false
+}
+
+fn tokio_listeners(listeners: Vec<TcpListenerStd>) -> AnyhowResult<Vec<TcpListener>> {
+    Ok(listeners
+        .into_iter()
+        .map(TcpListener::from_std)
+        .collect::<std::io::Result<Vec<TcpListener>>>()?)
+}
+
+/// Listens according to the changed settings after a reload. If only the ports changed, the
+/// listeners of the remaining ports are kept, st. these ports are never unbound, and the new
+/// ports are opened before anything is closed. A changed address may overlap with the current
+/// one, eg. all interfaces vs. a single one, so then all listeners are closed and reopened. If
+/// the changed settings can't be bound, we go on listening as before.
+fn rebind_listeners(
+    listeners: &mut Vec<TcpListener>,
+    current: &ListeningConfig,
+    changed: &ListeningConfig,
+) -> AnyhowResult<()> {
+    if changed.address == current.address {
+        let mut opened = changed
+            .ports
+            .iter()
+            .filter(|port| !current.ports.contains(port))
+            .map(|port| {
+                Ok((
+                    *port,
+                    TcpListener::from_std(tcp_listener(changed.address, *port, changed.backlog)?)?,
+                ))
+            })
+            .collect::<AnyhowResult<HashMap<u16, TcpListener>>>()?;
+        let mut kept = std::mem::take(listeners)
+            .into_iter()
+            .zip(current.ports.iter().copied())
+            .map(|(listener, port)| (port, listener))
+            .collect::<HashMap<u16, TcpListener>>();
+        // The listeners of ports which are gone are closed along with what is left of kept
+        *listeners = changed
+            .ports
+            .iter()
+            .filter_map(|port| kept.remove(port).or_else(|| opened.remove(port)))
+            .collect();
+        return Ok(());
+    }
+    listeners.clear();
+    match tcp_listeners(changed.clone()).and_then(tokio_listeners) {
+        Ok(rebound) => {
+            *listeners = rebound;
+            Ok(())
+        }
+        Err(err) => {
+            *listeners = tokio_listeners(tcp_listeners(current.clone())?)?;
+            Err(err)
+        }
+    }
+}
+
+/// Ports in use may just not have been released by a previous instance yet, eg. on restart
+async fn tcp_listeners_retrying(
+    listening_config: ListeningConfig,
+) -> AnyhowResult<Vec<TcpListenerStd>> {
+    let deadline = Instant::now() + Duration::from_secs(listening_config.retry_bind);
+    loop {
+        match tcp_listeners(listening_config.clone()) {
+            Err(err) if err.is::<PortInUse>() && Instant::now() < deadline => {
+                info!(
+                    "{:#} Retrying for another {}s.",
+                    err,
+                    deadline.saturating_duration_since(Instant::now()).as_secs()
+                );
+                tokio::time::sleep(Duration::from_secs(RETRY_BIND_INTERVAL)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn tcp_listener(address: Option<IpAddr>, port: u16, backlog: u32) -> AnyhowResult<TcpListenerStd> {
+    if let Some(address) = address {
+        let socket_address = SocketAddr::new(address, port);
+        let listener = match address {
+            IpAddr::V4(address) => tcp_listener_v4(address, port, backlog),
+            IpAddr::V6(address) => tcp_listener_v6(address, port, backlog),
+        }
+        .context(format!(
+            "Failed to listen on {} for incoming pull connections",
+            socket_address
+        ))?;
+        info!(
+            "Listening on {} for incoming pull connections",
+            listener.local_addr()?
+        );
+        return Ok(listener);
+    }
+    let err_v6 = match tcp_listener_v6(Ipv6Addr::UNSPECIFIED, port, backlog) {
         Ok(listener) => {
             info!(
                 "Listening on {} for incoming pull connections (IPv6 & IPv4 if activated)",
@@ -256,7 +2192,7 @@ fn tcp_listener(listening_config: ListeningConfig) -> AnyhowResult<TcpListenerSt
         Err(err_v6) => err_v6,
     };
     info!("Failed to open IPv6 socket for pull connections, attempting with IPv4");
-    let err_v4 = match tcp_listener_v4(listening_config.addr_v4, listening_config.port) {
+    let err_v4 = match tcp_listener_v4(Ipv4Addr::UNSPECIFIED, port, backlog) {
         Ok(listener) => {
             info!(
                 "Listening on {} for incoming pull connections (IPv4)",
@@ -264,281 +2200,1995 @@ fn tcp_listener(listening_config: ListeningConfig) -> AnyhowResult<TcpListenerSt
             );
             return Ok(listener);
         }
-        Err(err_v4) => err_v4,
-    };
-    bail!(
-        "Failed to listen on TCP socket for incoming pull connections.\n\nError with IPV6:\n{}\n\nError with IPV4:\n{}",
-        anyhow_error_to_human_readable(&err_v6),
-        anyhow_error_to_human_readable(&err_v4),
-    );
-}
+        Err(err_v4) => err_v4,
+    };
+    // Listening on IPv6 also covers IPv4, so both fail if the port is in use
+    if err_v6.is::<PortInUse>() || err_v4.is::<PortInUse>() {
+        return Err(anyhow!(PortInUse(port)).context(format!(
+            "Failed to listen on TCP port {} for incoming pull connections",
+            port
+        )));
+    }
+    bail!(
+        "Failed to listen on TCP port {} for incoming pull connections.\n\nError with IPV6:\n{}\n\nError with IPV4:\n{}",
+        port,
+        anyhow_error_to_human_readable(&err_v6),
+        anyhow_error_to_human_readable(&err_v4),
+    );
+}
+
+/// Accepts the next connection on any of the listeners. We start looking at the listener after
+/// the one which accepted last, st. a busy port can't starve the others.
+async fn accept_any(
+    listeners: &[TcpListener],
+    next_listener: &mut usize,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    std::future::poll_fn(|cx| {
+        for offset in 0..listeners.len() {
+            let index = (*next_listener + offset) % listeners.len();
+            if let std::task::Poll::Ready(accepted) = listeners[index].poll_accept(cx) {
+                *next_listener = index + 1;
+                return std::task::Poll::Ready(accepted);
+            }
+        }
+        std::task::Poll::Pending
+    })
+    .await
+}
+
+async fn _pull_cycle(
+    pull_state: &mut impl PullState,
+    guard: &mut MaxConnectionsGuard,
+    rate_limiter: &mut Option<RateLimiter>,
+    agent_output_collector: &mut impl AgentOutputCollector,
+    counters: &Arc<metrics::PullCounters>,
+    reload_trigger: &mut ReloadTrigger,
+    in_flight: &InFlight,
+) -> AnyhowResult<()> {
+    let mut listening_config = pull_state.listening_config();
+    let mut listeners = tokio_listeners(tcp_listeners_retrying(listening_config.clone()).await?)?;
+    let mut next_listener = 0;
+    // Connections from trusted proxies come back here once their PROXY header is read, st. a
+    // slow proxy doesn't hold up accepting other connections
+    let (proxied_tx, mut proxied_rx) = mpsc::channel(PROXIED_QUEUE_SIZE);
+    notify_ready();
+    let _listening = in_flight.health.listening();
+
+    loop {
+        let incoming = tokio::select! {
+            accepted = timeout(
+                Duration::from_secs(FIVE_MINUTES),
+                accept_any(&listeners, &mut next_listener),
+            ) => match accepted {
+                Ok(Ok((stream, remote))) => Incoming::Accepted(stream, remote),
+                Ok(Err(error)) => {
+                    warn!("Failed accepting pull connection. ({})", error);
+                    continue;
+                }
+                Err(_) => {
+                    debug!(
+                        "Got no pull request within five minutes. Registration may have changed, thus restarting pull handling."
+                    );
+                    return Ok(());
+                }
+            },
+            Some((stream, source)) = proxied_rx.recv() => Incoming::Proxied(stream, source),
+            _ = reload_trigger.triggered() => {
+                reload(pull_state, agent_output_collector, reload_trigger);
+                if !pull_state.is_active() {
+                    info!("Detected missing registry after reload, stop listening.");
+                    return Ok(());
+                }
+                apply_listening_config(pull_state, &mut listeners, &mut listening_config)?;
+                continue;
+            }
+        };
+        let (stream, remote) = match incoming {
+            Incoming::Proxied(stream, source) => (stream, source),
+            Incoming::Accepted(stream, remote) => {
+                // IPv4 peers connecting to our dual-stack socket show up with v4-mapped IPv6
+                // addresses. Normalize them st. the allowlist, the connection guard and the agent
+                // all see the same address, no matter which socket we ended up listening on.
+                let remote = SocketAddr::new(to_canonical(remote.ip()), remote.port());
+                if is_addr_in(&remote, pull_state.trusted_proxies()) {
+                    tokio::spawn(accept_proxied(
+                        stream,
+                        remote,
+                        pull_state.timeouts().handshake,
+                        pull_state.max_request_bytes(),
+                        proxied_tx.clone(),
+                        counters.clone(),
+                        pull_state.access_log(),
+                    ));
+                    continue;
+                }
+                (stream, remote)
+            }
+        };
+        let access = Arc::new(
+            access_log::Access::new(pull_state.access_log(), remote)
+                .security_logged(pull_state.security_log()),
+        );
+
+        if !is_addr_allowed(&remote, pull_state.ip_allowlist()) {
+            warn!(
+                peer = remote.to_string();
+                "{}: Rejecting pull request - connection from IP is not allowed.",
+                remote
+            );
+            let reason = match pull_state.ip_allowlist() {
+                Some([]) => String::from("allowlist (allowed_ip) is empty"),
+                _ => String::from("no entry of the allowlist (allowed_ip) matches"),
+            };
+            access.security_event(security_log::Event::Allowlist, &reason);
+            record_rejection(counters, &access, metrics::Rejection::Ip, reason);
+            continue;
+        }
+
+        if let Some(denied) = denied_by(&remote, pull_state.ip_denylist()) {
+            warn!(
+                peer = remote.to_string();
+                "{}: Rejecting pull request - connection from IP is denied.",
+                remote
+            );
+            let reason = format!("denylist (denied_ip) entry {} matches", denied);
+            access.security_event(security_log::Event::Denylist, &reason);
+            record_rejection(counters, &access, metrics::Rejection::Denylist, reason);
+            continue;
+        }
+        // Counted here rather than on accept, st. accepted and the IP rejections add up to the
+        // connections seen. Proxied connections are judged by the source the proxy announced.
+        counters.count_accepted();
+
+        if let Some(rate_limiter) = rate_limiter.as_mut() {
+            if !rate_limiter.allow(remote.ip(), Instant::now()) {
+                warn!(
+                    peer = remote.to_string();
+                    "{}: Rejecting pull request - too many connections from IP.",
+                    remote
+                );
+                let reason = format!(
+                    "more than {} connections at a rate of {}/s (pull_rate_limit)",
+                    rate_limiter.burst, rate_limiter.rate
+                );
+                access.security_event(security_log::Event::RateLimit, &reason);
+                record_rejection(counters, &access, metrics::Rejection::RateLimit, reason);
+                continue;
+            }
+        }
+
+        // Act on most recent registration data
+        pull_state.refresh()?;
+
+        // Check if pull was deactivated meanwhile before actually handling the request.
+        if !pull_state.is_active() {
+            info!("Detected missing registry, closing current connection and stop listening.");
+            // Close the listeners before the current stream, otherwise peers may still connect
+            // in between.
+            drop(listeners);
+            return Ok(());
+        }
+
+        info!(peer = remote.to_string(); "{}: Handling pull request.", remote);
+
+        let request_handler_fut = handle_request(
+            request_limit::Limited::new(stream, pull_state.max_request_bytes()),
+            agent_output_collector
+                .with_connection_channels(pull_state.agent_channel_overrides())
+                .with_agent_output_disabled(pull_state.agent_output_disabled()),
+            access.clone(),
+            pull_state.allow_legacy_pull(),
+            pull_state.tls_acceptor(),
+            ConnectionTimeouts {
+                default: pull_state.timeouts(),
+                overrides: pull_state.connection_timeout_overrides(),
+            },
+            counters.clone(),
+        );
+
+        match guard.try_make_task_for_addr(remote, request_handler_fut) {
+            Ok(connection_fut) => {
+                let counters = counters.clone();
+                let in_flight_guard = in_flight.track();
+                let active_connection = in_flight.connections.register(access.clone());
+                tokio::spawn(async move {
+                    let _in_flight_guard = in_flight_guard;
+                    counters.count_active_started();
+                    // Dropping the connection closes the stream and frees its slot
+                    let result = tokio::select! {
+                        result = connection_fut => result,
+                        _ = active_connection.cancelled() => Err(anyhow!("Cancelled via the admin socket")),
+                    };
+                    drop(active_connection);
+                    match result {
+                        Ok(()) => {
+                            counters.count_completed();
+                            access.finish(access_log::Outcome::Completed, None);
+                        }
+                        Err(err) => {
+                            warn!(peer = remote.to_string(); "{}: Request failed. ({})", remote, err);
+                            record_rejection(
+                                &counters,
+                                &access,
+                                if is_timeout(&err) {
+                                    metrics::Rejection::TimedOut
+                                } else {
+                                    metrics::Rejection::Failed
+                                },
+                                format!("{:#}", err),
+                            );
+                        }
+                    };
+                    counters.count_active_finished();
+                });
+            }
+            Err(limit) => {
+                warn!(peer = remote.to_string(); "{}: Request failed. ({})", remote, limit);
+                let (rejection, reason) = guard.rejection(&limit);
+                record_rejection(counters, &access, rejection, reason);
+            }
+        }
+        debug!("{}: Handling pull request DONE (Task detached).", remote);
+    }
+}
+
+/// Counts the rejection and records it in the access log, which gets exactly one record per
+/// connection, so this must be the last thing done with the connection.
+fn record_rejection(
+    counters: &metrics::PullCounters,
+    access: &access_log::Access,
+    rejection: metrics::Rejection,
+    reason: String,
+) {
+    access.finish(
+        match rejection {
+            metrics::Rejection::TimedOut => access_log::Outcome::TimedOut,
+            metrics::Rejection::Failed | metrics::Rejection::HandshakeFailed => {
+                access_log::Outcome::Failed
+            }
+            metrics::Rejection::Ip
+            | metrics::Rejection::Denylist
+            | metrics::Rejection::RateLimit
+            | metrics::Rejection::MaxConnections
+            | metrics::Rejection::MaxConnectionsTotal => access_log::Outcome::Rejected,
+        },
+        Some(&reason),
+    );
+    counters.count_rejection(rejection, &access.peer(), reason);
+}
+
+enum Incoming {
+    Accepted(TcpStream, SocketAddr),
+    /// With the source address from the PROXY header
+    Proxied(TcpStream, SocketAddr),
+}
+
+/// Reads the PROXY protocol header, which trusted proxies have to send, and hands the connection
+/// back to the accept loop, which then treats it as coming from the source in the header.
+async fn accept_proxied(
+    mut stream: TcpStream,
+    proxy: SocketAddr,
+    handshake_timeout: u64,
+    max_request_bytes: usize,
+    proxied_tx: mpsc::Sender<(TcpStream, SocketAddr)>,
+    counters: Arc<metrics::PullCounters>,
+    access_log: Option<Arc<access_log::AccessLog>>,
+) {
+    let source = match timeout(
+        Duration::from_secs(handshake_timeout),
+        proxy_protocol::read_header(&mut stream, max_request_bytes),
+    )
+    .await
+    {
+        // The proxy speaks for itself, eg. health checks
+        Ok(Ok(None)) => proxy,
+        Ok(Ok(Some(source))) => SocketAddr::new(to_canonical(source.ip()), source.port()),
+        Ok(Err(error)) => {
+            warn!(
+                peer = proxy.to_string();
+                "{}: Rejecting connection from trusted proxy. ({})", proxy, error
+            );
+            record_rejection(
+                &counters,
+                &access_log::Access::new(access_log, proxy),
+                metrics::Rejection::Failed,
+                format!("invalid PROXY protocol header ({})", error),
+            );
+            return;
+        }
+        Err(_) => {
+            warn!(
+                peer = proxy.to_string();
+                "{}: Rejecting connection from trusted proxy - no PROXY protocol header within {}s.",
+                proxy, handshake_timeout
+            );
+            record_rejection(
+                &counters,
+                &access_log::Access::new(access_log, proxy),
+                metrics::Rejection::TimedOut,
+                format!("no PROXY protocol header within {}s", handshake_timeout),
+            );
+            return;
+        }
+    };
+    debug!(peer = source.to_string(); "{}: Connected via proxy {}.", source, proxy);
+    // If the accept loop is gone, we are restarting, and the connection is dropped along with it
+    let _ = proxied_tx.send((stream, source)).await;
+}
+
+/// Unchanged listener settings keep the listeners as they are, in-flight requests are not
+/// affected either way, they own their streams
+fn apply_listening_config(
+    pull_state: &mut impl PullState,
+    listeners: &mut Vec<TcpListener>,
+    listening_config: &mut ListeningConfig,
+) -> AnyhowResult<()> {
+    let changed = pull_state.listening_config();
+    if changed == *listening_config {
+        return Ok(());
+    }
+    if socket_activated() {
+        warn!(
+            "Listener settings changed to {}, but the socket was passed by systemd. Change the socket unit instead.",
+            changed
+        );
+        return Ok(());
+    }
+    match rebind_listeners(listeners, listening_config, &changed) {
+        Ok(()) => {
+            info!(
+                "Listener settings changed from {} to {}, listening accordingly.",
+                listening_config, changed
+            );
+            *listening_config = changed;
+        }
+        Err(err) => {
+            warn!(
+                "Failed to listen on {}, keep listening on {}. ({})",
+                changed,
+                listening_config,
+                anyhow_error_to_human_readable(&err)
+            );
+            pull_state.keep_listening(listening_config);
+        }
+    }
+    Ok(())
+}
+
+fn reload(
+    pull_state: &mut impl PullState,
+    agent_output_collector: &mut impl AgentOutputCollector,
+    reload_trigger: &ReloadTrigger,
+) {
+    info!("Received SIGHUP, reloading registry, agent channel and listener settings.");
+    agent_output_collector.invalidate();
+    if let Err(error) = pull_state.reload() {
+        warn!(
+            "Failed to reload, keeping current connections and agent channel. ({})",
+            anyhow_error_to_human_readable(&error)
+        );
+    }
+    agent_output_collector.use_agent_channel(pull_state.agent_channel());
+    reload_trigger
+        .agent_channel
+        .send_replace(pull_state.agent_channel().clone());
+}
+
+fn is_addr_allowed(addr: &SocketAddr, allowed_ip: Option<&[config::IpEntry]>) -> bool {
+    allowed_ip.is_none_or(|allowed_ip| is_addr_in(addr, allowed_ip))
+}
+
+/// The denylist entry matching the address, if any
+fn denied_by<'a>(
+    addr: &SocketAddr,
+    denied_ip: &'a [config::IpEntry],
+) -> Option<&'a config::IpEntry> {
+    let can_addr = to_canonical(addr.ip());
+    denied_ip.iter().find(|net| net.contains(&can_addr))
+}
+
+fn is_addr_in(addr: &SocketAddr, nets: &[config::IpEntry]) -> bool {
+    let can_addr = to_canonical(addr.ip());
+    nets.iter().any(|net| net.contains(&can_addr))
+}
+
+fn to_canonical(ip_addr: IpAddr) -> IpAddr {
+    //IpAddr::to_canonical is unstable API :-(
+    match ip_addr {
+        IpAddr::V4(_v4) => ip_addr,
+        IpAddr::V6(v6) => {
+            let v4_map_subnet =
+                ipnet::Ipv6Net::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96).unwrap();
+            if v4_map_subnet.contains(&v6) {
+                IpAddr::V4(v6.to_ipv4().unwrap())
+            } else {
+                ip_addr
+            }
+        }
+    }
+}
+
+struct ConnectionTimeouts {
+    default: config::Timeouts,
+    /// By the UUID of the connection, see config::PullConfig::site_connection_timeouts
+    overrides: HashMap<String, u64>,
+}
+
+async fn handle_request(
+    mut stream: request_limit::Limited<TcpStream>,
+    agent_output_collector: impl AgentOutputCollector,
+    access: Arc<access_log::Access>,
+    is_legacy_pull: bool,
+    tls_acceptor: tls_server::PullTlsAcceptor,
+    connection_timeouts: ConnectionTimeouts,
+    counters: Arc<metrics::PullCounters>,
+) -> AnyhowResult<()> {
+    let remote_ip = access.peer().ip();
+    let timeouts = connection_timeouts.default;
+    let deadline = deadline(timeouts.total);
+    if is_legacy_pull {
+        return until_deadline(
+            handle_legacy_pull_request(
+                stream,
+                agent_output_collector.connect(remote_ip),
+                timeouts.idle,
+                counters,
+                access,
+            ),
+            deadline,
+        )
+        .await;
+    }
+    debug!("handle_request starts");
+
+    let handshake_timeout = timeouts.handshake;
+    let handshake_counters = counters.clone();
+    let handshake_access = access.clone();
+    let handshake = async move {
+        timeout(Duration::from_secs(handshake_timeout), async move {
+            stream.write_all(TLS_ID).await?;
+            stream.flush().await?;
+            tls_acceptor.accept(stream).await
+        })
+        .await
+        .map_err(|_| anyhow!(HandshakeTimeout(handshake_timeout)))
+        .and_then(|accepted| Ok(accepted?))
+        .inspect_err(|err| {
+            let reason = format!("TLS handshake ({:#})", err);
+            handshake_access.security_event(security_log::Event::Handshake, &reason);
+            handshake_counters.count_rejection(
+                metrics::Rejection::HandshakeFailed,
+                &remote_ip,
+                reason,
+            );
+            tls_debug::log_handshake_failure(&remote_ip, err);
+        })
+    };
+
+    // The agent starts collecting while we are still busy with the handshake. If the handshake
+    // fails, we don't wait for the agent but drop the connection right away. The other way
+    // round, the peer still gets to complete the handshake.
+    let agent_output = async { AnyhowResult::Ok(agent_output_collector.connect(remote_ip).await) };
+
+    let (agent_output, mut tls_stream) = until_deadline(
+        async {
+            match agent_output_collector.selects_by_connection() {
+                // We can only tell which agent channel to use once we know the connection the
+                // peer selected via SNI
+                true => {
+                    let tls_stream = handshake.await?;
+                    let uuid = tls_stream.get_ref().1.sni_hostname().map(String::from);
+                    Ok((
+                        agent_output_collector
+                            .connect_for(remote_ip, uuid.as_deref())
+                            .await,
+                        tls_stream,
+                    ))
+                }
+                false => tokio::try_join!(agent_output, handshake),
+            }
+        },
+        deadline,
+    )
+    .await?;
+    // From now on, the peer only gets to send what TLS allows for
+    tls_stream.get_mut().0.lift();
+    tls_debug::log_server_handshake(&remote_ip, tls_stream.get_ref().1);
+    access.set_handshake(tls_stream.get_ref().1);
+
+    let forwarded = async {
+        let agent_output = agent_output?
+            .counted(counters.clone())
+            .logged(access.clone());
+        // Only now we know which connection the peer selected via SNI
+        match tls_stream
+            .get_ref()
+            .1
+            .sni_hostname()
+            .and_then(|uuid| connection_timeouts.overrides.get(uuid))
+        {
+            Some(timeout_override) => {
+                debug!(
+                    "{}: Using connection timeout override of {}s.",
+                    remote_ip, timeout_override
+                );
+                with_deadline(
+                    agent_output.forward_encoded(&mut tls_stream, *timeout_override),
+                    *timeout_override,
+                )
+                .await
+            }
+            None => {
+                until_deadline(
+                    agent_output.forward_encoded(&mut tls_stream, timeouts.idle),
+                    deadline,
+                )
+                .await
+            }
+        }
+    }
+    .await;
+    // The pull protocol has no way to tell the site why we abort, the site only sees the agent
+    // output ending early. Closing the TLS session properly at least lets it tell us aborting
+    // from us crashing or the network failing.
+    close_gracefully(&mut tls_stream, &remote_ip).await;
+    if let Some(uuid) = tls_stream.get_ref().1.sni_hostname() {
+        counters.count_served(uuid, access.bytes_sent());
+    }
+    forwarded
+}
+
+/// Sends close_notify, then closes our side of the connection. A peer which doesn't even take
+/// these few bytes is not worth waiting for.
+async fn close_gracefully(stream: &mut (impl AsyncWrite + Unpin), remote_ip: &IpAddr) {
+    if let Err(err) = with_timeout(stream.shutdown(), CLOSE_TIMEOUT).await {
+        debug!(
+            "{}: Failed to close connection gracefully. ({})",
+            remote_ip, err
+        );
+    }
+}
+
+async fn handle_legacy_pull_request(
+    mut stream: impl AsyncWrite + Unpin,
+    agent_output: impl Future<Output = AnyhowResult<AgentOutput>>,
+    idle_timeout: u64,
+    counters: Arc<metrics::PullCounters>,
+    access: Arc<access_log::Access>,
+) -> AnyhowResult<()> {
+    agent_output
+        .await?
+        .counted(counters)
+        .logged(access)
+        .forward_plain(&mut stream, idle_timeout)
+        .await
+}
+
+/// Limits the overall duration, as opposed to with_timeout, which we use for single steps.
+async fn with_deadline(
+    fut: impl Future<Output = AnyhowResult<()>>,
+    seconds: u64,
+) -> AnyhowResult<()> {
+    timeout(Duration::from_secs(seconds), fut).await?
+}
+
+/// When a request started now has to be done, see config::Timeouts::total
+fn deadline(total: Option<u64>) -> Option<tokio::time::Instant> {
+    total.map(|seconds| tokio::time::Instant::now() + Duration::from_secs(seconds))
+}
+
+/// Like with_deadline, for a deadline shared by the steps of a request
+async fn until_deadline<T>(
+    fut: impl Future<Output = AnyhowResult<T>>,
+    deadline: Option<tokio::time::Instant>,
+) -> AnyhowResult<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut).await?,
+        None => fut.await,
+    }
+}
+
+fn is_timeout(err: &AnyhowError) -> bool {
+    err.downcast_ref::<tokio::time::error::Elapsed>().is_some()
+}
+
+async fn with_timeout<T, E: 'static + Error + Send + Sync>(
+    fut: impl Future<Output = Result<T, E>>,
+    seconds: u64,
+) -> AnyhowResult<T> {
+    Ok(timeout(Duration::from_secs(seconds), fut)
+        .await
+        .map_err(|e| anyhow!(e))??)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn agent_output(data: &'static [u8], max_output_bytes: usize) -> AgentOutput {
+        AgentOutput::new(Box::new(data), max_output_bytes)
+    }
+
+    #[tokio::test]
+    async fn test_forward_throttled() {
+        static DATA: [u8; 96 * 1024] = [b'x'; 96 * 1024];
+        let mut writer = vec![];
+        let start = Instant::now();
+        agent_output(&DATA, DATA.len())
+            .throttled(Some(256 * 1024))
+            .forward_plain(&mut writer, 10)
+            .await
+            .unwrap();
+        // The bucket starts empty, so sending takes payload / rate = 0.375s
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((0.3..1.0).contains(&elapsed), "took {}s", elapsed);
+        assert_eq!(writer, DATA);
+    }
+
+    #[tokio::test]
+    async fn test_throttle_burst() {
+        let mut throttle = Throttle::new(1000);
+        // Unused capacity of more than one second is not accumulated
+        throttle.last_refill = Instant::now() - Duration::from_secs(10);
+        let start = Instant::now();
+        throttle.consume(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        throttle.consume(100).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_collect_agent_channel_timeout() {
+        let agent = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        // Accepts the connection, but never sends anything
+        let collector = AgentOutputCollectorImpl::new(
+            &types::AgentChannel::Tcp(agent.local_addr().unwrap()),
+            1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            None,
+        );
+        let err = collector
+            .collect(&collector.agent_channel, IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<AgentChannelTimeout>());
+        assert!(!is_timeout(&err));
+        assert_eq!(
+            format!("{:#}", err),
+            "Error collecting monitoring data.: Agent did not respond within 1 seconds"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_plain() {
+        let mut sent = vec![];
+        agent_output(b"abc", 1024)
+            .forward_plain(&mut sent, 1)
+            .await
+            .unwrap();
+        assert_eq!(sent, b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_serve_timing() {
+        let mut output = agent_output(b"abc", 1024);
+        let mut buffer = [0; 16];
+        assert_eq!(output.read_chunk(&mut buffer).await.unwrap(), 3);
+        assert_eq!(output.read_chunk(&mut buffer).await.unwrap(), 0);
+        assert_eq!(output.timing.agent_bytes, 3);
+        // Collecting for the cache is no forwarding, there is nothing to log
+        assert!(!output.timing.forwarding);
+        let mut sent = vec![];
+        output.write_counted(&mut sent, b"abc", 1).await.unwrap();
+        assert!(output.timing.forwarding);
+        assert_eq!(sent, b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_encode_data_for_transport() {
+        let mut expected_result = b"\x00\x00\x01".to_vec();
+        expected_result.append(&mut monitoring_data::compress(b"abc").unwrap());
+        let mut sent = vec![];
+        agent_output(b"abc", 1024)
+            .forward_encoded(&mut sent, 1)
+            .await
+            .unwrap();
+        assert_eq!(sent, expected_result);
+    }
+
+    #[tokio::test]
+    async fn test_encode_data_for_transport_chunked() {
+        let data = vec![b'x'; 10 * constants::DEFAULT_IO_CHUNK_SIZE + 7];
+        let mut sent = vec![];
+        AgentOutput::new(Box::new(std::io::Cursor::new(data.clone())), data.len())
+            .forward_encoded(&mut sent, 1)
+            .await
+            .unwrap();
+        let mut decoded = vec![];
+        std::io::Read::read_to_end(
+            &mut flate2::read::ZlibDecoder::new(&sent[3..]),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[tokio::test]
+    async fn test_forward_encoded_transfers_less() {
+        let data = (0..5000)
+            .map(|i| format!("0 \"Service {}\" count={} OK - all good\n", i % 50, i))
+            .collect::<String>()
+            .into_bytes();
+        let mut plain = vec![];
+        AgentOutput::new(Box::new(std::io::Cursor::new(data.clone())), data.len())
+            .forward_plain(&mut plain, 1)
+            .await
+            .unwrap();
+        let mut encoded = vec![];
+        AgentOutput::new(Box::new(std::io::Cursor::new(data.clone())), data.len())
+            .forward_encoded(&mut encoded, 1)
+            .await
+            .unwrap();
+        assert_eq!(plain.len(), data.len());
+        assert!(encoded.len() * 5 < plain.len());
+    }
+
+    #[tokio::test]
+    async fn test_forward_exceeds_limit() {
+        // An endless agent output must not be buffered
+        let mut sent = vec![];
+        assert!(AgentOutput::new(
+            Box::new(tokio::io::repeat(b'x')),
+            3 * constants::DEFAULT_IO_CHUNK_SIZE
+        )
+        .forward_plain(&mut sent, 1)
+        .await
+        .is_err());
+        assert!(sent.len() <= 3 * constants::DEFAULT_IO_CHUNK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_forward_chunk_size() {
+        let data = vec![b'x'; 3 * constants::MIN_IO_CHUNK_SIZE + 7];
+        for chunk_size in [constants::MIN_IO_CHUNK_SIZE, constants::MAX_IO_CHUNK_SIZE] {
+            let mut sent = vec![];
+            AgentOutput::new(Box::new(std::io::Cursor::new(data.clone())), data.len())
+                .chunked(chunk_size)
+                .forward_plain(&mut sent, 1)
+                .await
+                .unwrap();
+            assert_eq!(sent, data);
+        }
+        // The limit is checked per chunk, so we never send more than that
+        let mut sent = vec![];
+        assert!(AgentOutput::new(
+            Box::new(tokio::io::repeat(b'x')),
+            2 * constants::MIN_IO_CHUNK_SIZE
+        )
+        .chunked(constants::MIN_IO_CHUNK_SIZE)
+        .forward_plain(&mut sent, 1)
+        .await
+        .is_err());
+        assert_eq!(sent.len(), 2 * constants::MIN_IO_CHUNK_SIZE);
+    }
+
+    /// Agent which sends its output in small bursts with gaps in between
+    fn agent_bursts(
+        mut writer: impl AsyncWrite + Unpin + Send + 'static,
+        bursts: usize,
+    ) -> tokio::task::JoinHandle<Vec<u8>> {
+        tokio::spawn(async move {
+            let mut written = vec![];
+            for burst in 0..bursts {
+                let data = format!("<<<burst_{}>>>\n{}\n", burst, "x".repeat(burst * 100));
+                writer.write_all(data.as_bytes()).await.unwrap();
+                writer.flush().await.unwrap();
+                written.extend_from_slice(data.as_bytes());
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            written
+        })
+    }
+
+    /// Fails every other read as if interrupted by a signal
+    struct Interrupting<R> {
+        inner: R,
+        interrupt: bool,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for Interrupting<R> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::Interrupted.into()));
+            }
+            std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_partial_reads() {
+        let (writer, reader) = tokio::io::duplex(64);
+        let agent = agent_bursts(writer, 20);
+        let mut sent = vec![];
+        AgentOutput::new(
+            Box::new(Interrupting {
+                inner: reader,
+                interrupt: false,
+            }),
+            1024 * 1024,
+        )
+        .forward_plain(&mut sent, 1)
+        .await
+        .unwrap();
+        assert_eq!(sent, agent.await.unwrap());
+
+        let (writer, reader) = tokio::io::duplex(64);
+        let agent = agent_bursts(writer, 20);
+        let data = AgentOutput::new(
+            Box::new(Interrupting {
+                inner: reader,
+                interrupt: false,
+            }),
+            1024 * 1024,
+        )
+        .read_all()
+        .await
+        .unwrap();
+        assert_eq!(data, agent.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_collect_partial_reads() {
+        let agent = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let collector = AgentOutputCollectorImpl::new(
+            &types::AgentChannel::Tcp(agent.local_addr().unwrap()),
+            1024 * 1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            None,
+        );
+        let agent = tokio::spawn(async move {
+            let (mut stream, _) = agent.accept().await.unwrap();
+            let mut remote_ip = [0u8; 10];
+            stream.read_exact(&mut remote_ip).await.unwrap();
+            agent_bursts(stream, 20).await.unwrap()
+        });
+        let mut sent = vec![];
+        collector
+            .collect(&collector.agent_channel, IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .await
+            .unwrap()
+            .forward_plain(&mut sent, 1)
+            .await
+            .unwrap();
+        assert_eq!(sent, agent.await.unwrap());
+    }
+
+    /// Sends the given output to each of the given number of connections
+    async fn fixed_agent(output: &'static [u8], connections: usize) -> types::AgentChannel {
+        let agent = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let agent_channel = types::AgentChannel::Tcp(agent.local_addr().unwrap());
+        tokio::spawn(async move {
+            for _ in 0..connections {
+                let (mut stream, _) = agent.accept().await.unwrap();
+                let mut remote_ip = [0u8; 10];
+                stream.read_exact(&mut remote_ip).await.unwrap();
+                stream.write_all(output).await.unwrap();
+            }
+        });
+        agent_channel
+    }
+
+    #[tokio::test]
+    async fn test_connect_for() {
+        let remote_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let collector = AgentOutputCollectorImpl::new(
+            &fixed_agent(b"classic", 2).await,
+            1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            Some(60),
+        );
+        assert!(!collector.selects_by_connection());
+        let collector = collector.with_connection_channels(HashMap::from([(
+            String::from("00000000-0000-0000-0000-000000000001"),
+            fixed_agent(b"custom", 2).await,
+        )]));
+        assert!(collector.selects_by_connection());
+        let output = |uuid: Option<&'static str>| {
+            let collector = collector.clone();
+            async move {
+                collector
+                    .connect_for(remote_ip, uuid)
+                    .await
+                    .unwrap()
+                    .read_all()
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(
+            output(Some("00000000-0000-0000-0000-000000000001")).await,
+            b"custom"
+        );
+        // Served from the cache of the respective channel
+        assert_eq!(
+            output(Some("00000000-0000-0000-0000-000000000001")).await,
+            b"custom"
+        );
+        assert_eq!(
+            output(Some("00000000-0000-0000-0000-000000000002")).await,
+            b"classic"
+        );
+        assert_eq!(output(None).await, b"classic");
+        collector.invalidate();
+        assert_eq!(
+            output(Some("00000000-0000-0000-0000-000000000001")).await,
+            b"custom"
+        );
+        assert_eq!(output(None).await, b"classic");
+    }
+
+    #[tokio::test]
+    async fn test_connect_for_agent_output_disabled() {
+        let remote_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let collector = AgentOutputCollectorImpl::new(
+            &fixed_agent(b"classic", 1).await,
+            1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            None,
+        )
+        .with_agent_output_disabled(HashSet::from([String::from(
+            "00000000-0000-0000-0000-000000000001",
+        )]));
+        assert!(collector.selects_by_connection());
+        assert!(collector
+            .connect_for(remote_ip, Some("00000000-0000-0000-0000-000000000001"))
+            .await
+            .unwrap()
+            .read_all()
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            collector
+                .connect_for(remote_ip, Some("00000000-0000-0000-0000-000000000002"))
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap(),
+            b"classic"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_agent_unavailable() {
+        let agent = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let agent_channel = types::AgentChannel::Tcp(agent.local_addr().unwrap());
+        drop(agent);
+        let collector = AgentOutputCollectorImpl::new(
+            &agent_channel,
+            1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            Some(60),
+        );
+        let remote_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(collector.connect(remote_ip).await.is_err());
+        let collector = collector.on_agent_unavailable(config::AgentUnavailablePolicy::Report);
+        let output = String::from_utf8(
+            collector
+                .connect(remote_ip)
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        let lines = output.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "<<<check_mk>>>");
+        assert_eq!(
+            lines[1],
+            format!("AgentController: cmk-agent-ctl {}", constants::VERSION)
+        );
+        assert_eq!(lines[2], "<<<cmk_agent_ctl_agent_unavailable:sep(0)>>>");
+        assert!(lines[3].starts_with("Error collecting monitoring data.: "));
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_output_hooks() {
+        let remote_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let hook = |script: &str| {
+            vec![
+                String::from("/bin/sh"),
+                String::from("-c"),
+                String::from(script),
+            ]
+        };
+        let collector = AgentOutputCollectorImpl::new(
+            &fixed_agent(b"<<<check_mk>>>\nsecret\n", 1).await,
+            1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            Some(60),
+        )
+        .on_agent_unavailable(config::AgentUnavailablePolicy::Report);
+        let transforming =
+            collector.output_hooks(&[hook("grep -v secret; echo '<<<custom>>>'")], 5);
+        for _ in 0..2 {
+            // Cached as collected, the agent is only asked once
+            assert_eq!(
+                transforming
+                    .connect(remote_ip)
+                    .await
+                    .unwrap()
+                    .read_all()
+                    .await
+                    .unwrap(),
+                b"<<<check_mk>>>\n<<<custom>>>\n"
+            );
+        }
+        // Never reported to the peer as if the agent was unavailable
+        let failing = AgentOutputCollectorImpl::new(
+            &fixed_agent(b"<<<check_mk>>>", 1).await,
+            1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            None,
+        )
+        .on_agent_unavailable(config::AgentUnavailablePolicy::Report)
+        .output_hooks(&[hook("exit 1")], 5);
+        assert_eq!(
+            failing.connect(remote_ip).await.err().unwrap().to_string(),
+            "Output hook '/bin/sh' failed: exit status: 1"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_output_hooks_per_peer() {
+        let collector = AgentOutputCollectorImpl::new(
+            &fixed_agent(b"<<<check_mk>>>\n", 1).await,
+            1024,
+            1,
+            None,
+            constants::DEFAULT_IO_CHUNK_SIZE,
+            Some(60),
+        )
+        .output_hooks(
+            &[vec![
+                String::from("/bin/sh"),
+                String::from("-c"),
+                String::from("cat; echo \"peer=$REMOTE_HOST\""),
+            ]],
+            5,
+        );
+        // The agent is only asked once, but each peer gets what the hooks made of it for them
+        for peer in ["127.0.0.1", "127.0.0.2", "127.0.0.1"] {
+            assert_eq!(
+                collector
+                    .connect(IpAddr::from_str(peer).unwrap())
+                    .await
+                    .unwrap()
+                    .read_all()
+                    .await
+                    .unwrap(),
+                format!("<<<check_mk>>>\npeer={}\n", peer).as_bytes()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_agent_output_marker() {
+        let remote_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let collector = |agent_channel: &types::AgentChannel| {
+            AgentOutputCollectorImpl::new(
+                agent_channel,
+                1024,
+                1,
+                None,
+                constants::DEFAULT_IO_CHUNK_SIZE,
+                None,
+            )
+            .agent_output_marker(Some(b"<<<check_mk>>>"))
+        };
+        assert_eq!(
+            collector(&fixed_agent(b"<<<check_mk>>>\nVersion: 2.2.0\n", 1).await)
+                .connect(remote_ip)
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap(),
+            b"<<<check_mk>>>\nVersion: 2.2.0\n"
+        );
+        let err = collector(&fixed_agent(b"HTTP/1.1 400 Bad Request\r\n", 1).await)
+            .connect(remote_ip)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<UnexpectedAgentOutput>());
+        assert_eq!(
+            format!("{:#}", err),
+            "Error collecting monitoring data.: Agent output does not start with \"<<<check_mk>>>\" \
+             but with \"HTTP/1.1 400 B\", is the agent channel misconfigured?"
+        );
+        // Less output than the marker is just as wrong
+        assert!(collector(&fixed_agent(b"<<<", 1).await)
+            .connect(remote_ip)
+            .await
+            .err()
+            .unwrap()
+            .is::<UnexpectedAgentOutput>());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pull.sock");
+        let listener = unix_pull_listener(&path, 0o660).unwrap();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(
+                &std::fs::metadata(&path).unwrap().permissions()
+            ) & 0o777,
+            0o660
+        );
+        let agent_channel = fixed_agent(b"<<<check_mk>>>", 1).await;
+        let (agent_channel_updates, agent_channel_updated) =
+            tokio::sync::watch::channel(agent_channel.clone());
+        let counters = Arc::new(metrics::PullCounters::default());
+        tokio::spawn(serve_unix_socket(
+            Some(listener),
+            AgentOutputCollectorImpl::new(
+                &agent_channel,
+                1024,
+                1,
+                None,
+                constants::DEFAULT_IO_CHUNK_SIZE,
+                None,
+            ),
+            config::Timeouts {
+                connect: 1,
+                handshake: 1,
+                idle: 1,
+                total: None,
+            },
+            counters.clone(),
+            InFlight::default(),
+            MaxConnectionsGuard::new(10, None, None),
+            agent_channel_updated,
+        ));
+        let pull = || async {
+            let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+            let mut output = vec![];
+            stream.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        assert_eq!(pull().await, b"<<<check_mk>>>");
+        assert_eq!(counters.snapshot().accepted, 1);
+        // Reloading switches the agent channel
+        agent_channel_updates.send_replace(fixed_agent(b"<<<check_mk>>>\nreloaded", 1).await);
+        assert_eq!(pull().await, b"<<<check_mk>>>\nreloaded");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix_socket_max_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pull.sock");
+        let agent_channel = fixed_agent(b"<<<check_mk>>>", 1).await;
+        let counters = Arc::new(metrics::PullCounters::default());
+        tokio::spawn(serve_unix_socket(
+            Some(unix_pull_listener(&path, 0o660).unwrap()),
+            AgentOutputCollectorImpl::new(
+                &agent_channel,
+                1024,
+                1,
+                None,
+                constants::DEFAULT_IO_CHUNK_SIZE,
+                None,
+            ),
+            config::Timeouts {
+                connect: 1,
+                handshake: 1,
+                idle: 1,
+                total: None,
+            },
+            counters.clone(),
+            InFlight::default(),
+            MaxConnectionsGuard::new(0, None, None),
+            tokio::sync::watch::channel(agent_channel).1,
+        ));
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let mut output = vec![];
+        stream.read_to_end(&mut output).await.unwrap();
+        assert!(output.is_empty());
+        assert_eq!(counters.snapshot().rejected_max_connections, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_pull_listener_refuses_live_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pull.sock");
+        let _live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(
+            format!("{}", unix_pull_listener(&path, 0o660).err().unwrap())
+                .contains("another process is serving on it")
+        );
+        assert!(path.exists());
+        // Nothing is left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_pull_listener_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pull.sock");
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let runtime = runtime(1).unwrap();
+        let listener = runtime.block_on(async { unix_pull_listener(&path, 0o660).unwrap() });
+        assert!(path.exists());
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_pull_listener_refuses_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pull.sock");
+        std::fs::write(&path, "data").unwrap();
+        assert!(
+            format!("{}", unix_pull_listener(&path, 0o660).err().unwrap()).contains("not a socket")
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    }
+
+    async fn cached_output(cache: &AgentOutputCache, data: &'static [u8]) -> Vec<u8> {
+        cache
+            .get_or_collect(async { Ok(agent_output(data, 1024)) })
+            .await
+            .unwrap()
+            .read_all()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_agent_output_cache() {
+        let cache = AgentOutputCache::new(Duration::from_secs(60), 1024);
+        assert_eq!(cached_output(&cache, b"first").await, b"first");
+        assert_eq!(cached_output(&cache, b"second").await, b"first");
+        assert!(cache
+            .lookup(Instant::now() + Duration::from_secs(61))
+            .is_none());
+        cache.invalidate();
+        assert_eq!(cached_output(&cache, b"second").await, b"second");
+    }
+
+    #[tokio::test]
+    async fn test_agent_output_cache_exceeds_limit() {
+        let cache = AgentOutputCache::new(Duration::from_secs(60), 3);
+        assert!(cache
+            .get_or_collect(async { Ok(agent_output(b"abcd", 3)) })
+            .await
+            .is_err());
+        assert!(cache.lookup(Instant::now()).is_none());
+        assert_eq!(
+            cache
+                .get_or_collect(async { Ok(agent_output(b"abc", 3)) })
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap(),
+            b"abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_output_cache_invalidated_while_collecting() {
+        let cache = AgentOutputCache::new(Duration::from_secs(60), 1024);
+        let output = cache
+            .get_or_collect(async {
+                cache.invalidate();
+                Ok(agent_output(b"outdated", 1024))
+            })
+            .await
+            .unwrap();
+        assert_eq!(output.read_all().await.unwrap(), b"outdated");
+        assert!(cache.lookup(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut rate_limiter = RateLimiter::new(2, 3);
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        let other_peer = IpAddr::from([10, 0, 0, 2]);
+        let start = Instant::now();
+        // The burst is available right away, the rate limit kicks in afterwards
+        for _ in 0..3 {
+            assert!(rate_limiter.allow(peer, start));
+        }
+        assert!(!rate_limiter.allow(peer, start));
+        assert!(rate_limiter.allow(other_peer, start));
+        // Two connections per second
+        assert!(rate_limiter.allow(peer, start + Duration::from_millis(500)));
+        assert!(!rate_limiter.allow(peer, start + Duration::from_millis(600)));
+        // Refilled, but never beyond the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(rate_limiter.allow(peer, later));
+        }
+        assert!(!rate_limiter.allow(peer, later));
+    }
 
-async fn _pull_cycle(
-    pull_state: &mut impl PullState,
-    guard: &mut MaxConnectionsGuard,
-    agent_output_collector: impl AgentOutputCollector,
-) -> AnyhowResult<()> {
-    let listener = TcpListener::from_std(tcp_listener(pull_state.listening_config())?)?;
+    #[test]
+    fn test_rate_limiter_bounded() {
+        let mut rate_limiter = RateLimiter::new(1, 1);
+        let start = Instant::now();
+        for n in 0..RateLimiter::MAX_TRACKED_PEERS as u32 {
+            assert!(rate_limiter.allow(IpAddr::from(n.to_be_bytes()), start));
+        }
+        assert_eq!(rate_limiter.buckets.len(), RateLimiter::MAX_TRACKED_PEERS);
+        // Nothing has refilled yet, so we forget one peer to make room
+        assert!(rate_limiter.allow(IpAddr::from([10, 0, 0, 1]), start));
+        assert_eq!(rate_limiter.buckets.len(), RateLimiter::MAX_TRACKED_PEERS);
+        // Now all buckets but the last one have refilled, they are not needed anymore
+        assert!(rate_limiter.allow(IpAddr::from([10, 0, 0, 2]), start + Duration::from_secs(1)));
+        assert_eq!(rate_limiter.buckets.len(), 1);
+    }
 
-    loop {
-        let (stream, remote) = match match timeout(
-            Duration::from_secs(FIVE_MINUTES),
-            listener.accept(),
-        )
-        .await
-        {
-            Ok(accepted_result) => accepted_result,
-            Err(_) => {
-                debug!(
-                        "Got no pull request within five minutes. Registration may have changed, thus restarting pull handling."
-                    );
-                return Ok(());
+    #[tokio::test]
+    async fn test_max_connections_guard() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 4242));
+        let other_peer = SocketAddr::from(([10, 0, 0, 2], 4242));
+        let (release, released) = tokio::sync::watch::channel(());
+        let task = || {
+            let mut released = released.clone();
+            async move {
+                let _ = released.changed().await;
+                Ok(())
             }
-        } {
-            Ok(accepted) => accepted,
-            Err(error) => {
-                warn!("Failed accepting pull connection. ({})", error);
-                continue;
+        };
+        let mut guard = MaxConnectionsGuard::new(2, Some(3), None);
+        let mut tasks = vec![];
+        for _ in 0..2 {
+            tasks.push(tokio::spawn(
+                guard.try_make_task_for_addr(peer, task()).unwrap(),
+            ));
+        }
+        assert_eq!(
+            guard.try_make_task_for_addr(peer, task()).err(),
+            Some(ConnectionLimit::PerIp)
+        );
+        tasks.push(tokio::spawn(
+            guard.try_make_task_for_addr(other_peer, task()).unwrap(),
+        ));
+        // The other peer still has slots left, but all slots are taken in total
+        assert_eq!(
+            guard.try_make_task_for_addr(other_peer, task()).err(),
+            Some(ConnectionLimit::Total)
+        );
+        assert_eq!(guard.active_connections.len(), 2);
+        release.send(()).unwrap();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        // Peers are forgotten once their connections are done
+        assert!(guard.try_make_task_for_addr(other_peer, task()).is_ok());
+        assert_eq!(guard.active_connections.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_guard_queue() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 4242));
+        let other_peer = SocketAddr::from(([10, 0, 0, 2], 4242));
+        let (release, released) = tokio::sync::watch::channel(());
+        let started = Arc::new(AtomicUsize::new(0));
+        let task = || {
+            let mut released = released.clone();
+            let started = started.clone();
+            async move {
+                started.fetch_add(1, Ordering::SeqCst);
+                let _ = released.changed().await;
+                Ok(())
             }
         };
+        let mut guard = MaxConnectionsGuard::new(1, Some(2), Some(2));
+        let active = tokio::spawn(guard.try_make_task_for_addr(peer, task()).unwrap());
+        let queued_per_ip = tokio::spawn(guard.try_make_task_for_addr(peer, task()).unwrap());
+        let other_active = tokio::spawn(guard.try_make_task_for_addr(other_peer, task()).unwrap());
+        // Waiting for the per-IP slot did not take one in total
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        let queued_total = tokio::spawn(
+            guard
+                .try_make_task_for_addr(SocketAddr::from(([10, 0, 0, 3], 4242)), task())
+                .unwrap(),
+        );
+        assert_eq!(
+            guard.try_make_task_for_addr(other_peer, task()).err(),
+            Some(ConnectionLimit::PerIp)
+        );
+        release.send(()).unwrap();
+        for task in [active, other_active] {
+            task.await.unwrap().unwrap();
+        }
+        // The queued ones got their turn, and wait for the next release
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(started.load(Ordering::SeqCst), 4);
+        release.send(()).unwrap();
+        for task in [queued_per_ip, queued_total] {
+            task.await.unwrap().unwrap();
+        }
+    }
 
-        if !is_addr_allowed(&remote, pull_state.ip_allowlist()) {
-            warn!(
-                "{}: Rejecting pull request - connection from IP is not allowed.",
-                remote
+    #[tokio::test]
+    async fn test_max_connections_guard_no_total() {
+        let mut guard = MaxConnectionsGuard::new(1, None, None);
+        let mut tasks = vec![];
+        for n in 0..100u8 {
+            tasks.push(
+                guard
+                    .try_make_task_for_addr(SocketAddr::from(([10, 0, 0, n], 4242)), async {
+                        Ok(())
+                    })
+                    .unwrap(),
             );
-            continue;
         }
+        assert_eq!(guard.active_connections.len(), 100);
+    }
 
-        // Act on most recent registration data
-        pull_state.refresh()?;
+    #[tokio::test]
+    async fn test_in_flight() {
+        let in_flight = InFlight::default();
+        let first = in_flight.track();
+        let second = in_flight.track();
+        assert_eq!(in_flight.count(), 2);
+        drop(first);
+        assert_eq!(in_flight.count(), 1);
+        drop(second);
+        assert_eq!(in_flight.count(), 0);
+        in_flight.drained().await;
+    }
 
-        // Check if pull was deactivated meanwhile before actually handling the request.
-        if !pull_state.is_active() {
-            info!("Detected empty registry, closing current connection and stop listening.");
-            return Ok(());
-        }
+    #[tokio::test]
+    async fn test_drain() {
+        let in_flight = InFlight::default();
+        assert_eq!(drain(&in_flight, Duration::ZERO).await, (0, 0));
 
-        info!("{}: Handling pull request.", remote);
+        let guard = in_flight.track();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(guard);
+        });
+        assert_eq!(drain(&in_flight, Duration::from_secs(5)).await, (1, 0));
 
-        let request_handler_fut = handle_request(
-            stream,
-            agent_output_collector.clone(),
-            remote.ip(),
-            pull_state.allow_legacy_pull(),
-            pull_state.tls_acceptor(),
-            pull_state.connection_timeout(),
+        let _guard = in_flight.track();
+        let guard = in_flight.track();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(guard);
+        });
+        assert_eq!(drain(&in_flight, Duration::from_millis(200)).await, (1, 1));
+    }
+
+    // we rely on our CI system using IPv6
+    #[test]
+    fn test_tcp_listener() {
+        let port = 45147;
+        assert_eq!(
+            tcp_listener(None, port, constants::DEFAULT_LISTEN_BACKLOG)
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string(),
+            format!("[::]:{}", port)
         );
+    }
 
-        match guard.try_make_task_for_addr(remote, request_handler_fut) {
-            Ok(connection_fut) => {
-                tokio::spawn(async move {
-                    if let Err(err) = connection_fut.await {
-                        warn!("{}: Request failed. ({})", remote, err)
-                    };
-                });
-            }
-            Err(error) => {
-                warn!("{}: Request failed. ({})", remote, error);
-            }
+    #[test]
+    fn test_tcp_listener_address() {
+        for address in ["127.0.0.1", "::1"] {
+            let listener = tcp_listener(
+                Some(IpAddr::from_str(address).unwrap()),
+                0,
+                constants::DEFAULT_LISTEN_BACKLOG,
+            )
+            .unwrap();
+            assert_eq!(
+                listener.local_addr().unwrap().ip(),
+                IpAddr::from_str(address).unwrap()
+            );
         }
-        debug!("{}: Handling pull request DONE (Task detached).", remote);
     }
-}
 
-fn is_addr_allowed(addr: &SocketAddr, allowed_ip: &[String]) -> bool {
-    if allowed_ip.is_empty() {
-        return true;
+    // On Windows, SO_REUSEADDR allows binding to a port which is in use
+    #[cfg(unix)]
+    #[test]
+    fn test_tcp_listener_address_in_use() {
+        let occupied = TcpListenerStd::bind("127.0.0.1:0").unwrap();
+        let port = occupied.local_addr().unwrap().port();
+        let err = tcp_listener(
+            Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            port,
+            constants::DEFAULT_LISTEN_BACKLOG,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains(&format!(
+            "Failed to listen on 127.0.0.1:{} for incoming pull connections",
+            port
+        )));
+        assert!(err.is::<PortInUse>());
+        assert_eq!(
+            crate::exit_codes::ExitCode::from(&err),
+            crate::exit_codes::ExitCode::ConfigInvalid
+        );
     }
-    let can_addr = to_canonical(addr.ip());
-    for ip in allowed_ip {
-        // Our list may contain both network, ip addresses and bad data(!)
-        // Examples: network - 192.168.1.14/24, address - 127.0.0.1
-        if let Ok(allowed_net) = ip.parse::<ipnet::IpNet>() {
-            if allowed_net.contains(&can_addr) {
-                return true;
-            }
-        }
-        if let Ok(allowed_addr) = ip.parse::<IpAddr>() {
-            if allowed_addr == can_addr {
-                return true;
-            }
-        }
-        // NOTE: no reporting about bad data here.
-        // We prefer to ignore error here: despite the possibility
-        // to have invalid settings we should check and report this once
+
+    #[test]
+    fn test_tcp_listener_all_interfaces_in_use() {
+        let occupied = TcpListenerStd::bind("0.0.0.0:0").unwrap();
+        let port = occupied.local_addr().unwrap().port();
+        let err = tcp_listener(None, port, constants::DEFAULT_LISTEN_BACKLOG).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "Failed to listen on TCP port {} for incoming pull connections: {}",
+                port,
+                PortInUse(port)
+            )
+        );
     }
-    false
-}
 
-fn to_canonical(ip_addr: IpAddr) -> IpAddr {
-    //IpAddr::to_canonical is unstable API :-(
-    match ip_addr {
-        IpAddr::V4(_v4) => ip_addr,
-        IpAddr::V6(v6) => {
-            let v4_map_subnet =
-                ipnet::Ipv6Net::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96).unwrap();
-            if v4_map_subnet.contains(&v6) {
-                IpAddr::V4(v6.to_ipv4().unwrap())
-            } else {
-                ip_addr
-            }
+    fn localhost_listening(ports: Vec<u16>) -> ListeningConfig {
+        ListeningConfig {
+            address: Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            ports,
+            backlog: constants::DEFAULT_LISTEN_BACKLOG,
+            retry_bind: 0,
         }
     }
-}
 
-async fn handle_request(
-    mut stream: TcpStream,
-    agent_output_collector: impl AgentOutputCollector,
-    remote_ip: IpAddr,
-    is_legacy_pull: bool,
-    tls_acceptor: TlsAcceptor,
-    connection_timeout: u64,
-) -> AnyhowResult<()> {
-    if is_legacy_pull {
-        return handle_legacy_pull_request(
-            stream,
-            agent_output_collector.plain_output(remote_ip),
-            connection_timeout,
-        )
-        .await;
+    fn free_port() -> u16 {
+        TcpListenerStd::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
     }
-    debug!("handle_request starts");
-
-    let handshake = with_timeout(
-        async move {
-            stream.write_all(TLS_ID).await?;
-            stream.flush().await?;
-            tls_acceptor.accept(stream).await
-        },
-        connection_timeout,
-    );
-
-    let encoded_mondata = agent_output_collector.encoded_output(remote_ip);
 
-    let (mon_data, tls_stream) = tokio::join!(encoded_mondata, handshake);
-    let mon_data = mon_data?;
-    let mut tls_stream = tls_stream?;
-    with_timeout(
-        async move {
-            tls_stream.write_all(&mon_data).await?;
-            tls_stream.flush().await
-        },
-        connection_timeout,
-    )
-    .await
-}
+    #[tokio::test]
+    async fn test_rebind_listeners_keeps_unchanged_ports() {
+        let (kept_port, removed_port, added_port) = (free_port(), free_port(), free_port());
+        let current = localhost_listening(vec![kept_port, removed_port]);
+        let mut listeners = tokio_listeners(tcp_listeners(current.clone()).unwrap()).unwrap();
+        // Waits in the backlog of the current listener, which must survive the rebind
+        let pending = std::net::TcpStream::connect(("127.0.0.1", kept_port)).unwrap();
+        let changed = localhost_listening(vec![added_port, kept_port]);
+        rebind_listeners(&mut listeners, &current, &changed).unwrap();
+        assert_eq!(
+            listeners
+                .iter()
+                .map(|listener| listener.local_addr().unwrap().port())
+                .collect::<Vec<u16>>(),
+            [added_port, kept_port]
+        );
+        let (_, remote) = listeners[1].accept().await.unwrap();
+        assert_eq!(remote, pending.local_addr().unwrap());
+        assert!(std::net::TcpStream::connect(("127.0.0.1", removed_port)).is_err());
+    }
 
-async fn handle_legacy_pull_request(
-    mut stream: TcpStream,
-    plain_mondata: impl Future<Output = AnyhowResult<Vec<u8>>>,
-    connection_timeout: u64,
-) -> AnyhowResult<()> {
-    let mon_data = plain_mondata
-        .await
-        .context("Error collecting monitoring data.")?;
+    #[tokio::test]
+    async fn test_rebind_listeners_changed_address() {
+        let port = free_port();
+        let current = localhost_listening(vec![port]);
+        let mut listeners = tokio_listeners(tcp_listeners(current.clone()).unwrap()).unwrap();
+        let changed = ListeningConfig {
+            address: Some(IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+            ..current.clone()
+        };
+        rebind_listeners(&mut listeners, &current, &changed).unwrap();
+        assert_eq!(
+            listeners[0].local_addr().unwrap(),
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))
+        );
+    }
 
-    with_timeout(
-        async move {
-            stream.write_all(&mon_data).await?;
-            stream.flush().await
-        },
-        connection_timeout,
-    )
-    .await
-}
+    // On Windows, SO_REUSEADDR allows binding to a port which is in use
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rebind_listeners_failure_keeps_listening() {
+        let occupied = TcpListenerStd::bind("127.0.0.1:0").unwrap();
+        let current = localhost_listening(vec![free_port()]);
+        let mut listeners = tokio_listeners(tcp_listeners(current.clone()).unwrap()).unwrap();
+        let current_address = listeners[0].local_addr().unwrap();
+        for changed in [
+            localhost_listening(vec![
+                current.ports[0],
+                occupied.local_addr().unwrap().port(),
+            ]),
+            ListeningConfig {
+                address: Some(IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+                ..localhost_listening(vec![occupied.local_addr().unwrap().port()])
+            },
+        ] {
+            assert!(rebind_listeners(&mut listeners, &current, &changed)
+                .unwrap_err()
+                .is::<PortInUse>());
+            assert_eq!(listeners.len(), 1);
+            assert_eq!(listeners[0].local_addr().unwrap(), current_address);
+        }
+    }
 
-async fn with_timeout<T, E: 'static + Error + Send + Sync>(
-    fut: impl Future<Output = Result<T, E>>,
-    seconds: u64,
-) -> AnyhowResult<T> {
-    Ok(timeout(Duration::from_secs(seconds), fut)
-        .await
-        .map_err(|e| anyhow!(e))??)
-}
+    #[test]
+    fn test_listening_config_display() {
+        assert_eq!(
+            localhost_listening(vec![6556, 6557]).to_string(),
+            "port(s) 6556, 6557 on 127.0.0.1"
+        );
+        assert_eq!(
+            ListeningConfig {
+                address: None,
+                ..localhost_listening(vec![6556])
+            }
+            .to_string(),
+            "port(s) 6556 on all interfaces"
+        );
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::str::FromStr;
+    #[tokio::test]
+    async fn test_tcp_listeners_retrying() {
+        let occupied = TcpListenerStd::bind("127.0.0.1:0").unwrap();
+        let listening_config = |retry_bind| ListeningConfig {
+            address: Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            ports: vec![occupied.local_addr().unwrap().port()],
+            backlog: constants::DEFAULT_LISTEN_BACKLOG,
+            retry_bind,
+        };
+        let (without_retry, with_retry) = (listening_config(0), listening_config(5));
+        assert!(tcp_listeners_retrying(without_retry)
+            .await
+            .unwrap_err()
+            .is::<PortInUse>());
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            drop(occupied);
+        });
+        assert_eq!(tcp_listeners_retrying(with_retry).await.unwrap().len(), 1);
+        release.await.unwrap();
+    }
 
-    use super::*;
-    use crate::types::AgentChannel;
+    #[test]
+    fn test_tcp_listeners() {
+        let listeners = tcp_listeners(ListeningConfig {
+            address: Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            ports: vec![45150, 45151],
+            backlog: constants::DEFAULT_LISTEN_BACKLOG,
+            retry_bind: 0,
+        })
+        .unwrap();
+        assert_eq!(
+            listeners
+                .iter()
+                .map(|listener| listener.local_addr().unwrap().port())
+                .collect::<Vec<u16>>(),
+            [45150, 45151]
+        );
+    }
 
+    // On Windows, SO_REUSEADDR allows binding to a port which is in use
+    #[cfg(unix)]
     #[test]
-    fn test_encode_data_for_transport() {
-        let mut expected_result = b"\x00\x00\x01".to_vec();
-        expected_result.append(&mut monitoring_data::compress(b"abc").unwrap());
-        let agout = AgentOutputCollectorImpl::from(AgentChannel::from("dummy"));
-        assert_eq!(agout.encode(b"abc").unwrap(), expected_result);
+    fn test_tcp_listeners_one_port_in_use() {
+        let occupied = TcpListenerStd::bind("127.0.0.1:0").unwrap();
+        let port = occupied.local_addr().unwrap().port();
+        let err = tcp_listeners(ListeningConfig {
+            address: Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            ports: vec![45152, port],
+            backlog: constants::DEFAULT_LISTEN_BACKLOG,
+            retry_bind: 0,
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains(&format!("127.0.0.1:{}", port)));
     }
 
-    fn listening_config(port: u16) -> ListeningConfig {
-        ListeningConfig {
-            addr_v4: Ipv4Addr::UNSPECIFIED,
-            addr_v6: Ipv6Addr::UNSPECIFIED,
-            port,
+    #[tokio::test]
+    async fn test_accept_any() {
+        let listeners = vec![
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+        ];
+        let mut next_listener = 0;
+        for listener in listeners.iter().rev() {
+            let _stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = accept_any(&listeners, &mut next_listener).await.unwrap();
+            assert_eq!(stream.local_addr().unwrap(), listener.local_addr().unwrap());
         }
+        assert_eq!(next_listener, 1);
     }
 
     // we rely on our CI system using IPv6
+    #[cfg(unix)]
     #[test]
-    fn test_tcp_listener() {
-        let port = 45147;
-        assert_eq!(
-            tcp_listener(listening_config(port))
+    fn test_socket_activation_fd() {
+        let own_pid = Some(std::process::id().to_string());
+        assert!(socket_activation_fd(None, None).unwrap().is_none());
+        assert!(socket_activation_fd(own_pid.clone(), None)
+            .unwrap()
+            .is_none());
+        assert!(
+            socket_activation_fd(Some(String::from("1")), Some(String::from("1")))
                 .unwrap()
-                .local_addr()
+                .is_none()
+        );
+        assert!(
+            socket_activation_fd(own_pid.clone(), Some(String::from("0")))
                 .unwrap()
-                .to_string(),
-            format!("[::]:{}", port)
+                .is_none()
+        );
+        assert_eq!(
+            socket_activation_fd(own_pid.clone(), Some(String::from("2"))).unwrap(),
+            Some(SD_LISTEN_FDS_START)
         );
+        assert!(socket_activation_fd(own_pid, Some(String::from("x"))).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_activated_tcp_listener() {
+        use std::os::unix::io::AsRawFd;
+        let inherited = TcpListenerStd::bind("127.0.0.1:0").unwrap();
+        let listener = activated_tcp_listener(inherited.as_raw_fd()).unwrap();
+        assert_ne!(listener.as_raw_fd(), inherited.as_raw_fd());
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            inherited.local_addr().unwrap()
+        );
+        // Dropping our listener must not close the inherited socket
+        drop(listener);
+        assert!(std::net::TcpStream::connect(inherited.local_addr().unwrap()).is_ok());
     }
 
-    // we rely on our CI system using IPv6
     #[test]
     fn test_tcp_listener_v6() {
-        let lc = listening_config(45148);
+        let port = 45148;
         assert_eq!(
-            tcp_listener_v6(lc.addr_v6, lc.port)
+            tcp_listener_v6(Ipv6Addr::UNSPECIFIED, port, 1)
                 .unwrap()
                 .local_addr()
                 .unwrap()
                 .to_string(),
-            format!("[::]:{}", lc.port)
+            format!("[::]:{}", port)
         );
     }
 
     #[test]
     fn test_tcp_listener_ipv4() {
-        let lc = listening_config(45149);
+        let port = 45149;
         assert_eq!(
-            tcp_listener_v4(lc.addr_v4, lc.port)
+            tcp_listener_v4(Ipv4Addr::UNSPECIFIED, port, 1)
                 .unwrap()
                 .local_addr()
                 .unwrap()
                 .to_string(),
-            format!("0.0.0.0:{}", lc.port)
+            format!("0.0.0.0:{}", port)
         );
     }
 
-    mod allowed_ip {
-        use super::*;
-        fn args_good() -> Vec<String> {
-            vec![
-                "192.168.1.14/24".to_string(), // net
-                "::1".to_string(),
-                "127.0.0.1".to_string(),
-                "fd00::/17".to_string(), // net
-                "fd05::3".to_string(),
-            ]
+    #[tokio::test]
+    async fn test_is_timeout() {
+        assert!(is_timeout(
+            &with_timeout(
+                async {
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    Ok::<(), std::io::Error>(())
+                },
+                0
+            )
+            .await
+            .unwrap_err()
+        ));
+        assert!(!is_timeout(&anyhow!("some other error")));
+    }
+
+    #[tokio::test]
+    async fn test_with_deadline() {
+        // An agent which never finishes is cut off, although no single read or write times out
+        let (_agent, agent_stream) = tokio::io::duplex(64);
+        let mut sent = vec![];
+        assert!(is_timeout(
+            &with_deadline(
+                AgentOutput::new(Box::new(agent_stream), 1024).forward_plain(&mut sent, 1),
+                0
+            )
+            .await
+            .unwrap_err()
+        ));
+        assert!(
+            with_deadline(agent_output(b"abc", 1024).forward_plain(&mut sent, 1), 1)
+                .await
+                .is_ok()
+        );
+    }
+
+    /// Takes at most 16 bytes at a time, pausing in between
+    fn slow_peer(pause: Duration) -> (tokio::io::DuplexStream, tokio::task::JoinHandle<Vec<u8>>) {
+        let (writer, mut reader) = tokio::io::duplex(16);
+        let peer = tokio::spawn(async move {
+            let mut received = vec![];
+            let mut buffer = [0; 16];
+            loop {
+                tokio::time::sleep(pause).await;
+                match reader.read(&mut buffer).await.unwrap() {
+                    0 => return received,
+                    read_bytes => received.extend_from_slice(&buffer[..read_bytes]),
+                }
+            }
+        });
+        (writer, peer)
+    }
+
+    static SLOW_DATA: [u8; 80] = [b'x'; 80];
+
+    #[tokio::test]
+    async fn test_idle_timeout_slow_peer() {
+        // Takes about 1.5s in total, but never more than 0.3s for a single step
+        let (mut writer, peer) = slow_peer(Duration::from_millis(300));
+        agent_output(&SLOW_DATA, 1024)
+            .forward_plain(&mut writer, 1)
+            .await
+            .unwrap();
+        drop(writer);
+        assert_eq!(peer.await.unwrap(), SLOW_DATA);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_stalled_peer() {
+        let (mut writer, _reader) = tokio::io::duplex(16);
+        assert!(is_timeout(
+            &agent_output(&SLOW_DATA, 1024)
+                .forward_plain(&mut writer, 1)
+                .await
+                .unwrap_err()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_total_timeout_slow_peer() {
+        // The total timeout cuts off a peer which keeps making progress, but too slowly
+        let (mut writer, _peer) = slow_peer(Duration::from_millis(300));
+        assert!(is_timeout(
+            &until_deadline(
+                agent_output(&SLOW_DATA, 1024).forward_plain(&mut writer, 1),
+                deadline(Some(1)),
+            )
+            .await
+            .unwrap_err()
+        ));
+        let mut sent = vec![];
+        assert!(until_deadline(
+            agent_output(b"abc", 1024).forward_plain(&mut sent, 1),
+            deadline(None)
+        )
+        .await
+        .is_ok());
+    }
+
+    /// Self-signed, valid until the given number of days from now
+    fn connection_valid_for(days: i64) -> config::TrustedConnection {
+        use openssl::{asn1, ec, hash, nid, pkey, x509};
+        let uuid = uuid::Uuid::new_v4();
+        let group = ec::EcGroup::from_curve_name(nid::Nid::X9_62_PRIME256V1).unwrap();
+        let key = pkey::PKey::from_ec_key(ec::EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", &uuid.to_string()).unwrap();
+        let name = name.build();
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let mut builder = x509::X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .append_extension(
+                x509::extension::BasicConstraints::new()
+                    .ca()
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        builder
+            .set_not_before(&asn1::Asn1Time::from_unix(now - 100 * 24 * 60 * 60).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&asn1::Asn1Time::from_unix(now + days * 24 * 60 * 60).unwrap())
+            .unwrap();
+        builder.sign(&key, hash::MessageDigest::sha256()).unwrap();
+        let certificate = String::from_utf8(builder.build().to_pem().unwrap()).unwrap();
+        config::TrustedConnection {
+            private_key: String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            root_cert: certificate.clone(),
+            certificate,
+            ..config::TrustedConnection::from(uuid)
+        }
+    }
+
+    #[test]
+    fn test_check_trust_material() {
+        let valid = connection_valid_for(365);
+        let expiring = connection_valid_for(3);
+        assert!(check_trust_material([&valid, &expiring].into_iter(), true).is_ok());
+
+        let expired = connection_valid_for(-1);
+        let broken = config::TrustedConnection::from(uuid::Uuid::new_v4());
+        let connections = [&valid, &expired, &broken];
+        assert!(check_trust_material(connections.into_iter(), false).is_ok());
+        let err = check_trust_material(connections.into_iter(), true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&format!("{}: Certificate has expired", expired.uuid)));
+        assert!(err.contains(&format!(
+            "{}: Invalid certificate or private key",
+            broken.uuid
+        )));
+        assert!(!err.contains(&valid.uuid.to_string()));
+    }
+
+    #[test]
+    fn test_check_client_auth() {
+        let allowlist = ["10.0.0.0/8".parse::<config::IpEntry>().unwrap()];
+        assert!(check_client_auth(false, None).is_ok());
+        assert!(check_client_auth(true, Some(&allowlist)).is_ok());
+        // An empty allowlist denies everybody, which is safe, too
+        assert!(check_client_auth(true, Some(&[])).is_ok());
+        assert!(check_client_auth(true, None).is_err());
+    }
+
+    #[test]
+    fn test_runtime() {
+        for worker_threads in [0, 1, 4] {
+            assert_eq!(runtime(worker_threads).unwrap().block_on(async { 42 }), 42);
         }
+    }
 
-        fn args_bad() -> Vec<String> {
+    mod allowed_ip {
+        use super::*;
+        fn args_good() -> Vec<config::IpEntry> {
             vec![
-                "192168114/24".to_string(), // invalid
-                "::1".to_string(),
-                "127.0.0.1".to_string(),
-                "fd00::/17".to_string(),
+                "192.168.1.14/24".parse().unwrap(), // net
+                "::1/128".parse().unwrap(),
+                "127.0.0.1/32".parse().unwrap(),
+                "fd00::/17".parse().unwrap(), // net
+                "fd05::3/128".parse().unwrap(),
             ]
         }
 
@@ -565,14 +4215,21 @@ mod tests {
             assert_eq!(to_canonical(to_ip_addr("1.2.3.4")), to_ip_addr("1.2.3.4"));
         }
         #[test]
+        fn test_no_list() {
+            assert!(is_addr_allowed(&to_sock_addr("127.0.0.2"), None));
+            assert!(is_addr_allowed(&to_sock_addr("127.0.0.1"), None));
+        }
+        #[test]
         fn test_empty_list() {
-            let args = &vec![];
-            assert!(is_addr_allowed(&to_sock_addr("127.0.0.2"), args));
-            assert!(is_addr_allowed(&to_sock_addr("127.0.0.1"), args));
+            // A configured allowlist without entries allows nobody
+            let args = Some(&[][..]);
+            assert!(!is_addr_allowed(&to_sock_addr("127.0.0.2"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("127.0.0.1"), args));
         }
         #[test]
         fn test_good_list_ipaddr() {
-            let args = &args_good();
+            let nets = args_good();
+            let args = Some(nets.as_slice());
             assert!(is_addr_allowed(&to_sock_addr("127.0.0.1"), args));
             assert!(!is_addr_allowed(&to_sock_addr("127.0.0.2"), args));
             assert!(is_addr_allowed(&to_sock_addr("[::ffff:127.0.0.1]"), args));
@@ -583,16 +4240,18 @@ mod tests {
             assert!(!is_addr_allowed(&to_sock_addr("[fd05::9]"), args));
         }
         #[test]
-        fn test_bad_list_ipaddr() {
-            let args = &args_bad();
-            assert!(is_addr_allowed(&to_sock_addr("127.0.0.1"), args));
-            assert!(!is_addr_allowed(&to_sock_addr("127.0.0.2"), args));
-            assert!(is_addr_allowed(&to_sock_addr("[::ffff:127.0.0.1]"), args));
-            assert!(!is_addr_allowed(&to_sock_addr("[::ffff:127.0.0.2]"), args));
+        fn test_ipv6_only_list() {
+            let nets = ["2001:db8::5/128".parse().unwrap()];
+            let args = Some(&nets[..]);
+            assert!(is_addr_allowed(&to_sock_addr("[2001:db8::5]"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("[2001:db8::6]"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("10.0.0.1"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("[::ffff:10.0.0.1]"), args));
         }
         #[test]
         fn test_valid_list_net() {
-            let args = &args_good();
+            let nets = args_good();
+            let args = Some(nets.as_slice());
             assert!(is_addr_allowed(&to_sock_addr("192.168.1.13"), args));
             assert!(!is_addr_allowed(&to_sock_addr("172.168.1.13"), args));
             assert!(is_addr_allowed(
@@ -606,5 +4265,21 @@ mod tests {
             assert!(is_addr_allowed(&to_sock_addr("[fd00::1]"), args));
             assert!(!is_addr_allowed(&to_sock_addr("[fd01::1]"), args));
         }
+        #[test]
+        fn test_denied() {
+            assert!(denied_by(&to_sock_addr("127.0.0.1"), &[]).is_none());
+            let args = &args_good();
+            assert!(denied_by(&to_sock_addr("192.168.1.13"), args).is_some());
+            assert!(denied_by(&to_sock_addr("[::ffff:192.168.1.13]"), args).is_some());
+            assert!(denied_by(&to_sock_addr("172.168.1.13"), args).is_none());
+            assert!(denied_by(&to_sock_addr("[fd05::3]"), args).is_some());
+            assert!(denied_by(&to_sock_addr("[fd05::9]"), args).is_none());
+            assert_eq!(
+                denied_by(&to_sock_addr("[::ffff:192.168.1.13]"), args)
+                    .unwrap()
+                    .to_string(),
+                "192.168.1.14/24"
+            );
+        }
     }
 }
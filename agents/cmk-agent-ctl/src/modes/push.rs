@@ -4,34 +4,236 @@
 
 use crate::{
     agent_receiver_api::{self, AgentData},
-    config, monitoring_data, site_spec,
+    config, constants, cron, metrics, misc, monitoring_data, site_spec, tls_debug,
     types::AgentChannel,
 };
 use anyhow::{Context, Result as AnyhowResult};
 use log::{debug, info, warn};
 use rand::Rng;
-use std::thread;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-fn sleep_randomly() {
-    let random_period = rand::thread_rng().gen_range(0..59);
-    debug!("Sleeping {}s to avoid DDOSing of sites", random_period);
-    thread::sleep(Duration::from_secs(random_period));
+struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    fn new(push_retry_config: &config::PushRetryConfig) -> Self {
+        Self {
+            base: push_retry_config.base,
+            max: push_retry_config.max,
+            failures: 0,
+        }
+    }
+
+    /// Delay before the next attempt after another failure: the base delay, doubled for every
+    /// previous failure and capped at the maximum. The second half of the delay is randomized,
+    /// st. hosts which lost their site at the same time do not reconnect in lockstep.
+    fn next_delay(&mut self) -> Duration {
+        let exponential = self
+            .base
+            .checked_mul(2_u32.saturating_pow(self.failures))
+            .unwrap_or(self.max)
+            .min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        let half = exponential / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=exponential - half)
+    }
+}
+
+/// Delay until the next interval tick after now. Ticks count from the start of the first push,
+/// st. the duration of the pushes doesn't accumulate. Ticks which passed during a long push
+/// are skipped.
+fn interval_delay(first_push: Instant, interval: Duration, now: Instant) -> Duration {
+    let elapsed = now.saturating_duration_since(first_push);
+    let ticks = elapsed.as_nanos() / interval.as_nanos() + 1;
+    let next_tick = interval.as_nanos() * ticks - elapsed.as_nanos();
+    Duration::from_nanos(u64::try_from(next_tick).unwrap_or(u64::MAX))
+}
+
+fn cron_delay(schedule: &cron::CronSchedule, now: time::OffsetDateTime) -> Duration {
+    match schedule.next_after(now) {
+        Some(next) => Duration::try_from(next - now).unwrap_or(Duration::ZERO),
+        // Checked when parsing the expression, but better safe than sorry
+        None => Duration::from_secs(constants::PUSH_INTERVAL),
+    }
+}
+
+/// How long to wait after a successful push which started at the given time. A push taking
+/// longer than scheduled holds up the next one, which then starts late with the then current
+/// agent output, so we never queue up agent output. Such skipped pushes are counted, since they
+/// mean that the receiver does not keep up.
+fn scheduled_delay(
+    schedule: &config::PushSchedule,
+    first_push: Instant,
+    push_started: (Instant, time::OffsetDateTime),
+    skipped_pushes: &mut u64,
+) -> Duration {
+    match schedule {
+        config::PushSchedule::Interval(interval) => {
+            if push_started.0.elapsed() > *interval {
+                *skipped_pushes += 1;
+                warn!(
+                    "Push took longer than the push interval of {}s, skipping to the next one ({} push(es) skipped so far)",
+                    interval.as_secs(),
+                    skipped_pushes
+                );
+            }
+            interval_delay(first_push, *interval, Instant::now())
+        }
+        config::PushSchedule::Cron(schedule) => {
+            let now = time::OffsetDateTime::now_utc();
+            if schedule.next_after(push_started.1) != schedule.next_after(now) {
+                *skipped_pushes += 1;
+                warn!(
+                    "Push took longer than scheduled by '{}', skipping to the next one ({} push(es) skipped so far)",
+                    schedule,
+                    skipped_pushes
+                );
+            }
+            cron_delay(schedule, now)
+        }
+    }
+}
+
+/// Connections whose last push failed are retried on a backoff of their own, st. an unreachable
+/// site neither holds up the regular pushes to the other sites nor is retried at their pace.
+struct Retries<'a> {
+    push_retry_config: &'a config::PushRetryConfig,
+    pending: HashMap<site_spec::SiteID, (Backoff, Instant)>,
+}
+
+impl<'a> Retries<'a> {
+    fn new(push_retry_config: &'a config::PushRetryConfig) -> Self {
+        Self {
+            push_retry_config,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Connections which are retried are only due once their retry is, the others on schedule
+    fn is_due(&self, site_id: &site_spec::SiteID, now: Instant, scheduled: bool) -> bool {
+        match self.pending.get(site_id) {
+            Some((_, retry_at)) => *retry_at <= now,
+            None => scheduled,
+        }
+    }
+
+    fn succeeded(&mut self, site_id: &site_spec::SiteID) {
+        self.pending.remove(site_id);
+    }
+
+    fn failed(&mut self, site_id: &site_spec::SiteID, now: Instant) {
+        let (backoff, retry_at) = self
+            .pending
+            .entry(site_id.clone())
+            .or_insert_with(|| (Backoff::new(self.push_retry_config), now));
+        let delay = backoff.next_delay();
+        *retry_at = now + delay;
+        warn!(
+            site = site_id.to_string();
+            "{}: Retrying push in {}s", site_id, delay.as_secs()
+        );
+    }
+
+    fn retain_registered(&mut self, registry: &config::Registry) {
+        self.pending
+            .retain(|site_id, _| registry.push_connections().any(|(id, _)| id == site_id));
+    }
+
+    fn next_retry(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, retry_at)| *retry_at).min()
+    }
 }
 
 pub fn push(
     mut registry: config::Registry,
     client_config: config::ClientConfig,
     agent_channel: AgentChannel,
+    push_retry_config: config::PushRetryConfig,
+    push_schedule_config: config::PushScheduleConfig,
+    push_results_path: PathBuf,
 ) -> AnyhowResult<()> {
-    sleep_randomly();
+    let mut push_results = PushResultsFile::load(&registry, push_results_path);
+    let shutdown = misc::shutdown_signal();
+    let mut retries = Retries::new(&push_retry_config);
+    let schedule = push_schedule_config.schedule;
+    let mut skipped_pushes = 0;
+
+    let initial_delay = match &schedule {
+        config::PushSchedule::Interval(_) => {
+            let random_period = Duration::from_secs(rand::thread_rng().gen_range(0..59));
+            debug!(
+                "Sleeping {}s to avoid DDOSing of sites",
+                random_period.as_secs()
+            );
+            random_period
+        }
+        // Aligned pushes are what the user asked for, so don't spread them
+        config::PushSchedule::Cron(cron_schedule) => {
+            let delay = cron_delay(cron_schedule, time::OffsetDateTime::now_utc());
+            debug!(
+                "Waiting {}s for the first push scheduled by '{}'",
+                delay.as_secs(),
+                cron_schedule
+            );
+            delay
+        }
+    };
+    if !misc::wait(&shutdown, initial_delay) {
+        return Ok(());
+    }
+    let first_push = Instant::now();
+    let mut next_scheduled = first_push;
     loop {
         registry.refresh()?;
-        let begin = Instant::now();
-        if let Err(error) = handle_push_cycle(&registry, &client_config, &agent_channel) {
-            warn!("Error running push cycle. ({})", error);
+        retries.retain_registered(&registry);
+        let push_started = (Instant::now(), time::OffsetDateTime::now_utc());
+        let scheduled = push_started.0 >= next_scheduled;
+        let is_due =
+            |site_id: &site_spec::SiteID| retries.is_due(site_id, push_started.0, scheduled);
+        let cycle_result = push_cycle(
+            &registry,
+            &client_config,
+            &agent_channel,
+            &mut push_results.results,
+            is_due,
+        );
+        push_results.save(&registry);
+        match cycle_result {
+            Ok(outcomes) => {
+                for (site_id, outcome) in outcomes {
+                    match outcome {
+                        Ok(()) => retries.succeeded(&site_id),
+                        Err(_) => retries.failed(&site_id, Instant::now()),
+                    }
+                }
+            }
+            Err(error) => {
+                warn!("Error running push cycle. ({})", error);
+                let due = registry
+                    .push_connections()
+                    .map(|(site_id, _)| site_id.clone())
+                    .filter(|site_id| is_due(site_id))
+                    .collect::<Vec<_>>();
+                for site_id in due {
+                    retries.failed(&site_id, Instant::now());
+                }
+            }
         };
-        thread::sleep(Duration::from_secs(60).saturating_sub(begin.elapsed()));
+        if scheduled {
+            next_scheduled = Instant::now()
+                + scheduled_delay(&schedule, first_push, push_started, &mut skipped_pushes);
+        }
+        let wake_up = retries
+            .next_retry()
+            .map_or(next_scheduled, |next_retry| next_retry.min(next_scheduled));
+        if !misc::wait(&shutdown, wake_up.saturating_duration_since(Instant::now())) {
+            return Ok(());
+        }
     }
 }
 
@@ -39,33 +241,287 @@ pub fn handle_push_cycle(
     registry: &config::Registry,
     client_config: &config::ClientConfig,
     agent_channel: &AgentChannel,
+    push_results_path: &Path,
 ) -> AnyhowResult<()> {
-    if registry.push_is_empty() {
-        return Ok(());
+    let mut push_results = PushResultsFile::load(registry, PathBuf::from(push_results_path));
+    let cycle_result = push_cycle(
+        registry,
+        client_config,
+        agent_channel,
+        &mut push_results.results,
+        |_| true,
+    );
+    push_results.save(registry);
+    let failures = cycle_result?
+        .into_iter()
+        .filter_map(|(_, outcome)| outcome.err())
+        .collect::<Vec<_>>();
+    let failed = failures.len();
+    match failures.into_iter().next() {
+        None => Ok(()),
+        // The first error determines the exit code
+        Some(error) => Err(error.context(format!(
+            "Pushing agent output failed for {} connection(s)",
+            failed
+        ))),
     }
+}
 
-    debug!("Handling registered push connections.");
+fn load_push_results(path: &Path) -> AnyhowResult<metrics::PushResults> {
+    <metrics::PushResults as config::JSONLoaderMissingSafe>::load_missing_safe(path)
+}
+
+/// The results of the most recent pushes, which are written to disk after every push cycle, st.
+/// the status command can report them. Nothing is written if the registry is read-only.
+struct PushResultsFile {
+    results: metrics::PushResults,
+    path: Option<PathBuf>,
+}
 
-    let compressed_mon_data = monitoring_data::compress(
+impl PushResultsFile {
+    fn load(registry: &config::Registry, path: PathBuf) -> Self {
+        // Keep the time of the last success across restarts
+        let results = load_push_results(&path).unwrap_or_else(|error| {
+            warn!(
+                "Failed to load results of previous pushes from {:?}, starting afresh. ({})",
+                path, error
+            );
+            metrics::PushResults::default()
+        });
+        Self {
+            results,
+            path: match registry.is_read_only() {
+                true => None,
+                false => Some(path),
+            },
+        }
+    }
+
+    fn save(&mut self, registry: &config::Registry) {
+        self.results.retain_registered(registry);
+        if let Some(path) = &self.path {
+            if let Err(error) = self.results.save(path) {
+                warn!(
+                    "Failed to write results of push cycle to {:?}. ({})",
+                    path, error
+                );
+            }
+        }
+    }
+}
+
+fn collect_compressed_monitoring_data(agent_channel: &AgentChannel) -> AnyhowResult<Vec<u8>> {
+    monitoring_data::compress(
         &monitoring_data::collect(agent_channel).context("Error collecting agent output")?,
     )
-    .context("Error compressing agent output")?;
-
-    for (site_id, connection) in registry.push_connections() {
-        info!("{}: Pushing agent output", site_id);
-        let site_url = site_spec::make_site_url(site_id, &connection.receiver_port)
-            .context("Failed to construct URL for pushing data")?;
-        if let Err(error) = (agent_receiver_api::Api {
-            use_proxy: client_config.use_proxy,
+    .context("Error compressing agent output")
+}
+
+/// Push to all push connections which are due and return the outcome per site. The outcome of
+/// every attempt is also recorded in the given results.
+/// Every push opens a new TLS connection to the receiver, so there is no connection which could
+/// go stale after a NAT timeout or a restart of the receiver. Still, we log when pushing
+/// succeeds again after a failure, st. this can be told apart from a fresh start.
+fn push_cycle(
+    registry: &config::Registry,
+    client_config: &config::ClientConfig,
+    agent_channel: &AgentChannel,
+    push_results: &mut metrics::PushResults,
+    is_due: impl Fn(&site_spec::SiteID) -> bool,
+) -> AnyhowResult<Vec<(site_spec::SiteID, AnyhowResult<()>)>> {
+    let push_connections = registry
+        .push_connections()
+        .filter(|(site_id, _)| is_due(site_id))
+        .filter(|(site_id, connection)| {
+            if connection.trust.agent_output_disabled {
+                debug!("{}: Agent output is disabled, not pushing", site_id);
+            }
+            !connection.trust.agent_output_disabled
+        })
+        .collect::<Vec<_>>();
+    if push_connections.is_empty() {
+        return Ok(vec![]);
+    }
+
+    debug!("Handling registered push connections.");
+
+    let started = time::OffsetDateTime::now_utc().unix_timestamp();
+    let compressed_mon_data = match collect_compressed_monitoring_data(agent_channel) {
+        Ok(compressed_mon_data) => compressed_mon_data,
+        Err(error) => {
+            for (_, connection) in &push_connections {
+                push_results.record(&connection.trust.uuid, started, Some(&error));
+            }
+            return Err(error);
+        }
+    };
+
+    let mut outcomes = vec![];
+    for (site_id, connection) in push_connections {
+        info!(
+            site = site_id.to_string(), uuid = connection.trust.uuid.to_string();
+            "{}: Pushing agent output", site_id
+        );
+        let site_url = site_spec::make_site_url(
+            site_id,
+            &connection.receiver_port,
+            connection.path_prefix.as_deref(),
+        )
+        .context("Failed to construct URL for pushing data")?;
+        let result = (agent_receiver_api::Api {
+            // A receiver taking in the data slowly must not hold up the other sites or the next push
+            request_timeout: Some(client_config.push_timeout),
+            tcp_keepalive: client_config.push_keepalive,
+            local_address: client_config.push_source_address,
+            ..agent_receiver_api::Api::from(client_config)
         })
         .agent_data(
             &site_url,
             &connection.trust,
             &monitoring_data::compression_header_info().push,
             &compressed_mon_data,
-        ) {
-            warn!("{}: Error pushing agent output. ({})", site_url, error);
+        );
+        let failed_before = push_results
+            .get(&connection.trust.uuid)
+            .is_some_and(|previous| !previous.success);
+        push_results.record(
+            &connection.trust.uuid,
+            time::OffsetDateTime::now_utc().unix_timestamp(),
+            result.as_ref().err(),
+        );
+        if result.is_ok() && failed_before {
+            info!(
+                site = site_id.to_string(), uuid = connection.trust.uuid.to_string();
+                "{}: Reconnected, pushing agent output succeeded again", site_id
+            );
+        }
+        if let Err(error) = &result {
+            tls_debug::log_handshake_failure(&site_url, error);
+            warn!(
+                site = site_id.to_string(), uuid = connection.trust.uuid.to_string();
+                "{}: Error pushing agent output. ({})", site_url, error
+            );
+        };
+        outcomes.push((site_id.clone(), result.map(|_| ())));
+    }
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    fn backoff(base: u64, max: u64) -> Backoff {
+        Backoff::new(&config::PushRetryConfig {
+            base: Duration::from_secs(base),
+            max: Duration::from_secs(max),
+        })
+    }
+
+    fn assert_within(delay: Duration, expected: u64) {
+        assert!(delay >= Duration::from_secs(expected) / 2);
+        assert!(delay <= Duration::from_secs(expected));
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially() {
+        let mut backoff = backoff(10, 1000);
+        for expected in [10, 20, 40, 80, 160] {
+            assert_within(backoff.next_delay(), expected);
+        }
+    }
+
+    #[test]
+    fn test_backoff_capped() {
+        let mut backoff = backoff(10, 30);
+        for expected in [10, 20, 30, 30, 30] {
+            assert_within(backoff.next_delay(), expected);
+        }
+        backoff.failures = u32::MAX;
+        assert_within(backoff.next_delay(), 30);
+    }
+
+    #[test]
+    fn test_retries_per_site() {
+        let push_retry_config = config::PushRetryConfig {
+            base: Duration::from_secs(10),
+            max: Duration::from_secs(1000),
         };
+        let mut retries = Retries::new(&push_retry_config);
+        let failing = site_spec::SiteID::from_str("server/failing").unwrap();
+        let healthy = site_spec::SiteID::from_str("server/healthy").unwrap();
+        let now = Instant::now();
+        retries.failed(&failing, now);
+        // The failing site waits for its retry, even if a regular push is due
+        assert!(!retries.is_due(&failing, now, true));
+        assert!(retries.is_due(&failing, now + Duration::from_secs(10), false));
+        assert!(retries.is_due(&healthy, now, true));
+        assert!(!retries.is_due(&healthy, now + Duration::from_secs(10), false));
+        // Another failure doubles the delay of this site only
+        retries.failed(&failing, now);
+        assert!(!retries.is_due(&failing, now + Duration::from_secs(9), false));
+        assert!(retries.next_retry().unwrap() <= now + Duration::from_secs(20));
+        retries.succeeded(&failing);
+        assert!(retries.is_due(&failing, now, true));
+        assert!(retries.next_retry().is_none());
+    }
+
+    #[test]
+    fn test_interval_delay() {
+        let first_push = Instant::now();
+        let interval = Duration::from_secs(60);
+        assert_eq!(
+            interval_delay(first_push, interval, first_push + Duration::from_secs(10)),
+            Duration::from_secs(50)
+        );
+        // No drift, even though the previous push took a while
+        assert_eq!(
+            interval_delay(first_push, interval, first_push + Duration::from_secs(70)),
+            Duration::from_secs(50)
+        );
+        // A push which took longer than the interval skips the next tick
+        assert_eq!(
+            interval_delay(first_push, interval, first_push + Duration::from_secs(130)),
+            Duration::from_secs(50)
+        );
+        assert_eq!(
+            interval_delay(first_push, interval, first_push + interval),
+            interval
+        );
+    }
+
+    #[test]
+    fn test_scheduled_delay_counts_skipped_pushes() {
+        let schedule = config::PushSchedule::Interval(Duration::from_secs(60));
+        let now = Instant::now();
+        let mut skipped_pushes = 0;
+        scheduled_delay(
+            &schedule,
+            now,
+            (now, time::OffsetDateTime::now_utc()),
+            &mut skipped_pushes,
+        );
+        assert_eq!(skipped_pushes, 0);
+        let long_ago = now - Duration::from_secs(90);
+        let delay = scheduled_delay(
+            &schedule,
+            long_ago,
+            (long_ago, time::OffsetDateTime::now_utc()),
+            &mut skipped_pushes,
+        );
+        assert_eq!(skipped_pushes, 1);
+        assert!(delay <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_cron_delay() {
+        let schedule = <cron::CronSchedule as std::str::FromStr>::from_str("*/5 * * * *").unwrap();
+        let now = time::Date::from_calendar_date(2022, time::Month::June, 1)
+            .unwrap()
+            .with_hms(10, 12, 30)
+            .unwrap()
+            .assume_utc();
+        assert_eq!(cron_delay(&schedule, now), Duration::from_secs(150));
     }
-    Ok(())
 }
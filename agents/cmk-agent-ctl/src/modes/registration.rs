@@ -2,21 +2,50 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use crate::{agent_receiver_api, certs, config, constants, misc, site_spec, types};
-use anyhow::{anyhow, Context, Result as AnyhowResult};
-use log::{error, info};
+use crate::{
+    agent_receiver_api, certs, cli, clock_skew, config, constants, exit_codes, misc, proxy,
+    site_spec, types,
+};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use log::{error, info, warn};
+use serde_with::DisplayFromStr;
+use std::io::IsTerminal;
+
+// What the post-register hook gets to know about the registration
+const HOOK_SITE_ID_VAR: &str = "CMK_SITE_ID";
+const HOOK_UUID_VAR: &str = "CMK_CONNECTION_UUID";
+const HOOK_CONNECTION_TYPE_VAR: &str = "CMK_CONNECTION_TYPE";
+const HOOK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
 
 trait TrustEstablishing {
     fn prompt_server_certificate(&self, server: &str, port: &u16) -> AnyhowResult<()>;
     fn prompt_password(&self, user: &str) -> AnyhowResult<String>;
+    fn server_cert_fingerprint(&self, server: &str, port: &u16) -> AnyhowResult<String>;
+    fn server_cert_is_self_signed(&self, server: &str, port: &u16) -> AnyhowResult<bool>;
 }
 
-struct InteractiveTrust {}
+struct InteractiveTrust {
+    use_proxy: bool,
+    proxy: Option<reqwest::Url>,
+}
 
 impl InteractiveTrust {
-    fn display_cert(server: &str, port: &u16) -> AnyhowResult<()> {
-        let pem_str = certs::fetch_server_cert_pem(server, port)?;
-        let pem = certs::parse_pem(&pem_str)?;
+    fn new(client_config: &config::ClientConfig) -> Self {
+        Self {
+            use_proxy: client_config.use_proxy,
+            proxy: client_config.proxy.clone(),
+        }
+    }
+
+    fn proxy(&self, server: &str) -> Option<&reqwest::Url> {
+        proxy::for_host(self.use_proxy, self.proxy.as_ref(), server).1
+    }
+
+    /// Returns whether the certificate is self-signed
+    fn display_cert(&self, server: &str, port: &u16) -> AnyhowResult<bool> {
+        let chain = certs::fetch_server_cert_chain_pem(server, port, self.proxy(server))?;
+        let pem_str = chain.first().context("Server presented no certificate")?;
+        let pem = certs::parse_pem(pem_str)?;
         let x509 = pem.parse_x509()?;
         let validity = x509.validity();
 
@@ -34,7 +63,11 @@ impl InteractiveTrust {
             validity.not_before.to_rfc2822(),
             validity.not_after.to_rfc2822(),
         );
-        Ok(())
+        eprintln!(
+            "SHA-256 fingerprint:\n\t{}",
+            certs::fingerprint_sha256(&pem.contents)?
+        );
+        certs::is_self_signed_leaf(&chain)
     }
 }
 
@@ -44,9 +77,15 @@ impl TrustEstablishing for InteractiveTrust {
             "Attempting to register at {}, port {}. Server certificate details:\n",
             server, port,
         );
-        InteractiveTrust::display_cert(server, port)?;
+        let self_signed = self.display_cert(server, port)?;
         eprintln!();
-        eprintln!("Do you want to establish this connection? [Y/n]");
+        if self_signed {
+            warn_self_signed(server, port);
+            // No default answer, the user has to confirm explicitly
+            eprintln!("Do you want to establish this connection anyway? [y/n]");
+        } else {
+            eprintln!("Do you want to establish this connection? [Y/n]");
+        }
         eprint!("> ");
         loop {
             let mut answer = String::new();
@@ -54,7 +93,8 @@ impl TrustEstablishing for InteractiveTrust {
                 .read_line(&mut answer)
                 .context("Failed to read answer from standard input")?;
             match answer.to_lowercase().trim() {
-                "y" | "" => return Ok(()),
+                "y" => return Ok(()),
+                "" if !self_signed => return Ok(()),
                 "n" => {
                     return Err(anyhow!(format!(
                         "Cannot continue without trusting {}, port {}",
@@ -70,10 +110,60 @@ impl TrustEstablishing for InteractiveTrust {
     }
 
     fn prompt_password(&self, user: &str) -> AnyhowResult<String> {
+        // Otherwise, we'd wait forever for a password nobody is going to type
+        if !std::io::stdin().is_terminal() {
+            bail!(
+                "No password for '{}' given and no terminal to prompt for it. Use --password-stdin \
+                 or --credential-helper for non-interactive registration.",
+                user
+            )
+        }
         eprintln!();
         eprint!("Please enter password for '{}'\n> ", user);
         rpassword::read_password().context("Failed to obtain API password")
     }
+
+    fn server_cert_fingerprint(&self, server: &str, port: &u16) -> AnyhowResult<String> {
+        certs::fetch_server_cert_fingerprint(server, port, self.proxy(server))
+    }
+
+    fn server_cert_is_self_signed(&self, server: &str, port: &u16) -> AnyhowResult<bool> {
+        certs::is_self_signed_leaf(&certs::fetch_server_cert_chain_pem(
+            server,
+            port,
+            self.proxy(server),
+        )?)
+    }
+}
+
+fn warn_self_signed(server: &str, port: &u16) {
+    eprintln!(
+        "WARNING: The server certificate of {}, port {} is self-signed, it is not issued by a \
+         separate CA. This is typical for misconfigured test setups, make sure you are \
+         connecting to the right agent receiver.",
+        server, port
+    );
+}
+
+/// Without the interactive prompt, a self-signed server certificate is only accepted on request
+fn check_self_signed(
+    config: &config::RegistrationConnectionConfig,
+    trust_establisher: &impl TrustEstablishing,
+) -> AnyhowResult<()> {
+    if !trust_establisher
+        .server_cert_is_self_signed(&config.site_id.server, &config.receiver_port)?
+    {
+        return Ok(());
+    }
+    warn_self_signed(&config.site_id.server, &config.receiver_port);
+    if !config.accept_self_signed {
+        bail!(
+            "Refusing to register with self-signed server certificate of {}, port {}, use --accept-self-signed to proceed",
+            config.site_id.server,
+            config.receiver_port
+        )
+    }
+    Ok(())
 }
 
 fn registration_server_cert<'a>(
@@ -91,7 +181,26 @@ fn registration_server_cert<'a>(
             Ok(Some(cert.as_str()))
         }
         None => {
-            if !config.trust_server_cert {
+            if let Some(trusted_fingerprint) = &config.trusted_fingerprint {
+                let fingerprint = trust_establisher
+                    .server_cert_fingerprint(&config.site_id.server, &config.receiver_port)?;
+                if &fingerprint != trusted_fingerprint {
+                    bail!(
+                        "Server certificate fingerprint {} of {}, port {} does not match the trusted fingerprint {}",
+                        fingerprint,
+                        config.site_id.server,
+                        config.receiver_port,
+                        trusted_fingerprint
+                    )
+                }
+                eprintln!(
+                    "Server certificate fingerprint {} matches the trusted fingerprint.",
+                    fingerprint
+                );
+                check_self_signed(config, trust_establisher)?;
+            } else if config.trust_server_cert {
+                check_self_signed(config, trust_establisher)?;
+            } else {
                 trust_establisher
                     .prompt_server_certificate(&config.site_id.server, &config.receiver_port)?;
             }
@@ -105,20 +214,25 @@ fn prepare_registration(
     agent_rec_api: &impl agent_receiver_api::Pairing,
     trust_establisher: &impl TrustEstablishing,
 ) -> AnyhowResult<(types::Credentials, PairingResult)> {
+    if let Some(identity) = &config.pkcs12_identity {
+        return Ok((
+            credentials(config, trust_establisher)?,
+            pairing_result_from_pkcs12(config, identity)?,
+        ));
+    }
     let uuid = uuid::Uuid::new_v4();
-    let (csr, private_key) = certs::make_csr(&uuid.to_string()).context("Error creating CSR.")?;
+    let (csr, private_key) =
+        certs::make_csr(&uuid.to_string(), config.key_type, &config.csr_fields)
+            .context("Error creating CSR.")?;
     let root_cert = registration_server_cert(config, trust_establisher)?;
-    let credentials = types::Credentials {
-        username: config.username.clone(),
-        password: if let Some(password) = &config.password {
-            String::from(password)
-        } else {
-            trust_establisher.prompt_password(&config.username)?
-        },
-    };
+    let credentials = credentials(config, trust_establisher)?;
     let pairing_response = agent_rec_api
         .pair(
-            &site_spec::make_site_url(&config.site_id, &config.receiver_port)?,
+            &site_spec::make_site_url(
+                &config.site_id,
+                &config.receiver_port,
+                config.path_prefix.as_deref(),
+            )?,
             root_cert,
             csr,
             &credentials,
@@ -127,6 +241,12 @@ fn prepare_registration(
             "Error pairing with {}, port {}",
             &config.site_id, &config.receiver_port
         ))?;
+    // The certificate was just issued, so it tells the time of the site
+    clock_skew::warn_certificate_skew(
+        &format!("The certificate issued by {}", config.site_id),
+        &pairing_response.client_cert,
+        config.client_config.clock_skew_threshold,
+    );
     Ok((
         credentials,
         PairingResult {
@@ -137,6 +257,54 @@ fn prepare_registration(
     ))
 }
 
+fn credentials(
+    config: &config::RegistrationConnectionConfig,
+    trust_establisher: &impl TrustEstablishing,
+) -> AnyhowResult<types::Credentials> {
+    let credentials = &config.credentials;
+    Ok(types::Credentials {
+        username: credentials.username.clone(),
+        password: if let Some(password) = &credentials.password {
+            String::from(password)
+        } else {
+            trust_establisher.prompt_password(&credentials.username)?
+        },
+    })
+}
+
+/// With an identity issued by some other PKI, there is nothing to pair. We still need the site CA
+/// to verify the agent receiver, which has to be given, since the CA certificates of the bundle
+/// belong to the other PKI. The intermediate CA certificates of the bundle are stored along with
+/// our certificate, since the site needs them to build the chain up to its root.
+fn pairing_result_from_pkcs12(
+    config: &config::RegistrationConnectionConfig,
+    identity: &certs::Pkcs12Identity,
+) -> AnyhowResult<PairingResult> {
+    let cn_checker =
+        certs::CNCheckerUUID::try_from(&certs::rustls_certificate(&identity.certificate)?)?;
+    let uuid = uuid::Uuid::parse_str(cn_checker.cn()).context(format!(
+        "The CN of the certificate in the PKCS#12 bundle has to be a UUID, got '{}'",
+        cn_checker.cn()
+    ))?;
+    let root_cert = config.root_certificate.clone().context(
+        "Registering with a PKCS#12 bundle requires the CA certificate of the site, use --site-ca",
+    )?;
+    let mut client_cert = identity.certificate.clone();
+    for ca_certificate in &identity.ca_certificates {
+        if !certs::is_self_signed(ca_certificate)? {
+            client_cert.push_str(ca_certificate);
+        }
+    }
+    Ok(PairingResult {
+        uuid,
+        private_key: identity.private_key.clone(),
+        pairing_response: agent_receiver_api::PairingResponse {
+            root_cert,
+            client_cert,
+        },
+    })
+}
+
 struct PairingResult {
     uuid: uuid::Uuid,
     private_key: String,
@@ -151,6 +319,9 @@ trait RegistrationEndpointCall {
         pairing_result: &PairingResult,
         agent_rec_api: &impl agent_receiver_api::Registration,
     ) -> AnyhowResult<()>;
+
+    /// The host name to store alongside the connection, if we registered with one
+    fn host_name(&self) -> Option<&str>;
 }
 
 struct HostNameRegistration<'a> {
@@ -167,7 +338,11 @@ impl RegistrationEndpointCall for HostNameRegistration<'_> {
     ) -> AnyhowResult<()> {
         agent_rec_api
             .register_with_hostname(
-                &site_spec::make_site_url(&config.site_id, &config.receiver_port)?,
+                &site_spec::make_site_url(
+                    &config.site_id,
+                    &config.receiver_port,
+                    config.path_prefix.as_deref(),
+                )?,
                 &pairing_result.pairing_response.root_cert,
                 credentials,
                 &pairing_result.uuid,
@@ -178,6 +353,10 @@ impl RegistrationEndpointCall for HostNameRegistration<'_> {
                 &config.site_id, &config.receiver_port
             ))
     }
+
+    fn host_name(&self) -> Option<&str> {
+        Some(self.host_name)
+    }
 }
 
 struct AgentLabelsRegistration<'a> {
@@ -194,7 +373,11 @@ impl RegistrationEndpointCall for AgentLabelsRegistration<'_> {
     ) -> AnyhowResult<()> {
         agent_rec_api
             .register_with_agent_labels(
-                &site_spec::make_site_url(&config.site_id, &config.receiver_port)?,
+                &site_spec::make_site_url(
+                    &config.site_id,
+                    &config.receiver_port,
+                    config.path_prefix.as_deref(),
+                )?,
                 &pairing_result.pairing_response.root_cert,
                 credentials,
                 &pairing_result.uuid,
@@ -205,6 +388,10 @@ impl RegistrationEndpointCall for AgentLabelsRegistration<'_> {
                 &config.site_id, &config.receiver_port
             ))
     }
+
+    fn host_name(&self) -> Option<&str> {
+        None
+    }
 }
 
 fn post_registration_conn_type(
@@ -214,7 +401,11 @@ fn post_registration_conn_type(
 ) -> AnyhowResult<config::ConnectionType> {
     loop {
         let status_resp = agent_rec_api.status(
-            &site_spec::make_site_url(site_id, &connection.receiver_port)?,
+            &site_spec::make_site_url(
+                site_id,
+                &connection.receiver_port,
+                connection.path_prefix.as_deref(),
+            )?,
             &connection.trust,
         )?;
         if let Some(agent_receiver_api::HostStatus::Declined) = status_resp.status {
@@ -230,7 +421,8 @@ fn post_registration_conn_type(
         if let Some(ct) = status_resp.connection_type {
             return Ok(ct);
         }
-        println!("Waiting for registration to complete on Checkmk instance, sleeping 20 s");
+        // Not on stdout, which is reserved for the result in case of JSON output
+        eprintln!("Waiting for registration to complete on Checkmk instance, sleeping 20 s");
         std::thread::sleep(std::time::Duration::from_secs(20));
     }
 }
@@ -244,19 +436,41 @@ fn direct_registration(
     trust_establisher: &impl TrustEstablishing,
     endpoint_call: &impl RegistrationEndpointCall,
 ) -> AnyhowResult<()> {
+    // Before anything happens at the site, st. we don't leave a host registered there which we
+    // have no connection for
+    registry.ensure_room_for(&config.site_id)?;
     let (credentials, pairing_result) =
         prepare_registration(config, agent_rec_api, trust_establisher)?;
 
     endpoint_call.call(config, &credentials, &pairing_result, agent_rec_api)?;
 
+    // The status query below already verifies the pin, st. we fail early if the certificate we
+    // just fetched is not signed by the site CA.
+    let pinned_fingerprint = if config.pin_fingerprint {
+        let fingerprint = trust_establisher
+            .server_cert_fingerprint(&config.site_id.server, &config.receiver_port)
+            .context("Failed to obtain the fingerprint of the server certificate")?;
+        info!(site = config.site_id.to_string(); "Pinning server certificate fingerprint {}", fingerprint);
+        Some(fingerprint)
+    } else {
+        None
+    };
+
     let connection = config::TrustedConnectionWithRemote {
         trust: config::TrustedConnection {
             uuid: pairing_result.uuid,
             private_key: pairing_result.private_key,
             certificate: pairing_result.pairing_response.client_cert,
             root_cert: pairing_result.pairing_response.root_cert,
+            pinned_fingerprint,
+            pinned_client_fingerprints: vec![],
+            labels: config.labels.clone(),
+            agent_output_disabled: config.agent_output_disabled,
+            min_tls_version: None,
         },
         receiver_port: config.receiver_port,
+        host_name: endpoint_call.host_name().map(String::from),
+        path_prefix: config.path_prefix.clone(),
     };
 
     registry.register_connection(
@@ -265,11 +479,87 @@ fn direct_registration(
         connection,
     );
 
-    registry.save()?;
-
     Ok(())
 }
 
+struct DryRunResult {
+    site_id: site_spec::SiteID,
+    receiver_port: u16,
+    connection_type: Option<config::ConnectionType>,
+}
+
+impl DryRunResult {
+    fn connection_type(&self) -> String {
+        match &self.connection_type {
+            Some(connection_type) => connection_type.to_string(),
+            None => String::from("decided by the site upon registration"),
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "would register, receiver port {}, connection type {}",
+            self.receiver_port,
+            self.connection_type()
+        )
+    }
+}
+
+impl std::fmt::Display for DryRunResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Site ID:         {}\nReceiver port:   {}\nConnection type: {}",
+            self.site_id,
+            self.receiver_port,
+            self.connection_type()
+        )
+    }
+}
+
+/// Pair with the site to check connectivity, trust and credentials, but do not register.
+/// The site has no way to check the credentials alone, so this is a real pairing call: The site
+/// issues a certificate for a fresh UUID, which we drop. No host gets registered for it.
+fn dry_run_registration(
+    config: &config::RegistrationConnectionConfig,
+    agent_rec_api: &(impl agent_receiver_api::Pairing + agent_receiver_api::Status),
+    trust_establisher: &impl TrustEstablishing,
+) -> AnyhowResult<DryRunResult> {
+    let (_, pairing_result) = prepare_registration(config, agent_rec_api, trust_establisher)?;
+
+    // The connection type is only known if the host is already registered at the site
+    let connection_type = match agent_rec_api.status(
+        &site_spec::make_site_url(
+            &config.site_id,
+            &config.receiver_port,
+            config.path_prefix.as_deref(),
+        )?,
+        &config::TrustedConnection {
+            uuid: pairing_result.uuid,
+            private_key: pairing_result.private_key,
+            certificate: pairing_result.pairing_response.client_cert,
+            root_cert: pairing_result.pairing_response.root_cert,
+            pinned_fingerprint: None,
+            pinned_client_fingerprints: vec![],
+            labels: config::ConnectionLabels::new(),
+            agent_output_disabled: false,
+            min_tls_version: None,
+        },
+    ) {
+        Ok(status_response) => status_response.connection_type,
+        Err(error) => {
+            info!("Could not query the registration status: {}", error);
+            None
+        }
+    };
+
+    Ok(DryRunResult {
+        site_id: config.site_id.clone(),
+        receiver_port: config.receiver_port,
+        connection_type,
+    })
+}
+
 fn proxy_registration(
     config: &config::RegistrationConfigHostName,
     agent_rec_api: &(impl agent_receiver_api::Pairing + agent_receiver_api::Registration),
@@ -297,6 +587,11 @@ fn proxy_registration(
                 private_key: pairing_result.private_key,
                 certificate: pairing_result.pairing_response.client_cert,
                 root_cert: pairing_result.pairing_response.root_cert,
+                pinned_fingerprint: None,
+                pinned_client_fingerprints: vec![],
+                labels: config.connection_config.labels.clone(),
+                agent_output_disabled: config.connection_config.agent_output_disabled,
+                min_tls_version: None,
             }
         })?
     );
@@ -311,25 +606,358 @@ pub struct ProxyPullData {
 
 impl config::JSONLoader for ProxyPullData {}
 
-pub fn register_host_name(
+fn registered_connection<'a>(
+    registry: &'a config::Registry,
+    site_id: &site_spec::SiteID,
+) -> AnyhowResult<(
+    config::ConnectionType,
+    &'a config::TrustedConnectionWithRemote,
+)> {
+    registry
+        .standard_connection(site_id)
+        .context(format!("No connection registered with {}", site_id))
+}
+
+/// The result of a successful registration as reported with '--output-format json'
+#[serde_with::serde_as]
+#[derive(serde::Serialize)]
+struct RegistrationOutput {
+    #[serde_as(as = "DisplayFromStr")]
+    site_id: site_spec::SiteID,
+    #[serde_as(as = "DisplayFromStr")]
+    uuid: uuid::Uuid,
+    connection_type: config::ConnectionType,
+    receiver_port: u16,
+    certificate_fingerprint: String,
+}
+
+impl RegistrationOutput {
+    fn from_registry(
+        registry: &config::Registry,
+        site_id: &site_spec::SiteID,
+    ) -> AnyhowResult<Self> {
+        let (connection_type, connection) = registered_connection(registry, site_id)?;
+        Ok(Self {
+            site_id: site_id.clone(),
+            uuid: connection.trust.uuid,
+            connection_type,
+            receiver_port: connection.receiver_port,
+            certificate_fingerprint: certs::fingerprint_sha256(
+                &certs::parse_pem(&connection.trust.certificate)?.contents,
+            )?,
+        })
+    }
+}
+
+/// The outcome per site, either a short description of what was done or the error.
+struct RegistrationSummary(Vec<(site_spec::SiteID, AnyhowResult<String>)>);
+
+impl RegistrationSummary {
+    fn succeeded(&self) -> usize {
+        self.0.iter().filter(|(_, result)| result.is_ok()).count()
+    }
+
+    fn failed(&self) -> usize {
+        self.0.len() - self.succeeded()
+    }
+}
+
+/// Run by the shell, like the host name command. Its stdout is discarded, st. it can't get in
+/// the way of the JSON output, stderr is passed through.
+fn run_post_register_hook(
+    hook: &config::PostRegisterHook,
+    site_id: &site_spec::SiteID,
+    registry: &config::Registry,
+) -> AnyhowResult<()> {
+    let (connection_type, connection) = registered_connection(registry, site_id)?;
+    let mut child = config::shell_command(&hook.command)
+        .env(HOOK_SITE_ID_VAR, site_id.to_string())
+        .env(HOOK_UUID_VAR, connection.trust.uuid.to_string())
+        .env(HOOK_CONNECTION_TYPE_VAR, connection_type.to_string())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .context(format!(
+            "Failed to run post-register hook '{}'",
+            hook.command
+        ))?;
+    let deadline = std::time::Instant::now() + hook.timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            bail!("Post-register hook '{}' failed ({})", hook.command, status)
+        }
+        if std::time::Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "Post-register hook '{}' timed out after {} s",
+                hook.command,
+                hook.timeout.as_secs_f64()
+            )
+        }
+        std::thread::sleep(HOOK_POLL_INTERVAL);
+    }
+}
+
+/// Only run once the registrations are persisted, st. a failing hook can't undo them
+fn run_post_register_hooks(
+    hook: &config::PostRegisterHook,
+    summary: &RegistrationSummary,
+    registry: &config::Registry,
+) -> AnyhowResult<()> {
+    let mut failed = 0;
+    for (site_id, _) in summary.0.iter().filter(|(_, result)| result.is_ok()) {
+        if let Err(err) = run_post_register_hook(hook, site_id, registry) {
+            warn!(site = site_id.to_string(); "{:#}, the registration is kept", err);
+            failed += 1;
+        }
+    }
+    if hook.fail_on_error && failed > 0 {
+        bail!(
+            "Post-register hook failed for {} of {} registrations, the registrations are kept",
+            failed,
+            summary.succeeded()
+        )
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for RegistrationSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let width = self
+            .0
+            .iter()
+            .map(|(site_id, _)| site_id.to_string().len())
+            .max()
+            .unwrap_or_default()
+            .max("Site".len());
+        write!(f, "{:<width$}  Result", "Site", width = width)?;
+        for (site_id, result) in self.0.iter() {
+            write!(
+                f,
+                "\n{:<width$}  {}",
+                site_id.to_string(),
+                match result {
+                    Ok(outcome) => outcome.clone(),
+                    Err(error) => format!(
+                        "failed: {}",
+                        misc::anyhow_error_to_human_readable(error).replace('\n', " - ")
+                    ),
+                },
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn _register_host_names(
+    configs: Vec<(
+        site_spec::SiteID,
+        AnyhowResult<config::RegistrationConfigHostName>,
+    )>,
+    registry: &mut config::Registry,
+    register: impl Fn(
+        &config::RegistrationConfigHostName,
+        &mut config::Registry,
+    ) -> AnyhowResult<String>,
+) -> RegistrationSummary {
+    RegistrationSummary(
+        configs
+            .into_iter()
+            .map(|(site_id, config)| {
+                let result = config.and_then(|config| register(&config, registry));
+                (site_id, result)
+            })
+            .collect(),
+    )
+}
+
+fn register_host_name(
     config: &config::RegistrationConfigHostName,
     registry: &mut config::Registry,
+) -> AnyhowResult<String> {
+    let agent_rec_api = agent_receiver_api::Api::from(&config.connection_config.client_config);
+    let trust_establisher = InteractiveTrust::new(&config.connection_config.client_config);
+    check_not_registered(config, registry)?;
+    if let Some(deadline) = config.wait_until {
+        wait_for_receiver(&config.connection_config, deadline)?;
+    }
+    with_retries(config, || {
+        if config.dry_run {
+            let dry_run_result = dry_run_registration(
+                &config.connection_config,
+                &agent_rec_api,
+                &trust_establisher,
+            )?;
+            return Ok(dry_run_result.summary());
+        }
+        direct_registration(
+            &config.connection_config,
+            registry,
+            &agent_rec_api,
+            &trust_establisher,
+            &HostNameRegistration {
+                host_name: &config.host_name,
+            },
+        )?;
+        Ok(String::from("registered"))
+    })
+}
+
+/// Registering again would leave the previous registration of this host behind at the site
+fn check_not_registered(
+    config: &config::RegistrationConfigHostName,
+    registry: &config::Registry,
 ) -> AnyhowResult<()> {
-    direct_registration(
-        &config.connection_config,
-        registry,
-        &agent_receiver_api::Api {
-            use_proxy: config.connection_config.client_config.use_proxy,
-        },
-        &InteractiveTrust {},
-        &HostNameRegistration {
-            host_name: &config.host_name,
+    let site_id = &config.connection_config.site_id;
+    let Some((_, connection)) = registry.standard_connection(site_id) else {
+        return Ok(());
+    };
+    if config.force {
+        info!(
+            site = site_id.to_string();
+            "Replacing existing connection with UUID {}", connection.trust.uuid
+        );
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Already registered with {} (UUID {}), use --force to replace the existing connection",
+        site_id,
+        connection.trust.uuid
+    )
+    .context(exit_codes::ExitCode::AlreadyExists))
+}
+
+/// The first contact with the receiver, without any credentials involved yet
+fn wait_for_receiver(
+    config: &config::RegistrationConnectionConfig,
+    deadline: std::time::Instant,
+) -> AnyhowResult<()> {
+    site_spec::wait_for_site(
+        &config.site_id,
+        deadline,
+        std::time::Duration::from_secs(constants::REGISTRATION_WAIT_INTERVAL),
+        || {
+            certs::fetch_server_cert_chain_pem(
+                &config.site_id.server,
+                &config.receiver_port,
+                proxy::for_host(
+                    config.client_config.use_proxy,
+                    config.client_config.proxy.as_ref(),
+                    &config.site_id.server,
+                )
+                .1,
+            )
+            .context(format!(
+                "Failed to connect to {}, port {}",
+                config.site_id, config.receiver_port
+            ))
         },
     )?;
-    println!("Registration complete.");
     Ok(())
 }
 
+/// Only network errors are retried. Everything else, in particular rejected credentials, won't
+/// go away by trying again.
+fn with_retries<T>(
+    config: &config::RegistrationConfigHostName,
+    mut attempt: impl FnMut() -> AnyhowResult<T>,
+) -> AnyhowResult<T> {
+    let attempts = config.retries + 1;
+    let mut attempt_number = 1;
+    loop {
+        match attempt() {
+            Err(err)
+                if attempt_number < attempts
+                    && exit_codes::ExitCode::from(&err) == exit_codes::ExitCode::Network =>
+            {
+                warn!(
+                    site = config.connection_config.site_id.to_string();
+                    "Registration attempt {} of {} failed, retrying in {}s: {:#}",
+                    attempt_number,
+                    attempts,
+                    config.retry_delay.as_secs(),
+                    err
+                );
+                std::thread::sleep(config.retry_delay);
+                attempt_number += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn register_host_names(
+    configs: Vec<(
+        site_spec::SiteID,
+        AnyhowResult<config::RegistrationConfigHostName>,
+    )>,
+    registry: &mut config::Registry,
+    output_format: &cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let multiple_sites = configs.len() > 1;
+    let json = *output_format == cli::OutputFormat::Json;
+    if json && multiple_sites {
+        return Err(
+            anyhow!("JSON output is only supported when registering with a single site")
+                .context(exit_codes::ExitCode::Usage),
+        );
+    }
+    let dry_run = configs
+        .iter()
+        .any(|(_, config)| config.as_ref().is_ok_and(|config| config.dry_run));
+    let post_register_hook = configs
+        .iter()
+        .find_map(|(_, config)| config.as_ref().ok()?.post_register_hook.clone());
+    let mut summary = _register_host_names(configs, registry, register_host_name);
+    // Keep whatever succeeded, even if other registrations failed
+    if !dry_run && summary.succeeded() > 0 {
+        registry.save()?;
+    }
+    let hook_result = match (&post_register_hook, dry_run) {
+        (Some(hook), false) => run_post_register_hooks(hook, &summary, registry),
+        _ => Ok(()),
+    };
+
+    if !multiple_sites {
+        if let Some((site_id, result)) = summary.0.pop() {
+            let outcome = result?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string(&RegistrationOutput::from_registry(registry, &site_id)?)?
+                );
+            } else if dry_run {
+                println!(
+                    "Dry run for {} successful, nothing was registered: {}.",
+                    site_id, outcome
+                );
+            } else {
+                println!("Registration complete.");
+            }
+        }
+        return hook_result;
+    }
+
+    if dry_run {
+        println!("Dry run, nothing was registered.");
+    }
+    println!("{}", summary);
+    match summary.failed() {
+        0 => hook_result,
+        failed => Err(anyhow!(
+            "{} failed for {} of {} sites",
+            if dry_run { "Dry run" } else { "Registration" },
+            failed,
+            summary.0.len()
+        )),
+    }
+}
+
 pub fn register_agent_labels(
     config: &config::RegistrationConfigAgentLabels,
     registry: &mut config::Registry,
@@ -337,14 +965,13 @@ pub fn register_agent_labels(
     direct_registration(
         &config.connection_config,
         registry,
-        &agent_receiver_api::Api {
-            use_proxy: config.connection_config.client_config.use_proxy,
-        },
-        &InteractiveTrust {},
+        &agent_receiver_api::Api::from(&config.connection_config.client_config),
+        &InteractiveTrust::new(&config.connection_config.client_config),
         &AgentLabelsRegistration {
             agent_labels: &config.agent_labels,
         },
     )?;
+    registry.save()?;
     println!("Registration complete. It may take few minutes until the newly created host and its services are visible in the site.");
     Ok(())
 }
@@ -432,7 +1059,11 @@ fn process_pre_configured_connection(
 ) -> AnyhowResult<()> {
     let receiver_port = match pre_configured.port {
         Some(receiver_port) => receiver_port,
-        None => site_spec::discover_receiver_port(site_id, client_config)?,
+        None => site_spec::discover_receiver_port(
+            site_id,
+            client_config.path_prefix.as_deref(),
+            client_config,
+        )?,
     };
 
     if let Some(registered_connection) = registry.get_mutable(site_id) {
@@ -448,17 +1079,28 @@ fn process_pre_configured_connection(
         config::RegistrationConnectionConfig {
             site_id: site_id.clone(),
             receiver_port,
-            username: pre_configured.credentials.username.clone(),
-            password: Some(pre_configured.credentials.password.clone()),
+            path_prefix: client_config.path_prefix.clone(),
+            credentials: config::RegistrationCredentials {
+                username: pre_configured.credentials.username.clone(),
+                password: Some(pre_configured.credentials.password.clone()),
+            },
             root_certificate: Some(pre_configured.root_cert.clone()),
             trust_server_cert: false,
+            accept_self_signed: false,
+            pin_fingerprint: false,
+            trusted_fingerprint: None,
+            pkcs12_identity: None,
+            key_type: certs::KeyType::Rsa,
+            csr_fields: certs::CsrFields::default(),
+            labels: config::ConnectionLabels::new(),
+            agent_output_disabled: false,
             client_config: client_config.clone(),
         },
         agent_labels.clone(),
     )?;
 
     registration_with_labels.register(&registration_config, registry)?;
-    info!("Registered new connection {}", site_id);
+    info!(site = site_id.to_string(); "Registered new connection {}", site_id);
 
     Ok(())
 }
@@ -481,13 +1123,23 @@ fn delete_vanished_connections<'a>(
 }
 
 pub fn proxy_register(config: &config::RegistrationConfigHostName) -> AnyhowResult<()> {
-    proxy_registration(
-        config,
-        &agent_receiver_api::Api {
-            use_proxy: config.connection_config.client_config.use_proxy,
-        },
-        &InteractiveTrust {},
-    )
+    let agent_rec_api = agent_receiver_api::Api::from(&config.connection_config.client_config);
+    let trust_establisher = InteractiveTrust::new(&config.connection_config.client_config);
+    if let Some(deadline) = config.wait_until {
+        wait_for_receiver(&config.connection_config, deadline)?;
+    }
+    if config.dry_run {
+        println!(
+            "Dry run successful, nothing was registered.\n{}",
+            dry_run_registration(
+                &config.connection_config,
+                &agent_rec_api,
+                &trust_establisher
+            )?
+        );
+        return Ok(());
+    }
+    proxy_registration(config, &agent_rec_api, &trust_establisher)
 }
 
 #[cfg(test)]
@@ -497,6 +1149,7 @@ mod tests {
 
     const SERVER: &str = "server";
     const PORT: u16 = 8000;
+    const FINGERPRINT: &str = "E3:B0:C4:42:98:FC:1C:14:9A:FB:F4:C8:99:6F:B9:24:27:AE:41:E4:64:9B:93:4C:A4:95:99:1B:78:52:B8:55";
     const SITE: &str = "site";
     const HOST_NAME: &str = "host";
     const USERNAME: &str = "user";
@@ -509,7 +1162,7 @@ mod tests {
     }
 
     fn expected_url() -> reqwest::Url {
-        site_spec::make_site_url(&site_id(), &PORT).unwrap()
+        site_spec::make_site_url(&site_id(), &PORT, None).unwrap()
     }
 
     enum RegistrationMethod {
@@ -594,6 +1247,7 @@ mod tests {
     struct MockInteractiveTrust {
         expect_server_cert_prompt: bool,
         expect_password_prompt: bool,
+        self_signed_server_cert: bool,
     }
 
     impl TrustEstablishing for MockInteractiveTrust {
@@ -609,6 +1263,18 @@ mod tests {
             assert_eq!(user, USERNAME);
             Ok(String::from("password"))
         }
+
+        fn server_cert_fingerprint(&self, server: &str, port: &u16) -> AnyhowResult<String> {
+            assert!(server == SERVER);
+            assert!(port == &PORT);
+            Ok(String::from(FINGERPRINT))
+        }
+
+        fn server_cert_is_self_signed(&self, server: &str, port: &u16) -> AnyhowResult<bool> {
+            assert!(server == SERVER);
+            assert!(port == &PORT);
+            Ok(self.self_signed_server_cert)
+        }
     }
 
     fn registry() -> config::Registry {
@@ -629,13 +1295,34 @@ mod tests {
         config::RegistrationConnectionConfig {
             site_id: site_id(),
             receiver_port: PORT,
-            username: String::from(USERNAME),
-            password,
+            path_prefix: None,
+            credentials: config::RegistrationCredentials {
+                username: String::from(USERNAME),
+                password,
+            },
             root_certificate,
             trust_server_cert,
+            accept_self_signed: false,
+            pin_fingerprint: false,
+            trusted_fingerprint: None,
+            pkcs12_identity: None,
+            key_type: certs::KeyType::Rsa,
+            csr_fields: certs::CsrFields::default(),
+            labels: config::ConnectionLabels::new(),
+            agent_output_disabled: false,
             client_config: config::ClientConfig {
+                path_prefix: None,
                 use_proxy: false,
+                proxy: None,
                 validate_api_cert: false,
+                tls_policy: certs::TlsPolicy::default(),
+                extra_root_certs: vec![],
+                tls_servername: None,
+                connect_timeout: std::time::Duration::from_secs(10),
+                push_timeout: std::time::Duration::from_secs(30),
+                push_keepalive: None,
+                push_source_address: None,
+                clock_skew_threshold: std::time::Duration::from_secs(60),
             },
         }
     }
@@ -654,6 +1341,7 @@ mod tests {
                 &MockInteractiveTrust {
                     expect_server_cert_prompt: true,
                     expect_password_prompt: true,
+                    self_signed_server_cert: false,
                 },
             )
             .is_ok());
@@ -670,11 +1358,35 @@ mod tests {
                 &MockInteractiveTrust {
                     expect_server_cert_prompt: false,
                     expect_password_prompt: false,
+                    self_signed_server_cert: false,
                 },
             )
             .is_ok());
         }
 
+        #[test]
+        fn test_blind_trust_self_signed() {
+            let pair = |config: &config::RegistrationConnectionConfig| {
+                prepare_registration(
+                    config,
+                    &MockApi {
+                        expect_root_cert_for_pairing: false,
+                        expected_registration_method: None,
+                    },
+                    &MockInteractiveTrust {
+                        expect_server_cert_prompt: false,
+                        expect_password_prompt: false,
+                        self_signed_server_cert: true,
+                    },
+                )
+            };
+            let mut config =
+                registration_connection_config(None, Some(String::from("password")), true);
+            assert!(pair(&config).is_err());
+            config.accept_self_signed = true;
+            assert!(pair(&config).is_ok());
+        }
+
         #[test]
         fn test_root_cert_from_config() {
             assert!(prepare_registration(
@@ -690,11 +1402,147 @@ mod tests {
                 &MockInteractiveTrust {
                     expect_server_cert_prompt: false,
                     expect_password_prompt: false,
+                    self_signed_server_cert: false,
+                },
+            )
+            .is_ok());
+        }
+
+        #[test]
+        fn test_trusted_fingerprint() {
+            let mut config = registration_connection_config(None, None, false);
+            config.trusted_fingerprint = Some(String::from(FINGERPRINT));
+            assert!(prepare_registration(
+                &config,
+                &MockApi {
+                    expect_root_cert_for_pairing: false,
+                    expected_registration_method: None,
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: true,
+                    self_signed_server_cert: false,
                 },
             )
             .is_ok());
         }
 
+        #[test]
+        fn test_trusted_fingerprint_mismatch() {
+            let mut config = registration_connection_config(None, None, false);
+            config.trusted_fingerprint = Some(FINGERPRINT.replace("E3", "E4"));
+            assert!(prepare_registration(
+                &config,
+                &MockApi {
+                    expect_root_cert_for_pairing: false,
+                    expected_registration_method: None,
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                    self_signed_server_cert: false,
+                },
+            )
+            .is_err());
+        }
+
+        fn pkcs12_identity(ca_certificates: Vec<String>) -> certs::Pkcs12Identity {
+            certs::Pkcs12Identity {
+                private_key: String::from("private_key"),
+                certificate: String::from(constants::TEST_CERT_CN_UUID),
+                ca_certificates,
+            }
+        }
+
+        #[test]
+        fn test_pkcs12_identity() {
+            let mut config = registration_connection_config(
+                Some(String::from(constants::TEST_ROOT_CERT)),
+                None,
+                false,
+            );
+            config.pkcs12_identity = Some(pkcs12_identity(vec![String::from(
+                constants::TEST_ROOT_CERT,
+            )]));
+            let (_, pairing_result) = prepare_registration(
+                &config,
+                &MockApi {
+                    expect_root_cert_for_pairing: false,
+                    expected_registration_method: None,
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: true,
+                    self_signed_server_cert: false,
+                },
+            )
+            .unwrap();
+            assert_eq!(
+                pairing_result.uuid.to_string(),
+                "cf771eeb-b666-4673-95c9-683960fb2939"
+            );
+            assert_eq!(pairing_result.private_key, "private_key");
+            assert_eq!(
+                pairing_result.pairing_response.root_cert,
+                constants::TEST_ROOT_CERT
+            );
+            assert_eq!(
+                pairing_result.pairing_response.client_cert,
+                constants::TEST_CERT_CN_UUID
+            );
+        }
+
+        #[test]
+        fn test_pkcs12_identity_with_intermediates() {
+            let chain =
+                certs::make_two_level_chain("cf771eeb-b666-4673-95c9-683960fb2939").unwrap();
+            let mut identity = pkcs12_identity(vec![
+                chain.intermediate_certs[1].clone(),
+                chain.intermediate_certs[0].clone(),
+                chain.root_cert.clone(),
+            ]);
+            identity.certificate = chain.cert.clone();
+            let pairing_result = pairing_result_from_pkcs12(
+                &registration_connection_config(Some(chain.root_cert.clone()), None, false),
+                &identity,
+            )
+            .unwrap();
+            assert_eq!(pairing_result.pairing_response.root_cert, chain.root_cert);
+            assert_eq!(pairing_result.pairing_response.client_cert, chain.chain());
+            assert_eq!(
+                certs::chain_gap(
+                    &pairing_result.pairing_response.client_cert,
+                    &pairing_result.pairing_response.root_cert
+                )
+                .unwrap(),
+                None
+            );
+        }
+
+        #[test]
+        fn test_pkcs12_identity_without_site_ca() {
+            let mut config = registration_connection_config(None, None, false);
+            // The CA certificates of the bundle don't tell anything about the site
+            assert!(pairing_result_from_pkcs12(
+                &config,
+                &pkcs12_identity(vec![String::from(constants::TEST_ROOT_CERT)])
+            )
+            .is_err());
+            config.root_certificate = Some(String::from(constants::TEST_ROOT_CERT));
+            assert!(pairing_result_from_pkcs12(&config, &pkcs12_identity(vec![])).is_ok());
+        }
+
+        #[test]
+        fn test_pkcs12_identity_cn_no_uuid() {
+            let mut identity = pkcs12_identity(vec![String::from(constants::TEST_ROOT_CERT)]);
+            identity.certificate = String::from(constants::TEST_CERT_OK);
+            assert!(pairing_result_from_pkcs12(
+                &registration_connection_config(None, None, false),
+                &identity
+            )
+            .is_err());
+        }
+
         #[test]
         fn test_root_cert_from_config_and_blind_trust() {
             assert!(prepare_registration(
@@ -706,6 +1554,7 @@ mod tests {
                 &MockInteractiveTrust {
                     expect_server_cert_prompt: false,
                     expect_password_prompt: true,
+                    self_signed_server_cert: false,
                 },
             )
             .is_ok());
@@ -729,6 +1578,7 @@ mod tests {
                 &MockInteractiveTrust {
                     expect_server_cert_prompt: true,
                     expect_password_prompt: true,
+                    self_signed_server_cert: false,
                 },
                 &HostNameRegistration {
                     host_name: HOST_NAME
@@ -736,7 +1586,47 @@ mod tests {
             )
             .is_ok());
             assert!(!registry.is_empty());
-            assert!(registry.path().exists());
+            // Saving is left to the caller, st. registering with multiple sites writes once
+            assert!(!registry.path().exists());
+            assert_eq!(
+                registry
+                    .get_mutable(&site_id())
+                    .unwrap()
+                    .host_name
+                    .as_deref(),
+                Some(HOST_NAME)
+            );
+        }
+
+        #[test]
+        fn test_registration_output() {
+            let mut registry = registry();
+            let mut connection =
+                config::TrustedConnectionWithRemote::from("99f56bbc-5965-4b34-bc70-1959ad1d32d6");
+            connection.trust.certificate = String::from(constants::TEST_CERT_OK);
+            registry.register_connection(&config::ConnectionType::Push, &site_id(), connection);
+            let output = serde_json::to_value(
+                RegistrationOutput::from_registry(&registry, &site_id()).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(
+                output,
+                serde_json::json!({
+                    "site_id": "server/site",
+                    "uuid": "99f56bbc-5965-4b34-bc70-1959ad1d32d6",
+                    "connection_type": "push-agent",
+                    "receiver_port": 8000,
+                    "certificate_fingerprint": certs::fingerprint_sha256(
+                        &certs::parse_pem(constants::TEST_CERT_OK).unwrap().contents
+                    )
+                    .unwrap(),
+                })
+            );
+            assert!(RegistrationOutput::from_registry(
+                &registry,
+                &site_spec::SiteID::from_str("server/other_site").unwrap()
+            )
+            .is_err());
         }
 
         #[test]
@@ -757,6 +1647,7 @@ mod tests {
                 &MockInteractiveTrust {
                     expect_server_cert_prompt: false,
                     expect_password_prompt: false,
+                    self_signed_server_cert: false,
                 },
                 &AgentLabelsRegistration {
                     agent_labels: &agent_labels()
@@ -764,7 +1655,96 @@ mod tests {
             )
             .is_ok());
             assert!(!registry.is_empty());
-            assert!(registry.path().exists());
+            // Saving is left to the caller, st. registering with multiple sites writes once
+            assert!(!registry.path().exists());
+            assert!(registry
+                .get_mutable(&site_id())
+                .unwrap()
+                .host_name
+                .is_none());
+        }
+
+        #[test]
+        fn test_pin_fingerprint() {
+            let mut registry = registry();
+            let mut config = registration_connection_config(None, None, true);
+            config.pin_fingerprint = true;
+            assert!(direct_registration(
+                &config,
+                &mut registry,
+                &MockApi {
+                    expect_root_cert_for_pairing: false,
+                    expected_registration_method: Some(RegistrationMethod::HostName),
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: true,
+                    self_signed_server_cert: false,
+                },
+                &HostNameRegistration {
+                    host_name: HOST_NAME
+                },
+            )
+            .is_ok());
+            assert_eq!(
+                registry
+                    .get_mutable(&site_id())
+                    .unwrap()
+                    .trust
+                    .pinned_fingerprint
+                    .as_deref(),
+                Some(FINGERPRINT)
+            );
+        }
+
+        #[test]
+        fn test_labels() {
+            let mut registry = registry();
+            let mut config = registration_connection_config(None, None, true);
+            config.labels =
+                config::ConnectionLabels::from([(String::from("env"), String::from("prod"))]);
+            assert!(direct_registration(
+                &config,
+                &mut registry,
+                &MockApi {
+                    expect_root_cert_for_pairing: false,
+                    expected_registration_method: Some(RegistrationMethod::HostName),
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: true,
+                    self_signed_server_cert: false,
+                },
+                &HostNameRegistration {
+                    host_name: HOST_NAME
+                },
+            )
+            .is_ok());
+            assert_eq!(
+                registry.get_mutable(&site_id()).unwrap().trust.labels,
+                config.labels
+            );
+        }
+
+        #[test]
+        fn test_dry_run() {
+            let dry_run_result = dry_run_registration(
+                &registration_connection_config(None, Some(String::from("password")), true),
+                &MockApi {
+                    expect_root_cert_for_pairing: false,
+                    expected_registration_method: None,
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                    self_signed_server_cert: false,
+                },
+            )
+            .unwrap();
+            assert_eq!(
+                format!("{}", dry_run_result),
+                "Site ID:         server/site\nReceiver port:   8000\nConnection type: pull-agent"
+            );
         }
 
         #[test]
@@ -773,6 +1753,12 @@ mod tests {
                 &config::RegistrationConfigHostName {
                     connection_config: registration_connection_config(None, None, true),
                     host_name: String::from(HOST_NAME),
+                    dry_run: false,
+                    force: false,
+                    retries: 0,
+                    retry_delay: std::time::Duration::ZERO,
+                    wait_until: None,
+                    post_register_hook: None,
                 },
                 &MockApi {
                     expect_root_cert_for_pairing: false,
@@ -781,12 +1767,270 @@ mod tests {
                 &MockInteractiveTrust {
                     expect_server_cert_prompt: false,
                     expect_password_prompt: true,
+                    self_signed_server_cert: false,
                 },
             )
             .is_ok());
         }
     }
 
+    #[cfg(unix)]
+    mod test_post_register_hook {
+        use super::*;
+
+        fn hook(command: &str, fail_on_error: bool) -> config::PostRegisterHook {
+            config::PostRegisterHook {
+                command: String::from(command),
+                timeout: std::time::Duration::from_secs(5),
+                fail_on_error,
+            }
+        }
+
+        fn registered() -> (config::Registry, RegistrationSummary, uuid::Uuid) {
+            let mut registry = registry();
+            let uuid = uuid::Uuid::new_v4();
+            registry.register_connection(
+                &config::ConnectionType::Push,
+                &site_id(),
+                config::TrustedConnectionWithRemote::from(uuid),
+            );
+            let summary = RegistrationSummary(vec![
+                (site_id(), Ok(String::from("registered"))),
+                (
+                    site_spec::SiteID::from_str("server/failed-site").unwrap(),
+                    Err(anyhow!("Registration failed")),
+                ),
+            ]);
+            (registry, summary, uuid)
+        }
+
+        #[test]
+        fn test_environment() {
+            let (registry, summary, uuid) = registered();
+            let dir = tempfile::tempdir().unwrap();
+            let out = dir.path().join("out");
+            run_post_register_hooks(
+                &hook(
+                    &format!(
+                        "echo $CMK_SITE_ID $CMK_CONNECTION_UUID $CMK_CONNECTION_TYPE >> {}",
+                        out.display()
+                    ),
+                    true,
+                ),
+                &summary,
+                &registry,
+            )
+            .unwrap();
+            // Only run for the successful registration
+            assert_eq!(
+                std::fs::read_to_string(out).unwrap(),
+                format!("{} {} push-agent\n", site_id(), uuid)
+            );
+        }
+
+        #[test]
+        fn test_failure() {
+            let (registry, summary, _) = registered();
+            assert!(run_post_register_hooks(&hook("exit 3", false), &summary, &registry).is_ok());
+            assert_eq!(
+                run_post_register_hooks(&hook("exit 3", true), &summary, &registry)
+                    .unwrap_err()
+                    .to_string(),
+                "Post-register hook failed for 1 of 1 registrations, the registrations are kept"
+            );
+        }
+
+        #[test]
+        fn test_timeout() {
+            let (registry, _, _) = registered();
+            let started = std::time::Instant::now();
+            let err = run_post_register_hook(
+                &config::PostRegisterHook {
+                    timeout: std::time::Duration::from_millis(200),
+                    ..hook("exec sleep 10", true)
+                },
+                &site_id(),
+                &registry,
+            )
+            .unwrap_err();
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            assert_eq!(
+                err.to_string(),
+                "Post-register hook 'exec sleep 10' timed out after 0.2 s"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_not_registered() {
+        let mut config = config::RegistrationConfigHostName {
+            connection_config: registration_connection_config(None, None, true),
+            host_name: String::from(HOST_NAME),
+            dry_run: false,
+            force: false,
+            retries: 0,
+            retry_delay: std::time::Duration::ZERO,
+            wait_until: None,
+            post_register_hook: None,
+        };
+        let mut registry = registry();
+        assert!(check_not_registered(&config, &registry).is_ok());
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &site_id(),
+            config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+        );
+        let err = check_not_registered(&config, &registry).unwrap_err();
+        assert_eq!(
+            exit_codes::ExitCode::from(&err),
+            exit_codes::ExitCode::AlreadyExists
+        );
+        config.force = true;
+        assert!(check_not_registered(&config, &registry).is_ok());
+    }
+
+    mod test_retries {
+        use super::*;
+
+        fn retry_config(retries: u32) -> config::RegistrationConfigHostName {
+            config::RegistrationConfigHostName {
+                connection_config: registration_connection_config(None, None, true),
+                host_name: String::from(HOST_NAME),
+                dry_run: false,
+                force: false,
+                retries,
+                retry_delay: std::time::Duration::ZERO,
+                wait_until: None,
+                post_register_hook: None,
+            }
+        }
+
+        fn attempts_until_done(
+            config: &config::RegistrationConfigHostName,
+            error: impl Fn() -> anyhow::Error,
+            failing_attempts: u32,
+        ) -> (u32, AnyhowResult<()>) {
+            let mut attempts = 0;
+            let result = with_retries(config, || {
+                attempts += 1;
+                match attempts <= failing_attempts {
+                    true => Err(error()),
+                    false => Ok(()),
+                }
+            });
+            (attempts, result)
+        }
+
+        fn network_error() -> anyhow::Error {
+            anyhow!(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+                .context("Error pairing")
+        }
+
+        #[test]
+        fn test_no_retries() {
+            let (attempts, result) = attempts_until_done(&retry_config(0), network_error, 1);
+            assert_eq!(attempts, 1);
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn test_retry_network_error() {
+            let (attempts, result) = attempts_until_done(&retry_config(2), network_error, 2);
+            assert_eq!(attempts, 3);
+            assert!(result.is_ok());
+            let (attempts, result) = attempts_until_done(&retry_config(2), network_error, 5);
+            assert_eq!(attempts, 3);
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn test_no_retry_on_rejection() {
+            let (attempts, result) = attempts_until_done(
+                &retry_config(2),
+                || {
+                    anyhow!(agent_receiver_api::ResponseError {
+                        status: http::StatusCode::UNAUTHORIZED,
+                        description: String::from("Invalid credentials"),
+                    })
+                },
+                1,
+            );
+            assert_eq!(attempts, 1);
+            assert!(result.is_err());
+        }
+    }
+
+    mod test_register_multiple {
+        use super::*;
+
+        fn host_name_config(site_id: &site_spec::SiteID) -> config::RegistrationConfigHostName {
+            let mut connection_config = registration_connection_config(None, None, true);
+            connection_config.site_id = site_id.clone();
+            config::RegistrationConfigHostName {
+                connection_config,
+                host_name: String::from(HOST_NAME),
+                dry_run: false,
+                force: false,
+                retries: 0,
+                retry_delay: std::time::Duration::ZERO,
+                wait_until: None,
+                post_register_hook: None,
+            }
+        }
+
+        fn register(
+            config: &config::RegistrationConfigHostName,
+            registry: &mut config::Registry,
+        ) -> AnyhowResult<String> {
+            if config.connection_config.site_id.site == "failing_site" {
+                return Err(anyhow!("Registration declined"));
+            }
+            registry.register_connection(
+                &config::ConnectionType::Pull,
+                &config.connection_config.site_id,
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            );
+            Ok(String::from("registered"))
+        }
+
+        #[test]
+        fn test_partial_failure() {
+            let mut registry = registry();
+            let site_ids: Vec<site_spec::SiteID> =
+                ["server/site", "server/failing_site", "other/site"]
+                    .into_iter()
+                    .map(|site_id| site_spec::SiteID::from_str(site_id).unwrap())
+                    .collect();
+            let mut configs: Vec<(
+                site_spec::SiteID,
+                AnyhowResult<config::RegistrationConfigHostName>,
+            )> = site_ids
+                .iter()
+                .map(|site_id| (site_id.clone(), Ok(host_name_config(site_id))))
+                .collect();
+            configs.push((
+                site_spec::SiteID::from_str("unreachable/site").unwrap(),
+                Err(anyhow!("Failed to discover agent receiver port")),
+            ));
+
+            let summary = _register_host_names(configs, &mut registry, register);
+
+            assert_eq!(summary.succeeded(), 2);
+            assert_eq!(summary.failed(), 2);
+            assert!(registry.get_mutable(&site_ids[0]).is_some());
+            assert!(registry.get_mutable(&site_ids[1]).is_none());
+            assert!(registry.get_mutable(&site_ids[2]).is_some());
+            assert_eq!(
+                format!("{}", summary),
+                "Site                 Result\n\
+                 server/site          registered\n\
+                 server/failing_site  failed: Registration declined\n\
+                 other/site           registered\n\
+                 unreachable/site     failed: Failed to discover agent receiver port"
+            );
+        }
+    }
+
     mod test_register_pre_configured {
         use super::*;
 
@@ -884,7 +2128,7 @@ mod tests {
                 config: &config::RegistrationConfigAgentLabels,
                 registry: &mut config::Registry,
             ) -> AnyhowResult<()> {
-                assert!(config.connection_config.password.is_some());
+                assert!(config.connection_config.credentials.password.is_some());
                 assert!(config.connection_config.root_certificate.is_some());
                 assert!(!config.connection_config.trust_server_cert);
                 assert_eq!(config.agent_labels.get("key").unwrap(), "value");
@@ -904,8 +2148,15 @@ mod tests {
                             private_key: String::from("private_key"),
                             certificate: String::from("certificate"),
                             root_cert: String::from("root_cert"),
+                            pinned_fingerprint: None,
+                            pinned_client_fingerprints: vec![],
+                            labels: config::ConnectionLabels::new(),
+                            agent_output_disabled: false,
+                            min_tls_version: None,
                         },
                         receiver_port: config.connection_config.receiver_port,
+                        host_name: None,
+                        path_prefix: config.connection_config.path_prefix.clone(),
                     },
                 );
                 Ok(())
@@ -985,13 +2236,34 @@ mod tests {
             assert!(_register_pre_configured(
                 &pre_configured_connections(true),
                 &config::ClientConfig {
+                    path_prefix: Some(String::from("/monitoring")),
                     use_proxy: false,
+                    proxy: None,
                     validate_api_cert: false,
+                    tls_policy: certs::TlsPolicy::default(),
+                    extra_root_certs: vec![],
+                    tls_servername: None,
+                    connect_timeout: std::time::Duration::from_secs(10),
+                    push_timeout: std::time::Duration::from_secs(30),
+                    push_keepalive: None,
+                    push_source_address: None,
+                    clock_skew_threshold: std::time::Duration::from_secs(60),
                 },
                 &mut registry,
                 &MockRegistrationWithAgentLabelsImpl {},
             )
             .is_ok());
+            // The path prefix from the config file applies to the new connections
+            assert_eq!(
+                registry
+                    .get_mutable(
+                        &site_spec::SiteID::from_str("server/pre-baked-pull-site-2").unwrap()
+                    )
+                    .unwrap()
+                    .path_prefix
+                    .as_deref(),
+                Some("/monitoring")
+            );
             test_registry_after_registration(true, &mut registry)
         }
 
@@ -1001,8 +2273,18 @@ mod tests {
             assert!(_register_pre_configured(
                 &pre_configured_connections(false),
                 &config::ClientConfig {
+                    path_prefix: None,
                     use_proxy: false,
+                    proxy: None,
                     validate_api_cert: false,
+                    tls_policy: certs::TlsPolicy::default(),
+                    extra_root_certs: vec![],
+                    tls_servername: None,
+                    connect_timeout: std::time::Duration::from_secs(10),
+                    push_timeout: std::time::Duration::from_secs(30),
+                    push_keepalive: None,
+                    push_source_address: None,
+                    clock_skew_threshold: std::time::Duration::from_secs(60),
                 },
                 &mut registry,
                 &MockRegistrationWithAgentLabelsImpl {},
@@ -1040,8 +2322,18 @@ mod tests {
             assert!(_register_pre_configured(
                 &pre_configured_connections,
                 &config::ClientConfig {
+                    path_prefix: None,
                     use_proxy: false,
+                    proxy: None,
                     validate_api_cert: false,
+                    tls_policy: certs::TlsPolicy::default(),
+                    extra_root_certs: vec![],
+                    tls_servername: None,
+                    connect_timeout: std::time::Duration::from_secs(10),
+                    push_timeout: std::time::Duration::from_secs(30),
+                    push_keepalive: None,
+                    push_source_address: None,
+                    clock_skew_threshold: std::time::Duration::from_secs(60),
                 },
                 &mut registry,
                 &MockRegistrationWithAgentLabelsImpl {},
@@ -0,0 +1,347 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Removes or disables the connections whose TLS setup would fail, eg. because the certificate
+//! doesn't parse, the private key doesn't belong to it or it was issued for another UUID. A
+//! registry with malformed UUIDs can't be loaded at all, so these have to be fixed by hand.
+//! Connections with the UUID of another one are always removed, disabling them wouldn't resolve
+//! the clash.
+
+use crate::{cli, config, site_spec};
+use anyhow::Result as AnyhowResult;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum ConnectionRef {
+    Standard(site_spec::SiteID),
+    Imported(uuid::Uuid),
+    /// Left out when loading the registry already, see config::DuplicateUuid
+    Duplicate(String),
+}
+
+impl std::fmt::Display for ConnectionRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Standard(site_id) => write!(f, "Connection {}", site_id),
+            Self::Imported(uuid) => write!(f, "Imported connection {}", uuid),
+            Self::Duplicate(connection) => write!(f, "{}", connection),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum RepairAction {
+    Remove,
+    Disable,
+}
+
+#[derive(Debug)]
+struct Repair {
+    connection: ConnectionRef,
+    problem: String,
+    action: RepairAction,
+}
+
+impl Repair {
+    fn report(&self, dry_run: bool) -> String {
+        let action = match (self.action, dry_run) {
+            (RepairAction::Remove, false) => "removed",
+            (RepairAction::Remove, true) => "would be removed",
+            (RepairAction::Disable, false) => "disabled",
+            (RepairAction::Disable, true) => "would be disabled",
+        };
+        format!("{}: {}, {}", self.connection, self.problem, action)
+    }
+}
+
+/// Disabled connections are neither pushed nor served, so they don't need repairing, unless
+/// they are to be removed.
+fn broken_connections(
+    registry: &config::Registry,
+    action: RepairAction,
+) -> Vec<(ConnectionRef, String)> {
+    let needs_repair = |connection: &config::TrustedConnection| {
+        action == RepairAction::Remove || !connection.agent_output_disabled
+    };
+    let mut broken = vec![];
+    for (site_id, connection) in registry
+        .push_connections()
+        .chain(registry.standard_pull_connections())
+    {
+        if !needs_repair(&connection.trust) {
+            continue;
+        }
+        if let Err(err) = connection.trust.validate() {
+            broken.push((
+                ConnectionRef::Standard(site_id.clone()),
+                format!("{:#}", err),
+            ));
+        }
+    }
+    for connection in registry.imported_pull_connections() {
+        if !needs_repair(connection) {
+            continue;
+        }
+        if let Err(err) = connection.validate() {
+            broken.push((
+                ConnectionRef::Imported(connection.uuid),
+                format!("{:#}", err),
+            ));
+        }
+    }
+    broken
+}
+
+fn apply(
+    registry: &mut config::Registry,
+    connection: &ConnectionRef,
+    action: RepairAction,
+) -> AnyhowResult<()> {
+    match (connection, action) {
+        // Saving the registry removes it from the file
+        (ConnectionRef::Duplicate(..), _) => Ok(()),
+        (ConnectionRef::Standard(site_id), RepairAction::Remove) => {
+            registry.delete_standard_connection(site_id)
+        }
+        (ConnectionRef::Imported(uuid), RepairAction::Remove) => {
+            registry.delete_imported_connection(uuid)
+        }
+        (ConnectionRef::Standard(site_id), RepairAction::Disable) => {
+            if let Some(connection) = registry.get_mutable(site_id) {
+                connection.trust.agent_output_disabled = true;
+            }
+            Ok(())
+        }
+        // Imported connections are only identified by their UUID, so they are replaced as a whole
+        (ConnectionRef::Imported(uuid), RepairAction::Disable) => {
+            let disabled = registry
+                .imported_pull_connections()
+                .find(|connection| &connection.uuid == uuid)
+                .cloned();
+            if let Some(mut disabled) = disabled {
+                registry.delete_imported_connection(uuid)?;
+                disabled.agent_output_disabled = true;
+                registry.register_imported_connection(disabled);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn _repair(
+    registry: &mut config::Registry,
+    action: RepairAction,
+    dry_run: bool,
+) -> AnyhowResult<Vec<Repair>> {
+    let mut repairs: Vec<Repair> = registry
+        .dropped_duplicates()
+        .iter()
+        .map(|duplicate| Repair {
+            connection: ConnectionRef::Duplicate(duplicate.connection.clone()),
+            problem: duplicate.problem(),
+            action: RepairAction::Remove,
+        })
+        .collect();
+    for (connection, problem) in broken_connections(registry, action) {
+        if !dry_run {
+            apply(registry, &connection, action)?;
+        }
+        repairs.push(Repair {
+            connection,
+            problem,
+            action,
+        });
+    }
+    if !dry_run && !repairs.is_empty() {
+        registry.save()?;
+    }
+    Ok(repairs)
+}
+
+pub fn repair(registry: &mut config::Registry, args: &cli::RepairArgs) -> AnyhowResult<()> {
+    let action = match args.disable {
+        true => RepairAction::Disable,
+        false => RepairAction::Remove,
+    };
+    let repairs = _repair(registry, action, args.dry_run)?;
+    if repairs.is_empty() {
+        println!("No broken connections found");
+        return Ok(());
+    }
+    for repair in &repairs {
+        println!("{}", repair.report(args.dry_run));
+    }
+    match args.dry_run {
+        true => println!(
+            "{} broken connection(s) found, nothing was changed (dry run)",
+            repairs.len()
+        ),
+        false => println!("{} broken connection(s) repaired", repairs.len()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certs;
+    use std::str::FromStr;
+
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_PULL: &str = "b1a2c9e8-7b1a-4e0c-9d43-2f5b8e9f6a10";
+    const UUID_PULL_IMP: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+
+    fn valid_connection(uuid: &str) -> config::TrustedConnection {
+        let mut connection = config::TrustedConnection::from(uuid);
+        let (root_cert, certificate, private_key) =
+            certs::make_throwaway_connection_identity(&connection.uuid).unwrap();
+        connection.root_cert = root_cert;
+        connection.certificate = certificate;
+        connection.private_key = private_key;
+        connection
+    }
+
+    fn push_site() -> site_spec::SiteID {
+        site_spec::SiteID::from_str("server/push-site").unwrap()
+    }
+
+    fn pull_site() -> site_spec::SiteID {
+        site_spec::SiteID::from_str("server/pull-site").unwrap()
+    }
+
+    /// The push connection is valid, the pull connection has the key of another connection and
+    /// the imported connection a certificate issued for another UUID.
+    fn registry() -> (tempfile::NamedTempFile, config::Registry) {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut registry = config::Registry::new(file.path()).unwrap();
+        let mut push = config::TrustedConnectionWithRemote::from(UUID_PUSH);
+        push.trust = valid_connection(UUID_PUSH);
+        registry.register_connection(&config::ConnectionType::Push, &push_site(), push);
+        let mut pull = config::TrustedConnectionWithRemote::from(UUID_PULL);
+        pull.trust = valid_connection(UUID_PULL);
+        pull.trust.private_key = valid_connection(UUID_PULL).private_key;
+        registry.register_connection(&config::ConnectionType::Pull, &pull_site(), pull);
+        let mut imported = valid_connection(UUID_PULL_IMP);
+        imported.uuid = uuid::Uuid::new_v4();
+        registry.register_imported_connection(imported);
+        (file, registry)
+    }
+
+    fn connection_count(registry: &config::Registry) -> usize {
+        registry.push_connections().count() + registry.pull_connections().count()
+    }
+
+    fn reloaded(registry: &config::Registry) -> config::Registry {
+        config::Registry::from_file(registry.path()).unwrap()
+    }
+
+    #[test]
+    fn test_repair_remove() {
+        let (_file, mut registry) = registry();
+        let repairs = _repair(&mut registry, RepairAction::Remove, false).unwrap();
+        assert_eq!(repairs.len(), 2);
+        assert_eq!(repairs[0].connection, ConnectionRef::Standard(pull_site()));
+        assert_eq!(
+            repairs[0].problem,
+            "Private key does not belong to the certificate"
+        );
+        assert!(matches!(repairs[1].connection, ConnectionRef::Imported(..)));
+        assert!(repairs[1].problem.starts_with("Certificate was issued for"));
+        let registry = reloaded(&registry);
+        assert_eq!(connection_count(&registry), 1);
+        assert!(registry
+            .push_connections()
+            .any(|(site_id, _)| site_id == &push_site()));
+    }
+
+    #[test]
+    fn test_repair_disable() {
+        let (_file, mut registry) = registry();
+        assert_eq!(
+            _repair(&mut registry, RepairAction::Disable, false)
+                .unwrap()
+                .len(),
+            2
+        );
+        let registry = reloaded(&registry);
+        assert_eq!(connection_count(&registry), 3);
+        let disabled = registry
+            .pull_connections()
+            .chain(registry.push_connections().map(|(_, c)| &c.trust))
+            .filter(|connection| connection.agent_output_disabled)
+            .count();
+        assert_eq!(disabled, 2);
+        // Once disabled, there is nothing left to do
+        let mut registry = registry;
+        assert!(_repair(&mut registry, RepairAction::Disable, false)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            _repair(&mut registry, RepairAction::Remove, true)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_repair_dry_run() {
+        let (_file, mut registry) = registry();
+        registry.save().unwrap();
+        for action in [RepairAction::Remove, RepairAction::Disable] {
+            assert_eq!(_repair(&mut registry, action, true).unwrap().len(), 2);
+        }
+        assert_eq!(connection_count(&registry), 3);
+        assert!(reloaded(&registry)
+            .pull_connections()
+            .all(|connection| !connection.agent_output_disabled));
+    }
+
+    #[test]
+    fn test_repair_duplicate_uuid() {
+        let (_file, registry) = registry();
+        let mut duplicate = config::TrustedConnectionWithRemote::from(UUID_PUSH);
+        duplicate.trust = valid_connection(UUID_PUSH);
+        let mut registry = registry;
+        registry.register_connection(
+            &config::ConnectionType::Pull,
+            &site_spec::SiteID::from_str("server/z-site").unwrap(),
+            duplicate,
+        );
+        registry.save().unwrap();
+        let mut registry = reloaded(&registry);
+        // Removed even when disabling, the UUID would still clash
+        let repairs = _repair(&mut registry, RepairAction::Disable, true).unwrap();
+        assert_eq!(repairs.len(), 3);
+        assert_eq!(
+            repairs[0].report(true),
+            format!(
+                "Pull connection server/z-site: Duplicate UUID {}, already used by the push \
+                 connection server/push-site, would be removed",
+                UUID_PUSH
+            )
+        );
+        assert_eq!(connection_count(&reloaded(&registry)), 3);
+        _repair(&mut registry, RepairAction::Disable, false).unwrap();
+        let registry = reloaded(&registry);
+        assert!(registry.dropped_duplicates().is_empty());
+        assert_eq!(connection_count(&registry), 3);
+    }
+
+    #[test]
+    fn test_report() {
+        let repair = Repair {
+            connection: ConnectionRef::Standard(pull_site()),
+            problem: String::from("Invalid private key"),
+            action: RepairAction::Disable,
+        };
+        assert_eq!(
+            repair.report(false),
+            "Connection server/pull-site: Invalid private key, disabled"
+        );
+        assert_eq!(
+            repair.report(true),
+            "Connection server/pull-site: Invalid private key, would be disabled"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Checks the pull path end to end without a site, eg. for smoke tests after installing. Like
+//! test-pull, but the connection is a temporary one with throwaway certificates and the agent
+//! output comes from a dummy agent. Nothing is read from or written to the registry or the
+//! config file.
+
+use crate::modes::test_pull;
+use crate::{certs, cli, config, types};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const DUMMY_AGENT_OUTPUT: &[u8] = b"<<<check_mk>>>\nVersion: self-test\nAgentOS: self-test\n";
+
+/// Serves a single request like the agent does: it reads the remote IP and then writes its output
+async fn dummy_agent(listener: TcpListener) -> AnyhowResult<()> {
+    let (mut stream, _) = listener.accept().await?;
+    let mut remote_ip = [0u8; 64];
+    let _ = stream.read(&mut remote_ip).await?;
+    stream.write_all(DUMMY_AGENT_OUTPUT).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn agent_channel(address: SocketAddr) -> types::AgentChannel {
+    #[cfg(unix)]
+    return types::AgentChannel::Tcp(address);
+    #[cfg(windows)]
+    return types::AgentChannel::from(format!("ip/{}", address).as_str());
+}
+
+fn temporary_connection() -> AnyhowResult<config::TrustedConnection> {
+    let uuid = uuid::Uuid::new_v4();
+    let (root_cert, certificate, private_key) = certs::make_throwaway_connection_identity(&uuid)?;
+    let connection = config::TrustedConnection {
+        uuid,
+        private_key,
+        certificate,
+        root_cert,
+        pinned_fingerprint: None,
+        pinned_client_fingerprints: vec![],
+        labels: config::ConnectionLabels::new(),
+        agent_output_disabled: false,
+        min_tls_version: None,
+    };
+    connection.validate()?;
+    Ok(connection)
+}
+
+/// The registry only lives in memory, its path is never written to
+fn pull_config(
+    pull_opts: cli::PullOpts,
+    agent_address: SocketAddr,
+) -> AnyhowResult<config::PullConfig> {
+    let scratch =
+        std::env::temp_dir().join(format!("cmk-agent-ctl-self-test-{}", uuid::Uuid::new_v4()));
+    let mut registry = config::Registry::new(&scratch.join("registered_connections.json"))?;
+    registry.register_imported_connection(temporary_connection()?);
+    let mut pull_config = config::PullConfig::new(
+        config::RuntimeConfig::default(),
+        pull_opts,
+        registry,
+        &scratch.join("pull_counters.json"),
+    )?;
+    pull_config.agent_channel = agent_channel(agent_address);
+    Ok(pull_config)
+}
+
+async fn _self_test(pull_opts: cli::PullOpts) -> AnyhowResult<test_pull::FetchedOutput> {
+    let agent_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let pull_config = pull_config(pull_opts, agent_listener.local_addr()?)
+        .context("Failed to set up the temporary connection")?;
+    let (agent, fetched) = tokio::join!(
+        dummy_agent(agent_listener),
+        test_pull::fetch_agent_output(&pull_config, None)
+    );
+    let fetched = fetched?;
+    agent.context("Dummy agent failed")?;
+    if fetched.agent_output != DUMMY_AGENT_OUTPUT {
+        bail!(
+            "Received agent output differs from what the dummy agent sent: {:?}",
+            String::from_utf8_lossy(&fetched.agent_output)
+        )
+    }
+    Ok(fetched)
+}
+
+pub fn self_test(pull_opts: cli::PullOpts) -> AnyhowResult<()> {
+    let fetched = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the async runtime for self-test.")?
+        .block_on(_self_test(pull_opts))
+        .context("Self-test failed")?;
+    println!(
+        "Self-test passed: {} bytes of agent output fetched via TLS ({} bytes transferred)",
+        fetched.agent_output.len(),
+        fetched.received_bytes
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temporary_connection() {
+        let first = temporary_connection().unwrap();
+        let second = temporary_connection().unwrap();
+        assert_ne!(first.uuid, second.uuid);
+        assert_ne!(first.root_cert, second.root_cert);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_self_test() {
+        let fetched = _self_test(crate::pull_opts_from_config()).await.unwrap();
+        assert_eq!(fetched.agent_output, DUMMY_AGENT_OUTPUT);
+        assert!(fetched.received_bytes > 0);
+    }
+}
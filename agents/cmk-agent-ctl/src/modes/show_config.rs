@@ -0,0 +1,399 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Shows the pull settings the daemon would use, after merging the environment, the command line
+//! and the config file. Nothing is started and no connection is opened.
+//!
+//! The settings are named like in the config file. The registry is only summarized, st. the
+//! private keys and certificates of the connections never show up.
+
+use crate::{certs, cli, config, site_spec};
+use anyhow::{Context, Result as AnyhowResult};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+fn tls_version(version: certs::TlsVersion) -> String {
+    clap::ValueEnum::to_possible_value(&version)
+        .map(|value| String::from(value.get_name()))
+        .unwrap_or_default()
+}
+
+fn session_resumption(session_resumption: &certs::SessionResumption) -> &'static str {
+    match session_resumption {
+        certs::SessionResumption::Off => "off",
+        certs::SessionResumption::Cache => "cache",
+        certs::SessionResumption::Tickets => "tickets",
+    }
+}
+
+fn on_agent_unavailable(policy: config::AgentUnavailablePolicy) -> &'static str {
+    match policy {
+        config::AgentUnavailablePolicy::Fail => "fail",
+        config::AgentUnavailablePolicy::Report => "report",
+    }
+}
+
+fn on_overload(policy: config::OverloadPolicy) -> &'static str {
+    match policy {
+        config::OverloadPolicy::Reject => "reject",
+        config::OverloadPolicy::Queue => "queue",
+    }
+}
+
+fn to_strings<T: ToString>(items: impl IntoIterator<Item = T>) -> Value {
+    json!(items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<String>>())
+}
+
+fn by_site<T>(map: &HashMap<site_spec::SiteID, T>, to_value: impl Fn(&T) -> Value) -> Value {
+    json!(map
+        .iter()
+        .map(|(site_id, value)| (site_id.to_string(), to_value(value)))
+        .collect::<BTreeMap<String, Value>>())
+}
+
+/// In the order they are shown as text
+fn settings(pull_config: &config::PullConfig) -> Vec<(&'static str, Value)> {
+    let tls_policy = &pull_config.tls_policy;
+    vec![
+        (
+            "config_file",
+            json!(pull_config
+                .config_path
+                .as_ref()
+                .map(|path| path.display().to_string())),
+        ),
+        (
+            "registry",
+            json!(pull_config.registry.path().display().to_string()),
+        ),
+        ("pull_connections", json!(pull_config.connections().count())),
+        (
+            "registry_readonly",
+            json!(pull_config.registry.is_read_only()),
+        ),
+        ("require_registry", json!(pull_config.require_registry)),
+        ("pull_port", json!(pull_config.ports)),
+        (
+            "listen_address",
+            json!(pull_config
+                .listen_address
+                .map(|address| address.to_string())),
+        ),
+        ("listen_backlog", json!(pull_config.listen_backlog)),
+        ("retry_bind", json!(pull_config.retry_bind)),
+        ("worker_threads", json!(pull_config.worker_threads)),
+        ("max_connections", json!(pull_config.max_connections)),
+        (
+            "max_connections_total",
+            json!(pull_config.max_connections_total),
+        ),
+        ("on_overload", json!(on_overload(pull_config.on_overload))),
+        ("overload_queue", json!(pull_config.overload_queue)),
+        ("allowed_ip", to_strings(&pull_config.allowed_ip)),
+        (
+            "allowed_ip_file",
+            json!(pull_config
+                .allowed_ip_file
+                .as_ref()
+                .map(|path| path.display().to_string())),
+        ),
+        ("allow_any", json!(pull_config.allow_any)),
+        (
+            "no_client_auth",
+            json!(pull_config.tls_policy.no_client_auth),
+        ),
+        ("denied_ip", to_strings(&pull_config.denied_ip)),
+        ("trusted_proxies", to_strings(&pull_config.trusted_proxies)),
+        ("pull_rate_limit", json!(pull_config.pull_rate_limit)),
+        (
+            "pull_rate_limit_burst",
+            json!(pull_config.pull_rate_limit_burst),
+        ),
+        (
+            "pull_bandwidth_limit",
+            json!(pull_config.pull_bandwidth_limit),
+        ),
+        ("idle_timeout", json!(pull_config.timeouts.idle)),
+        ("total_timeout", json!(pull_config.timeouts.total)),
+        (
+            "connection_timeouts",
+            by_site(&pull_config.site_connection_timeouts, |timeout| {
+                json!(timeout)
+            }),
+        ),
+        ("handshake_timeout", json!(pull_config.timeouts.handshake)),
+        (
+            "shutdown_grace_period",
+            json!(pull_config.shutdown_grace_period),
+        ),
+        (
+            "tls_min_version",
+            json!(tls_policy.min_version.map(tls_version)),
+        ),
+        (
+            "tls_cipher_suites",
+            to_strings(
+                tls_policy
+                    .cipher_suites()
+                    .iter()
+                    .map(|suite| format!("{:?}", suite.suite())),
+            ),
+        ),
+        (
+            "tls_session_resumption",
+            json!(session_resumption(&tls_policy.session_resumption)),
+        ),
+        (
+            "tls_session_cache_size",
+            json!(tls_policy.session_cache_size),
+        ),
+        ("tls_max_chain_depth", json!(tls_policy.max_chain_depth)),
+        (
+            "agent_channel",
+            json!(pull_config.agent_channel.to_string()),
+        ),
+        (
+            "agent_channels",
+            by_site(&pull_config.site_agent_channels, |agent_channel| {
+                json!(agent_channel.to_string())
+            }),
+        ),
+        ("agent_channel_timeout", json!(pull_config.timeouts.connect)),
+        (
+            "on_agent_unavailable",
+            json!(on_agent_unavailable(pull_config.on_agent_unavailable)),
+        ),
+        ("output_hooks", json!(pull_config.output_hooks)),
+        (
+            "output_hook_timeout",
+            json!(pull_config.output_hook_timeout),
+        ),
+        (
+            "agent_output_marker",
+            json!(pull_config
+                .agent_output_marker
+                .as_ref()
+                .map(|marker| String::from_utf8_lossy(marker))),
+        ),
+        ("max_output_bytes", json!(pull_config.max_output_bytes)),
+        ("max_request_bytes", json!(pull_config.max_request_bytes)),
+        ("io_chunk_size", json!(pull_config.io_chunk_size)),
+        ("cache_ttl", json!(pull_config.cache_ttl)),
+        (
+            "metrics_listen",
+            json!(pull_config
+                .metrics_listen
+                .map(|address| address.to_string())),
+        ),
+        (
+            "health_listen",
+            json!(pull_config.health_listen.map(|address| address.to_string())),
+        ),
+        (
+            "access_log",
+            json!(pull_config
+                .access_log
+                .as_ref()
+                .map(|path| path.display().to_string())),
+        ),
+        (
+            "security_log",
+            json!(pull_config
+                .security_log
+                .as_ref()
+                .map(|path| path.display().to_string())),
+        ),
+        (
+            "pull_unix_socket",
+            json!(pull_config
+                .pull_unix_socket
+                .as_ref()
+                .map(|path| path.display().to_string())),
+        ),
+        (
+            "pull_admin_socket",
+            json!(pull_config
+                .pull_admin_socket
+                .as_ref()
+                .map(|path| path.display().to_string())),
+        ),
+        (
+            "counters_file",
+            json!(pull_config.counters_path.display().to_string()),
+        ),
+        (
+            "connection_totals_file",
+            json!(pull_config.connection_totals_path.display().to_string()),
+        ),
+        (
+            "connection_totals_interval",
+            json!(pull_config.connection_totals_interval),
+        ),
+        ("explain_rejections", json!(pull_config.explain_rejections)),
+        ("strict_startup", json!(pull_config.strict_startup)),
+    ]
+}
+
+fn readable_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("-"),
+        Value::String(string) => string.clone(),
+        Value::Array(items) if items.is_empty() => String::from("-"),
+        Value::Array(items) => items
+            .iter()
+            .map(readable_value)
+            .collect::<Vec<String>>()
+            .join(", "),
+        Value::Object(entries) if entries.is_empty() => String::from("-"),
+        Value::Object(entries) => entries
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, readable_value(value)))
+            .collect::<Vec<String>>()
+            .join(", "),
+        other => other.to_string(),
+    }
+}
+
+fn to_string(
+    pull_config: &config::PullConfig,
+    output_format: &cli::OutputFormat,
+) -> AnyhowResult<String> {
+    let settings = settings(pull_config);
+    match output_format {
+        cli::OutputFormat::Json => serde_json::to_string(
+            &settings
+                .into_iter()
+                .map(|(key, value)| (String::from(key), value))
+                .collect::<serde_json::Map<String, Value>>(),
+        )
+        .context("Failed to serialize configuration to JSON"),
+        cli::OutputFormat::Text => Ok(settings
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key, readable_value(value)))
+            .collect::<Vec<String>>()
+            .join("\n")),
+    }
+}
+
+pub fn show_config(
+    pull_config: &config::PullConfig,
+    output_format: &cli::OutputFormat,
+) -> AnyhowResult<()> {
+    println!("{}", to_string(pull_config, output_format)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TOMLLoader;
+    use std::io::Write;
+
+    fn pull_config(config: &str, pull_opts: cli::PullOpts) -> config::PullConfig {
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        write!(config_file, "{}", config).unwrap();
+        config::PullConfig::new(
+            config::RuntimeConfig::load(config_file.path()).unwrap(),
+            pull_opts,
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap(),
+            tempfile::NamedTempFile::new().unwrap().path(),
+        )
+        .unwrap()
+    }
+
+    fn pull_opts(port: Vec<u16>) -> cli::PullOpts {
+        cli::PullOpts {
+            port,
+            tls_min_version: None,
+            cache_ttl: None,
+            metrics_listen: None,
+            health_listen: None,
+            max_connections: None,
+            worker_threads: None,
+            listen_backlog: None,
+            retry_bind: None,
+            pull_bandwidth_limit: None,
+            io_chunk_size: None,
+            registry_readonly: false,
+            registry_load_retry: None,
+            require_registry: false,
+            allow_any: false,
+            no_client_auth: false,
+            explain_rejections: false,
+            strict_startup: false,
+            on_agent_unavailable: None,
+            #[cfg(unix)]
+            pull_unix_socket: None,
+            #[cfg(windows)]
+            agent_channel: None,
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let pull_config = pull_config(
+            r#"
+pull_port = 7556
+allowed_ip = ["192.168.0.0/24"]
+tls_min_version = "1.3"
+connection_timeouts = { "server/site" = 30 }
+"#,
+            pull_opts(vec![8556]),
+        );
+        let shown: Value =
+            serde_json::from_str(&to_string(&pull_config, &cli::OutputFormat::Json).unwrap())
+                .unwrap();
+        // The command line wins over the config file
+        assert_eq!(shown["pull_port"], json!([8556]));
+        assert_eq!(shown["allowed_ip"], json!(["192.168.0.0/24"]));
+        assert_eq!(shown["tls_min_version"], "1.3");
+        assert_eq!(shown["connection_timeouts"], json!({"server/site": 30}));
+        assert_eq!(shown["listen_address"], Value::Null);
+        assert_eq!(shown["pull_connections"], 0);
+        assert_eq!(shown["on_agent_unavailable"], "fail");
+        assert_eq!(shown["on_overload"], "reject");
+        assert_eq!(
+            shown.as_object().unwrap().len(),
+            settings(&pull_config).len()
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let pull_config = pull_config(
+            "allowed_ip = [\"10.0.0.1\", \"10.0.1.0/24\"]",
+            pull_opts(vec![]),
+        );
+        let shown = to_string(&pull_config, &cli::OutputFormat::Text).unwrap();
+        let lines = shown.lines().collect::<Vec<&str>>();
+        assert!(lines[0].starts_with("config_file: "));
+        assert!(lines.contains(&"allowed_ip: 10.0.0.1, 10.0.1.0/24"));
+        assert!(lines.contains(&"denied_ip: -"));
+        assert!(lines.contains(&"allow_any: false"));
+    }
+
+    #[test]
+    fn test_no_secrets() {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        let mut connection =
+            config::TrustedConnection::from("00c21714-5086-46d7-848e-5be72c715cfd");
+        connection.private_key = String::from("private key");
+        registry.register_imported_connection(connection);
+        let pull_config = config::PullConfig::new(
+            config::RuntimeConfig::default(),
+            pull_opts(vec![]),
+            registry,
+            tempfile::NamedTempFile::new().unwrap().path(),
+        )
+        .unwrap();
+        let shown = to_string(&pull_config, &cli::OutputFormat::Json).unwrap();
+        assert!(!shown.contains("private key"));
+        assert!(!shown.contains("00c21714"));
+        assert!(shown.contains("\"pull_connections\":1"));
+    }
+}
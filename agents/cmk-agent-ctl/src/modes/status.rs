@@ -2,17 +2,49 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use crate::{agent_receiver_api, certs, config, constants, site_spec};
-use anyhow::{Context, Result as AnyhowResult};
-use log::debug;
+use crate::{
+    agent_receiver_api, certs, cli, config, constants, metrics, misc, monitoring_data, site_spec,
+    types,
+};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use log::{debug, warn};
 use serde::ser::SerializeStruct;
 use serde_with::DisplayFromStr;
 
+// Increment whenever the JSON output changes in an incompatible manner
+const STATUS_FORMAT_VERSION: u32 = 1;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// Returned by the status command if any of the reported certificates has expired, such that
+/// this can be told apart from other failures by the exit code.
+#[derive(Debug)]
+pub struct CertificateExpired;
+
+impl std::fmt::Display for CertificateExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "At least one connection certificate has expired")
+    }
+}
+
+impl std::error::Error for CertificateExpired {}
+
+#[derive(serde::Serialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum CertExpiry {
+    Valid,
+    ExpiresSoon,
+    Expired,
+}
+
 #[derive(serde::Serialize)]
 struct CertInfo {
     issuer: String,
     from: String,
     to: String,
+    fingerprint: String,
+    days_until_expiry: i64,
+    expiry: CertExpiry,
 }
 
 #[derive(serde::Serialize)]
@@ -22,10 +54,81 @@ enum CertParsingResult {
     Error(String),
 }
 
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum PullCountersResult {
+    Success(metrics::PullCountersSnapshot),
+    Error(String),
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+enum AgentChannelStatus {
+    Reachable {
+        /// Only known if we have read the agent output
+        #[serde(skip_serializing_if = "Option::is_none")]
+        agent_version: Option<String>,
+    },
+    Unreachable {
+        error: String,
+    },
+    NotProbed {
+        reason: String,
+    },
+}
+
+impl AgentChannelStatus {
+    fn probe(agent_channel: &types::AgentChannel, read_output: bool) -> AgentChannelStatus {
+        match monitoring_data::probe(
+            agent_channel,
+            read_output,
+            std::time::Duration::from_secs(constants::AGENT_CHANNEL_PROBE_TIMEOUT),
+        ) {
+            Ok(agent_version) => AgentChannelStatus::Reachable { agent_version },
+            Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+                AgentChannelStatus::NotProbed {
+                    reason: err.to_string(),
+                }
+            }
+            Err(err) => AgentChannelStatus::Unreachable {
+                error: err.to_string(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for AgentChannelStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Reachable {
+                agent_version: None,
+            } => write!(f, "reachable"),
+            Self::Reachable {
+                agent_version: Some(agent_version),
+            } => write!(f, "reachable (agent version {})", agent_version),
+            Self::Unreachable { error } => {
+                write!(
+                    f,
+                    "{}",
+                    mark_problematic(&format!("unreachable ({})", error))
+                )
+            }
+            Self::NotProbed { reason } => write!(f, "not probed ({})", reason),
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
 struct LocalConnectionStatus {
     connection_type: config::ConnectionType,
+    #[serde(skip_serializing_if = "config::ConnectionLabels::is_empty")]
+    labels: config::ConnectionLabels,
+    agent_output_disabled: bool,
     cert_info: CertParsingResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_push: Option<metrics::PushResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pull_usage: Option<metrics::ConnectionUsage>,
 }
 
 #[derive(serde::Serialize)]
@@ -76,38 +179,103 @@ struct ConnectionStatus {
 struct SiteData {
     site_id: site_spec::SiteID,
     receiver_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_name: Option<String>,
 }
 
 #[derive(serde::Serialize)]
 struct Status {
+    format_version: u32,
     version: String,
     agent_socket_operational: bool,
+    agent_channel: AgentChannelStatus,
+    /// No allowlist configured or allow_any set
+    allow_any_ip: bool,
     ip_allowlist: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ip_denylist: Vec<String>,
     allow_legacy_pull: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pull_counters: Option<PullCountersResult>,
     connections: Vec<ConnectionStatus>,
 }
 
+impl CertExpiry {
+    fn from(seconds_until_expiry: i64, expiry_warning_days: u32) -> CertExpiry {
+        if seconds_until_expiry < 0 {
+            CertExpiry::Expired
+        } else if seconds_until_expiry < i64::from(expiry_warning_days) * SECONDS_PER_DAY {
+            CertExpiry::ExpiresSoon
+        } else {
+            CertExpiry::Valid
+        }
+    }
+}
+
 impl CertInfo {
-    fn from(certificate: &str) -> AnyhowResult<CertInfo> {
+    fn from(certificate: &str, expiry_warning_days: u32) -> AnyhowResult<CertInfo> {
         let pem = certs::parse_pem(certificate)?;
         let x509 = pem.parse_x509()?;
+        let seconds_until_expiry =
+            x509.validity().not_after.timestamp() - x509_parser::time::ASN1Time::now().timestamp();
         Ok(CertInfo {
             issuer: certs::common_names(x509.issuer())?.join(", "),
             from: x509.validity().not_before.to_rfc2822(),
             to: x509.validity().not_after.to_rfc2822(),
+            fingerprint: certs::fingerprint_sha256(&pem.contents)?,
+            days_until_expiry: seconds_until_expiry.div_euclid(SECONDS_PER_DAY),
+            expiry: CertExpiry::from(seconds_until_expiry, expiry_warning_days),
         })
     }
+
+    fn expiry_line_readable(&self) -> Option<String> {
+        match self.expiry {
+            CertExpiry::Valid => None,
+            CertExpiry::ExpiresSoon => Some(format!(
+                "Certificate expires in {} day(s), fingerprint {} (!)",
+                self.days_until_expiry, self.fingerprint
+            )),
+            CertExpiry::Expired => Some(mark_problematic(&format!(
+                "CERTIFICATE EXPIRED, fingerprint {}",
+                self.fingerprint
+            ))),
+        }
+    }
 }
 
 impl CertParsingResult {
-    fn from(certificate: &str) -> CertParsingResult {
-        match CertInfo::from(certificate) {
+    fn from(certificate: &str, expiry_warning_days: u32) -> CertParsingResult {
+        match CertInfo::from(certificate, expiry_warning_days) {
             Ok(cert_info) => CertParsingResult::Success(cert_info),
             _ => CertParsingResult::Error(String::from("parsing_error")),
         }
     }
 }
 
+impl PullCountersResult {
+    fn from(path: &std::path::Path) -> PullCountersResult {
+        if !path.exists() {
+            return PullCountersResult::Error(String::from("not_available"));
+        }
+        match <metrics::PullCountersSnapshot as config::JSONLoader>::load(path) {
+            Ok(snapshot) => PullCountersResult::Success(snapshot),
+            _ => PullCountersResult::Error(String::from("parsing_error")),
+        }
+    }
+}
+
+impl std::fmt::Display for PullCountersResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Success(snapshot) => write!(f, "{}", snapshot),
+            Self::Error(err) => match err.as_str() {
+                "not_available" => write!(f, "not available (pull listener not running?)"),
+                _ => write!(f, "{}", mark_problematic("reading counters failed")),
+            },
+        }
+    }
+}
+
 impl ConnectionStatus {
     fn query_remote(
         site_id: &site_spec::SiteID,
@@ -115,7 +283,7 @@ impl ConnectionStatus {
         agent_rec_api: &impl agent_receiver_api::Status,
     ) -> AnyhowResult<RemoteConnectionStatus> {
         let status_response = agent_rec_api.status(
-            &site_spec::make_site_url(site_id, &conn.receiver_port)?,
+            &site_spec::make_site_url(site_id, &conn.receiver_port, conn.path_prefix.as_deref())?,
             &conn.trust,
         )?;
         Ok(RemoteConnectionStatus {
@@ -130,16 +298,22 @@ impl ConnectionStatus {
         conn: &config::TrustedConnectionWithRemote,
         conn_type: config::ConnectionType,
         agent_rec_api: &Option<impl agent_receiver_api::Status>,
+        expiry_warning_days: u32,
     ) -> ConnectionStatus {
         ConnectionStatus {
             site_data: Some(SiteData {
                 site_id: site_id.clone(),
                 receiver_port: conn.receiver_port,
+                host_name: conn.host_name.clone(),
             }),
             uuid: conn.trust.uuid,
             local: LocalConnectionStatus {
                 connection_type: conn_type,
-                cert_info: CertParsingResult::from(&conn.trust.certificate),
+                labels: conn.trust.labels.clone(),
+                agent_output_disabled: conn.trust.agent_output_disabled,
+                cert_info: CertParsingResult::from(&conn.trust.certificate, expiry_warning_days),
+                last_push: None,
+                pull_usage: None,
             },
             remote: match agent_rec_api {
                 Some(agent_rec_api) => {
@@ -150,13 +324,20 @@ impl ConnectionStatus {
         }
     }
 
-    fn from_imported_conn(conn: &config::TrustedConnection) -> ConnectionStatus {
+    fn from_imported_conn(
+        conn: &config::TrustedConnection,
+        expiry_warning_days: u32,
+    ) -> ConnectionStatus {
         ConnectionStatus {
             site_data: None,
             uuid: conn.uuid,
             local: LocalConnectionStatus {
                 connection_type: config::ConnectionType::Pull,
-                cert_info: CertParsingResult::from(&conn.certificate),
+                labels: conn.labels.clone(),
+                agent_output_disabled: conn.agent_output_disabled,
+                cert_info: CertParsingResult::from(&conn.certificate, expiry_warning_days),
+                last_push: None,
+                pull_usage: None,
             },
             remote: Remote::Imported,
         }
@@ -173,6 +354,27 @@ impl ConnectionStatus {
                 String::from("None (imported connection)")
             }
         ));
+        if let Some(host_name) = self
+            .site_data
+            .as_ref()
+            .and_then(|site_data| site_data.host_name.as_ref())
+        {
+            lines.push(format!("Registered host name: {}", host_name));
+        }
+        if !self.local.labels.is_empty() {
+            lines.push(format!(
+                "Labels: {}",
+                self.local
+                    .labels
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+        if self.local.agent_output_disabled {
+            lines.push(String::from("Agent output: disabled"));
+        }
         match &self.local.cert_info {
             CertParsingResult::Success(cert_info) => {
                 lines.push(format!("Certificate issuer: {}", cert_info.issuer));
@@ -180,11 +382,27 @@ impl ConnectionStatus {
                     "Certificate validity: {} - {}",
                     cert_info.from, cert_info.to
                 ));
+                if let Some(expiry_line) = cert_info.expiry_line_readable() {
+                    lines.push(expiry_line);
+                }
             }
             CertParsingResult::Error(..) => {
                 lines.push(mark_problematic("Certificate parsing failed"))
             }
         }
+        if self.local.connection_type == config::ConnectionType::Push {
+            lines.push(match &self.local.last_push {
+                Some(last_push) if last_push.success => format!("Last push: {}", last_push),
+                Some(last_push) => mark_problematic(&format!("Last push: {}", last_push)),
+                None => String::from("Last push: none recorded"),
+            });
+        }
+        if self.local.connection_type == config::ConnectionType::Pull {
+            lines.push(match &self.local.pull_usage {
+                Some(pull_usage) => format!("Served: {}", pull_usage),
+                None => String::from("Served: none recorded"),
+            });
+        }
         lines
     }
 
@@ -280,43 +498,155 @@ impl std::fmt::Display for ConnectionStatus {
     }
 }
 
+/// Restricts the reported connections, all given criteria have to match
+#[derive(Default)]
+pub struct ConnectionFilter {
+    pub connection_type: Option<config::ConnectionType>,
+    pub site_id: Option<site_spec::SiteID>,
+    pub labels: Vec<(String, String)>,
+}
+
+impl ConnectionFilter {
+    fn is_active(&self) -> bool {
+        self.connection_type.is_some() || self.site_id.is_some() || !self.labels.is_empty()
+    }
+
+    fn matches(
+        &self,
+        connection_type: &config::ConnectionType,
+        site_id: Option<&site_spec::SiteID>,
+        labels: &config::ConnectionLabels,
+    ) -> bool {
+        self.connection_type
+            .as_ref()
+            .is_none_or(|filter_type| filter_type == connection_type)
+            && self
+                .site_id
+                .as_ref()
+                .is_none_or(|filter_site_id| Some(filter_site_id) == site_id)
+            && self
+                .labels
+                .iter()
+                .all(|(key, value)| labels.get(key) == Some(value))
+    }
+}
+
 impl Status {
     fn from(
         registry: &config::Registry,
         pull_config: &config::PullConfig,
         agent_rec_api: &Option<impl agent_receiver_api::Status>,
+        counters: bool,
+        filter: &ConnectionFilter,
+        expiry_warning_days: u32,
+        agent_channel: AgentChannelStatus,
     ) -> Status {
         let mut conn_stats = Vec::new();
 
-        for (site_id, push_conn) in registry.push_connections() {
+        for (site_id, push_conn) in registry.push_connections().filter(|(site_id, conn)| {
+            filter.matches(
+                &config::ConnectionType::Push,
+                Some(site_id),
+                &conn.trust.labels,
+            )
+        }) {
             conn_stats.push(ConnectionStatus::from_standard_conn(
                 site_id,
                 push_conn,
                 config::ConnectionType::Push,
                 agent_rec_api,
+                expiry_warning_days,
             ));
         }
-        for (site_id, pull_conn) in registry.standard_pull_connections() {
+        for (site_id, pull_conn) in
+            registry
+                .standard_pull_connections()
+                .filter(|(site_id, conn)| {
+                    filter.matches(
+                        &config::ConnectionType::Pull,
+                        Some(site_id),
+                        &conn.trust.labels,
+                    )
+                })
+        {
             conn_stats.push(ConnectionStatus::from_standard_conn(
                 site_id,
                 pull_conn,
                 config::ConnectionType::Pull,
                 agent_rec_api,
+                expiry_warning_days,
             ));
         }
-        for imp_pull_conn in registry.imported_pull_connections() {
-            conn_stats.push(ConnectionStatus::from_imported_conn(imp_pull_conn));
+        for imp_pull_conn in registry
+            .imported_pull_connections()
+            .filter(|conn| filter.matches(&config::ConnectionType::Pull, None, &conn.labels))
+        {
+            conn_stats.push(ConnectionStatus::from_imported_conn(
+                imp_pull_conn,
+                expiry_warning_days,
+            ));
         }
 
         Status {
+            format_version: STATUS_FORMAT_VERSION,
             version: String::from(constants::VERSION),
             agent_socket_operational: pull_config.agent_channel.operational(),
-            ip_allowlist: pull_config.allowed_ip.to_vec(),
+            agent_channel,
+            allow_any_ip: pull_config.ip_allowlist().is_none(),
+            ip_allowlist: pull_config
+                .ip_allowlist()
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect(),
+            ip_denylist: pull_config
+                .denied_ip
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect(),
             allow_legacy_pull: pull_config.allow_legacy_pull(),
+            pull_counters: match counters {
+                true => Some(PullCountersResult::from(&pull_config.counters_path)),
+                false => None,
+            },
             connections: conn_stats,
         }
     }
 
+    /// The results are recorded by the process doing the pushes, independently of the registry
+    fn add_push_results(&mut self, push_results: &metrics::PushResults) {
+        for conn in self
+            .connections
+            .iter_mut()
+            .filter(|conn| conn.local.connection_type == config::ConnectionType::Push)
+        {
+            conn.local.last_push = push_results.get(&conn.uuid).cloned();
+        }
+    }
+
+    /// The totals are written by the pull daemon, independently of the registry
+    fn add_connection_totals(&mut self, connection_totals: &metrics::ConnectionTotals) {
+        for conn in self
+            .connections
+            .iter_mut()
+            .filter(|conn| conn.local.connection_type == config::ConnectionType::Pull)
+        {
+            conn.local.pull_usage = connection_totals.get(&conn.uuid).cloned();
+        }
+    }
+
+    fn has_expired_certificates(&self) -> bool {
+        self.connections.iter().any(|conn| {
+            matches!(
+                &conn.local.cert_info,
+                CertParsingResult::Success(CertInfo {
+                    expiry: CertExpiry::Expired,
+                    ..
+                })
+            )
+        })
+    }
+
     fn to_json(&self) -> AnyhowResult<String> {
         serde_json::to_string(&self).context("Failed to serialize status to JSON")
     }
@@ -334,20 +664,30 @@ impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Version: {}\nAgent socket: {}\nIP allowlist: {}{}{}",
+            "Version: {}\nAgent socket: {}\nAgent channel: {}\nIP allowlist: {}{}{}{}{}",
             self.version,
             match self.agent_socket_operational {
                 true => String::from("operational"),
                 false => mark_problematic("inoperational"),
             },
-            match self.ip_allowlist.is_empty() {
-                true => String::from("any"),
-                false => self.ip_allowlist.join(" "),
+            self.agent_channel,
+            match (self.allow_any_ip, self.ip_allowlist.is_empty()) {
+                (true, _) => String::from("any"),
+                (false, true) => mark_problematic("none"),
+                (false, false) => self.ip_allowlist.join(" "),
+            },
+            match self.ip_denylist.is_empty() {
+                true => String::new(),
+                false => format!("\nIP denylist: {}", self.ip_denylist.join(" ")),
             },
             match self.allow_legacy_pull {
                 true => "\nLegacy mode: enabled",
                 false => "",
             },
+            match &self.pull_counters {
+                Some(pull_counters) => format!("\nPull counters: {}", pull_counters),
+                None => String::new(),
+            },
             if self.connections.is_empty() {
                 String::from("\nNo connections")
             } else {
@@ -371,34 +711,130 @@ fn mark_problematic(to_mark: &str) -> String {
 fn _status(
     registry: &config::Registry,
     pull_config: &config::PullConfig,
-    json: bool,
     agent_rec_api: &Option<impl agent_receiver_api::Status>,
-) -> AnyhowResult<String> {
-    Status::from(registry, pull_config, agent_rec_api).to_string(json)
+    counters: bool,
+    filter: &ConnectionFilter,
+    expiry_warning_days: u32,
+    agent_channel: AgentChannelStatus,
+) -> AnyhowResult<Status> {
+    let status = Status::from(
+        registry,
+        pull_config,
+        agent_rec_api,
+        counters,
+        filter,
+        expiry_warning_days,
+        agent_channel,
+    );
+    if filter.is_active() && status.connections.is_empty() {
+        bail!("No connections match the given filter")
+    }
+    Ok(status)
+}
+
+fn current_status(
+    registry: &config::Registry,
+    pull_config: &config::PullConfig,
+    client_config: &config::ClientConfig,
+    status_args: &cli::StatusArgs,
+    push_results_path: &std::path::Path,
+) -> AnyhowResult<Status> {
+    let mut status = _status(
+        registry,
+        pull_config,
+        &match status_args.no_query_remote {
+            false => Some(agent_receiver_api::Api::from(client_config)),
+            true => None,
+        },
+        status_args.counters,
+        &ConnectionFilter {
+            connection_type: status_args.connection_type.clone(),
+            site_id: status_args.site.clone(),
+            labels: status_args.labels.clone(),
+        },
+        status_args.cert_expiry_warning_days,
+        AgentChannelStatus::probe(&pull_config.agent_channel, status_args.probe_agent_output),
+    )?;
+    match <metrics::PushResults as config::JSONLoaderMissingSafe>::load_missing_safe(
+        push_results_path,
+    ) {
+        Ok(push_results) => status.add_push_results(&push_results),
+        Err(err) => warn!(
+            "Failed to load results of recent pushes from {:?}. ({})",
+            push_results_path, err
+        ),
+    }
+    match <metrics::ConnectionTotals as config::JSONLoaderMissingSafe>::load_missing_safe(
+        &pull_config.connection_totals_path,
+    ) {
+        Ok(connection_totals) => status.add_connection_totals(&connection_totals),
+        Err(err) => warn!(
+            "Failed to load connection totals from {:?}. ({})",
+            pull_config.connection_totals_path, err
+        ),
+    }
+    Ok(status)
+}
+
+/// Runs tick right away and then once per interval, until we receive a shutdown signal
+fn watch(
+    interval: std::time::Duration,
+    shutdown: &std::sync::mpsc::Receiver<()>,
+    mut tick: impl FnMut() -> AnyhowResult<()>,
+) -> AnyhowResult<()> {
+    loop {
+        tick()?;
+        if !misc::wait(shutdown, interval) {
+            return Ok(());
+        }
+    }
 }
 
 pub fn status(
     registry: &config::Registry,
     pull_config: &config::PullConfig,
     client_config: config::ClientConfig,
-    json: bool,
-    query_remote: bool,
+    status_args: &cli::StatusArgs,
+    push_results_path: &std::path::Path,
 ) -> AnyhowResult<()> {
     debug!("Mode status started");
+    if status_args.watch {
+        let mut registry = registry.clone();
+        return watch(
+            std::time::Duration::from_secs(status_args.interval),
+            &misc::shutdown_signal(),
+            || {
+                // Connections may be registered or deleted while we are watching
+                if let Err(err) = registry.refresh() {
+                    warn!("Failed to reload connection registry. ({})", err);
+                }
+                let status = current_status(
+                    &registry,
+                    pull_config,
+                    &client_config,
+                    status_args,
+                    push_results_path,
+                )?;
+                println!("{}", status.to_json()?);
+                Ok(())
+            },
+        );
+    }
+    let status = current_status(
+        registry,
+        pull_config,
+        &client_config,
+        status_args,
+        push_results_path,
+    )?;
     println!(
         "{}",
-        _status(
-            registry,
-            pull_config,
-            json,
-            &match query_remote {
-                true => Some(agent_receiver_api::Api {
-                    use_proxy: client_config.use_proxy
-                }),
-                false => None,
-            }
-        )?
+        status
+            .to_string(status_args.json || status_args.output_format == cli::OutputFormat::Json)?
     );
+    if status.has_expired_certificates() {
+        return Err(anyhow!(CertificateExpired));
+    }
     debug!("Mode status finished");
     Ok(())
 }
@@ -406,8 +842,6 @@ pub fn status(
 #[cfg(test)]
 mod test_status {
     use super::*;
-    use crate::cli;
-    use anyhow::anyhow;
     use std::str::FromStr;
 
     fn cert_info() -> CertInfo {
@@ -415,16 +849,79 @@ mod test_status {
             issuer: String::from("Site 'site' local CA"),
             from: String::from("Thu, 16 Dec 2021 08:18:41 +0000"),
             to: String::from("Tue, 18 Apr 3020 08:18:41 +0000"),
+            fingerprint: String::from("AB:CD"),
+            days_until_expiry: 365000,
+            expiry: CertExpiry::Valid,
         }
     }
 
     fn local_connection_status() -> LocalConnectionStatus {
         LocalConnectionStatus {
             connection_type: config::ConnectionType::Pull,
+            labels: config::ConnectionLabels::new(),
+            agent_output_disabled: false,
             cert_info: CertParsingResult::Success(cert_info()),
+            last_push: None,
+            pull_usage: None,
         }
     }
 
+    #[test]
+    fn test_cert_expiry() {
+        assert_eq!(CertExpiry::from(-1, 30), CertExpiry::Expired);
+        assert_eq!(CertExpiry::from(0, 30), CertExpiry::ExpiresSoon);
+        assert_eq!(
+            CertExpiry::from(29 * SECONDS_PER_DAY, 30),
+            CertExpiry::ExpiresSoon
+        );
+        assert_eq!(
+            CertExpiry::from(30 * SECONDS_PER_DAY, 30),
+            CertExpiry::Valid
+        );
+        assert_eq!(CertExpiry::from(0, 0), CertExpiry::Valid);
+    }
+
+    #[test]
+    fn test_cert_info_expiry() {
+        let cert_info = CertInfo::from(constants::TEST_CERT_OK, 30).unwrap();
+        assert_eq!(cert_info.expiry, CertExpiry::Valid);
+        assert!(cert_info.days_until_expiry > 365 * 900);
+        assert!(cert_info.expiry_line_readable().is_none());
+        assert_eq!(
+            CertInfo::from(constants::TEST_CERT_OK, u32::MAX)
+                .unwrap()
+                .expiry,
+            CertExpiry::ExpiresSoon
+        );
+    }
+
+    #[test]
+    fn test_cert_info_expiry_lines() {
+        let mut cert_info = cert_info();
+        cert_info.days_until_expiry = 12;
+        cert_info.expiry = CertExpiry::ExpiresSoon;
+        assert_eq!(
+            cert_info.expiry_line_readable().unwrap(),
+            "Certificate expires in 12 day(s), fingerprint AB:CD (!)"
+        );
+        cert_info.days_until_expiry = -3;
+        cert_info.expiry = CertExpiry::Expired;
+        assert_eq!(
+            cert_info.expiry_line_readable().unwrap(),
+            "CERTIFICATE EXPIRED, fingerprint AB:CD (!!)"
+        );
+    }
+
+    #[test]
+    fn test_status_has_expired_certificates() {
+        let mut status = build_status();
+        assert!(!status.has_expired_certificates());
+        let mut cert_info = cert_info();
+        cert_info.expiry = CertExpiry::Expired;
+        status.connections[0].local.cert_info = CertParsingResult::Success(cert_info);
+        assert!(status.has_expired_certificates());
+    }
+
     #[test]
     fn test_connection_status_remote_disabled() {
         assert_eq!(
@@ -434,11 +931,16 @@ mod test_status {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
                         receiver_port: 8000,
+                        host_name: Some(String::from("my-host")),
                     }),
                     uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
                     local: LocalConnectionStatus {
                         connection_type: config::ConnectionType::Pull,
-                        cert_info: CertParsingResult::Success(cert_info())
+                        labels: config::ConnectionLabels::new(),
+                        agent_output_disabled: false,
+                        cert_info: CertParsingResult::Success(cert_info()),
+                        last_push: None,
+                        pull_usage: None,
                     },
                     remote: Remote::QueryDisabled
                 }
@@ -449,8 +951,10 @@ mod test_status {
                  \tLocal:\n\
                  \t\tConnection type: pull-agent\n\
                  \t\tConnecting to receiver port: 8000\n\
+                 \t\tRegistered host name: my-host\n\
                  \t\tCertificate issuer: Site 'site' local CA\n\
                  \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \t\tServed: none recorded\n\
                  \tRemote:\n\
                  \t\tRemote query disabled"
             )
@@ -466,6 +970,7 @@ mod test_status {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
                         receiver_port: 8000,
+                        host_name: None,
                     }),
                     uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
                     local: local_connection_status(),
@@ -486,6 +991,7 @@ mod test_status {
                  \t\tConnecting to receiver port: 8000\n\
                  \t\tCertificate issuer: Site 'site' local CA\n\
                  \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \t\tServed: none recorded\n\
                  \tRemote:\n\
                  \t\tConnection type: pull-agent\n\
                  \t\tRegistration state: operational\n\
@@ -503,6 +1009,7 @@ mod test_status {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
                         receiver_port: 8000,
+                        host_name: None,
                     }),
                     uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
                     local: local_connection_status(),
@@ -523,6 +1030,7 @@ mod test_status {
                  \t\tConnecting to receiver port: 8000\n\
                  \t\tCertificate issuer: Site 'site' local CA\n\
                  \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \t\tServed: none recorded\n\
                  \tRemote:\n\
                  \t\tConnection type: pull-agent\n\
                  \t\tRegistration state: discoverable\n\
@@ -551,12 +1059,53 @@ mod test_status {
                  \t\tConnecting to receiver port: None (imported connection)\n\
                  \t\tCertificate issuer: Site 'site' local CA\n\
                  \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \t\tServed: none recorded\n\
                  \tRemote:\n\
                  \t\tNo remote address (imported connection)"
             )
         );
     }
 
+    #[test]
+    fn test_connection_status_fmt_labels() {
+        let mut local = local_connection_status();
+        local.labels = config::ConnectionLabels::from([
+            (String::from("team"), String::from("ops")),
+            (String::from("env"), String::from("prod")),
+        ]);
+        let connection_status = ConnectionStatus {
+            site_data: None,
+            uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            local,
+            remote: Remote::Imported,
+        };
+        assert!(format!("{}", connection_status)
+            .contains("\t\tConnecting to receiver port: None (imported connection)\n\t\tLabels: env=prod, team=ops\n"));
+        assert_eq!(
+            serde_json::to_value(&connection_status).unwrap()["local"]["labels"]["env"],
+            "prod"
+        );
+    }
+
+    #[test]
+    fn test_connection_status_fmt_agent_output_disabled() {
+        let mut local = local_connection_status();
+        local.agent_output_disabled = true;
+        let connection_status = ConnectionStatus {
+            site_data: None,
+            uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            local,
+            remote: Remote::Imported,
+        };
+        assert!(format!("{}", connection_status).contains(
+            "\t\tConnecting to receiver port: None (imported connection)\n\t\tAgent output: disabled\n"
+        ));
+        assert_eq!(
+            serde_json::to_value(&connection_status).unwrap()["local"]["agent_output_disabled"],
+            true
+        );
+    }
+
     #[test]
     fn test_connection_status_fmt_error() {
         assert_eq!(
@@ -566,6 +1115,7 @@ mod test_status {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
                         receiver_port: 8000,
+                        host_name: None,
                     }),
                     uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
                     local: local_connection_status(),
@@ -580,6 +1130,7 @@ mod test_status {
                  \t\tConnecting to receiver port: 8000\n\
                  \t\tCertificate issuer: Site 'site' local CA\n\
                  \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \t\tServed: none recorded\n\
                  \tRemote:\n\
                  \t\tError: You shall not pass (!!)"
             )
@@ -595,6 +1146,7 @@ mod test_status {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
                         receiver_port: 8000,
+                        host_name: None,
                     }),
                     uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
                     local: local_connection_status(),
@@ -615,6 +1167,7 @@ mod test_status {
                  \t\tConnecting to receiver port: 8000\n\
                  \t\tCertificate issuer: Site 'site' local CA\n\
                  \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \t\tServed: none recorded\n\
                  \tRemote:\n\
                  \t\tConnection type: push-agent (!!)\n\
                  \t\tRegistration state: operational\n\
@@ -632,6 +1185,7 @@ mod test_status {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
                         receiver_port: 8000,
+                        host_name: None,
                     }),
                     uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
                     local: local_connection_status(),
@@ -652,6 +1206,7 @@ mod test_status {
                  \t\tConnecting to receiver port: 8000\n\
                  \t\tCertificate issuer: Site 'site' local CA\n\
                  \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \t\tServed: none recorded\n\
                  \tRemote:\n\
                  \t\tConnection type: pull-agent\n\
                  \t\tRegistration state: unknown (!!)\n\
@@ -662,15 +1217,23 @@ mod test_status {
 
     fn build_status() -> Status {
         Status {
+            format_version: STATUS_FORMAT_VERSION,
             version: String::from("1.0.0"),
             agent_socket_operational: true,
+            agent_channel: AgentChannelStatus::Reachable {
+                agent_version: Some(String::from("2.2.0")),
+            },
+            allow_any_ip: false,
             ip_allowlist: vec![String::from("192.168.1.13"), String::from("[::1]")],
+            ip_denylist: vec![],
             allow_legacy_pull: false,
+            pull_counters: None,
             connections: vec![
                 ConnectionStatus {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
                         receiver_port: 8000,
+                        host_name: None,
                     }),
                     uuid: uuid::Uuid::from_str("50611369-7a42-4c0b-927e-9a14330401fe").unwrap(),
                     local: local_connection_status(),
@@ -684,15 +1247,23 @@ mod test_status {
                     site_data: Some(SiteData {
                         site_id: site_spec::SiteID::from_str("somewhere/site2").unwrap(),
                         receiver_port: 8000,
+                        host_name: None,
                     }),
                     uuid: uuid::Uuid::from_str("3c87778b-8bb8-434d-bcc6-6d05f2668c80").unwrap(),
                     local: LocalConnectionStatus {
                         connection_type: config::ConnectionType::Push,
+                        labels: config::ConnectionLabels::new(),
+                        agent_output_disabled: false,
                         cert_info: CertParsingResult::Success(CertInfo {
                             issuer: String::from("Site 'site2' local CA"),
                             from: String::from("Thu, 16 Dec 2021 08:18:41 +0000"),
                             to: String::from("Tue, 18 Apr 3020 08:18:41 +0000"),
+                            fingerprint: String::from("EF:01"),
+                            days_until_expiry: 365000,
+                            expiry: CertExpiry::Valid,
                         }),
+                        last_push: None,
+                        pull_usage: None,
                     },
                     remote: Remote::StatusResponse(Ok(RemoteConnectionStatus {
                         connection_type: Some(config::ConnectionType::Push),
@@ -710,6 +1281,7 @@ mod test_status {
             build_status().to_string(false).unwrap(),
             "Version: 1.0.0\n\
              Agent socket: operational\n\
+             Agent channel: reachable (agent version 2.2.0)\n\
              IP allowlist: 192.168.1.13 [::1]\n\n\n\
              Connection: localhost/site\n\
              \tUUID: 50611369-7a42-4c0b-927e-9a14330401fe\n\
@@ -718,6 +1290,7 @@ mod test_status {
              \t\tConnecting to receiver port: 8000\n\
              \t\tCertificate issuer: Site 'site' local CA\n\
              \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+             \t\tServed: none recorded\n\
              \tRemote:\n\
              \t\tConnection type: pull-agent\n\
              \t\tRegistration state: operational\n\
@@ -729,6 +1302,7 @@ mod test_status {
              \t\tConnecting to receiver port: 8000\n\
              \t\tCertificate issuer: Site 'site2' local CA\n\
              \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+             \t\tLast push: none recorded\n\
              \tRemote:\n\
              \t\tConnection type: push-agent\n\
              \t\tRegistration state: operational\n\
@@ -736,6 +1310,62 @@ mod test_status {
         );
     }
 
+    #[test]
+    fn test_status_push_results() {
+        let mut status = build_status();
+        let push_uuid = uuid::Uuid::from_str("3c87778b-8bb8-434d-bcc6-6d05f2668c80").unwrap();
+        let pull_uuid = uuid::Uuid::from_str("50611369-7a42-4c0b-927e-9a14330401fe").unwrap();
+        let mut push_results = metrics::PushResults::default();
+        push_results.record(&push_uuid, 0, None);
+        push_results.record(
+            &push_uuid,
+            60,
+            Some(&anyhow!(std::io::Error::from(std::io::ErrorKind::TimedOut))),
+        );
+        push_results.record(&pull_uuid, 0, None);
+        status.add_push_results(&push_results);
+
+        assert!(status.connections[0].local.last_push.is_none());
+        assert!(status.to_string(false).unwrap().contains(
+            "\t\tLast push: 1970-01-01T00:01:00Z, failed (network), last success: 1970-01-01T00:00:00Z (!!)\n"
+        ));
+        let json: serde_json::Value =
+            serde_json::from_str(&status.to_string(true).unwrap()).unwrap();
+        assert_eq!(
+            json["connections"][1]["local"]["last_push"],
+            serde_json::json!({
+                "timestamp": 60,
+                "success": false,
+                "error_kind": "network",
+                "last_success": 0,
+            })
+        );
+        assert!(json["connections"][0]["local"].get("last_push").is_none());
+    }
+
+    #[test]
+    fn test_status_connection_totals() {
+        let mut status = build_status();
+        let mut connection_totals = metrics::ConnectionTotals::default();
+        connection_totals.record("50611369-7a42-4c0b-927e-9a14330401fe", 1024, 0);
+        connection_totals.record("50611369-7a42-4c0b-927e-9a14330401fe", 2048, 60);
+        connection_totals.record("3c87778b-8bb8-434d-bcc6-6d05f2668c80", 1024, 0);
+        status.add_connection_totals(&connection_totals);
+
+        // Push connections are never pulled from
+        assert!(status.connections[1].local.pull_usage.is_none());
+        assert!(status
+            .to_string(false)
+            .unwrap()
+            .contains("\t\tServed: 2 pull(s), 3072 bytes served, last: 1970-01-01T00:01:00Z\n"));
+        let json: serde_json::Value =
+            serde_json::from_str(&status.to_string(true).unwrap()).unwrap();
+        assert_eq!(
+            json["connections"][0]["local"]["pull_usage"],
+            serde_json::json!({"pulls": 2, "bytes_served": 3072, "last_served": 60})
+        );
+    }
+
     #[test]
     fn test_status_str_json() {
         assert_eq!(
@@ -744,26 +1374,97 @@ mod test_status {
         );
     }
 
+    #[test]
+    fn test_status_json_schema() {
+        let status: serde_json::Value =
+            serde_json::from_str(&build_status().to_string(true).unwrap()).unwrap();
+        assert_eq!(status["format_version"], STATUS_FORMAT_VERSION);
+        assert_eq!(status["agent_socket_operational"], true);
+        assert_eq!(status["version"], "1.0.0");
+        assert_eq!(status["agent_channel"]["state"], "reachable");
+        assert_eq!(status["agent_channel"]["agent_version"], "2.2.0");
+        let connection = &status["connections"][1];
+        assert_eq!(connection["site_id"], "somewhere/site2");
+        assert_eq!(connection["uuid"], "3c87778b-8bb8-434d-bcc6-6d05f2668c80");
+        assert_eq!(connection["receiver_port"], 8000);
+        assert_eq!(connection["local"]["connection_type"], "push-agent");
+        assert_eq!(connection["local"]["cert_info"]["fingerprint"], "EF:01");
+    }
+
     #[test]
     fn test_status_str_empty() {
         assert_eq!(
             Status {
+                format_version: STATUS_FORMAT_VERSION,
                 version: String::from("2.3r18"),
                 agent_socket_operational: false,
+                agent_channel: AgentChannelStatus::Unreachable {
+                    error: String::from("Connection refused"),
+                },
+                allow_any_ip: true,
                 ip_allowlist: vec![],
+                ip_denylist: vec![],
                 allow_legacy_pull: true,
+                pull_counters: None,
                 connections: vec![],
             }
             .to_string(false)
             .unwrap(),
             "Version: 2.3r18\n\
              Agent socket: inoperational (!!)\n\
+             Agent channel: unreachable (Connection refused) (!!)\n\
              IP allowlist: any\n\
              Legacy mode: enabled\n\
              No connections"
         );
     }
 
+    #[test]
+    fn test_status_str_denylist() {
+        let mut status = build_status();
+        status.connections = vec![];
+        status.ip_denylist = vec![String::from("192.168.1.66"), String::from("10.0.0.0/8")];
+        assert_eq!(
+            status.to_string(false).unwrap(),
+            "Version: 1.0.0\n\
+             Agent socket: operational\n\
+             Agent channel: reachable (agent version 2.2.0)\n\
+             IP allowlist: 192.168.1.13 [::1]\n\
+             IP denylist: 192.168.1.66 10.0.0.0/8\n\
+             No connections"
+        );
+    }
+
+    #[test]
+    fn test_status_str_pull_counters() {
+        let mut status = build_status();
+        status.connections = vec![];
+        status.pull_counters = Some(PullCountersResult::Success(metrics::PullCountersSnapshot {
+            accepted: 4,
+            rejected_ip: 1,
+            handshake_failed: 1,
+            completed: 2,
+            timed_out: 0,
+            ..metrics::PullCountersSnapshot::default()
+        }));
+        assert_eq!(
+            status.to_string(false).unwrap(),
+            "Version: 1.0.0\n\
+             Agent socket: operational\n\
+             Agent channel: reachable (agent version 2.2.0)\n\
+             IP allowlist: 192.168.1.13 [::1]\n\
+             Pull counters: accepted: 4, rejected (IP): 1, TLS handshake failed: 1, completed: 2, timed out: 0\n\
+             No connections"
+        );
+        status.pull_counters = Some(PullCountersResult::from(
+            &tempfile::tempdir().unwrap().path().join("missing.json"),
+        ));
+        assert!(status
+            .to_string(false)
+            .unwrap()
+            .contains("Pull counters: not available (pull listener not running?)"));
+    }
+
     struct MockApi {}
 
     impl agent_receiver_api::Status for MockApi {
@@ -797,20 +1498,49 @@ mod test_status {
                 &config::PullConfig::new(
                     config::RuntimeConfig::default(),
                     cli::PullOpts {
-                        port: None,
+                        port: vec![],
+                        tls_min_version: None,
+                        cache_ttl: None,
+                        metrics_listen: None,
+                        health_listen: None,
+                        max_connections: None,
+                        worker_threads: None,
+                        listen_backlog: None,
+                        retry_bind: None,
+                        pull_bandwidth_limit: None,
+                        io_chunk_size: None,
+                        registry_readonly: false,
+                        registry_load_retry: None,
+                        require_registry: false,
+                        allow_any: false,
+                        no_client_auth: false,
+                        explain_rejections: false,
+                        strict_startup: false,
+                        on_agent_unavailable: None,
+                        #[cfg(unix)]
+                        pull_unix_socket: None,
                         #[cfg(windows)]
                         agent_channel: None,
                     },
-                    registry.clone()
+                    registry.clone(),
+                    tempfile::NamedTempFile::new().unwrap().as_ref(),
                 )
                 .unwrap(),
-                false,
                 &Some(MockApi {}),
+                false,
+                &ConnectionFilter::default(),
+                30,
+                AgentChannelStatus::NotProbed {
+                    reason: String::from("testing"),
+                },
             )
+            .unwrap()
+            .to_string(false)
             .unwrap(),
             format!(
                 "Version: {}\n\
                  Agent socket: {}\n\
+                 Agent channel: not probed (testing)\n\
                  IP allowlist: any\n\n\n\
                  Connection: server/push-site\n\
                  \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
@@ -818,6 +1548,7 @@ mod test_status {
                  \t\tConnection type: push-agent\n\
                  \t\tConnecting to receiver port: 8000\n\
                  \t\tCertificate parsing failed (!!)\n\
+                 \t\tLast push: none recorded\n\
                  \tRemote:\n\
                  \t\tConnection type: pull-agent (!!)\n\
                  \t\tRegistration state: operational\n\
@@ -831,4 +1562,245 @@ mod test_status {
             )
         );
     }
+
+    fn registry_for_filtering() -> config::Registry {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+        );
+        let mut pull_conn = config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4());
+        pull_conn.trust.labels = config::ConnectionLabels::from([
+            (String::from("env"), String::from("prod")),
+            (String::from("team"), String::from("ops")),
+        ]);
+        registry.register_connection(
+            &config::ConnectionType::Pull,
+            &site_spec::SiteID::from_str("server/pull-site").unwrap(),
+            pull_conn,
+        );
+        let mut imported_conn = config::TrustedConnection::from(uuid::Uuid::new_v4());
+        imported_conn.labels =
+            config::ConnectionLabels::from([(String::from("env"), String::from("staging"))]);
+        registry.register_imported_connection(imported_conn);
+        registry
+    }
+
+    fn filtered_connections(filter: &ConnectionFilter) -> Vec<(String, Option<String>)> {
+        let registry = registry_for_filtering();
+        Status::from(
+            &registry,
+            &config::PullConfig::new(
+                config::RuntimeConfig::default(),
+                cli::PullOpts {
+                    port: vec![],
+                    tls_min_version: None,
+                    cache_ttl: None,
+                    metrics_listen: None,
+                    health_listen: None,
+                    max_connections: None,
+                    worker_threads: None,
+                    listen_backlog: None,
+                    retry_bind: None,
+                    pull_bandwidth_limit: None,
+                    io_chunk_size: None,
+                    registry_readonly: false,
+                    registry_load_retry: None,
+                    require_registry: false,
+                    allow_any: false,
+                    no_client_auth: false,
+                    explain_rejections: false,
+                    strict_startup: false,
+                    on_agent_unavailable: None,
+                    #[cfg(unix)]
+                    pull_unix_socket: None,
+                    #[cfg(windows)]
+                    agent_channel: None,
+                },
+                registry.clone(),
+                tempfile::NamedTempFile::new().unwrap().as_ref(),
+            )
+            .unwrap(),
+            &None::<MockApi>,
+            false,
+            filter,
+            30,
+            AgentChannelStatus::Reachable {
+                agent_version: None,
+            },
+        )
+        .connections
+        .iter()
+        .map(|conn| {
+            (
+                conn.local.connection_type.to_string(),
+                conn.site_data
+                    .as_ref()
+                    .map(|site_data| site_data.site_id.to_string()),
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_filter_connection_type() {
+        assert_eq!(filtered_connections(&ConnectionFilter::default()).len(), 3);
+        assert_eq!(
+            filtered_connections(&ConnectionFilter {
+                connection_type: Some(config::ConnectionType::Push),
+                site_id: None,
+                labels: vec![],
+            }),
+            vec![(
+                String::from("push-agent"),
+                Some(String::from("server/push-site"))
+            )]
+        );
+        assert_eq!(
+            filtered_connections(&ConnectionFilter {
+                connection_type: Some(config::ConnectionType::Pull),
+                site_id: None,
+                labels: vec![],
+            }),
+            vec![
+                (
+                    String::from("pull-agent"),
+                    Some(String::from("server/pull-site"))
+                ),
+                (String::from("pull-agent"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter_site_and_connection_type() {
+        let site_id = site_spec::SiteID::from_str("server/pull-site").unwrap();
+        assert_eq!(
+            filtered_connections(&ConnectionFilter {
+                connection_type: None,
+                site_id: Some(site_id.clone()),
+                labels: vec![],
+            }),
+            vec![(
+                String::from("pull-agent"),
+                Some(String::from("server/pull-site"))
+            )]
+        );
+        assert!(filtered_connections(&ConnectionFilter {
+            connection_type: Some(config::ConnectionType::Push),
+            site_id: Some(site_id),
+            labels: vec![],
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_filter_labels() {
+        let env_prod = (String::from("env"), String::from("prod"));
+        assert_eq!(
+            filtered_connections(&ConnectionFilter {
+                labels: vec![env_prod.clone()],
+                ..ConnectionFilter::default()
+            }),
+            vec![(
+                String::from("pull-agent"),
+                Some(String::from("server/pull-site"))
+            )]
+        );
+        assert_eq!(
+            filtered_connections(&ConnectionFilter {
+                labels: vec![(String::from("env"), String::from("staging"))],
+                ..ConnectionFilter::default()
+            }),
+            vec![(String::from("pull-agent"), None)]
+        );
+        // All labels have to match
+        assert!(filtered_connections(&ConnectionFilter {
+            labels: vec![env_prod, (String::from("team"), String::from("dev"))],
+            ..ConnectionFilter::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_status_filter_no_match() {
+        let registry = registry_for_filtering();
+        assert_eq!(
+            _status(
+                &registry,
+                &config::PullConfig::new(
+                    config::RuntimeConfig::default(),
+                    cli::PullOpts {
+                        port: vec![],
+                        tls_min_version: None,
+                        cache_ttl: None,
+                        metrics_listen: None,
+                        health_listen: None,
+                        max_connections: None,
+                        worker_threads: None,
+                        listen_backlog: None,
+                        retry_bind: None,
+                        pull_bandwidth_limit: None,
+                        io_chunk_size: None,
+                        registry_readonly: false,
+                        registry_load_retry: None,
+                        require_registry: false,
+                        allow_any: false,
+                        no_client_auth: false,
+                        explain_rejections: false,
+                        strict_startup: false,
+                        on_agent_unavailable: None,
+                        #[cfg(unix)]
+                        pull_unix_socket: None,
+                        #[cfg(windows)]
+                        agent_channel: None,
+                    },
+                    registry.clone(),
+                    tempfile::NamedTempFile::new().unwrap().as_ref(),
+                )
+                .unwrap(),
+                &None::<MockApi>,
+                false,
+                &ConnectionFilter {
+                    connection_type: None,
+                    site_id: Some(site_spec::SiteID::from_str("other/site").unwrap()),
+                    labels: vec![],
+                },
+                30,
+                AgentChannelStatus::Reachable {
+                    agent_version: None,
+                },
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "No connections match the given filter"
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut ticks = 0;
+        watch(std::time::Duration::from_millis(10), &rx, || {
+            ticks += 1;
+            if ticks == 3 {
+                tx.send(()).unwrap();
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ticks, 3);
+    }
+
+    #[test]
+    fn test_watch_fails() {
+        let (_tx, rx) = std::sync::mpsc::channel();
+        assert!(watch(std::time::Duration::from_millis(10), &rx, || bail!(
+            "failed"
+        ))
+        .is_err());
+    }
 }
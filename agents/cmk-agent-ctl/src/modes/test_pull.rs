@@ -0,0 +1,251 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Fetches the agent output via pull from ourselves, to verify that a pull connection works end
+//! to end before relying on it. The request is served by the code of the pull daemon, on a
+//! listener of its own, st. this works no matter if the daemon is running.
+
+use crate::modes::pull;
+use crate::{certs, cli, config, monitoring_data, site_spec};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+pub struct FetchedOutput {
+    pub uuid: uuid::Uuid,
+    /// What we received via TLS, ie. the compressed output including the header
+    pub received_bytes: usize,
+    pub agent_output: Vec<u8>,
+}
+
+impl FetchedOutput {
+    fn summary(&self, lines: usize) -> String {
+        let mut summary = vec![format!(
+            "Fetched {} bytes of agent output via connection {} ({} bytes transferred)",
+            self.agent_output.len(),
+            self.uuid,
+            self.received_bytes
+        )];
+        summary.extend(
+            String::from_utf8_lossy(&self.agent_output)
+                .lines()
+                .take(lines)
+                .map(String::from),
+        );
+        summary.join("\n")
+    }
+}
+
+fn pull_connection<'a>(
+    registry: &'a config::Registry,
+    connection_id: Option<&str>,
+) -> AnyhowResult<&'a config::TrustedConnection> {
+    let connections: Vec<(Option<&site_spec::SiteID>, &config::TrustedConnection)> = registry
+        .standard_pull_connections()
+        .map(|(site_id, connection)| (Some(site_id), &connection.trust))
+        .chain(
+            registry
+                .imported_pull_connections()
+                .map(|connection| (None, connection)),
+        )
+        .collect();
+    match connection_id {
+        Some(connection_id) => connections
+            .into_iter()
+            .find(|(site_id, connection)| {
+                connection.uuid.to_string() == connection_id
+                    || site_id.is_some_and(|site_id| site_id.to_string() == connection_id)
+            })
+            .map(|(_, connection)| connection)
+            .ok_or_else(|| anyhow!("No pull connection '{}'", connection_id)),
+        None => match connections.as_slice() {
+            [] => bail!("No pull connections registered"),
+            [(_, connection)] => Ok(connection),
+            _ => bail!("There are several pull connections, specify the one to use"),
+        },
+    }
+}
+
+fn decode(received: &[u8]) -> AnyhowResult<Vec<u8>> {
+    let received = received
+        .strip_prefix(pull::HEADER_VERSION)
+        .context("Received data does not start with the expected header")?;
+    let compressed = received
+        .strip_prefix(monitoring_data::compression_header_info().pull.as_slice())
+        .context("Received data is not zlib-compressed")?;
+    let mut agent_output = vec![];
+    flate2::read::ZlibDecoder::new(compressed)
+        .read_to_end(&mut agent_output)
+        .context("Failed to decompress received data")?;
+    Ok(agent_output)
+}
+
+async fn request(
+    connection: &config::TrustedConnection,
+    client_identity: certs::TLSIdentity,
+    tls_policy: &certs::TlsPolicy,
+    address: std::net::SocketAddr,
+) -> AnyhowResult<Vec<u8>> {
+    let tls_connector = tokio_rustls::TlsConnector::from(Arc::new(certs::pull_client_config(
+        &connection.root_cert,
+        client_identity,
+        tls_policy,
+    )?));
+    let server_name = rustls::ServerName::try_from(connection.uuid.to_string().as_str())?;
+    let mut stream = TcpStream::connect(address).await?;
+    let mut tls_id = [0; 2];
+    stream.read_exact(&mut tls_id).await?;
+    if tls_id != pull::TLS_ID {
+        bail!("Expected the TLS announcement, got {:?}", tls_id)
+    }
+    let mut tls_stream = tls_connector
+        .connect(server_name, stream)
+        .await
+        .context("TLS handshake failed")?;
+    let mut received = vec![];
+    match tls_stream.read_to_end(&mut received).await {
+        Ok(_) => Ok(received),
+        // The pull handler closes the connection without a close_notify, the site doesn't mind
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(received),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn fetch_agent_output(
+    pull_config: &config::PullConfig,
+    connection_id: Option<&str>,
+) -> AnyhowResult<FetchedOutput> {
+    let connection = pull_connection(&pull_config.registry, connection_id)?;
+    let (client_root_cert, client_identity) =
+        certs::make_throwaway_client_identity("cmk-agent-ctl test-pull")?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let address = listener.local_addr()?;
+    let (served, received) = tokio::join!(
+        pull::serve_single_request(pull_config, connection, &client_root_cert, listener),
+        request(
+            connection,
+            client_identity,
+            &pull_config.tls_policy,
+            address
+        ),
+    );
+    served.context("Failed to serve the pull request")?;
+    let received = received.context("Failed to fetch the agent output")?;
+    Ok(FetchedOutput {
+        uuid: connection.uuid,
+        received_bytes: received.len(),
+        agent_output: decode(&received)?,
+    })
+}
+
+pub fn test_pull(
+    pull_config: &config::PullConfig,
+    test_pull_args: &cli::TestPullArgs,
+) -> AnyhowResult<()> {
+    let fetched = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the async runtime for test-pull.")?
+        .block_on(fetch_agent_output(
+            pull_config,
+            test_pull_args.connection.as_deref(),
+        ))?;
+    if test_pull_args.full {
+        std::io::stdout().write_all(&fetched.agent_output)?;
+        return Ok(());
+    }
+    println!("{}", fetched.summary(test_pull_args.lines));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const UUID_PULL: &str = "b1e0d4c4-1bb1-4a39-8bd6-8e5b1b7c3b6d";
+    const UUID_PULL_IMP: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+
+    fn registry() -> config::Registry {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Pull,
+            &site_spec::SiteID::from_str("server/pull-site").unwrap(),
+            config::TrustedConnectionWithRemote::from(UUID_PULL),
+        );
+        registry
+    }
+
+    #[test]
+    fn test_pull_connection() {
+        let mut registry = registry();
+        assert_eq!(
+            pull_connection(&registry, None).unwrap().uuid.to_string(),
+            UUID_PULL
+        );
+        registry.register_imported_connection(config::TrustedConnection::from(UUID_PULL_IMP));
+        assert_eq!(
+            pull_connection(&registry, None).unwrap_err().to_string(),
+            "There are several pull connections, specify the one to use"
+        );
+        assert_eq!(
+            pull_connection(&registry, Some("server/pull-site"))
+                .unwrap()
+                .uuid
+                .to_string(),
+            UUID_PULL
+        );
+        assert_eq!(
+            pull_connection(&registry, Some(UUID_PULL_IMP))
+                .unwrap()
+                .uuid
+                .to_string(),
+            UUID_PULL_IMP
+        );
+        assert_eq!(
+            pull_connection(&registry, Some("server/other-site"))
+                .unwrap_err()
+                .to_string(),
+            "No pull connection 'server/other-site'"
+        );
+        registry.clear();
+        assert_eq!(
+            pull_connection(&registry, None).unwrap_err().to_string(),
+            "No pull connections registered"
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut received = b"\x00\x00\x01".to_vec();
+        received.extend(monitoring_data::compress(b"<<<check_mk>>>\nVersion: 2.2.0").unwrap());
+        assert_eq!(
+            decode(&received).unwrap(),
+            b"<<<check_mk>>>\nVersion: 2.2.0"
+        );
+        assert!(decode(b"\x00\x00\x00plain").is_err());
+        assert!(decode(b"16").is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let fetched = FetchedOutput {
+            uuid: uuid::Uuid::from_str(UUID_PULL).unwrap(),
+            received_bytes: 20,
+            agent_output: b"<<<check_mk>>>\nVersion: 2.2.0\nAgentOS: linux".to_vec(),
+        };
+        assert_eq!(
+            fetched.summary(2),
+            format!(
+                "Fetched 44 bytes of agent output via connection {} (20 bytes transferred)\n\
+                 <<<check_mk>>>\nVersion: 2.2.0",
+                UUID_PULL
+            )
+        );
+    }
+}
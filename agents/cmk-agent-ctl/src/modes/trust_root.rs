@@ -0,0 +1,346 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use std::str::FromStr;
+
+use crate::modes::delete_connection::retrieve_standard_connection_by_uuid;
+use crate::{certs, cli, config, site_spec};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use log::info;
+use x509_parser::traits::FromDer;
+
+fn site_id_from_connection_id(
+    registry: &config::Registry,
+    connection_id: &str,
+) -> AnyhowResult<site_spec::SiteID> {
+    if let Ok(site_id) = site_spec::SiteID::from_str(connection_id) {
+        return Ok(site_id);
+    }
+    let uuid = uuid::Uuid::from_str(connection_id)
+        .context("Provided connection identifier is neither a valid site ID nor a valid UUID")?;
+    retrieve_standard_connection_by_uuid(&uuid, registry).ok_or_else(|| {
+        match registry
+            .imported_pull_connections()
+            .any(|conn| conn.uuid == uuid)
+        {
+            true => anyhow!(
+                "Connection with UUID '{}' is imported, import it again to change its root certificates",
+                uuid
+            ),
+            false => anyhow!("No connection with UUID '{}'", uuid),
+        }
+    })
+}
+
+fn fingerprint(certificate: &rustls::Certificate) -> AnyhowResult<String> {
+    certs::fingerprint_sha256(certificate.as_ref())
+}
+
+fn describe(certificate: &rustls::Certificate) -> AnyhowResult<String> {
+    let (_, x509) = x509_parser::certificate::X509Certificate::from_der(certificate.as_ref())?;
+    Ok(format!(
+        "{} (valid until {}), fingerprint {}",
+        x509.subject(),
+        x509.validity().not_after.to_rfc2822(),
+        fingerprint(certificate)?
+    ))
+}
+
+/// Only CA certificates can be trust anchors for the certificates of the receiver
+fn validate_root(certificate: &rustls::Certificate) -> AnyhowResult<()> {
+    let (_, x509) = x509_parser::certificate::X509Certificate::from_der(certificate.as_ref())
+        .context("Invalid root certificate")?;
+    let is_ca = x509
+        .basic_constraints()
+        .ok()
+        .flatten()
+        .map(|basic_constraints| basic_constraints.value.ca)
+        .unwrap_or(false);
+    if !is_ca {
+        bail!("Certificate '{}' is not a CA certificate", x509.subject())
+    }
+    Ok(())
+}
+
+fn add_roots(root_cert: &str, additional_pem: &str) -> AnyhowResult<String> {
+    let mut roots = certs::root_certificates(root_cert)?;
+    let additional =
+        certs::root_certificates(additional_pem).context("Invalid root certificate")?;
+    for certificate in additional {
+        validate_root(&certificate)?;
+        if roots.contains(&certificate) {
+            info!(
+                "Root certificate with fingerprint {} is already trusted",
+                fingerprint(&certificate)?
+            );
+            continue;
+        }
+        roots.push(certificate);
+    }
+    certs::pem_bundle(&roots)
+}
+
+fn remove_root(root_cert: &str, fingerprint_to_remove: &str) -> AnyhowResult<String> {
+    let fingerprint_to_remove = certs::normalize_fingerprint(fingerprint_to_remove)?;
+    let mut roots = certs::root_certificates(root_cert)?;
+    let trusted = roots.len();
+    roots.retain(|certificate| {
+        fingerprint(certificate).ok().as_ref() != Some(&fingerprint_to_remove)
+    });
+    if roots.len() == trusted {
+        bail!(
+            "No trusted root certificate with fingerprint {}",
+            fingerprint_to_remove
+        )
+    }
+    if roots.is_empty() {
+        bail!("Refusing to remove the only trusted root certificate of the connection")
+    }
+    certs::pem_bundle(&roots)
+}
+
+fn _trust_root(
+    registry: &mut config::Registry,
+    trust_root_args: &cli::TrustRootArgs,
+    additional_pem: Option<&str>,
+) -> AnyhowResult<Vec<String>> {
+    let site_id = site_id_from_connection_id(registry, &trust_root_args.connection)?;
+    let connection = registry
+        .get_mutable(&site_id)
+        .context(format!("Connection '{}' not found", site_id))?;
+    // UUID and client identity stay as they are, only the trusted roots change
+    if let Some(additional_pem) = additional_pem {
+        connection.trust.root_cert = add_roots(&connection.trust.root_cert, additional_pem)?;
+    }
+    if let Some(fingerprint_to_remove) = &trust_root_args.remove {
+        connection.trust.root_cert =
+            remove_root(&connection.trust.root_cert, fingerprint_to_remove)?;
+    }
+    certs::root_certificates(&connection.trust.root_cert)?
+        .iter()
+        .map(describe)
+        .collect()
+}
+
+pub fn trust_root(
+    registry: &mut config::Registry,
+    trust_root_args: &cli::TrustRootArgs,
+) -> AnyhowResult<()> {
+    let additional_pem = trust_root_args
+        .add
+        .as_ref()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .context(format!("Failed to read root certificates from {:?}", path))
+        })
+        .transpose()?;
+    let trusted = _trust_root(registry, trust_root_args, additional_pem.as_deref())?;
+    if trust_root_args.add.is_some() || trust_root_args.remove.is_some() {
+        registry.save()?;
+        info!(
+            "Updated the trusted root certificates of connection '{}'",
+            trust_root_args.connection
+        );
+    }
+    println!(
+        "Trusted root certificates of connection '{}':",
+        trust_root_args.connection
+    );
+    for root in trusted {
+        println!("  {}", root);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_PULL_IMP: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+
+    fn new_ca() -> String {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "Site 'heute' new CA")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(365).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    fn registry() -> config::Registry {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        let mut connection = config::TrustedConnectionWithRemote::from(UUID_PUSH);
+        connection.trust.root_cert = String::from(constants::TEST_ROOT_CERT);
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            connection,
+        );
+        registry.register_imported_connection(config::TrustedConnection::from(UUID_PULL_IMP));
+        registry
+    }
+
+    fn args(connection: &str, remove: Option<&str>) -> cli::TrustRootArgs {
+        cli::TrustRootArgs {
+            connection: String::from(connection),
+            add: None,
+            remove: remove.map(String::from),
+            logging_opts: cli::LoggingOpts {
+                verbose: 0,
+                log_filter: None,
+                tls_debug: false,
+                insecure_keylog: false,
+                log_format: crate::logging::LogFormat::Text,
+                #[cfg(unix)]
+                log_target: crate::logging::LogTarget::Stderr,
+                #[cfg(unix)]
+                syslog_facility: crate::logging::SyslogFacility::Daemon,
+                registry: None,
+                config: None,
+                tmp_dir: None,
+            },
+        }
+    }
+
+    fn push_connection(registry: &mut config::Registry) -> config::TrustedConnection {
+        registry
+            .get_mutable(&site_spec::SiteID::from_str("server/push-site").unwrap())
+            .unwrap()
+            .trust
+            .clone()
+    }
+
+    fn root_fingerprint(pem: &str) -> String {
+        fingerprint(&certs::rustls_certificate(pem).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_add_and_remove_root() {
+        let mut registry = registry();
+        let before = push_connection(&mut registry);
+        let new_root = new_ca();
+
+        let trusted = _trust_root(&mut registry, &args(UUID_PUSH, None), Some(&new_root)).unwrap();
+        assert_eq!(trusted.len(), 2);
+        assert!(trusted[1].contains(&root_fingerprint(&new_root)));
+        let after = push_connection(&mut registry);
+        assert_eq!(after.uuid, before.uuid);
+        assert_eq!(after.private_key, before.private_key);
+        assert_eq!(after.certificate, before.certificate);
+        assert_eq!(certs::root_certificates(&after.root_cert).unwrap().len(), 2);
+
+        // Adding the same root again changes nothing
+        assert_eq!(
+            _trust_root(
+                &mut registry,
+                &args("server/push-site", None),
+                Some(&new_root)
+            )
+            .unwrap()
+            .len(),
+            2
+        );
+
+        let old_fingerprint = root_fingerprint(constants::TEST_ROOT_CERT);
+        let trusted = _trust_root(
+            &mut registry,
+            &args(UUID_PUSH, Some(&old_fingerprint.to_lowercase())),
+            None,
+        )
+        .unwrap();
+        assert_eq!(trusted.len(), 1);
+        assert!(trusted[0].contains(&root_fingerprint(&new_root)));
+    }
+
+    #[test]
+    fn test_remove_root_errors() {
+        let mut registry = registry();
+        let only_fingerprint = root_fingerprint(constants::TEST_ROOT_CERT);
+        assert_eq!(
+            _trust_root(
+                &mut registry,
+                &args(UUID_PUSH, Some(&only_fingerprint)),
+                None
+            )
+            .unwrap_err()
+            .to_string(),
+            "Refusing to remove the only trusted root certificate of the connection"
+        );
+        let unknown_fingerprint = root_fingerprint(constants::TEST_CERT_OK);
+        assert!(_trust_root(
+            &mut registry,
+            &args(UUID_PUSH, Some(&unknown_fingerprint)),
+            None
+        )
+        .unwrap_err()
+        .to_string()
+        .starts_with("No trusted root certificate with fingerprint"));
+        assert_eq!(
+            push_connection(&mut registry).root_cert,
+            constants::TEST_ROOT_CERT
+        );
+    }
+
+    #[test]
+    fn test_add_invalid_root() {
+        let mut registry = registry();
+        assert!(_trust_root(
+            &mut registry,
+            &args(UUID_PUSH, None),
+            Some("no certificate")
+        )
+        .is_err());
+        assert_eq!(
+            _trust_root(
+                &mut registry,
+                &args(UUID_PUSH, None),
+                Some(constants::TEST_CERT_OK)
+            )
+            .unwrap_err()
+            .to_string(),
+            "Certificate 'CN=heute' is not a CA certificate"
+        );
+        assert_eq!(
+            push_connection(&mut registry).root_cert,
+            constants::TEST_ROOT_CERT
+        );
+    }
+
+    #[test]
+    fn test_imported_connection() {
+        assert_eq!(
+            _trust_root(&mut registry(), &args(UUID_PULL_IMP, None), Some(&new_ca()))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Connection with UUID '{}' is imported, import it again to change its root certificates",
+                UUID_PULL_IMP
+            )
+        );
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Changes the stored settings of a registered connection, eg. after the agent receiver of the
+//! site moved to another port. The trust material and the UUID are left as they are.
+
+use crate::modes::delete_connection::standard_connection_site_id;
+use crate::{agent_receiver_api, cli, config, site_spec};
+use anyhow::{Context, Result as AnyhowResult};
+use log::info;
+
+struct PortUpdate {
+    site_id: site_spec::SiteID,
+    old_port: u16,
+    new_port: u16,
+}
+
+impl std::fmt::Display for PortUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Updated receiver port of connection '{}' from {} to {}",
+            self.site_id, self.old_port, self.new_port
+        )
+    }
+}
+
+fn _update_connection(
+    registry: &mut config::Registry,
+    connection_id: &str,
+    receiver_port: u16,
+    agent_rec_api: &impl agent_receiver_api::Status,
+) -> AnyhowResult<PortUpdate> {
+    let site_id = standard_connection_site_id(
+        registry,
+        connection_id,
+        "imported connections have no receiver port",
+    )?;
+    let connection = registry
+        .get_mutable(&site_id)
+        .context(format!("Connection '{}' not found", site_id))?;
+
+    // Only store the new port once we know that the site answers there with our credentials,
+    // otherwise we would break a working connection
+    agent_rec_api
+        .status(
+            &site_spec::make_site_url(&site_id, &receiver_port, connection.path_prefix.as_deref())?,
+            &connection.trust,
+        )
+        .context(format!(
+            "Failed to reach the agent receiver of '{}' on port {}, the connection was not changed",
+            site_id, receiver_port
+        ))?;
+
+    let old_port = connection.receiver_port;
+    connection.receiver_port = receiver_port;
+    Ok(PortUpdate {
+        site_id,
+        old_port,
+        new_port: receiver_port,
+    })
+}
+
+pub fn update_connection(
+    registry: &mut config::Registry,
+    update_args: &cli::UpdateConnectionArgs,
+    client_config: &config::ClientConfig,
+) -> AnyhowResult<()> {
+    let update = _update_connection(
+        registry,
+        &update_args.connection,
+        update_args.receiver_port,
+        &agent_receiver_api::Api::from(client_config),
+    )?;
+    registry.save()?;
+    info!(site = update.site_id.to_string(); "{}", update);
+    println!("{}", update);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::str::FromStr;
+
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_PULL_IMP: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+
+    struct MockApi {
+        reachable: bool,
+    }
+
+    impl agent_receiver_api::Status for MockApi {
+        fn status(
+            &self,
+            base_url: &reqwest::Url,
+            connection: &config::TrustedConnection,
+        ) -> AnyhowResult<agent_receiver_api::StatusResponse> {
+            assert_eq!(base_url.to_string(), "https://server:8001/push-site");
+            assert_eq!(connection.uuid.to_string(), UUID_PUSH);
+            match self.reachable {
+                true => Ok(agent_receiver_api::StatusResponse {
+                    hostname: Some(String::from("host")),
+                    status: None,
+                    connection_type: Some(config::ConnectionType::Push),
+                    message: None,
+                }),
+                false => Err(anyhow!("Connection refused")),
+            }
+        }
+    }
+
+    fn registry() -> config::Registry {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            config::TrustedConnectionWithRemote::from(UUID_PUSH),
+        );
+        registry.register_imported_connection(config::TrustedConnection::from(UUID_PULL_IMP));
+        registry
+    }
+
+    fn push_connection(registry: &mut config::Registry) -> config::TrustedConnectionWithRemote {
+        registry
+            .get_mutable(&site_spec::SiteID::from_str("server/push-site").unwrap())
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn test_update_receiver_port() {
+        for connection_id in ["server/push-site", UUID_PUSH] {
+            let mut registry = registry();
+            let before = push_connection(&mut registry);
+            let update = _update_connection(
+                &mut registry,
+                connection_id,
+                8001,
+                &MockApi { reachable: true },
+            )
+            .unwrap();
+            assert_eq!(
+                update.to_string(),
+                "Updated receiver port of connection 'server/push-site' from 8000 to 8001"
+            );
+            let after = push_connection(&mut registry);
+            assert_eq!(after.receiver_port, 8001);
+            assert_eq!(after.trust.uuid, before.trust.uuid);
+            assert_eq!(after.trust.private_key, before.trust.private_key);
+            assert_eq!(after.trust.certificate, before.trust.certificate);
+            assert_eq!(after.trust.root_cert, before.trust.root_cert);
+        }
+    }
+
+    #[test]
+    fn test_update_unreachable_keeps_port() {
+        let mut registry = registry();
+        assert!(_update_connection(
+            &mut registry,
+            "server/push-site",
+            8001,
+            &MockApi { reachable: false }
+        )
+        .is_err());
+        assert_eq!(push_connection(&mut registry).receiver_port, 8000);
+    }
+
+    #[test]
+    fn test_update_imported_connection() {
+        assert_eq!(
+            _update_connection(
+                &mut registry(),
+                UUID_PULL_IMP,
+                8001,
+                &MockApi { reachable: true }
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            format!(
+                "Connection with UUID '{}' is imported, imported connections have no receiver port",
+                UUID_PULL_IMP
+            )
+        );
+    }
+}
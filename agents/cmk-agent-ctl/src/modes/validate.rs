@@ -0,0 +1,138 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::config::{self, TOMLLoaderMissingSafe};
+use anyhow::{bail, Result as AnyhowResult};
+use std::path::Path;
+
+/// Collects all problems instead of stopping at the first one, st. they can be fixed in one go.
+fn problems(config_path: &Path, registry_path: &Path) -> Vec<String> {
+    let located = |problem: String, path: &Path| format!("{}: {}", path.display(), problem);
+    let mut problems = vec![];
+    match config::RuntimeConfig::load_missing_safe(config_path) {
+        Ok(runtime_config) => problems.extend(
+            runtime_config
+                .validation_problems()
+                .into_iter()
+                .map(|problem| located(problem, config_path)),
+        ),
+        Err(err) => problems.push(located(format!("{:#}", err), config_path)),
+    }
+    // Keeps the first of duplicate UUIDs, no matter the policy, st. the duplicates are reported
+    // along with all other problems
+    match config::Registry::from_file(registry_path) {
+        Ok(registry) => problems.extend(
+            registry
+                .validation_problems()
+                .into_iter()
+                .map(|problem| located(problem, registry_path)),
+        ),
+        Err(err) => problems.push(located(format!("{:#}", err), registry_path)),
+    }
+    problems
+}
+
+pub fn validate(config_path: &Path, registry_path: &Path) -> AnyhowResult<()> {
+    let problems = problems(config_path, registry_path);
+    if problems.is_empty() {
+        println!("Configuration is valid.");
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    bail!("Found {} problem(s) in the configuration", problems.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    struct Paths {
+        dir: tempfile::TempDir,
+    }
+
+    impl Paths {
+        fn new() -> Self {
+            Self {
+                dir: tempfile::tempdir().unwrap(),
+            }
+        }
+
+        fn config(&self) -> std::path::PathBuf {
+            self.dir.path().join("cmk-agent-ctl.toml")
+        }
+
+        fn registry(&self) -> std::path::PathBuf {
+            self.dir.path().join("registered_connections.json")
+        }
+
+        fn problems(&self) -> Vec<String> {
+            problems(&self.config(), &self.registry())
+        }
+    }
+
+    #[test]
+    fn test_nothing_configured() {
+        let paths = Paths::new();
+        assert!(paths.problems().is_empty());
+        assert!(validate(&paths.config(), &paths.registry()).is_ok());
+    }
+
+    #[test]
+    fn test_all_problems_reported() {
+        let paths = Paths::new();
+        std::fs::write(
+            paths.config(),
+            "allowed_ip = [\"127.0.0.1\", \"no-ip\", \"10.0.0.0/33\"]\npull_port = 0",
+        )
+        .unwrap();
+        let mut registry = config::Registry::from_file(&paths.registry()).unwrap();
+        registry.register_connection(
+            &config::ConnectionType::Pull,
+            &crate::site_spec::SiteID::from_str("server/site").unwrap(),
+            config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+        );
+        registry.save().unwrap();
+
+        let problems = paths.problems();
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].contains("'no-ip'"));
+        assert!(problems[1].contains("'10.0.0.0/33'"));
+        assert!(problems[2].contains("pull_port"));
+        assert!(problems[3].contains("Connection server/site"));
+        assert!(validate(&paths.config(), &paths.registry()).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_uuids() {
+        let paths = Paths::new();
+        std::fs::write(paths.config(), "duplicate_uuids = \"reject\"").unwrap();
+        std::fs::write(
+            paths.registry(),
+            include_str!("../../tests/fixtures/registry_duplicate_uuid.json"),
+        )
+        .unwrap();
+        let problems = paths.problems();
+        assert!(problems[0].ends_with(
+            "registered_connections.json: Pull connection server/site-b: Duplicate UUID \
+             9a2c4eb5-35f5-4bf7-82c0-e2f2c06215ea, already used by the push connection \
+             server/site-a"
+        ));
+        assert!(problems[1]
+            .contains("Imported connection 3f9d0c27-5b1e-4f6a-9c8d-7e2a1b4c5d6e: Duplicate UUID"));
+    }
+
+    #[test]
+    fn test_unparsable_files() {
+        let paths = Paths::new();
+        std::fs::write(paths.config(), "pull_port = \"abc\"").unwrap();
+        std::fs::write(paths.registry(), "{").unwrap();
+        let problems = paths.problems();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with(&paths.config().display().to_string()));
+        assert!(problems[1].starts_with(&paths.registry().display().to_string()));
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Check a single connection end-to-end against its site, eg. after migrating a host or a site.
+//! Unlike status, this stops at the first step which fails and makes the exit code tell why.
+//! Nothing is changed, neither locally nor at the site.
+
+use crate::exit_codes::ExitCode;
+use crate::modes::delete_connection::standard_connection_site_id;
+use crate::{agent_receiver_api, certs, config, site_spec};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+
+fn check_certificate(connection: &config::TrustedConnection) -> AnyhowResult<String> {
+    let seconds = certs::seconds_until_expiry(&connection.certificate)
+        .context("Failed to decode the client certificate")?;
+    if seconds < 0 {
+        return Err(
+            anyhow!("The client certificate has expired").context(ExitCode::CertificateExpired)
+        );
+    }
+    Ok(format!("valid for {} more days", seconds / 86400))
+}
+
+fn check_registration(
+    connection_type: &config::ConnectionType,
+    status: &agent_receiver_api::StatusResponse,
+) -> AnyhowResult<String> {
+    if let Some(agent_receiver_api::HostStatus::Declined) = status.status {
+        return Err(anyhow!(
+            "The site declined the registration{}",
+            status
+                .message
+                .as_ref()
+                .map(|message| format!(" ({})", message))
+                .unwrap_or_default()
+        )
+        .context(ExitCode::RegistrationDeclined));
+    }
+    if let Some(remote_type) = &status.connection_type {
+        if remote_type != connection_type {
+            return Err(anyhow!(
+                "The site expects a {} connection, but it is registered as {} connection",
+                remote_type,
+                connection_type
+            )
+            .context(ExitCode::ConnectionTypeMismatch));
+        }
+    }
+    Ok(format!(
+        "host {}, {}",
+        status.hostname.as_deref().unwrap_or("unknown"),
+        status
+            .status
+            .as_ref()
+            .map(|host_status| host_status.to_string())
+            .unwrap_or_else(|| String::from("no status reported"))
+    ))
+}
+
+fn _verify(
+    registry: &config::Registry,
+    connection_id: &str,
+    agent_rec_api: &impl agent_receiver_api::Status,
+) -> AnyhowResult<Vec<(&'static str, String)>> {
+    let site_id = standard_connection_site_id(
+        registry,
+        connection_id,
+        "there is no site to verify imported connections against",
+    )?;
+    let (connection_type, connection) = registry
+        .standard_connection(&site_id)
+        .context(format!("Connection '{}' not found", site_id))?;
+    let mut passed = vec![("Client certificate", check_certificate(&connection.trust)?)];
+    let site_url = site_spec::make_site_url(
+        &site_id,
+        &connection.receiver_port,
+        connection.path_prefix.as_deref(),
+    )?;
+    // The TLS handshake uses the stored root certificate and authenticates with the stored key
+    let status = agent_rec_api
+        .status(&site_url, &connection.trust)
+        .context(format!("Failed to query site '{}'", site_id))?;
+    passed.push(("Authentication", site_url.to_string()));
+    passed.push((
+        "Registration",
+        check_registration(&connection_type, &status)
+            .context(format!("Site '{}' does not accept the connection", site_id))?,
+    ));
+    Ok(passed)
+}
+
+pub fn verify(
+    registry: &config::Registry,
+    connection_id: &str,
+    client_config: &config::ClientConfig,
+) -> AnyhowResult<()> {
+    let passed = _verify(
+        registry,
+        connection_id,
+        &agent_receiver_api::Api::from(client_config),
+    )?;
+    for (check, detail) in passed {
+        println!("{}: ok ({})", check, detail);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use std::str::FromStr;
+
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_PULL_IMP: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+
+    struct MockApi {
+        authenticated: bool,
+        host_status: Option<&'static str>,
+        connection_type: config::ConnectionType,
+    }
+
+    impl MockApi {
+        fn responding(
+            host_status: Option<&'static str>,
+            connection_type: config::ConnectionType,
+        ) -> Self {
+            Self {
+                authenticated: true,
+                host_status,
+                connection_type,
+            }
+        }
+    }
+
+    impl agent_receiver_api::Status for MockApi {
+        fn status(
+            &self,
+            base_url: &reqwest::Url,
+            connection: &config::TrustedConnection,
+        ) -> AnyhowResult<agent_receiver_api::StatusResponse> {
+            assert_eq!(base_url.to_string(), "https://server:8000/push-site");
+            assert_eq!(connection.uuid.to_string(), UUID_PUSH);
+            if !self.authenticated {
+                return Err(anyhow!(agent_receiver_api::ResponseError {
+                    status: http::StatusCode::UNAUTHORIZED,
+                    description: String::from("Client certificate rejected"),
+                }));
+            }
+            Ok(agent_receiver_api::StatusResponse {
+                hostname: Some(String::from("my-host")),
+                status: self
+                    .host_status
+                    .map(|status| agent_receiver_api::HostStatus::from_str(status).unwrap()),
+                connection_type: Some(self.connection_type.clone()),
+                message: Some(String::from("Registration declined by admin")),
+            })
+        }
+    }
+
+    fn registry(certificate: &str) -> config::Registry {
+        let mut registry =
+            config::Registry::new(tempfile::NamedTempFile::new().unwrap().path()).unwrap();
+        let mut connection = config::TrustedConnectionWithRemote::from(UUID_PUSH);
+        connection.trust.certificate = String::from(certificate);
+        registry.register_connection(
+            &config::ConnectionType::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            connection,
+        );
+        registry.register_imported_connection(config::TrustedConnection::from(UUID_PULL_IMP));
+        registry
+    }
+
+    #[test]
+    fn test_verify_ok() {
+        let passed = _verify(
+            &registry(constants::TEST_CERT_OK),
+            UUID_PUSH,
+            &MockApi::responding(Some("ready"), config::ConnectionType::Push),
+        )
+        .unwrap();
+        assert_eq!(
+            passed.iter().map(|(check, _)| *check).collect::<Vec<_>>(),
+            ["Client certificate", "Authentication", "Registration"]
+        );
+        assert_eq!(passed[2].1, "host my-host, ready");
+    }
+
+    #[test]
+    fn test_verify_not_authenticated() {
+        let err = _verify(
+            &registry(constants::TEST_CERT_OK),
+            "server/push-site",
+            &MockApi {
+                authenticated: false,
+                ..MockApi::responding(None, config::ConnectionType::Push)
+            },
+        )
+        .unwrap_err();
+        assert_eq!(ExitCode::from(&err), ExitCode::Authentication);
+        assert!(format!("{:#}", err).starts_with("Failed to query site 'server/push-site'"));
+    }
+
+    #[test]
+    fn test_verify_declined() {
+        let err = _verify(
+            &registry(constants::TEST_CERT_OK),
+            UUID_PUSH,
+            &MockApi::responding(Some("declined"), config::ConnectionType::Push),
+        )
+        .unwrap_err();
+        assert_eq!(ExitCode::from(&err), ExitCode::RegistrationDeclined);
+        assert_eq!(
+            format!("{:#}", err),
+            "Site 'server/push-site' does not accept the connection: Registration declined: \
+             The site declined the registration (Registration declined by admin)"
+        );
+    }
+
+    #[test]
+    fn test_verify_connection_type_mismatch() {
+        let err = _verify(
+            &registry(constants::TEST_CERT_OK),
+            UUID_PUSH,
+            &MockApi::responding(Some("ready"), config::ConnectionType::Pull),
+        )
+        .unwrap_err();
+        assert_eq!(ExitCode::from(&err), ExitCode::ConnectionTypeMismatch);
+        assert!(format!("{:#}", err).ends_with(
+            "The site expects a pull-agent connection, but it is registered as push-agent connection"
+        ));
+    }
+
+    #[test]
+    fn test_verify_local_failures() {
+        let api = MockApi::responding(None, config::ConnectionType::Push);
+        assert!(_verify(&registry("certificate"), UUID_PUSH, &api).is_err());
+        assert!(_verify(
+            &registry(constants::TEST_CERT_OK),
+            "server/other-site",
+            &api
+        )
+        .is_err());
+        assert!(format!(
+            "{}",
+            _verify(&registry(constants::TEST_CERT_OK), UUID_PULL_IMP, &api).unwrap_err()
+        )
+        .contains("is imported"));
+    }
+}
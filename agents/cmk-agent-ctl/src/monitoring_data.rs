@@ -2,19 +2,98 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use std::io::{Result as IoResult, Write};
+use crate::types::AgentChannel;
+use std::io::{Error, ErrorKind, Result as IoResult, Write};
+use std::time::Duration;
 
 #[cfg(unix)]
 mod linux;
 #[cfg(unix)]
-pub use linux::{async_collect, collect};
+use linux::async_probe;
+#[cfg(unix)]
+pub use linux::{async_connect, collect};
 #[cfg(windows)]
 mod windows;
 #[cfg(windows)]
-pub use windows::{async_collect, collect};
+use windows::async_probe;
+#[cfg(windows)]
+pub use windows::{async_connect, collect};
+
+/// Checks whether the agent channel accepts connections, connecting the same way as when
+/// collecting. With read_output, we also read the beginning of the agent output, which means
+/// that the agent runs once, and return the agent version if the output contains it. Channels
+/// which can't be probed yield ErrorKind::Unsupported.
+#[tokio::main(flavor = "current_thread")]
+pub async fn probe(
+    agent_channel: &AgentChannel,
+    read_output: bool,
+    timeout: Duration,
+) -> IoResult<Option<String>> {
+    tokio::time::timeout(timeout, async_probe(agent_channel, read_output))
+        .await
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::TimedOut,
+                format!("No answer within {}s", timeout.as_secs()),
+            )
+        })?
+}
+
+/// Only used for probing, the agent output is read by the caller otherwise
+fn no_output_error() -> Error {
+    Error::new(
+        ErrorKind::UnexpectedEof,
+        "The agent closed the connection without sending any output",
+    )
+}
+
+/// Enough for the check_mk section, which the agent sends first
+const PROBE_READ_LIMIT: usize = 4096;
+
+/// The beginning of the agent output read when probing, until we know the agent version
+#[derive(Default)]
+struct ProbedOutput(Vec<u8>);
+
+impl ProbedOutput {
+    /// Returns whether we have read enough. An empty chunk means that the agent is done.
+    fn add(&mut self, chunk: &[u8]) -> bool {
+        if chunk.is_empty() {
+            self.0.push(b'\n');
+            return true;
+        }
+        self.0.extend_from_slice(chunk);
+        self.0.len() >= PROBE_READ_LIMIT || agent_version(&self.0).is_some()
+    }
+
+    fn agent_version(self) -> IoResult<Option<String>> {
+        match self.0.as_slice() {
+            [] | [b'\n'] => Err(no_output_error()),
+            output => Ok(agent_version(output)),
+        }
+    }
+}
+
+/// The version reported in the check_mk section. Only complete lines count, st. we don't
+/// cut the version short when reading chunk-wise.
+fn agent_version(output: &[u8]) -> Option<String> {
+    let complete = &output[..output.iter().rposition(|byte| *byte == b'\n')?];
+    String::from_utf8_lossy(complete)
+        .lines()
+        .skip_while(|line| line.trim() != "<<<check_mk>>>")
+        .skip(1)
+        .take_while(|line| !line.starts_with("<<<"))
+        .find_map(|line| line.strip_prefix("Version:"))
+        .map(|version| String::from(version.trim()))
+}
+
+/// Compressor for chunk-wise processing. The compressed data accumulates in the inner Vec,
+/// callers may take it out at any time.
+pub fn compressor() -> flate2::write::ZlibEncoder<Vec<u8>> {
+    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default())
+}
 
 pub fn compress(data: &[u8]) -> IoResult<Vec<u8>> {
-    let mut zlib_enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut zlib_enc = compressor();
     zlib_enc.write_all(data)?;
     zlib_enc.finish()
 }
@@ -36,6 +115,38 @@ mod tests {
     use super::*;
     use std::io::Read;
 
+    #[test]
+    fn test_agent_version() {
+        assert_eq!(
+            agent_version(b"<<<check_mk>>>\nVersion: 2.2.0p1\nAgentOS: linux\n<<<df>>>\n"),
+            Some(String::from("2.2.0p1"))
+        );
+        // Incomplete lines may be cut short
+        assert!(agent_version(b"<<<check_mk>>>\nVersion: 2.2").is_none());
+        assert!(agent_version(b"<<<check_mk>>>\nAgentOS: linux\n<<<df>>>\nVersion: 1\n").is_none());
+        assert!(agent_version(b"").is_none());
+    }
+
+    #[test]
+    fn test_probed_output() {
+        let mut probed = ProbedOutput::default();
+        assert!(!probed.add(b"<<<check_mk>>>\nVersion: 2.3"));
+        assert!(probed.add(b".0\n"));
+        assert_eq!(probed.agent_version().unwrap().unwrap(), "2.3.0");
+
+        let mut probed = ProbedOutput::default();
+        assert!(!probed.add(b"<<<local>>>\n"));
+        assert!(probed.add(b""));
+        assert!(probed.agent_version().unwrap().is_none());
+
+        let mut probed = ProbedOutput::default();
+        assert!(probed.add(b""));
+        assert!(probed.agent_version().is_err());
+
+        let mut probed = ProbedOutput::default();
+        assert!(probed.add(&[b'x'; PROBE_READ_LIMIT]));
+    }
+
     #[test]
     fn test_compress() {
         let input_str = "abc";
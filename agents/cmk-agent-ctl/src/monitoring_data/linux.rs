@@ -2,30 +2,376 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use crate::types::AgentChannel;
-use std::io::{Read, Result as IoResult, Write};
+use crate::{constants, types::AgentChannel};
+use std::io::Result as IoResult;
 
-use std::os::unix::net::UnixStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixStream as AsyncUnixStream;
+use faccess::PathExt;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpStream as AsyncTcpStream, UnixStream as AsyncUnixStream};
+use tokio::process::{Child, ChildStdout, Command as AsyncCommand};
 
-pub async fn async_collect(
+/// Like xinetd, we pass the address of the peer to the agent command in the environment
+const REMOTE_HOST_VAR: &str = "REMOTE_HOST";
+
+/// The agent output is read chunk-wise by the caller. We never read more than one byte beyond
+/// the limit, st. a misbehaving agent can't keep us reading forever.
+pub type AgentStream = tokio::io::Take<Box<dyn AsyncRead + Unpin + Send>>;
+
+/// The stdout of an agent command. The child belongs to it, st. dropping the output, eg. on a
+/// timeout or if the output is too large, also kills a command which is still running.
+struct CommandOutput {
+    _child: Child,
+    stdout: ChildStdout,
+}
+
+impl AsyncRead for CommandOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+fn spawn_command(
+    command: &[String],
+    remote_ip: Option<std::net::IpAddr>,
+) -> IoResult<CommandOutput> {
+    let mut async_command = AsyncCommand::new(&command[0]);
+    async_command
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+    if let Some(remote_ip) = remote_ip {
+        async_command.env(REMOTE_HOST_VAR, remote_ip.to_string());
+    }
+    let mut child = async_command.spawn()?;
+    let stdout = child.stdout.take().ok_or_else(|| {
+        std::io::Error::other("Failed to capture the output of the agent command")
+    })?;
+    Ok(CommandOutput {
+        _child: child,
+        stdout,
+    })
+}
+
+/// Every connection serves exactly one agent output, which ends when the agent closes the
+/// connection. So there is nothing to keep open between requests, we connect anew each time.
+pub async fn async_connect(
     agent_channel: &AgentChannel,
     remote_ip: std::net::IpAddr,
-) -> IoResult<Vec<u8>> {
+    max_output_bytes: usize,
+) -> IoResult<AgentStream> {
+    let remote_ip_line = format!("{}\n", remote_ip);
+    let agent_stream: Box<dyn AsyncRead + Unpin + Send> = match agent_channel {
+        AgentChannel::Socket(path) => {
+            let mut stream = AsyncUnixStream::connect(path).await?;
+            stream.write_all(remote_ip_line.as_bytes()).await?;
+            Box::new(stream)
+        }
+        AgentChannel::Tcp(address) => {
+            let mut stream = AsyncTcpStream::connect(address).await?;
+            stream.write_all(remote_ip_line.as_bytes()).await?;
+            Box::new(stream)
+        }
+        AgentChannel::Command(command) => Box::new(spawn_command(command, Some(remote_ip))?),
+    };
+    Ok(agent_stream.take(max_output_bytes as u64 + 1))
+}
+
+async fn probe_stream(
+    mut agent_stream: impl AsyncRead + AsyncWrite + Unpin,
+    read_output: bool,
+) -> IoResult<Option<String>> {
+    if !read_output {
+        return Ok(None);
+    }
+    // No remote IP, as in collect
+    agent_stream.write_all(b"\n").await?;
+    probe_output(agent_stream).await
+}
+
+async fn probe_output(mut agent_output: impl AsyncRead + Unpin) -> IoResult<Option<String>> {
+    let mut probed = super::ProbedOutput::default();
+    let mut chunk = [0u8; 1024];
+    loop {
+        let read = agent_output.read(&mut chunk).await?;
+        if probed.add(&chunk[..read]) {
+            return probed.agent_version();
+        }
+    }
+}
+
+pub async fn async_probe(
+    agent_channel: &AgentChannel,
+    read_output: bool,
+) -> IoResult<Option<String>> {
+    match agent_channel {
+        AgentChannel::Socket(path) => {
+            probe_stream(AsyncUnixStream::connect(path).await?, read_output).await
+        }
+        AgentChannel::Tcp(address) => {
+            probe_stream(AsyncTcpStream::connect(address).await?, read_output).await
+        }
+        // Nothing to connect to, so without reading the output, we can only check the program
+        AgentChannel::Command(command) => match read_output {
+            false if std::path::Path::new(&command[0]).executable() => Ok(None),
+            false => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not an executable file", command[0]),
+            )),
+            true => probe_output(spawn_command(command, None)?).await,
+        },
+    }
+}
+
+async fn collect_from(mut agent_stream: impl AsyncRead + AsyncWrite + Unpin) -> IoResult<Vec<u8>> {
     let mut mondata: Vec<u8> = vec![];
-    let mut agent_stream = AsyncUnixStream::connect(agent_channel).await?;
-    agent_stream
-        .write_all(format!("{}\n", remote_ip).as_bytes())
-        .await?;
+    agent_stream.write_all(b"\n").await?; // No remote IP, signalize agent to continue and collect
     agent_stream.read_to_end(&mut mondata).await?;
     Ok(mondata)
 }
 
+async fn async_collect(agent_channel: &AgentChannel) -> IoResult<Vec<u8>> {
+    match agent_channel {
+        AgentChannel::Socket(path) => collect_from(AsyncUnixStream::connect(path).await?).await,
+        AgentChannel::Tcp(address) => collect_from(AsyncTcpStream::connect(address).await?).await,
+        AgentChannel::Command(command) => {
+            let mut mondata: Vec<u8> = vec![];
+            spawn_command(command, None)?
+                .read_to_end(&mut mondata)
+                .await?;
+            Ok(mondata)
+        }
+    }
+}
+
+/// Collects the complete agent output, eg. for push. An agent which doesn't finish in time
+/// yields ErrorKind::TimedOut, a command still running is killed then.
 pub fn collect(agent_channel: &AgentChannel) -> IoResult<Vec<u8>> {
-    let mut mondata: Vec<u8> = vec![];
-    let mut agent_stream = UnixStream::connect(agent_channel)?;
-    agent_stream.write_all("\n".as_bytes())?; // No remote IP, signalize agent to continue and collect
-    agent_stream.read_to_end(&mut mondata)?;
-    Ok(mondata)
+    collect_within(
+        agent_channel,
+        Duration::from_secs(constants::DEFAULT_AGENT_CHANNEL_TIMEOUT),
+    )
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn collect_within(agent_channel: &AgentChannel, timeout: Duration) -> IoResult<Vec<u8>> {
+    tokio::time::timeout(timeout, async_collect(agent_channel))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("No complete agent output within {}s", timeout.as_secs()),
+            )
+        })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    /// Fake agent, reports whether it was able to write its complete output
+    fn run_agent(
+        socket_path: &std::path::Path,
+        output_size: usize,
+    ) -> std::thread::JoinHandle<bool> {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut remote_ip = [0u8; 10];
+            stream.read_exact(&mut remote_ip).unwrap();
+            stream.write_all(&vec![b'x'; output_size]).is_ok()
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.socket");
+        let agent = run_agent(&socket_path, 10 * 1024 * 1024);
+        let mut mondata = vec![];
+        async_connect(
+            &AgentChannel::from(socket_path.clone()),
+            std::net::IpAddr::from([127, 0, 0, 1]),
+            1024,
+        )
+        .await
+        .unwrap()
+        .read_to_end(&mut mondata)
+        .await
+        .unwrap();
+        assert_eq!(mondata.len(), 1025);
+        // We hung up instead of reading the complete output
+        assert!(!agent.join().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_connect_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let agent_channel = AgentChannel::Tcp(listener.local_addr().unwrap());
+        let agent = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut remote_ip = [0u8; 9];
+            stream.read_exact(&mut remote_ip).unwrap();
+            stream.write_all(b"<<<check_mk>>>").unwrap();
+            String::from_utf8(remote_ip.to_vec()).unwrap()
+        });
+        let mut mondata = vec![];
+        async_connect(&agent_channel, std::net::IpAddr::from([10, 0, 0, 1]), 1024)
+            .await
+            .unwrap()
+            .read_to_end(&mut mondata)
+            .await
+            .unwrap();
+        assert_eq!(mondata, b"<<<check_mk>>>");
+        assert_eq!(agent.join().unwrap(), "10.0.0.1\n");
+    }
+
+    fn command(script: &str) -> AgentChannel {
+        AgentChannel::command(&[
+            String::from("/bin/sh"),
+            String::from("-c"),
+            String::from(script),
+        ])
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_connect_command() {
+        let mut mondata = vec![];
+        async_connect(
+            &command("echo \"<<<check_mk>>>\"; echo \"$REMOTE_HOST\""),
+            std::net::IpAddr::from([10, 0, 0, 1]),
+            1024,
+        )
+        .await
+        .unwrap()
+        .read_to_end(&mut mondata)
+        .await
+        .unwrap();
+        assert_eq!(mondata, b"<<<check_mk>>>\n10.0.0.1\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_connect_command_killed_on_drop() {
+        let mut agent_stream = async_connect(
+            &command("echo $$; exec sleep 60"),
+            std::net::IpAddr::from([127, 0, 0, 1]),
+            1024,
+        )
+        .await
+        .unwrap();
+        let mut pid = vec![];
+        while !pid.ends_with(b"\n") {
+            let mut byte = [0u8; 1];
+            agent_stream.read_exact(&mut byte).await.unwrap();
+            pid.push(byte[0]);
+        }
+        let proc_path = format!("/proc/{}/stat", String::from_utf8(pid).unwrap().trim());
+        assert!(std::path::Path::new(&proc_path).exists());
+        drop(agent_stream);
+        // Either gone or a zombie which was not reaped yet
+        for _ in 0..50 {
+            match std::fs::read_to_string(&proc_path) {
+                Err(_) => return,
+                Ok(stat) if stat.contains(") Z ") => return,
+                Ok(_) => tokio::time::sleep(std::time::Duration::from_millis(100)).await,
+            }
+        }
+        panic!("The agent command is still running");
+    }
+
+    #[test]
+    fn test_collect_command() {
+        assert_eq!(
+            collect(&command("echo \"<<<check_mk>>>\"")).unwrap(),
+            b"<<<check_mk>>>\n"
+        );
+    }
+
+    #[test]
+    fn test_collect_timeout() {
+        let now = std::time::Instant::now();
+        assert_eq!(
+            collect_within(&command("sleep 60"), Duration::from_secs(1))
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::TimedOut
+        );
+        assert!(now.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_probe_command() {
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(super::super::probe(&command("exit 1"), false, timeout)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            super::super::probe(
+                &command("echo \"<<<check_mk>>>\nVersion: 2.2.0\"; exec sleep 60"),
+                true,
+                timeout
+            )
+            .unwrap(),
+            Some(String::from("2.2.0"))
+        );
+        assert!(super::super::probe(
+            &AgentChannel::command(&[String::from("/does/not/exist")]).unwrap(),
+            false,
+            timeout
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_probe() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.socket");
+        let agent_channel = AgentChannel::from(socket_path.clone());
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(super::super::probe(&agent_channel, false, timeout).is_err());
+
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let agent = std::thread::spawn(move || {
+            // Connecting only
+            drop(listener.accept().unwrap());
+            // Reading up to the version, the agent keeps the connection open
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut remote_ip = [0u8; 1];
+            stream.read_exact(&mut remote_ip).unwrap();
+            stream
+                .write_all(b"<<<check_mk>>>\nVersion: 2.2.0\nAgentOS: linux\n")
+                .unwrap();
+            // No output at all
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut remote_ip).unwrap();
+        });
+        assert!(super::super::probe(&agent_channel, false, timeout)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            super::super::probe(&agent_channel, true, timeout).unwrap(),
+            Some(String::from("2.2.0"))
+        );
+        assert_eq!(
+            super::super::probe(&agent_channel, true, timeout)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        agent.join().unwrap();
+    }
 }
@@ -22,12 +22,14 @@ const MAX_ANSWER_WAIT_TIME: Duration = Duration::from_secs(180);
 enum ChannelType {
     Ip,
     Mailslot,
+    Pipe,
 }
 
 impl AgentChannel {
     const CHANNEL_MAILSLOT_PREFIX: &'static str = "ms";
     const CHANNEL_IP_PREFIX: &'static str = "ip";
     const CHANNEL_PREFIX_SEPARATOR: char = '/';
+    const CHANNEL_PIPE_PREFIX: &'static str = r"\\.\pipe\";
 
     fn split(&self) -> Vec<&str> {
         return self
@@ -40,7 +42,11 @@ impl AgentChannel {
     /// where
     ///     type is either "ms" or "ip"
     ///     address is arbitrary string
+    /// or as a named pipe "\\.\pipe\name"
     fn parse(&self) -> IoResult<(ChannelType, String)> {
+        if self.as_ref().starts_with(Self::CHANNEL_PIPE_PREFIX) {
+            return Ok((ChannelType::Pipe, self.as_ref().to_string()));
+        }
         let split = self.split();
         // Legacy case support: agent_channel is "localhost:28250"
         if split.len() == 1 && !split[0].is_empty() {
@@ -67,13 +73,30 @@ impl AgentChannel {
     }
 }
 
+fn check_output_size(mondata: Vec<u8>, max_output_bytes: usize) -> IoResult<Vec<u8>> {
+    if mondata.len() > max_output_bytes {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Agent output exceeds the maximum size of {} bytes",
+                max_output_bytes
+            ),
+        ));
+    }
+    Ok(mondata)
+}
+
 fn is_error_acceptable(error: &Error) -> bool {
     // special case for Windows related to server/clients with strnage behavior
     error.kind() == std::io::Error::from_raw_os_error(10054).kind()
 }
 
 // TODO(sk): add logging and unit testing(using local server)
-async fn async_collect_from_ip(agent_ip: &str, remote_ip: IpAddr) -> IoResult<Vec<u8>> {
+async fn async_collect_from_ip(
+    agent_ip: &str,
+    remote_ip: IpAddr,
+    max_output_bytes: usize,
+) -> IoResult<Vec<u8>> {
     let mut data: Vec<u8> = vec![];
     debug!("connect to {}", agent_ip);
     let mut stream = AsyncTcpStream::connect(agent_ip).await?;
@@ -81,14 +104,17 @@ async fn async_collect_from_ip(agent_ip: &str, remote_ip: IpAddr) -> IoResult<Ve
         .write_all(format!("{}", remote_ip).as_bytes())
         .await?;
     stream.flush().await?;
-    let result = stream.read_to_end(&mut data).await;
+    let result = (&mut stream)
+        .take(max_output_bytes as u64 + 1)
+        .read_to_end(&mut data)
+        .await;
     let _ = stream.shutdown(std::net::Shutdown::Both); // can't return here, error could be ignored
     match result {
-        Ok(_) => Ok(data),
+        Ok(_) => check_output_size(data, max_output_bytes),
         Err(some_err) => {
             if is_error_acceptable(&some_err) {
                 debug!("error during receive");
-                Ok(data)
+                check_output_size(data, max_output_bytes)
             } else {
                 Err(some_err)
             }
@@ -96,6 +122,92 @@ async fn async_collect_from_ip(agent_ip: &str, remote_ip: IpAddr) -> IoResult<Ve
     }
 }
 
+async fn read_probed_output(stream: &mut AsyncTcpStream) -> IoResult<Option<String>> {
+    let mut probed = super::ProbedOutput::default();
+    let mut chunk = [0u8; 1024];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if probed.add(&chunk[..read]) {
+            return probed.agent_version();
+        }
+    }
+}
+
+async fn async_probe_ip(agent_ip: &str, read_output: bool) -> IoResult<Option<String>> {
+    debug!("probe {}", agent_ip);
+    let mut stream = AsyncTcpStream::connect(agent_ip).await?;
+    if !read_output {
+        return Ok(None);
+    }
+    stream
+        .write_all(format!("{}", IpAddr::from([127, 0, 0, 1])).as_bytes())
+        .await?;
+    stream.flush().await?;
+    let result = read_probed_output(&mut stream).await;
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    result
+}
+
+mod pipe {
+    use super::check_output_size;
+    use log::debug;
+    use std::io::Result as IoResult;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+
+    /// All pipe instances are busy, see winerror.h
+    const ERROR_PIPE_BUSY: i32 = 231;
+    const PIPE_BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+    async fn open(pipe_name: &str) -> IoResult<NamedPipeClient> {
+        loop {
+            match ClientOptions::new().open(pipe_name) {
+                Ok(pipe) => return Ok(pipe),
+                Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+                Err(err) => return Err(err),
+            }
+            tokio::time::sleep(PIPE_BUSY_RETRY_INTERVAL).await;
+        }
+    }
+
+    pub async fn async_probe_pipe(pipe_name: &str, read_output: bool) -> IoResult<Option<String>> {
+        debug!("probe {}", pipe_name);
+        let mut pipe = open(pipe_name).await?;
+        if !read_output {
+            return Ok(None);
+        }
+        pipe.write_all(format!("{}\n", IpAddr::from([127, 0, 0, 1])).as_bytes())
+            .await?;
+        let mut probed = super::super::ProbedOutput::default();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let read = pipe.read(&mut chunk).await?;
+            if probed.add(&chunk[..read]) {
+                return probed.agent_version();
+            }
+        }
+    }
+
+    pub async fn async_collect_from_pipe(
+        pipe_name: &str,
+        remote_ip: IpAddr,
+        max_output_bytes: usize,
+    ) -> IoResult<Vec<u8>> {
+        debug!("connect to {}", pipe_name);
+        let mut pipe = open(pipe_name).await?;
+        pipe.write_all(format!("{}\n", remote_ip).as_bytes())
+            .await?;
+        let mut data: Vec<u8> = vec![];
+        (&mut pipe)
+            .take(max_output_bytes as u64 + 1)
+            .read_to_end(&mut data)
+            .await?;
+        check_output_size(data, max_output_bytes)
+    }
+}
+
 /// Generates correct request for windows agent mailslot
 ///
 /// Attention: must be in sync with windows agent code
@@ -136,17 +248,55 @@ async fn async_collect_from_mailslot(agent_mailslot: &str, remote_ip: IpAddr) ->
     Ok(value.as_bytes().to_owned())
 }
 
+/// Neither channel type lets us hand out the agent output while it is produced, thus we
+/// collect it first (up to the given limit) and serve it from memory.
+pub type AgentStream = std::io::Cursor<Vec<u8>>;
+
+pub async fn async_connect(
+    agent_channel: &AgentChannel,
+    remote_ip: std::net::IpAddr,
+    max_output_bytes: usize,
+) -> IoResult<AgentStream> {
+    Ok(std::io::Cursor::new(
+        async_collect(agent_channel, remote_ip, max_output_bytes).await?,
+    ))
+}
+
 /// Sends the command to the agent channel and awaits
 ///
-/// This is a simple wrapper for Ip and Mailslot channel
-pub async fn async_collect(
+/// This is a simple wrapper for Ip, Mailslot and Pipe channel
+async fn async_collect(
     agent_channel: &AgentChannel,
     remote_ip: std::net::IpAddr,
+    max_output_bytes: usize,
 ) -> IoResult<Vec<u8>> {
     let (ch_type, ch_addr) = agent_channel.parse()?;
     match ch_type {
-        ChannelType::Ip => async_collect_from_ip(&ch_addr, remote_ip).await,
-        ChannelType::Mailslot => async_collect_from_mailslot(&ch_addr, remote_ip).await,
+        ChannelType::Ip => async_collect_from_ip(&ch_addr, remote_ip, max_output_bytes).await,
+        // The mailslot delivers the answer in one piece, we can only check it afterwards
+        ChannelType::Mailslot => check_output_size(
+            async_collect_from_mailslot(&ch_addr, remote_ip).await?,
+            max_output_bytes,
+        ),
+        ChannelType::Pipe => {
+            pipe::async_collect_from_pipe(&ch_addr, remote_ip, max_output_bytes).await
+        }
+    }
+}
+
+pub async fn async_probe(
+    agent_channel: &AgentChannel,
+    read_output: bool,
+) -> IoResult<Option<String>> {
+    let (ch_type, ch_addr) = agent_channel.parse()?;
+    match ch_type {
+        ChannelType::Ip => async_probe_ip(&ch_addr, read_output).await,
+        // Sending a request to the mailslot always succeeds, only the answer would tell
+        ChannelType::Mailslot => Err(Error::new(
+            ErrorKind::Unsupported,
+            "Mailslot channels can't be probed",
+        )),
+        ChannelType::Pipe => pipe::async_probe_pipe(&ch_addr, read_output).await,
     }
 }
 
@@ -154,6 +304,7 @@ fn collect_from_ip(agent_ip: &str) -> IoResult<Vec<u8>> {
     async_std::task::block_on(async_collect_from_ip(
         agent_ip,
         IpAddr::from([127, 0, 0, 1]),
+        usize::MAX,
     ))
 }
 
@@ -162,11 +313,17 @@ async fn collect_from_mailslot(mailslot: &str) -> IoResult<Vec<u8>> {
     async_collect_from_mailslot(mailslot, IpAddr::from([127, 0, 0, 1])).await
 }
 
+#[tokio::main(flavor = "current_thread")]
+async fn collect_from_pipe(pipe_name: &str) -> IoResult<Vec<u8>> {
+    pipe::async_collect_from_pipe(pipe_name, IpAddr::from([127, 0, 0, 1]), usize::MAX).await
+}
+
 pub fn collect(agent_channel: &AgentChannel) -> IoResult<Vec<u8>> {
     let (ch_type, ch_addr) = agent_channel.parse()?;
     match ch_type {
         ChannelType::Ip => collect_from_ip(&ch_addr),
         ChannelType::Mailslot => collect_from_mailslot(&ch_addr),
+        ChannelType::Pipe => collect_from_pipe(&ch_addr),
     }
 }
 
@@ -192,6 +349,7 @@ mod tests {
             match *self {
                 ChannelType::Ip => write!(f, "Ip"),
                 ChannelType::Mailslot => write!(f, "Mailslot"),
+                ChannelType::Pipe => write!(f, "Pipe"),
             }
         }
     }
@@ -226,6 +384,10 @@ mod tests {
             parse_me("buzz_inc").unwrap(),
             (ChannelType::Ip, "buzz_inc".to_string())
         );
+        assert_eq!(
+            parse_me(r"\\.\pipe\check_mk_agent").unwrap(),
+            (ChannelType::Pipe, r"\\.\pipe\check_mk_agent".to_string())
+        );
     }
 
     #[test]
@@ -251,7 +413,7 @@ mod tests {
     #[tokio::test(flavor = "multi_thread")]
     async fn test_async_collect_bad_input() {
         assert_eq!(
-            async_collect(&AgentChannel::from(""), addr())
+            async_collect(&AgentChannel::from(""), addr(), usize::MAX)
                 .await
                 .map_err(|e| e.kind()),
             Err(ErrorKind::InvalidInput)
@@ -264,7 +426,7 @@ mod tests {
         assert_eq!(
             tokio::time::timeout(
                 MAILSLOT_SERVER_TIMEOUT + Duration::from_secs(1),
-                async_collect(&AgentChannel::from("ms/xxxx"), addr())
+                async_collect(&AgentChannel::from("ms/xxxx"), addr(), usize::MAX)
             )
             .await
             .unwrap_or_else(|_| Ok(EMPTY_DATA)) // this is semi-OK: timeout
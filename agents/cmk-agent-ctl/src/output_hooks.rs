@@ -0,0 +1,277 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Pipes the agent output through the configured hooks before it is served, eg. to add a
+//! section or to strip sensitive data. Each hook gets the output of the previous one on stdin
+//! and writes its result to stdout. The hooks only come from the config file.
+
+use anyhow::{anyhow, bail, Result as AnyhowResult};
+use std::error::Error;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+/// Like xinetd and agent commands, hooks get the address of the peer in the environment
+const REMOTE_HOST_VAR: &str = "REMOTE_HOST";
+/// Enough to tell why a hook failed, we don't want to log whole stack traces
+const MAX_STDERR_BYTES: u64 = 1024;
+
+/// Unlike an unavailable agent, this is never reported to the peer, st. the site never gets
+/// output which was meant to be transformed
+#[derive(Debug)]
+pub struct HookFailed {
+    program: String,
+    reason: String,
+}
+
+impl std::fmt::Display for HookFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Output hook '{}' failed: {}", self.program, self.reason)
+    }
+}
+
+impl Error for HookFailed {}
+
+fn failed(hook: &[String], reason: String) -> anyhow::Error {
+    anyhow!(HookFailed {
+        program: hook[0].clone(),
+        reason,
+    })
+}
+
+/// The status tells most of the time, stderr is only mentioned if there is something in it
+fn failure_reason(status: std::process::ExitStatus, stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    match stderr.trim() {
+        "" => status.to_string(),
+        stderr => format!("{} ({})", status, stderr),
+    }
+}
+
+async fn _run_hook(
+    hook: &[String],
+    input: &[u8],
+    remote_ip: std::net::IpAddr,
+    max_output_bytes: usize,
+) -> AnyhowResult<Vec<u8>> {
+    // Killed on timeout, when this future is dropped
+    let mut child = Command::new(&hook[0])
+        .args(&hook[1..])
+        .env(REMOTE_HOST_VAR, remote_ip.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| failed(hook, format!("could not be started ({})", err)))?;
+    let (Some(mut stdin), Some(stdout), Some(mut stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        bail!(failed(hook, String::from("could not be connected to")))
+    };
+    // Drained on the side, st. a chatty hook can't block on a full pipe
+    let error_output = tokio::spawn(async move {
+        let mut error_output = vec![];
+        let _ = (&mut stderr)
+            .take(MAX_STDERR_BYTES)
+            .read_to_end(&mut error_output)
+            .await;
+        let _ = tokio::io::copy(&mut stderr, &mut tokio::io::sink()).await;
+        error_output
+    });
+    let mut stdout = stdout.take(max_output_bytes as u64 + 1);
+    let mut output = vec![];
+    // Fed while reading, a hook may well start writing before it has read all of its input
+    let (written, read) = tokio::join!(
+        async {
+            let written = stdin.write_all(input).await;
+            drop(stdin);
+            written
+        },
+        stdout.read_to_end(&mut output),
+    );
+    read.map_err(|err| failed(hook, format!("output could not be read ({})", err)))?;
+    if output.len() > max_output_bytes {
+        bail!(failed(
+            hook,
+            format!(
+                "output exceeds the maximum size of {} bytes",
+                max_output_bytes
+            )
+        ))
+    }
+    let status = child
+        .wait()
+        .await
+        .map_err(|err| failed(hook, err.to_string()))?;
+    if !status.success() {
+        let error_output = error_output.await.unwrap_or_default();
+        bail!(failed(hook, failure_reason(status, &error_output)))
+    }
+    // A hook which does not care about its input may well exit before reading all of it
+    if let Err(err) = written {
+        if err.kind() != std::io::ErrorKind::BrokenPipe {
+            bail!(failed(
+                hook,
+                format!("input could not be written ({})", err)
+            ))
+        }
+    }
+    Ok(output)
+}
+
+async fn run_hook(
+    hook: &[String],
+    input: &[u8],
+    remote_ip: std::net::IpAddr,
+    max_output_bytes: usize,
+    hook_timeout: u64,
+) -> AnyhowResult<Vec<u8>> {
+    tokio::time::timeout(
+        Duration::from_secs(hook_timeout),
+        _run_hook(hook, input, remote_ip, max_output_bytes),
+    )
+    .await
+    .map_err(|_| {
+        failed(
+            hook,
+            format!("did not finish within {} seconds", hook_timeout),
+        )
+    })?
+}
+
+/// The size limit applies to the output of each hook, and thereby to the final result
+pub async fn run(
+    hooks: &[Vec<String>],
+    mut output: Vec<u8>,
+    remote_ip: std::net::IpAddr,
+    max_output_bytes: usize,
+    hook_timeout: u64,
+) -> AnyhowResult<Vec<u8>> {
+    for hook in hooks {
+        output = run_hook(hook, &output, remote_ip, max_output_bytes, hook_timeout).await?;
+    }
+    Ok(output)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn hook(script: &str) -> Vec<String> {
+        vec![
+            String::from("/bin/sh"),
+            String::from("-c"),
+            String::from(script),
+        ]
+    }
+
+    fn remote_ip() -> std::net::IpAddr {
+        std::net::IpAddr::from([10, 0, 0, 1])
+    }
+
+    #[tokio::test]
+    async fn test_run_in_order() {
+        assert_eq!(
+            run(
+                &[
+                    hook("grep -v secret"),
+                    hook("cat; echo \"<<<custom>>>\"; echo \"$REMOTE_HOST\""),
+                ],
+                b"<<<check_mk>>>\nsecret: 42\nVersion: 2.2.0\n".to_vec(),
+                remote_ip(),
+                1024,
+                5,
+            )
+            .await
+            .unwrap(),
+            b"<<<check_mk>>>\nVersion: 2.2.0\n<<<custom>>>\n10.0.0.1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_no_hooks() {
+        assert_eq!(
+            run(&[], b"<<<check_mk>>>".to_vec(), remote_ip(), 1, 5)
+                .await
+                .unwrap(),
+            b"<<<check_mk>>>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_failing_hook() {
+        let err = run(
+            &[hook("cat"), hook("echo 'no such section' >&2; exit 3")],
+            b"<<<check_mk>>>".to_vec(),
+            remote_ip(),
+            1024,
+            5,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.is::<HookFailed>());
+        assert_eq!(
+            err.to_string(),
+            "Output hook '/bin/sh' failed: exit status: 3 (no such section)"
+        );
+        assert!(run(
+            &[vec![String::from("/does/not/exist")]],
+            vec![],
+            remote_ip(),
+            1024,
+            5
+        )
+        .await
+        .unwrap_err()
+        .to_string()
+        .starts_with("Output hook '/does/not/exist' failed: could not be started"));
+    }
+
+    #[tokio::test]
+    async fn test_run_output_too_large() {
+        // The agent output fits, but not what the hook makes of it
+        assert_eq!(
+            run(
+                &[hook("cat; cat /dev/zero")],
+                vec![b'x'; 512],
+                remote_ip(),
+                1024,
+                5
+            )
+            .await
+            .unwrap_err()
+            .to_string(),
+            "Output hook '/bin/sh' failed: output exceeds the maximum size of 1024 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout() {
+        assert_eq!(
+            run(&[hook("sleep 10")], vec![], remote_ip(), 1024, 1)
+                .await
+                .unwrap_err()
+                .to_string(),
+            "Output hook '/bin/sh' failed: did not finish within 1 seconds"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_hook_ignoring_input() {
+        assert_eq!(
+            run(
+                &[hook("echo '<<<replaced>>>'")],
+                vec![b'x'; 1024 * 1024],
+                remote_ip(),
+                4 * 1024 * 1024,
+                5
+            )
+            .await
+            .unwrap(),
+            b"<<<replaced>>>\n"
+        );
+    }
+}
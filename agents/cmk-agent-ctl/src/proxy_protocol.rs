@@ -0,0 +1,261 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! The header of the PROXY protocol, versions 1 and 2, which load balancers send ahead of the
+//! proxied data to tell us the address of the actual peer, see
+//! https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+//! We read exactly the header and nothing more, st. the TLS handshake starts right after it.
+
+use anyhow::{bail, Context, Result as AnyhowResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+// Including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// The source address from the header. None if the proxy speaks for itself, eg. for health
+/// checks (LOCAL in version 2, UNKNOWN in version 1). Version 2 headers larger than max_length
+/// are rejected before reading them, version 1 headers are short anyway.
+pub async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_length: usize,
+) -> AnyhowResult<Option<SocketAddr>> {
+    let mut start = [0u8; 5];
+    stream
+        .read_exact(&mut start)
+        .await
+        .context("Failed to read PROXY protocol header")?;
+    if start == V1_PREFIX {
+        return read_v1(stream).await;
+    }
+    if start == V2_SIGNATURE[..start.len()] {
+        return read_v2(stream, max_length).await;
+    }
+    bail!("Missing PROXY protocol header")
+}
+
+async fn read_v1(stream: &mut (impl AsyncRead + Unpin)) -> AnyhowResult<Option<SocketAddr>> {
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            bail!("PROXY protocol header too long")
+        }
+        line.push(
+            stream
+                .read_u8()
+                .await
+                .context("Failed to read PROXY protocol header")?,
+        );
+    }
+    parse_v1(std::str::from_utf8(&line[..line.len() - 2])?)
+}
+
+fn parse_v1(line: &str) -> AnyhowResult<Option<SocketAddr>> {
+    match line.split(' ').collect::<Vec<&str>>()[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let source = source
+                .parse::<IpAddr>()
+                .context(format!("Invalid source address '{}'", source))?;
+            if source.is_ipv4() != (family == "TCP4") {
+                bail!("Source address {} does not match {}", source, family)
+            }
+            Ok(Some(SocketAddr::new(
+                source,
+                source_port
+                    .parse()
+                    .context(format!("Invalid source port '{}'", source_port))?,
+            )))
+        }
+        _ => bail!("Invalid PROXY protocol header '{}'", line),
+    }
+}
+
+async fn read_v2(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_length: usize,
+) -> AnyhowResult<Option<SocketAddr>> {
+    let mut rest = [0u8; 16 - 5];
+    stream
+        .read_exact(&mut rest)
+        .await
+        .context("Failed to read PROXY protocol header")?;
+    if rest[..7] != V2_SIGNATURE[5..] {
+        bail!("Invalid PROXY protocol signature")
+    }
+    let (version_command, family) = (rest[7], rest[8]);
+    let addresses_length: usize = u16::from_be_bytes([rest[9], rest[10]]).into();
+    if 16 + addresses_length > max_length {
+        bail!(
+            "PROXY protocol header of {} bytes exceeds the maximum of {} bytes",
+            16 + addresses_length,
+            max_length
+        )
+    }
+    let mut addresses = vec![0u8; addresses_length];
+    stream
+        .read_exact(&mut addresses)
+        .await
+        .context("Failed to read PROXY protocol header")?;
+    parse_v2(version_command, family, &addresses)
+}
+
+/// Anything after the addresses are TLVs, which we don't need
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> AnyhowResult<Option<SocketAddr>> {
+    if version_command & 0xf0 != V2_VERSION {
+        bail!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        )
+    }
+    match version_command & 0x0f {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        command => bail!("Unsupported PROXY protocol command {}", command),
+    }
+    let (source, port_offset) = match family {
+        V2_FAMILY_TCP4 if addresses.len() >= 12 => (
+            IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?)),
+            8,
+        ),
+        V2_FAMILY_TCP6 if addresses.len() >= 36 => (
+            IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?)),
+            32,
+        ),
+        V2_FAMILY_TCP4 | V2_FAMILY_TCP6 => bail!("PROXY protocol addresses too short"),
+        // Unix sockets or UDP, which we don't listen on
+        _ => bail!("Unsupported PROXY protocol address family {:#04x}", family),
+    };
+    Ok(Some(SocketAddr::new(
+        source,
+        u16::from_be_bytes([addresses[port_offset], addresses[port_offset + 1]]),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(header: &[u8]) -> AnyhowResult<Option<SocketAddr>> {
+        read_header(&mut std::io::Cursor::new(header.to_vec()), 1024).await
+    }
+
+    fn v2(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 6556\r\n")
+                .await
+                .unwrap(),
+            Some(SocketAddr::from(([192, 168, 0, 1], 56324)))
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4242 6556\r\n")
+                .await
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "[2001:db8::1]:4242"
+        );
+        assert!(read(b"PROXY UNKNOWN\r\n").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_v1_invalid() {
+        for header in [
+            &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 4242 6556\r\n",
+            b"PROXY TCP4 no-ip 192.168.0.11 56324 6556\r\n",
+            b"PROXY UDP4 192.168.0.1 192.168.0.11 56324 6556\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 6556",
+        ] {
+            assert!(read(header).await.is_err());
+        }
+        let mut too_long = b"PROXY TCP4 ".to_vec();
+        too_long.extend_from_slice(&[b'1'; 200]);
+        assert_eq!(
+            read(&too_long).await.unwrap_err().to_string(),
+            "PROXY protocol header too long"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut tcp4 = vec![10, 0, 0, 1, 10, 0, 0, 2];
+        tcp4.extend_from_slice(&4242u16.to_be_bytes());
+        tcp4.extend_from_slice(&6556u16.to_be_bytes());
+        // Some TLV, which we skip
+        tcp4.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        assert_eq!(
+            read(&v2(0x21, V2_FAMILY_TCP4, &tcp4)).await.unwrap(),
+            Some(SocketAddr::from(([10, 0, 0, 1], 4242)))
+        );
+        let mut tcp6 = Ipv6Addr::LOCALHOST.octets().to_vec();
+        tcp6.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        tcp6.extend_from_slice(&4242u16.to_be_bytes());
+        tcp6.extend_from_slice(&6556u16.to_be_bytes());
+        assert_eq!(
+            read(&v2(0x21, V2_FAMILY_TCP6, &tcp6)).await.unwrap(),
+            Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 4242)))
+        );
+        assert!(read(&v2(0x20, 0x00, &[])).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_v2_invalid() {
+        assert!(read(&v2(0x11, V2_FAMILY_TCP4, &[0; 12])).await.is_err());
+        assert!(read(&v2(0x22, V2_FAMILY_TCP4, &[0; 12])).await.is_err());
+        assert!(read(&v2(0x21, V2_FAMILY_TCP4, &[0; 8])).await.is_err());
+        assert!(read(&v2(0x21, 0x31, &[0; 216])).await.is_err());
+        // Truncated
+        assert!(read(&v2(0x21, V2_FAMILY_TCP4, &[0; 12])[..20])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_too_long() {
+        // Rejected from the length field alone, the TLVs never arrive
+        let mut header = v2(0x21, V2_FAMILY_TCP4, &[0; 12]);
+        header[14..16].copy_from_slice(&60000u16.to_be_bytes());
+        assert_eq!(
+            read(&header).await.unwrap_err().to_string(),
+            "PROXY protocol header of 60016 bytes exceeds the maximum of 1024 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_header_only() {
+        let mut header = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 6556\r\n".to_vec();
+        header.extend_from_slice(b"\x16\x03\x01");
+        let mut stream = std::io::Cursor::new(header);
+        read_header(&mut stream, 1024).await.unwrap();
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn test_missing_header() {
+        assert_eq!(
+            read(b"\x16\x03\x01\x02\x00").await.unwrap_err().to_string(),
+            "Missing PROXY protocol header"
+        );
+    }
+}
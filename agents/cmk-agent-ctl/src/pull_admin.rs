@@ -0,0 +1,253 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Admin interface of the pull daemon, served on a local Unix socket. It takes one command per
+//! line and answers each with the requested lines, followed by "ok" or a line starting with
+//! "error:".
+//!
+//! - list: The pull connections being handled, one per line with ID, peer, UUID of the
+//!   connection ("-" before the TLS handshake) and seconds since they were accepted
+//! - cancel ID: Closes the pull connection with this ID, which frees its slot
+//!
+//! There is no authentication, access is controlled by the permissions of the socket, which is
+//! only accessible to our own user.
+
+use super::access_log;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+
+// Way more than anybody types, but admin sessions must not grow our memory without bounds
+const MAX_SESSION_BYTES: u64 = 64 * 1024;
+/// Sessions without a command for this long are closed, st. forgotten ones don't pile up
+pub const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+struct Entry {
+    access: Arc<access_log::Access>,
+    cancel: Arc<Notify>,
+}
+
+/// The pull connections currently being handled, by an ID which is unique for the lifetime of
+/// the daemon
+#[derive(Default)]
+pub struct ActiveConnections {
+    last_id: AtomicU64,
+    entries: Mutex<BTreeMap<u64, Entry>>,
+}
+
+/// Removes the connection from the list once it is done
+pub struct ActiveConnection {
+    id: u64,
+    cancel: Arc<Notify>,
+    connections: Arc<ActiveConnections>,
+}
+
+impl ActiveConnections {
+    pub fn register(self: &Arc<Self>, access: Arc<access_log::Access>) -> ActiveConnection {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancel = Arc::new(Notify::new());
+        self.entries.lock().unwrap().insert(
+            id,
+            Entry {
+                access,
+                cancel: cancel.clone(),
+            },
+        );
+        ActiveConnection {
+            id,
+            cancel,
+            connections: self.clone(),
+        }
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| {
+                format!(
+                    "{} {} {} {}",
+                    id,
+                    entry.access.peer(),
+                    entry.access.uuid().unwrap_or("-"),
+                    entry.access.elapsed().as_secs()
+                )
+            })
+            .collect()
+    }
+
+    fn cancel(&self, id: u64) -> bool {
+        match self.entries.lock().unwrap().get(&id) {
+            Some(entry) => {
+                info!(
+                    peer = entry.access.peer().to_string();
+                    "{}: Cancelling pull connection {} on request of the admin socket.",
+                    entry.access.peer(),
+                    id
+                );
+                // Stores a permit if the connection is not waiting yet, st. it can't be missed
+                entry.cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl ActiveConnection {
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.connections.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The answer to a single command line, empty lines are ignored
+fn execute(connections: &ActiveConnections, command: &str) -> Vec<String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let result = match words[..] {
+        [] => return vec![],
+        ["list"] => Ok(connections.list()),
+        ["cancel", id] => match id.parse::<u64>() {
+            Ok(id) if connections.cancel(id) => Ok(vec![]),
+            Ok(id) => Err(format!("no active pull connection with ID {}", id)),
+            Err(_) => Err(format!("invalid ID '{}'", id)),
+        },
+        _ => Err(format!(
+            "unknown command '{}', expected 'list' or 'cancel ID'",
+            command.trim()
+        )),
+    };
+    match result {
+        Ok(mut lines) => {
+            lines.push(String::from("ok"));
+            lines
+        }
+        Err(err) => vec![format!("error: {}", err)],
+    }
+}
+
+pub async fn handle(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    connections: &ActiveConnections,
+    idle_timeout: std::time::Duration,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = tokio::io::BufReader::new(reader.take(MAX_SESSION_BYTES)).lines();
+    while let Some(command) = tokio::time::timeout(idle_timeout, lines.next_line())
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("No command within {}s", idle_timeout.as_secs()),
+            )
+        })??
+    {
+        debug!("Admin socket: Executing '{}'.", command);
+        for line in execute(connections, &command) {
+            writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        }
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(port: u16) -> Arc<access_log::Access> {
+        Arc::new(access_log::Access::new(
+            None,
+            std::net::SocketAddr::from(([10, 0, 0, 1], port)),
+        ))
+    }
+
+    #[test]
+    fn test_register() {
+        let connections = Arc::new(ActiveConnections::default());
+        let first = connections.register(access(4242));
+        let second = connections.register(access(4343));
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(
+            connections.list(),
+            ["1 10.0.0.1:4242 - 0", "2 10.0.0.1:4343 - 0"]
+        );
+        drop(first);
+        assert_eq!(connections.list(), ["2 10.0.0.1:4343 - 0"]);
+        // IDs are not reused
+        assert_eq!(connections.register(access(4444)).id, 3);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let connections = Arc::new(ActiveConnections::default());
+        let connection = connections.register(access(4242));
+        assert!(!connections.cancel(2));
+        // Cancelling before anybody waits for it must not get lost
+        assert!(connections.cancel(1));
+        tokio::time::timeout(std::time::Duration::from_secs(5), connection.cancelled())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_execute() {
+        let connections = Arc::new(ActiveConnections::default());
+        let _connection = connections.register(access(4242));
+        assert_eq!(execute(&connections, "list"), ["1 10.0.0.1:4242 - 0", "ok"]);
+        assert_eq!(execute(&connections, "  cancel 1 "), ["ok"]);
+        assert_eq!(
+            execute(&connections, "cancel 2"),
+            ["error: no active pull connection with ID 2"]
+        );
+        assert_eq!(
+            execute(&connections, "cancel first"),
+            ["error: invalid ID 'first'"]
+        );
+        assert_eq!(
+            execute(&connections, "kill 1"),
+            ["error: unknown command 'kill 1', expected 'list' or 'cancel ID'"]
+        );
+        assert!(execute(&connections, "").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handle() {
+        let connections = Arc::new(ActiveConnections::default());
+        let _connection = connections.register(access(4242));
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"list\n\ncancel 7\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        handle(server, &connections, IDLE_TIMEOUT).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "1 10.0.0.1:4242 - 0\nok\nerror: no active pull connection with ID 7\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_idle_timeout() {
+        let connections = Arc::new(ActiveConnections::default());
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"list\n").await.unwrap();
+        let error = handle(server, &connections, std::time::Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "ok\n");
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Bounds what a pull peer may send before it is served. Besides the PROXY protocol header, the
+//! only thing a peer sends is the TLS handshake, and rustls happily buffers handshake messages
+//! of up to 64 KiB. Reading fails as soon as the peer sent more than allowed, st. it is
+//! rejected before any of the excess is processed, and before the agent is even asked.
+
+use std::error::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Debug)]
+pub struct RequestTooLarge(pub usize);
+
+impl std::fmt::Display for RequestTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Peer sent more than the maximum of {} bytes before being served",
+            self.0
+        )
+    }
+}
+
+impl Error for RequestTooLarge {}
+
+/// Passes everything through, but reading fails once more than the limit was read. Writing is
+/// never limited.
+pub struct Limited<S> {
+    inner: S,
+    max_bytes: usize,
+    /// None once the limit was lifted
+    remaining: Option<usize>,
+}
+
+impl<S> Limited<S> {
+    pub fn new(inner: S, max_bytes: usize) -> Self {
+        Self {
+            inner,
+            max_bytes,
+            remaining: Some(max_bytes),
+        }
+    }
+
+    /// Once the handshake is done, the peer is served and what it sends is up to TLS again
+    pub fn lift(&mut self) {
+        self.remaining = None;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Limited<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        std::task::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let max_bytes = self.max_bytes;
+        if let Some(remaining) = self.remaining.as_mut() {
+            let read = buf.filled().len() - filled_before;
+            if read > *remaining {
+                // Like any reader failing, we must not leave anything in the buffer
+                buf.set_filled(filled_before);
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    RequestTooLarge(max_bytes),
+                )));
+            }
+            *remaining -= read;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Limited<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_within_limit() {
+        let mut limited = Limited::new(std::io::Cursor::new(b"0123456789".to_vec()), 10);
+        let mut read = vec![];
+        limited.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_exceeded() {
+        let mut limited = Limited::new(std::io::Cursor::new(b"0123456789".to_vec()), 9);
+        let err = limited.read_to_end(&mut vec![]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.into_inner().unwrap().is::<RequestTooLarge>());
+    }
+
+    #[tokio::test]
+    async fn test_lifted() {
+        let mut limited = Limited::new(std::io::Cursor::new(b"0123456789".to_vec()), 4);
+        let mut start = [0u8; 4];
+        limited.read_exact(&mut start).await.unwrap();
+        limited.lift();
+        let mut rest = vec![];
+        limited.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"456789");
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Minimal implementation of the systemd notification protocol, see sd_notify(3). Everything
+//! in here is a no-op if we are not started by systemd with NOTIFY_SOCKET set.
+
+use anyhow::{Context, Result as AnyhowResult};
+use log::{debug, warn};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Once;
+use std::time::Duration;
+
+const ENV_NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+const ENV_WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const ENV_WATCHDOG_PID: &str = "WATCHDOG_PID";
+
+static READY: Once = Once::new();
+
+fn send(notify_socket: &str, state: &str) -> AnyhowResult<()> {
+    let socket = UnixDatagram::unbound()?;
+    // Sockets starting with '@' live in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = notify_socket.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        socket.send_to_addr(
+            state.as_bytes(),
+            &std::os::unix::net::SocketAddr::from_abstract_name(name)?,
+        )?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), notify_socket)?;
+    Ok(())
+}
+
+fn notify(state: &str) {
+    let notify_socket = match std::env::var(ENV_NOTIFY_SOCKET) {
+        Ok(notify_socket) => notify_socket,
+        Err(_) => return,
+    };
+    debug!("Notifying systemd: {}", state);
+    if let Err(err) = send(&notify_socket, state) {
+        warn!("Failed to notify systemd via {}. ({})", notify_socket, err);
+    }
+}
+
+/// Tells systemd that we are up and running. Only the first call has an effect.
+pub fn ready() {
+    READY.call_once(|| notify("READY=1"));
+}
+
+fn watchdog_interval_from(
+    watchdog_usec: Option<String>,
+    watchdog_pid: Option<String>,
+) -> AnyhowResult<Option<Duration>> {
+    let watchdog_usec = match watchdog_usec {
+        Some(watchdog_usec) => watchdog_usec,
+        None => return Ok(None),
+    };
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid
+            .parse::<u32>()
+            .context(format!("Invalid {} '{}'", ENV_WATCHDOG_PID, watchdog_pid))?
+            != std::process::id()
+        {
+            return Ok(None);
+        }
+    }
+    let watchdog_usec = watchdog_usec
+        .parse::<u64>()
+        .context(format!("Invalid {} '{}'", ENV_WATCHDOG_USEC, watchdog_usec))?;
+    if watchdog_usec == 0 {
+        return Ok(None);
+    }
+    // As recommended by sd_watchdog_enabled(3), send heartbeats at half the timeout
+    Ok(Some(Duration::from_micros(watchdog_usec / 2)))
+}
+
+/// Sends watchdog heartbeats forever, if systemd asked for them.
+pub async fn watchdog() {
+    let interval = if std::env::var(ENV_NOTIFY_SOCKET).is_err() {
+        None
+    } else {
+        watchdog_interval_from(
+            std::env::var(ENV_WATCHDOG_USEC).ok(),
+            std::env::var(ENV_WATCHDOG_PID).ok(),
+        )
+        .unwrap_or_else(|err| {
+            warn!("Not sending watchdog heartbeats. ({})", err);
+            None
+        })
+    };
+    let mut interval = match interval {
+        Some(interval) => tokio::time::interval(interval),
+        None => return std::future::pending().await,
+    };
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.socket");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buffer = [0u8; 32];
+        let received = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..received], b"READY=1");
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        let own_pid = Some(std::process::id().to_string());
+        assert!(watchdog_interval_from(None, None).unwrap().is_none());
+        assert_eq!(
+            watchdog_interval_from(Some(String::from("30000000")), None).unwrap(),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some(String::from("30000000")), own_pid.clone()).unwrap(),
+            Some(Duration::from_secs(15))
+        );
+        assert!(
+            watchdog_interval_from(Some(String::from("30000000")), Some(String::from("1")))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            watchdog_interval_from(Some(String::from("0")), own_pid.clone())
+                .unwrap()
+                .is_none()
+        );
+        assert!(watchdog_interval_from(Some(String::from("x")), own_pid).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! One line per security relevant event of the pull daemon, meant for tools like fail2ban which
+//! ban peers that keep failing. Unlike the access log, the format is plain text and stable:
+//!
+//! ```text
+//! <timestamp> cmk-agent-ctl-security event=<event> src=<ip> port=<port> reason="<reason>"
+//! ```
+//!
+//! - timestamp: RFC 3339 in UTC, eg. 2024-01-31T12:00:00.123Z
+//! - event: allowlist (no entry of allowed_ip matches), denylist (an entry of denied_ip
+//!   matches), rate-limit (pull_rate_limit exceeded) or handshake (TLS handshake failed or timed
+//!   out)
+//! - ip: The source address, as given in the PROXY header for connections via trusted proxies
+//! - reason: For humans only, quoted and escaped, as it may contain what the peer sent
+//!
+//! New events may be added, but the fields up to the port will not change. A fail2ban filter
+//! may look like this:
+//!
+//! ```text
+//! [Definition]
+//! failregex = ^\s*cmk-agent-ctl-security event=(allowlist|denylist|handshake) src=<HOST> port=\d+
+//! ```
+
+use anyhow::{Context, Result as AnyhowResult};
+use log::warn;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const MARKER: &str = "cmk-agent-ctl-security";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    Allowlist,
+    Denylist,
+    RateLimit,
+    Handshake,
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Allowlist => "allowlist",
+                Self::Denylist => "denylist",
+                Self::RateLimit => "rate-limit",
+                Self::Handshake => "handshake",
+            }
+        )
+    }
+}
+
+fn format_line(
+    timestamp: time::OffsetDateTime,
+    event: Event,
+    peer: &SocketAddr,
+    reason: &str,
+) -> String {
+    format!(
+        "{} {} event={} src={} port={} reason={:?}\n",
+        timestamp
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default(),
+        MARKER,
+        event,
+        // Without the brackets of IPv6 socket addresses, st. it's usable as is
+        peer.ip(),
+        peer.port(),
+        reason
+    )
+}
+
+pub struct SecurityLog {
+    path: PathBuf,
+    file: Mutex<std::fs::File>,
+}
+
+fn open(path: &Path) -> AnyhowResult<std::fs::File> {
+    let mut open_options = std::fs::OpenOptions::new();
+    open_options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o640);
+    open_options
+        .open(path)
+        .context(format!("Failed to open security log {}", path.display()))
+}
+
+impl SecurityLog {
+    pub fn open(path: &Path) -> AnyhowResult<Self> {
+        Ok(Self {
+            path: PathBuf::from(path),
+            file: Mutex::new(open(path)?),
+        })
+    }
+
+    /// Continues in a new file if the current one was moved away, eg. by logrotate
+    pub fn reopen(&self) -> AnyhowResult<()> {
+        let file = open(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+
+    pub fn write(&self, event: Event, peer: &SocketAddr, reason: &str) {
+        let line = format_line(time::OffsetDateTime::now_utc(), event, peer, reason);
+        // A single write, st. concurrent events don't interleave
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!(
+                "Failed to write to security log {}. ({})",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let timestamp = time::OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        assert_eq!(
+            format_line(
+                timestamp,
+                Event::Allowlist,
+                &SocketAddr::from(([10, 0, 0, 1], 4242)),
+                "no entry of the allowlist (allowed_ip) matches"
+            ),
+            "2023-11-14T22:13:20Z cmk-agent-ctl-security event=allowlist src=10.0.0.1 port=4242 \
+             reason=\"no entry of the allowlist (allowed_ip) matches\"\n"
+        );
+        // Peers must not be able to forge lines or fields
+        assert_eq!(
+            format_line(
+                timestamp,
+                Event::Handshake,
+                &"[::1]:4343".parse().unwrap(),
+                "unknown UUID \"x\"\nsrc=10.0.0.2"
+            ),
+            "2023-11-14T22:13:20Z cmk-agent-ctl-security event=handshake src=::1 port=4343 \
+             reason=\"unknown UUID \\\"x\\\"\\nsrc=10.0.0.2\"\n"
+        );
+    }
+
+    #[test]
+    fn test_write_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("security.log");
+        let rotated = dir.path().join("security.log.1");
+        let log = SecurityLog::open(&path).unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 4242));
+        log.write(Event::Denylist, &peer, "denied");
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen().unwrap();
+        log.write(Event::RateLimit, &peer, "too many");
+        let rotated = std::fs::read_to_string(rotated).unwrap();
+        assert_eq!(rotated.lines().count(), 1);
+        assert!(rotated.contains(" event=denylist src=10.0.0.1 port=4242 "));
+        assert!(std::fs::read_to_string(path)
+            .unwrap()
+            .contains(" event=rate-limit src=10.0.0.1 port=4242 "));
+    }
+}
@@ -2,10 +2,14 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+use super::exit_codes::ExitCode;
+#[cfg(unix)]
+use super::log_syslog;
 #[cfg(windows)]
 use super::misc;
-use super::{cli, constants, types};
+use super::{audit, cli, constants, logging, tls_debug, tls_keylog, tmp_dir, types};
 #[cfg(unix)]
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result as AnyhowResult;
 use clap::Parser;
@@ -51,6 +55,8 @@ pub struct PathResolver {
     pub config_path: PathBuf,
     pub pre_configured_connections_path: PathBuf,
     pub registry_path: PathBuf,
+    pub pull_counters_path: PathBuf,
+    pub push_results_path: PathBuf,
 }
 
 #[cfg(unix)]
@@ -66,6 +72,8 @@ impl PathResolver {
                 .join(constants::PRE_CONFIGURED_CONNECTIONS_FILE)
                 .exists_or(etc_dir.join(constants::PRE_CONFIGURED_CONNECTIONS_FILE)),
             registry_path: home_dir.join(Path::new(constants::REGISTRY_FILE)),
+            pull_counters_path: home_dir.join(Path::new(constants::PULL_COUNTERS_FILE)),
+            push_results_path: home_dir.join(Path::new(constants::PUSH_RESULTS_FILE)),
         }
     }
 }
@@ -79,6 +87,8 @@ impl PathResolver {
             pre_configured_connections_path: home_dir
                 .join(Path::new(constants::PRE_CONFIGURED_CONNECTIONS_FILE)),
             registry_path: home_dir.join(Path::new(constants::REGISTRY_FILE)),
+            pull_counters_path: home_dir.join(Path::new(constants::PULL_COUNTERS_FILE)),
+            push_results_path: home_dir.join(Path::new(constants::PUSH_RESULTS_FILE)),
         }
     }
 }
@@ -143,11 +153,25 @@ pub fn agent_channel() -> types::AgentChannel {
 }
 
 #[cfg(unix)]
-fn init_logging(level: &str) -> Result<flexi_logger::LoggerHandle, flexi_logger::FlexiLoggerError> {
-    flexi_logger::Logger::try_with_env_or_str(level)?
-        .log_to_stderr()
-        .format(flexi_logger::default_format)
-        .start()
+fn init_logging(
+    level: &str,
+    log_format: logging::LogFormat,
+    log_target: logging::LogTarget,
+    syslog_facility: logging::SyslogFacility,
+) -> Result<flexi_logger::LoggerHandle, flexi_logger::FlexiLoggerError> {
+    let logger = flexi_logger::Logger::try_with_env_or_str(level)?.format(
+        logging::format_function(log_format, flexi_logger::default_format),
+    );
+    match log_target {
+        logging::LogTarget::Stderr => logger.log_to_stderr(),
+        logging::LogTarget::Syslog => {
+            logger.log_to_writer(log_syslog::make_syslog_writer(level, syslog_facility)?)
+        }
+        logging::LogTarget::Both => logger
+            .log_to_writer(log_syslog::make_syslog_writer(level, syslog_facility)?)
+            .duplicate_to_stderr(flexi_logger::Duplicate::All),
+    }
+    .start()
 }
 
 #[cfg(windows)]
@@ -161,6 +185,7 @@ fn make_log_file_spec() -> FileSpec {
 #[cfg(windows)]
 fn init_logging(
     level: &str,
+    log_format: logging::LogFormat,
     duplicate_level: flexi_logger::Duplicate,
 ) -> Result<flexi_logger::LoggerHandle, flexi_logger::FlexiLoggerError> {
     let mut logger = flexi_logger::Logger::try_with_env_or_str(level)?;
@@ -176,7 +201,10 @@ fn init_logging(
     }
     logger
         .append()
-        .format(flexi_logger::detailed_format)
+        .format(logging::format_function(
+            log_format,
+            flexi_logger::detailed_format,
+        ))
         .rotate(
             constants::log::FILE_MAX_SIZE,
             constants::log::FILE_NAMING,
@@ -225,7 +253,12 @@ fn determine_paths() -> AnyhowResult<PathResolver> {
 
 #[cfg(unix)]
 fn setup(args: &cli::Args) -> AnyhowResult<PathResolver> {
-    if let Err(err) = init_logging(&args.logging_level()) {
+    if let Err(err) = init_logging(
+        &args.logging_level(),
+        args.log_format(),
+        args.log_target(),
+        args.syslog_facility(),
+    ) {
         io::stderr()
             .write_all(format!("Failed to initialize logging: {:?}", err).as_bytes())
             .unwrap_or(());
@@ -253,7 +286,7 @@ fn setup(args: &cli::Args) -> AnyhowResult<PathResolver> {
     } else {
         flexi_logger::Duplicate::All
     };
-    if let Err(err) = init_logging(&args.logging_level(), duplicate_level) {
+    if let Err(err) = init_logging(&args.logging_level(), args.log_format(), duplicate_level) {
         io::stderr()
             .write_all(format!("Failed to initialize logging: {:?}", err).as_bytes())
             .unwrap_or(());
@@ -261,17 +294,64 @@ fn setup(args: &cli::Args) -> AnyhowResult<PathResolver> {
     Ok(paths)
 }
 
+/// Like for the pull settings, the environment variable takes precedence over the command line.
+/// Locking and atomic writes work with whatever path we end up with, since they happen next to
+/// the registry file, unless the temporary files go elsewhere, see tmp_dir.
+fn registry_path(
+    from_env: Option<std::ffi::OsString>,
+    from_args: Option<&Path>,
+    default: PathBuf,
+) -> PathBuf {
+    from_env
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| from_args.map(PathBuf::from))
+        .unwrap_or(default)
+}
+
+/// Unlike the default location, an explicitly given config file has to be there
+fn config_path(from_args: Option<&Path>, default: PathBuf) -> AnyhowResult<PathBuf> {
+    match from_args {
+        None => Ok(default),
+        Some(path) if path.exists() => Ok(PathBuf::from(path)),
+        Some(path) => bail!("Config file {} does not exist", path.display()),
+    }
+}
+
 pub fn init() -> AnyhowResult<(cli::Args, PathResolver)> {
     if !is_os_supported() {
         eprintln!("This OS is unsupported");
-        std::process::exit(1);
+        std::process::exit(ExitCode::Failure.code());
     }
     // Parse args as first action to directly exit from --help or malformatted arguments
-    let args = cli::Args::parse();
+    let args = cli::Args::try_parse().unwrap_or_else(|err| {
+        let _ = err.print();
+        std::process::exit(match err.use_stderr() {
+            true => ExitCode::Usage.code(),
+            // --help and --version
+            false => ExitCode::Success.code(),
+        })
+    });
     #[cfg(windows)]
     misc::validate_elevation()?;
 
-    let paths = setup(&args)?;
+    audit::capture_invoker();
+    let mut paths = setup(&args)?;
+    paths.registry_path = registry_path(
+        env::var_os(constants::ENV_REGISTRY),
+        args.registry(),
+        paths.registry_path,
+    );
+    paths.config_path = config_path(args.config(), paths.config_path)?;
+    tmp_dir::init(
+        env::var_os(constants::ENV_TMP_DIR),
+        args.tmp_dir(),
+        &paths.registry_path,
+    )?;
+    if args.tls_debug() {
+        tls_debug::enable();
+    }
+    tls_keylog::init(args.insecure_keylog())?;
     Ok((args, paths))
 }
 
@@ -285,6 +365,40 @@ mod tests {
         assert_eq!(PathResolver::new(home_dir).home_dir, home_dir);
     }
 
+    #[test]
+    fn test_registry_path() {
+        let default = PathBuf::from("/home/registered_connections.json");
+        assert_eq!(registry_path(None, None, default.clone()), default);
+        assert_eq!(
+            registry_path(None, Some(Path::new("/b.json")), default.clone()),
+            Path::new("/b.json")
+        );
+        assert_eq!(
+            registry_path(
+                Some("/a.json".into()),
+                Some(Path::new("/b.json")),
+                default.clone()
+            ),
+            Path::new("/a.json")
+        );
+        assert_eq!(
+            registry_path(Some("".into()), Some(Path::new("/b.json")), default),
+            Path::new("/b.json")
+        );
+    }
+
+    #[test]
+    fn test_config_path() {
+        let default = PathBuf::from("/home/cmk-agent-ctl.toml");
+        assert_eq!(config_path(None, default.clone()).unwrap(), default);
+        let config_file = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(
+            config_path(Some(config_file.path()), default.clone()).unwrap(),
+            config_file.path()
+        );
+        assert!(config_path(Some(Path::new("/does/not/exist.toml")), default).is_err());
+    }
+
     #[cfg(windows)]
     #[test]
     fn test_windows_paths() {
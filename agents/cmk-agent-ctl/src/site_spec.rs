@@ -3,8 +3,10 @@
 // conditions defined in the file COPYING, which is part of this source code package.
 
 use super::config::ClientConfig;
+use super::exit_codes::ExitCode;
 use super::misc::anyhow_error_to_human_readable;
-use anyhow::{anyhow, Context, Error as AnyhowError, Result as AnyhowResult};
+use super::proxy;
+use anyhow::{anyhow, bail, Context, Error as AnyhowError, Result as AnyhowResult};
 use std::fmt::Display;
 use std::str::FromStr;
 
@@ -16,6 +18,35 @@ pub fn parse_port(src: &str) -> AnyhowResult<u16> {
     ))
 }
 
+/// Path under which a reverse proxy exposes the site, eg. "/monitoring/checkmk". Normalized to
+/// a leading and no trailing slash, since it is put in front of "/<site>". The root path, given
+/// as "" or "/", is normalized to "", eg. to override a prefix from the config file.
+pub fn parse_path_prefix(src: &str) -> AnyhowResult<String> {
+    let prefix = src.trim_end_matches('/');
+    if prefix.is_empty() && !src.contains("//") {
+        return Ok(String::new());
+    }
+    if !prefix.starts_with('/') {
+        bail!("Invalid path prefix '{}', expected an absolute path", src)
+    }
+    if prefix.contains(['?', '#', ' ']) || prefix.contains("//") {
+        bail!(
+            "Invalid path prefix '{}', expected only path segments separated by '/'",
+            src
+        )
+    }
+    if prefix
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        bail!(
+            "Invalid path prefix '{}', '.' and '..' are not allowed",
+            src
+        )
+    }
+    Ok(String::from(prefix))
+}
+
 #[derive(serde::Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct ServerSpec {
     pub server: String,
@@ -76,10 +107,17 @@ impl Display for SiteID {
     }
 }
 
-pub fn make_site_url(site_id: &SiteID, port: &u16) -> AnyhowResult<reqwest::Url> {
+pub fn make_site_url(
+    site_id: &SiteID,
+    port: &u16,
+    path_prefix: Option<&str>,
+) -> AnyhowResult<reqwest::Url> {
     reqwest::Url::parse(&format!(
-        "https://{}:{}/{}",
-        site_id.server, port, site_id.site
+        "https://{}:{}{}/{}",
+        site_id.server,
+        port,
+        path_prefix.unwrap_or_default(),
+        site_id.site
     ))
     .context(format!(
         "Failed to construct a URL from {} with port {}",
@@ -87,24 +125,62 @@ pub fn make_site_url(site_id: &SiteID, port: &u16) -> AnyhowResult<reqwest::Url>
     ))
 }
 
-pub fn discover_receiver_port(site_id: &SiteID, client_config: &ClientConfig) -> AnyhowResult<u16> {
+pub fn discover_receiver_port(
+    site_id: &SiteID,
+    path_prefix: Option<&str>,
+    client_config: &ClientConfig,
+) -> AnyhowResult<u16> {
     AgentRecvPortDiscoverer {
         site_id,
+        path_prefix,
         client_config,
     }
     .discover()
 }
 
+/// Repeats the probe while the site does not respond, i.e. as long as it fails with a network
+/// error, until the deadline passes. Any other error, such as rejected credentials, is returned
+/// right away.
+pub fn wait_for_site<T>(
+    site_id: &SiteID,
+    deadline: std::time::Instant,
+    poll_interval: std::time::Duration,
+    mut probe: impl FnMut() -> AnyhowResult<T>,
+) -> AnyhowResult<T> {
+    loop {
+        match probe() {
+            Err(err) if ExitCode::from(&err) == ExitCode::Network => {
+                let left = deadline.saturating_duration_since(std::time::Instant::now());
+                if left.is_zero() {
+                    return Err(err.context(format!("Site {} did not respond in time", site_id)));
+                }
+                eprintln!(
+                    "Waiting for site {} to respond, giving up in {}s. ({:#})",
+                    site_id,
+                    left.as_secs(),
+                    err
+                );
+                std::thread::sleep(poll_interval.min(left));
+            }
+            result => return result,
+        }
+    }
+}
+
 struct AgentRecvPortDiscoverer<'a> {
     site_id: &'a SiteID,
+    path_prefix: Option<&'a str>,
     client_config: &'a ClientConfig,
 }
 
 impl<'a> AgentRecvPortDiscoverer<'a> {
     fn url(&self, protocol: &str) -> AnyhowResult<reqwest::Url> {
         reqwest::Url::parse(&format!(
-            "{}://{}/{}/check_mk/api/1.0/domain-types/internal/actions/discover-receiver/invoke",
-            protocol, self.site_id.server, self.site_id.site,
+            "{}://{}{}/{}/check_mk/api/1.0/domain-types/internal/actions/discover-receiver/invoke",
+            protocol,
+            self.site_id.server,
+            self.path_prefix.unwrap_or_default(),
+            self.site_id.site,
         ))
         .context(format!(
             "Failed to construct URL for discovering agent receiver port using server {} and site {}",
@@ -131,8 +207,16 @@ impl<'a> AgentRecvPortDiscoverer<'a> {
 
     fn build_client(&self) -> reqwest::Result<reqwest::blocking::Client> {
         let mut client_builder = reqwest::blocking::ClientBuilder::new()
+            .connect_timeout(self.client_config.connect_timeout)
             .danger_accept_invalid_certs(!self.client_config.validate_api_cert);
-        if !self.client_config.use_proxy {
+        let (use_proxy, proxy) = proxy::for_host(
+            self.client_config.use_proxy,
+            self.client_config.proxy.as_ref(),
+            &self.site_id.server,
+        );
+        if let Some(proxy) = proxy {
+            client_builder = client_builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        } else if !use_proxy {
             client_builder = client_builder.no_proxy();
         }
         client_builder.build()
@@ -151,11 +235,19 @@ impl<'a> AgentRecvPortDiscoverer<'a> {
             }
         }
 
-        Err(anyhow!(
+        let err = anyhow!(
             "Failed to discover agent receiver port from Checkmk REST API, both with http and https.\n\nError with https:\n{}\n\nError with http:\n{}",
             anyhow_error_to_human_readable(&error_messages["https"]),
             anyhow_error_to_human_readable(&error_messages["http"]),
-        ))
+        );
+        // The underlying errors are only kept as text, so tell whether the site was unreachable
+        if error_messages
+            .values()
+            .all(|err| ExitCode::from(err) == ExitCode::Network)
+        {
+            return Err(err.context(ExitCode::Network));
+        }
+        Err(err)
     }
 }
 
@@ -172,6 +264,67 @@ mod test_parse_port {
     }
 }
 
+#[cfg(test)]
+mod test_wait_for_site {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn site_id() -> SiteID {
+        SiteID::from_str("server/site").unwrap()
+    }
+
+    fn unreachable() -> AnyhowError {
+        anyhow!(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+    }
+
+    #[test]
+    fn test_site_comes_online() {
+        let mut probes = 0;
+        let result = wait_for_site(
+            &site_id(),
+            Instant::now() + Duration::from_secs(60),
+            Duration::ZERO,
+            || {
+                probes += 1;
+                match probes < 3 {
+                    true => Err(unreachable()),
+                    false => Ok(probes),
+                }
+            },
+        );
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn test_no_wait_on_other_errors() {
+        let mut probes = 0;
+        let result: AnyhowResult<()> = wait_for_site(
+            &site_id(),
+            Instant::now() + Duration::from_secs(60),
+            Duration::ZERO,
+            || {
+                probes += 1;
+                Err(anyhow!("Invalid credentials"))
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let err = wait_for_site(
+            &site_id(),
+            Instant::now() + Duration::from_millis(50),
+            Duration::from_millis(10),
+            || -> AnyhowResult<()> { Err(unreachable()) },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Site server/site did not respond in time");
+        assert_eq!(ExitCode::from(&err), ExitCode::Network);
+    }
+}
+
 #[cfg(test)]
 mod test_server_spec {
     use super::*;
@@ -247,24 +400,47 @@ mod test_site_id {
 mod test_agent_recv_port_discoverer {
     use super::*;
 
+    fn client_config() -> ClientConfig {
+        ClientConfig {
+            path_prefix: None,
+            use_proxy: false,
+            proxy: None,
+            validate_api_cert: false,
+            tls_policy: crate::certs::TlsPolicy::default(),
+            extra_root_certs: vec![],
+            tls_servername: None,
+            connect_timeout: std::time::Duration::from_secs(10),
+            push_timeout: std::time::Duration::from_secs(30),
+            push_keepalive: None,
+            push_source_address: None,
+            clock_skew_threshold: std::time::Duration::from_secs(60),
+        }
+    }
+
+    fn url(path_prefix: Option<&str>) -> String {
+        AgentRecvPortDiscoverer {
+            site_id: &SiteID {
+                server: String::from("some-server"),
+                site: String::from("some-site"),
+            },
+            path_prefix,
+            client_config: &client_config(),
+        }
+        .url("http")
+        .unwrap()
+        .to_string()
+    }
+
     #[test]
     fn test_url() {
         assert_eq!(
-            AgentRecvPortDiscoverer {
-                site_id: &SiteID {
-                    server: String::from("some-server"),
-                    site: String::from("some-site"),
-                },
-                client_config: &ClientConfig {
-                    use_proxy: false,
-                    validate_api_cert: false,
-                },
-            }
-            .url("http")
-            .unwrap()
-            .to_string(),
+            url(None),
             "http://some-server/some-site/check_mk/api/1.0/domain-types/internal/actions/discover-receiver/invoke",
-        )
+        );
+        assert_eq!(
+            url(Some("/monitoring/checkmk")),
+            "http://some-server/monitoring/checkmk/some-site/check_mk/api/1.0/domain-types/internal/actions/discover-receiver/invoke",
+        );
     }
 }
 
@@ -281,9 +457,49 @@ mod test_make_site_url {
                     site: String::from("some-site"),
                 },
                 &8000,
+                None,
             )
             .unwrap(),
             reqwest::Url::from_str("https://some-server:8000/some-site").unwrap()
         )
     }
+
+    #[test]
+    fn test_make_site_url_with_path_prefix() {
+        assert_eq!(
+            make_site_url(
+                &SiteID {
+                    server: String::from("some-server"),
+                    site: String::from("some-site"),
+                },
+                &443,
+                Some("/monitoring/checkmk"),
+            )
+            .unwrap()
+            .to_string(),
+            "https://some-server/monitoring/checkmk/some-site"
+        )
+    }
+
+    #[test]
+    fn test_parse_path_prefix() {
+        assert_eq!(
+            parse_path_prefix("/monitoring/checkmk/").unwrap(),
+            "/monitoring/checkmk"
+        );
+        assert_eq!(parse_path_prefix("/checkmk").unwrap(), "/checkmk");
+        assert_eq!(parse_path_prefix("").unwrap(), "");
+        assert_eq!(parse_path_prefix("/").unwrap(), "");
+        for invalid in [
+            "monitoring",
+            "//",
+            "/a//b",
+            "/a?b=c",
+            "/a#b",
+            "/a/../b",
+            "/a b",
+        ] {
+            assert!(parse_path_prefix(invalid).is_err(), "{}", invalid);
+        }
+    }
 }
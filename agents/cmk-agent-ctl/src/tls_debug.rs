@@ -0,0 +1,225 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Diagnostics of TLS handshakes, enabled with --tls-debug. Only public handshake metadata is
+//! logged: protocol versions, cipher suites, server names, the names and fingerprints of peer
+//! certificates and the errors reported by rustls. Nothing derived from key material or
+//! session secrets must ever end up here.
+
+use super::certs;
+use log::info;
+use rustls::{
+    client::ServerCertVerified, client::ServerCertVerifier, client::ServerName,
+    server::ClientCertVerified, server::ClientCertVerifier, Certificate, Error as RustlsError,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use x509_parser::traits::FromDer;
+
+/// Lets the diagnostics pass the log level set via -v
+pub const LOG_DIRECTIVE: &str = "cmk_agent_ctl::tls_debug=info";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed)
+}
+
+fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn names<T: std::fmt::Debug>(items: impl Iterator<Item = T>) -> String {
+    items
+        .map(|item| format!("{:?}", item))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn certificate_summary(certificate: &Certificate) -> String {
+    let fingerprint =
+        certs::fingerprint_sha256(certificate.as_ref()).unwrap_or_else(|_| String::from("unknown"));
+    match x509_parser::certificate::X509Certificate::from_der(certificate.as_ref()) {
+        Ok((_, x509)) => format!(
+            "subject '{}', issuer '{}', fingerprint {}",
+            x509.subject(),
+            x509.issuer(),
+            fingerprint
+        ),
+        Err(_) => format!("unparsable, fingerprint {}", fingerprint),
+    }
+}
+
+fn log_peer_certificate(
+    role: &str,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    error: Option<&RustlsError>,
+) {
+    info!(
+        "TLS: {} certificate {} ({} intermediate(s)), verification {}",
+        role,
+        certificate_summary(end_entity),
+        intermediates.len(),
+        match error {
+            None => String::from("succeeded"),
+            Some(error) => format!("failed: {:?}", error),
+        }
+    );
+}
+
+/// What we offer to the server, the negotiated parameters are not exposed by reqwest
+pub fn log_client_offer(tls_policy: &certs::TlsPolicy) {
+    if !is_enabled() {
+        return;
+    }
+    info!(
+        "TLS: Offering protocol versions [{}], cipher suites [{}]",
+        names(tls_policy.protocol_versions().iter().map(|v| v.version)),
+        names(tls_policy.cipher_suites().iter().map(|suite| suite.suite())),
+    );
+}
+
+pub fn log_server_handshake(peer: &impl std::fmt::Display, connection: &rustls::ServerConnection) {
+    if !is_enabled() {
+        return;
+    }
+    info!(
+        "TLS: Handshake with {} succeeded, protocol version {}, cipher suite {}, server name {}",
+        peer,
+        connection
+            .protocol_version()
+            .map(|version| format!("{:?}", version))
+            .unwrap_or_else(|| String::from("unknown")),
+        connection
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_else(|| String::from("unknown")),
+        connection.sni_hostname().unwrap_or("none"),
+    );
+}
+
+/// The rustls error behind a failed handshake, eg. the alert sent by the peer
+fn rustls_error(error: &anyhow::Error) -> Option<&RustlsError> {
+    error.chain().find_map(|cause| {
+        cause.downcast_ref::<RustlsError>().or_else(|| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|io_error| io_error.get_ref())
+                .and_then(|inner| inner.downcast_ref::<RustlsError>())
+        })
+    })
+}
+
+pub fn log_handshake_failure(peer: &impl std::fmt::Display, error: &anyhow::Error) {
+    if !is_enabled() {
+        return;
+    }
+    match rustls_error(error) {
+        Some(rustls_error) => info!("TLS: Handshake with {} failed: {:?}", peer, rustls_error),
+        None => info!("TLS: Connection to {} failed: {:#}", peer, error),
+    }
+}
+
+struct LoggingServerCertVerifier {
+    verifier: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for LoggingServerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        );
+        log_peer_certificate("Server", end_entity, intermediates, verified.as_ref().err());
+        verified
+    }
+}
+
+/// Logs the peer certificate and the outcome of its verification if enabled
+pub fn server_cert_verifier(verifier: Arc<dyn ServerCertVerifier>) -> Arc<dyn ServerCertVerifier> {
+    match is_enabled() {
+        true => Arc::new(LoggingServerCertVerifier { verifier }),
+        false => verifier,
+    }
+}
+
+struct LoggingClientCertVerifier {
+    verifier: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientCertVerifier for LoggingClientCertVerifier {
+    fn client_auth_root_subjects(
+        &self,
+    ) -> Option<rustls::internal::msgs::handshake::DistinguishedNames> {
+        self.verifier.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: std::time::SystemTime,
+    ) -> Result<ClientCertVerified, RustlsError> {
+        let verified = self
+            .verifier
+            .verify_client_cert(end_entity, intermediates, now);
+        log_peer_certificate("Client", end_entity, intermediates, verified.as_ref().err());
+        verified
+    }
+}
+
+/// Logs the peer certificate and the outcome of its verification if enabled
+pub fn client_cert_verifier(verifier: Arc<dyn ClientCertVerifier>) -> Arc<dyn ClientCertVerifier> {
+    match is_enabled() {
+        true => Arc::new(LoggingClientCertVerifier { verifier }),
+        false => verifier,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+
+    #[test]
+    fn test_certificate_summary() {
+        let summary =
+            certificate_summary(&certs::rustls_certificate(constants::TEST_CERT_OK).unwrap());
+        assert!(summary
+            .starts_with("subject 'CN=heute', issuer 'CN=Site 'heute' local CA', fingerprint "));
+        assert_eq!(
+            certificate_summary(&Certificate(b"garbage".to_vec())),
+            format!(
+                "unparsable, fingerprint {}",
+                certs::fingerprint_sha256(b"garbage").unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_rustls_error() {
+        let alert =
+            RustlsError::AlertReceived(rustls::internal::msgs::enums::AlertDescription::UnknownCA);
+        let error = anyhow::anyhow!(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            alert.clone()
+        ))
+        .context("Handshake failed");
+        assert_eq!(rustls_error(&error), Some(&alert));
+        assert!(rustls_error(&anyhow::anyhow!("some error")).is_none());
+    }
+}
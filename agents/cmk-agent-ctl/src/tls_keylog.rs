@@ -0,0 +1,149 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Writes the TLS session secrets of our pull and push connections to the file named by
+//! SSLKEYLOGFILE, st. captured traffic can be decrypted, eg. with Wireshark. This is for
+//! debugging the interoperability with other TLS implementations only and must never be used
+//! in production: anyone who can read the file can decrypt the agent output of all sessions.
+//!
+//! The variable alone is ignored, writing the secrets also requires --insecure-keylog.
+
+use super::constants;
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::{info, warn};
+use rustls::{KeyLog, NoKeyLog};
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+
+static KEY_LOG: OnceLock<Arc<KeyLogFile>> = OnceLock::new();
+
+/// Like the one of rustls, but the file is readable for us only. It is appended to, in the
+/// NSS key log format.
+struct KeyLogFile(Mutex<fs::File>);
+
+impl KeyLogFile {
+    fn open(path: &std::path::Path) -> AnyhowResult<Self> {
+        let mut open_options = fs::OpenOptions::new();
+        open_options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+        let file = open_options
+            .open(path)
+            .context(format!("Failed to open {}", path.display()))?;
+        // The mode only applies to files we create
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+            .context(format!("Failed to restrict access to {}", path.display()))?;
+        Ok(Self(Mutex::new(file)))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        let Ok(mut file) = self.0.lock() else {
+            return;
+        };
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write TLS session secrets: {}", err);
+        }
+    }
+}
+
+fn key_log_file(from_env: Option<std::ffi::OsString>) -> AnyhowResult<std::path::PathBuf> {
+    match from_env.filter(|path| !path.is_empty()) {
+        Some(path) => Ok(path.into()),
+        None => bail!(
+            "--insecure-keylog requires the environment variable {} to name the file to write to",
+            constants::ENV_SSLKEYLOGFILE
+        ),
+    }
+}
+
+/// Called once at startup. With insecure_keylog, all TLS configs created afterwards write
+/// their secrets.
+pub fn init(insecure_keylog: bool) -> AnyhowResult<()> {
+    let from_env = std::env::var_os(constants::ENV_SSLKEYLOGFILE);
+    if !insecure_keylog {
+        if from_env.is_some() {
+            info!(
+                "Ignoring {}, TLS secrets are only written with --insecure-keylog",
+                constants::ENV_SSLKEYLOGFILE
+            );
+        }
+        return Ok(());
+    }
+    let path = key_log_file(from_env)?;
+    warn!(
+        "INSECURE: Writing TLS session secrets to {}. Anyone with access to this file can \
+         decrypt the traffic of all connections made by this process. For debugging only, never \
+         use --insecure-keylog in production!",
+        path.display()
+    );
+    let key_log = KeyLogFile::open(&path)?;
+    KEY_LOG.get_or_init(|| Arc::new(key_log));
+    Ok(())
+}
+
+pub fn key_log() -> Arc<dyn KeyLog> {
+    match KEY_LOG.get() {
+        Some(key_log) => key_log.clone(),
+        None => Arc::new(NoKeyLog {}),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_log_file() {
+        assert_eq!(
+            key_log_file(Some("/tmp/keys.log".into())).unwrap(),
+            std::path::Path::new("/tmp/keys.log")
+        );
+        assert!(key_log_file(Some("".into())).is_err());
+        assert!(key_log_file(None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_log_file_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.log");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let key_log = KeyLogFile::open(&path).unwrap();
+        key_log.log("CLIENT_RANDOM", &[0, 1], &[0xab]);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "CLIENT_RANDOM 0001 ab\n"
+        );
+        KeyLogFile::open(&dir.path().join("new.log")).unwrap();
+        assert_eq!(
+            fs::metadata(dir.path().join("new.log"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(init(false).is_ok());
+        assert!(!key_log().will_log("CLIENT_RANDOM"));
+    }
+}
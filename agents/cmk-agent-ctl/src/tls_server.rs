@@ -2,46 +2,279 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use super::{certs, config};
-use anyhow::Result as AnyhowResult;
+use super::{certs, clock_skew, config, constants, tls_debug, tls_keylog};
+use anyhow::{Context, Result as AnyhowResult};
+use log::{debug, warn};
+use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::rustls::{
     server::AllowAnyAuthenticatedClient, server::ClientCertVerified, server::ClientCertVerifier,
-    server::ResolvesServerCertUsingSni, sign::CertifiedKey, sign::RsaSigningKey, Certificate,
-    Error as RusttlsError, RootCertStore, ServerConfig,
+    server::NoServerSessionStorage, server::ResolvesServerCertUsingSni,
+    server::ServerSessionMemoryCache, sign, sign::CertifiedKey, Certificate, Error as RustlsError,
+    RootCertStore, ServerConfig, ServerConnection, Ticketer,
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
 #[cfg(windows)]
 use std::io::{Read, Result as IoResult, Write};
 
+/// Accepts the TLS connections of the pull server. The client certificate verifier can't tell
+/// which connection the peer is going to select via SNI, so it accepts certificates signed by
+/// the root of any connection. Whether the root of the selected connection signed it, pinned
+/// client certificates and per-connection minimum TLS versions are checked once the handshake
+/// is done, before anything is sent.
+#[derive(Clone)]
+pub struct PullTlsAcceptor {
+    acceptor: TlsAcceptor,
+    /// Verifying against the root of a single connection, by its UUID. Empty without client auth.
+    client_roots: Arc<HashMap<String, Arc<dyn ClientCertVerifier>>>,
+    /// Normalized fingerprints by the UUID of the connection, only for connections pinning any
+    pinned_clients: Arc<HashMap<String, Vec<String>>>,
+    /// By the UUID of the connection, only for connections with a minimum of their own
+    min_versions: Arc<HashMap<String, certs::TlsVersion>>,
+}
+
+impl PullTlsAcceptor {
+    pub async fn accept<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: IO,
+    ) -> std::io::Result<TlsStream<IO>> {
+        let tls_stream = self.acceptor.accept(stream).await?;
+        check_client_root(&self.client_roots, tls_stream.get_ref().1)
+            .and_then(|()| check_pinned_client(&self.pinned_clients, tls_stream.get_ref().1))
+            .and_then(|()| check_min_version(&self.min_versions, tls_stream.get_ref().1))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::PermissionDenied, err))?;
+        Ok(tls_stream)
+    }
+}
+
+/// The global minimum is enforced by the config already, this only tightens it
+fn check_min_version(
+    min_versions: &HashMap<String, certs::TlsVersion>,
+    connection: &ServerConnection,
+) -> Result<(), String> {
+    let Some((uuid, min_version)) = connection.sni_hostname().and_then(|uuid| {
+        min_versions
+            .get(uuid)
+            .map(|min_version| (uuid, min_version))
+    }) else {
+        return Ok(());
+    };
+    match connection
+        .protocol_version()
+        .and_then(certs::TlsVersion::negotiated)
+    {
+        Some(version) if version >= *min_version => Ok(()),
+        Some(version) => Err(format!(
+            "Connection {} requires at least {}, but the peer negotiated {}",
+            uuid, min_version, version
+        )),
+        None => Err(format!(
+            "Connection {} requires at least {}, but the peer negotiated an unknown version",
+            uuid, min_version
+        )),
+    }
+}
+
+fn min_versions<'a>(
+    connections: impl Iterator<Item = &'a config::TrustedConnection>,
+) -> HashMap<String, certs::TlsVersion> {
+    connections
+        .filter_map(|connection| {
+            connection
+                .min_tls_version
+                .map(|min_version| (connection.uuid.to_string(), min_version))
+        })
+        .collect()
+}
+
+/// Otherwise, a certificate from the site of one connection could be used to pull via another
+/// one, bypassing the pins of the former
+fn check_client_root(
+    client_roots: &HashMap<String, Arc<dyn ClientCertVerifier>>,
+    connection: &ServerConnection,
+) -> Result<(), String> {
+    let Some((uuid, verifier)) = connection
+        .sni_hostname()
+        .and_then(|uuid| client_roots.get(uuid).map(|verifier| (uuid, verifier)))
+    else {
+        return Ok(());
+    };
+    let (end_entity, intermediates) = connection
+        .peer_certificates()
+        .and_then(|chain| chain.split_first())
+        .ok_or_else(|| String::from("No client certificate"))?;
+    verifier
+        .verify_client_cert(end_entity, intermediates, std::time::SystemTime::now())
+        .map_err(|err| {
+            format!(
+                "Client certificate is not signed by the root of connection {}: {}",
+                uuid, err
+            )
+        })?;
+    Ok(())
+}
+
+fn client_roots(
+    connections: &[&config::TrustedConnection],
+    tls_policy: &certs::TlsPolicy,
+) -> AnyhowResult<HashMap<String, Arc<dyn ClientCertVerifier>>> {
+    if tls_policy.no_client_auth {
+        return Ok(HashMap::new());
+    }
+    connections
+        .iter()
+        .map(|connection| {
+            Ok((
+                connection.uuid.to_string(),
+                AllowAnyAuthenticatedClient::new(certs::root_cert_store(std::iter::once(
+                    connection.root_cert.as_str(),
+                ))?),
+            ))
+        })
+        .collect()
+}
+
+fn check_pinned_client(
+    pinned_clients: &HashMap<String, Vec<String>>,
+    connection: &ServerConnection,
+) -> Result<(), String> {
+    let Some((uuid, pins)) = connection
+        .sni_hostname()
+        .and_then(|uuid| pinned_clients.get(uuid).map(|pins| (uuid, pins)))
+    else {
+        return Ok(());
+    };
+    let fingerprint = connection
+        .peer_certificates()
+        .and_then(|chain| chain.first())
+        .map(|certificate| certs::fingerprint_sha256(certificate.as_ref()))
+        .transpose()
+        .map_err(|err| format!("Failed to hash client certificate: {}", err))?
+        .ok_or_else(|| String::from("No client certificate"))?;
+    if !pins.contains(&fingerprint) {
+        return Err(format!(
+            "Client certificate with fingerprint {} is not pinned for connection {}",
+            fingerprint, uuid
+        ));
+    }
+    debug!(
+        "Client certificate with fingerprint {} is pinned for connection {}",
+        fingerprint, uuid
+    );
+    Ok(())
+}
+
+fn pinned_clients<'a>(
+    connections: impl Iterator<Item = &'a config::TrustedConnection>,
+) -> AnyhowResult<HashMap<String, Vec<String>>> {
+    connections
+        .filter(|connection| !connection.pinned_client_fingerprints.is_empty())
+        .map(|connection| {
+            Ok((
+                connection.uuid.to_string(),
+                connection
+                    .pinned_client_fingerprints
+                    .iter()
+                    .map(|fingerprint| certs::normalize_fingerprint(fingerprint))
+                    .collect::<AnyhowResult<Vec<String>>>()?,
+            ))
+        })
+        .collect()
+}
+
 pub fn tls_acceptor<'a>(
     connections: impl Iterator<Item = &'a config::TrustedConnection>,
-) -> AnyhowResult<TlsAcceptor> {
-    Ok(TlsAcceptor::from(tls_config(connections)?))
+    tls_policy: &certs::TlsPolicy,
+) -> AnyhowResult<PullTlsAcceptor> {
+    let connections = served_connections(connections);
+    Ok(PullTlsAcceptor {
+        acceptor: TlsAcceptor::from(tls_config(&connections, tls_policy)?),
+        client_roots: Arc::new(client_roots(&connections, tls_policy)?),
+        pinned_clients: Arc::new(pinned_clients(connections.iter().copied())?),
+        min_versions: Arc::new(min_versions(connections.into_iter())),
+    })
 }
 
-fn tls_config<'a>(
+fn served_connections<'a>(
     connections: impl Iterator<Item = &'a config::TrustedConnection>,
+) -> Vec<&'a config::TrustedConnection> {
+    // A single broken connection must not take down the others
+    connections
+        .filter(|connection| match check_trust_material(connection) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!(
+                    uuid = connection.uuid.to_string();
+                    "Not serving connection {}: {:#}", connection.uuid, err
+                );
+                false
+            }
+        })
+        .collect()
+}
+
+fn tls_config(
+    connections: &[&config::TrustedConnection],
+    tls_policy: &certs::TlsPolicy,
 ) -> AnyhowResult<Arc<ServerConfig>> {
-    let connections: Vec<&config::TrustedConnection> = connections.collect();
-    Ok(Arc::new(
-        ServerConfig::builder()
-            .with_safe_defaults()
-            .with_client_cert_verifier(CNNoUUIDVerifier::from_roots(certs::root_cert_store(
-                connections.iter().map(|it| it.root_cert.as_str()),
-            )?))
-            .with_cert_resolver(sni_resolver(connections.into_iter())?),
-    ))
+    let builder = ServerConfig::builder()
+        .with_cipher_suites(tls_policy.cipher_suites())
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(tls_policy.protocol_versions())?;
+    let builder = match tls_policy.no_client_auth {
+        true => builder.with_no_client_auth(),
+        false => builder.with_client_cert_verifier(tls_debug::client_cert_verifier(
+            CNNoUUIDVerifier::from_roots(
+                certs::root_cert_store(connections.iter().map(|it| it.root_cert.as_str()))?,
+                tls_policy.max_chain_depth,
+            ),
+        )),
+    };
+    let mut config = builder.with_cert_resolver(sni_resolver(connections.iter().copied())?);
+    configure_session_resumption(&mut config, tls_policy)?;
+    config.key_log = tls_keylog::key_log();
+    Ok(Arc::new(config))
+}
+
+/// Sessions and ticket keys belong to this very config. Whenever the registry changes, we
+/// build a new one, st. a peer can't resume a session of a connection which was deleted.
+fn configure_session_resumption(
+    config: &mut ServerConfig,
+    tls_policy: &certs::TlsPolicy,
+) -> AnyhowResult<()> {
+    match tls_policy.session_resumption {
+        certs::SessionResumption::Off => {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+        }
+        certs::SessionResumption::Cache => {
+            config.session_storage = ServerSessionMemoryCache::new(
+                tls_policy
+                    .session_cache_size
+                    .unwrap_or(constants::DEFAULT_TLS_SESSION_CACHE_SIZE),
+            );
+        }
+        certs::SessionResumption::Tickets => {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+            config.ticketer = Ticketer::new().context("Failed to set up TLS session tickets")?;
+        }
+    }
+    Ok(())
 }
 struct CNNoUUIDVerifier {
     verifier: Arc<dyn ClientCertVerifier>,
+    max_chain_depth: Option<usize>,
 }
 
 impl CNNoUUIDVerifier {
-    pub fn from_roots(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
+    pub fn from_roots(
+        roots: RootCertStore,
+        max_chain_depth: Option<usize>,
+    ) -> Arc<dyn ClientCertVerifier> {
         Arc::new(Self {
             verifier: AllowAnyAuthenticatedClient::new(roots),
+            max_chain_depth,
         })
     }
 }
@@ -58,16 +291,18 @@ impl ClientCertVerifier for CNNoUUIDVerifier {
         end_entity: &Certificate,
         intermediates: &[Certificate],
         now: std::time::SystemTime,
-    ) -> Result<ClientCertVerified, RusttlsError> {
+    ) -> Result<ClientCertVerified, RustlsError> {
         let cn_checker = certs::CNCheckerUUID::try_from(end_entity)?;
         if cn_checker.cn_is_uuid() {
-            return Err(RusttlsError::General(format!(
+            return Err(RustlsError::General(format!(
                 "CN in client certificate is a valid UUID: {}",
                 cn_checker.cn()
             )));
         }
+        certs::check_chain_depth(intermediates, self.max_chain_depth)?;
         self.verifier
             .verify_client_cert(end_entity, intermediates, now)
+            .map_err(clock_skew::explain_validity_error)
     }
 }
 
@@ -77,17 +312,31 @@ fn sni_resolver<'a>(
     let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
 
     for conn in connections {
-        let key = certs::rustls_private_key(&conn.private_key)?;
-        let cert = certs::rustls_certificate(&conn.certificate)?;
-
-        let certified_key = CertifiedKey::new(vec![cert], Arc::new(RsaSigningKey::new(&key)?));
-
-        resolver.add(&conn.uuid.to_string(), certified_key)?;
+        resolver.add(&conn.uuid.to_string(), certified_key(conn)?)?;
     }
 
     Ok(Arc::new(resolver))
 }
 
+fn certified_key(conn: &config::TrustedConnection) -> AnyhowResult<CertifiedKey> {
+    let key = certs::rustls_private_key(&conn.private_key)?;
+    Ok(CertifiedKey::new(
+        certs::certificate_chain(&conn.certificate)?,
+        sign::any_supported_type(&key)?,
+    ))
+}
+
+/// Decodes the trust material of a connection just like building the TLS config does
+pub fn check_trust_material(conn: &config::TrustedConnection) -> AnyhowResult<()> {
+    certified_key(conn).context("Invalid certificate or private key")?;
+    certs::root_cert_store(std::iter::once(conn.root_cert.as_str()))
+        .context("Invalid root certificate")?;
+    for fingerprint in &conn.pinned_client_fingerprints {
+        certs::normalize_fingerprint(fingerprint).context("Invalid pinned client fingerprint")?;
+    }
+    Ok(())
+}
+
 #[cfg(windows)]
 pub struct IoStream {
     // Windows Agent will not use stdio/stdin as a communication channel
@@ -116,6 +365,85 @@ impl Write for IoStream {
     }
 }
 
+#[cfg(test)]
+mod test_check_trust_material {
+    use super::*;
+
+    #[test]
+    fn test_broken_connection_is_skipped() {
+        let broken = config::TrustedConnection::from(uuid::Uuid::new_v4());
+        assert!(check_trust_material(&broken).is_err());
+        assert!(tls_acceptor([&broken].into_iter(), &certs::TlsPolicy::default()).is_ok());
+    }
+
+    #[test]
+    fn test_certified_key_presents_chain() {
+        let uuid = uuid::Uuid::new_v4();
+        let chain = certs::make_two_level_chain(&uuid.to_string()).unwrap();
+        let mut connection = config::TrustedConnection::from(uuid);
+        connection.certificate = chain.chain();
+        connection.private_key = chain.private_key;
+        connection.root_cert = chain.root_cert;
+        assert!(check_trust_material(&connection).is_ok());
+        assert_eq!(certified_key(&connection).unwrap().cert.len(), 3);
+    }
+}
+
+#[cfg(test)]
+mod test_min_versions {
+    use super::*;
+
+    #[test]
+    fn test_min_versions() {
+        let default = config::TrustedConnection::from(uuid::Uuid::new_v4());
+        let mut strict = config::TrustedConnection::from(uuid::Uuid::new_v4());
+        strict.min_tls_version = Some(certs::TlsVersion::Tls13);
+        assert_eq!(
+            min_versions([&default, &strict].into_iter()),
+            HashMap::from([(strict.uuid.to_string(), certs::TlsVersion::Tls13)])
+        );
+        assert!(certs::TlsVersion::Tls13 > certs::TlsVersion::Tls12);
+        assert_eq!(
+            certs::TlsVersion::negotiated(rustls::ProtocolVersion::TLSv1_2),
+            Some(certs::TlsVersion::Tls12)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_session_resumption {
+    use super::*;
+
+    fn configured(session_resumption: certs::SessionResumption) -> ServerConfig {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ResolvesServerCertUsingSni::new()));
+        configure_session_resumption(
+            &mut config,
+            &certs::TlsPolicy {
+                session_resumption,
+                ..certs::TlsPolicy::default()
+            },
+        )
+        .unwrap();
+        config
+    }
+
+    #[test]
+    fn test_configure_session_resumption() {
+        let config = configured(certs::SessionResumption::Off);
+        assert!(!config.session_storage.can_cache());
+        assert!(!config.ticketer.enabled());
+        let config = configured(certs::SessionResumption::Cache);
+        assert!(config.session_storage.can_cache());
+        assert!(!config.ticketer.enabled());
+        let config = configured(certs::SessionResumption::Tickets);
+        assert!(!config.session_storage.can_cache());
+        assert!(config.ticketer.enabled());
+    }
+}
+
 #[cfg(test)]
 mod test_cn_no_uuid_verifier {
     use super::super::constants;
@@ -124,6 +452,7 @@ mod test_cn_no_uuid_verifier {
     fn verifier() -> Arc<dyn ClientCertVerifier> {
         CNNoUUIDVerifier::from_roots(
             certs::root_cert_store([constants::TEST_ROOT_CERT].into_iter()).unwrap(),
+            None,
         )
     }
 
@@ -156,6 +485,26 @@ mod test_cn_no_uuid_verifier {
         )
     }
 
+    #[test]
+    fn test_verify_client_cert_two_level_intermediates() {
+        let chain = certs::make_two_level_chain("some-site").unwrap();
+        let intermediates = certs::certificate_chain(&chain.chain()).unwrap()[1..].to_vec();
+        let verify = |intermediates: &[Certificate], max_chain_depth: Option<usize>| {
+            CNNoUUIDVerifier::from_roots(
+                certs::root_cert_store([chain.root_cert.as_str()].into_iter()).unwrap(),
+                max_chain_depth,
+            )
+            .verify_client_cert(
+                &certs::rustls_certificate(&chain.cert).unwrap(),
+                intermediates,
+                std::time::SystemTime::now(),
+            )
+        };
+        assert!(verify(&intermediates, None).is_ok());
+        assert!(verify(&intermediates[..1], None).is_err());
+        assert!(verify(&intermediates, Some(1)).is_err());
+    }
+
     #[test]
     fn test_verify_client_cert_invalid_signature() {
         assert!(verifier()
@@ -0,0 +1,246 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Where the temporary files of atomic writes go, see --tmp-dir. By default, they are created
+//! next to the file they replace. Another directory on the same file system keeps replacing
+//! atomic. On another file system, the content is copied over the target instead, which is not
+//! atomic anymore, but works if only the target itself is writable. This is warned about at
+//! startup.
+//!
+//! The only temporary files are those of saving the registry and the files of the metrics
+//! module, certificates and private keys are never written to temporary files of their own.
+
+use super::constants;
+use anyhow::{Context, Result as AnyhowResult};
+use log::{debug, warn};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static TMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Like for the registry, the environment variable takes precedence over the command line
+fn configured(from_env: Option<OsString>, from_args: Option<&Path>) -> Option<PathBuf> {
+    from_env
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| from_args.map(PathBuf::from))
+}
+
+/// Creating a file is the only reliable test, permissions don't tell about read-only mounts
+fn ensure_writable(dir: &Path) -> AnyhowResult<()> {
+    let probe = dir.join(format!(".cmk-agent-ctl-probe.{}", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .context(format!(
+            "Temporary directory {} is not writable (--tmp-dir, {})",
+            dir.display(),
+            constants::ENV_TMP_DIR
+        ))?;
+    fs::remove_file(&probe).context(format!("Failed to remove {}", probe.display()))
+}
+
+/// None if either one can't be inspected, eg. since the registry's directory doesn't exist yet
+#[cfg(unix)]
+fn same_file_system(dir: &Path, target: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let target_dir = target
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())?;
+    Some(fs::metadata(dir).ok()?.dev() == fs::metadata(target_dir).ok()?.dev())
+}
+
+#[cfg(windows)]
+fn same_file_system(_dir: &Path, _target: &Path) -> Option<bool> {
+    None
+}
+
+/// Called once at startup, registry_path is where the registry goes
+pub fn init(
+    from_env: Option<OsString>,
+    from_args: Option<&Path>,
+    registry_path: &Path,
+) -> AnyhowResult<()> {
+    let Some(dir) = configured(from_env, from_args) else {
+        return Ok(());
+    };
+    ensure_writable(&dir)?;
+    if same_file_system(&dir, registry_path) == Some(false) {
+        warn!(
+            "Temporary directory {} is on another file system than the registry {}, saving the registry copies it over the old one, which is not atomic.",
+            dir.display(),
+            registry_path.display()
+        );
+    }
+    debug!("Creating temporary files in {}", dir.display());
+    TMP_DIR.get_or_init(|| dir);
+    Ok(())
+}
+
+/// The process ID keeps instances with registries of the same name apart, which share the
+/// temporary directory
+fn tmp_path_in(dir: Option<&Path>, target: &Path) -> PathBuf {
+    let mut file_name = target.file_name().unwrap_or_default().to_owned();
+    match dir {
+        Some(dir) => {
+            file_name.push(format!(".{}.tmp", std::process::id()));
+            dir.join(file_name)
+        }
+        None => {
+            file_name.push(".tmp");
+            target.with_file_name(file_name)
+        }
+    }
+}
+
+/// Where to write the content which is to replace target
+pub fn tmp_path(target: &Path) -> PathBuf {
+    tmp_path_in(TMP_DIR.get().map(PathBuf::as_path), target)
+}
+
+/// Creates the temporary file readable for the owner only. Its name is predictable and the
+/// temporary directory may be shared, so the file must not exist yet and symlinks are never
+/// followed. A file left over from an interrupted write is removed first.
+pub fn create(tmp_path: &Path) -> io::Result<fs::File> {
+    match fs::remove_file(tmp_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut open_options = fs::OpenOptions::new();
+    open_options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        open_options
+            .mode(0o600)
+            .custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits());
+    }
+    open_options.open(tmp_path)
+}
+
+/// Moves the temporary file into place. On Windows, rename replaces an existing target as well.
+pub fn replace(tmp_path: &Path, target: &Path) -> io::Result<()> {
+    match fs::rename(tmp_path, target) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            debug!(
+                "{} is on another file system than {}, copying instead of renaming, which is not atomic.",
+                tmp_path.display(),
+                target.display()
+            );
+            fs::copy(tmp_path, target)?;
+            fs::remove_file(tmp_path)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configured() {
+        assert_eq!(configured(None, None), None);
+        assert_eq!(
+            configured(None, Some(Path::new("/b"))),
+            Some(PathBuf::from("/b"))
+        );
+        assert_eq!(
+            configured(Some("/a".into()), Some(Path::new("/b"))),
+            Some(PathBuf::from("/a"))
+        );
+        assert_eq!(
+            configured(Some("".into()), Some(Path::new("/b"))),
+            Some(PathBuf::from("/b"))
+        );
+    }
+
+    #[test]
+    fn test_ensure_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ensure_writable(dir.path()).is_ok());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        let err = ensure_writable(&dir.path().join("missing")).unwrap_err();
+        assert!(format!("{}", err).starts_with(&format!(
+            "Temporary directory {} is not writable",
+            dir.path().join("missing").display()
+        )));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tmp_path_in() {
+        let target = Path::new("/var/lib/cmk-agent/registered_connections.json");
+        assert_eq!(
+            tmp_path_in(None, target),
+            Path::new("/var/lib/cmk-agent/registered_connections.json.tmp")
+        );
+        assert_eq!(
+            tmp_path_in(Some(Path::new("/run/tmp")), target),
+            PathBuf::from(format!(
+                "/run/tmp/registered_connections.json.{}.tmp",
+                std::process::id()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = dir.path().join("file.tmp");
+        fs::write(&tmp, "left over").unwrap();
+        assert_eq!(create(&tmp).unwrap().metadata().unwrap().len(), 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&tmp).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_does_not_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let victim = dir.path().join("victim");
+        fs::write(&victim, "precious").unwrap();
+        let tmp = dir.path().join("file.tmp");
+        std::os::unix::fs::symlink(&victim, &tmp).unwrap();
+        create(&tmp).unwrap();
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "precious");
+        assert!(!fs::symlink_metadata(&tmp).unwrap().file_type().is_symlink());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_same_file_system() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            same_file_system(dir.path(), &dir.path().join("registry.json")),
+            Some(true)
+        );
+        assert_eq!(
+            same_file_system(dir.path(), &dir.path().join("missing/registry.json")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = dir.path().join("file.tmp");
+        let target = dir.path().join("file");
+        fs::write(&target, "old").unwrap();
+        fs::write(&tmp, "new").unwrap();
+        replace(&tmp, &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert!(!tmp.exists());
+    }
+}
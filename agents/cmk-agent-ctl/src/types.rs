@@ -6,35 +6,59 @@
 use faccess::PathExt;
 use std::fmt::Display;
 #[cfg(unix)]
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 pub type AgentLabels = std::collections::HashMap<String, String>;
 
+/// Where to get the agent output from: usually the Unix socket of the agent, or a TCP address in
+/// the format "tcp://<ip>:<port>", for example if the agent runs in another container. On hosts
+/// without a persistent agent, a command which is run for every request and prints the agent
+/// output, as with the classic xinetd setup.
 #[cfg(unix)]
-#[derive(Clone)]
-pub struct AgentChannel(std::path::PathBuf);
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AgentChannel {
+    Socket(PathBuf),
+    Tcp(std::net::SocketAddr),
+    /// The program, which is an absolute path, followed by its arguments
+    Command(Vec<String>),
+}
 #[cfg(windows)]
 #[derive(Clone)]
 pub struct AgentChannel(String);
 
+#[cfg(unix)]
+const TCP_SCHEME: &str = "tcp://";
+
 #[cfg(unix)]
 impl std::convert::From<PathBuf> for AgentChannel {
     fn from(p: PathBuf) -> Self {
-        AgentChannel(p)
+        AgentChannel::Socket(p)
     }
 }
 
 #[cfg(unix)]
 impl std::convert::From<&str> for AgentChannel {
     fn from(s: &str) -> Self {
-        AgentChannel(PathBuf::from(s))
+        AgentChannel::Socket(PathBuf::from(s))
     }
 }
 
 #[cfg(unix)]
-impl std::convert::AsRef<Path> for AgentChannel {
-    fn as_ref(&self) -> &Path {
-        &self.0
+impl std::str::FromStr for AgentChannel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.strip_prefix(TCP_SCHEME) {
+            Some(address) => Ok(AgentChannel::Tcp(address.parse().map_err(|err| {
+                anyhow::anyhow!(
+                    "Invalid agent channel '{}', expected {}<ip>:<port> ({})",
+                    s,
+                    TCP_SCHEME,
+                    err
+                )
+            })?)),
+            None => Ok(AgentChannel::from(s)),
+        }
     }
 }
 
@@ -55,8 +79,10 @@ impl std::convert::AsRef<String> for AgentChannel {
 impl Display for AgentChannel {
     #[cfg(unix)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        {
-            write!(f, "{}", self.0.to_string_lossy())
+        match self {
+            AgentChannel::Socket(path) => write!(f, "{}", path.to_string_lossy()),
+            AgentChannel::Tcp(address) => write!(f, "{}{}", TCP_SCHEME, address),
+            AgentChannel::Command(command) => write!(f, "command '{}'", command.join(" ")),
         }
     }
 
@@ -69,11 +95,39 @@ impl Display for AgentChannel {
 }
 
 impl AgentChannel {
+    /// Only from the config file, never from anything a peer sends. We don't search the PATH,
+    /// st. it's clear which program runs.
+    #[cfg(unix)]
+    pub fn command(command: &[String]) -> anyhow::Result<Self> {
+        match command.first() {
+            None => anyhow::bail!("Invalid agent command, expected at least the program"),
+            Some(program) if !std::path::Path::new(program).is_absolute() => anyhow::bail!(
+                "Invalid agent command '{}', expected an absolute path to the program",
+                program
+            ),
+            Some(_) => Ok(AgentChannel::Command(command.to_vec())),
+        }
+    }
+
     #[cfg(unix)]
     pub fn operational(&self) -> bool {
-        // https://man7.org/linux/man-pages/man7/unix.7.html
-        // On Linux, connecting to a stream socket object requires write permission on that socket;
-        self.0.writable()
+        match self {
+            // https://man7.org/linux/man-pages/man7/unix.7.html
+            // On Linux, connecting to a stream socket object requires write permission on that socket;
+            AgentChannel::Socket(path) => path.writable(),
+            // Nothing to check without connecting, which would trigger the agent
+            AgentChannel::Tcp(_) => true,
+            AgentChannel::Command(command) => std::path::Path::new(&command[0]).executable(),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn is_loopback(&self) -> bool {
+        match self {
+            AgentChannel::Socket(_) => true,
+            AgentChannel::Tcp(address) => address.ip().is_loopback(),
+            AgentChannel::Command(_) => true,
+        }
     }
 
     #[cfg(windows)]
@@ -90,3 +144,68 @@ pub struct Credentials {
     pub username: String,
     pub password: String,
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_agent_channel_from_str() {
+        assert_eq!(
+            AgentChannel::from_str("/run/check-mk-agent.socket").unwrap(),
+            AgentChannel::Socket(PathBuf::from("/run/check-mk-agent.socket"))
+        );
+        assert_eq!(
+            AgentChannel::from_str("tcp://127.0.0.1:6556").unwrap(),
+            AgentChannel::Tcp(std::net::SocketAddr::from(([127, 0, 0, 1], 6556)))
+        );
+        assert!(AgentChannel::from_str("tcp://localhost:6556").is_err());
+        assert!(AgentChannel::from_str("tcp://127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_agent_channel_display() {
+        for channel in ["/run/check-mk-agent.socket", "tcp://[::1]:6556"] {
+            assert_eq!(
+                AgentChannel::from_str(channel).unwrap().to_string(),
+                channel
+            );
+        }
+    }
+
+    #[test]
+    fn test_agent_channel_command() {
+        let command = AgentChannel::command(&[
+            String::from("/usr/bin/check_mk_agent"),
+            String::from("--debug"),
+        ])
+        .unwrap();
+        assert_eq!(
+            command.to_string(),
+            "command '/usr/bin/check_mk_agent --debug'"
+        );
+        assert!(command.is_loopback());
+        assert!(AgentChannel::command(&[]).is_err());
+        assert!(AgentChannel::command(&[String::from("check_mk_agent")]).is_err());
+        assert!(AgentChannel::command(&[String::from("/bin/sh")])
+            .unwrap()
+            .operational());
+        assert!(!AgentChannel::command(&[String::from("/does/not/exist")])
+            .unwrap()
+            .operational());
+    }
+
+    #[test]
+    fn test_agent_channel_is_loopback() {
+        assert!(AgentChannel::from_str("/some/socket")
+            .unwrap()
+            .is_loopback());
+        assert!(AgentChannel::from_str("tcp://[::1]:6556")
+            .unwrap()
+            .is_loopback());
+        assert!(!AgentChannel::from_str("tcp://10.0.0.1:6556")
+            .unwrap()
+            .is_loopback());
+    }
+}
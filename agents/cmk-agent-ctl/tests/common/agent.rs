@@ -5,10 +5,11 @@
 #[cfg(windows)]
 use anyhow::Error as AnyhowError;
 use anyhow::Result as AnyhowResult;
-#[cfg(unix)]
+#[cfg(windows)]
+use tokio::io::AsyncReadExt;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
 #[cfg(windows)]
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
 #[cfg(windows)]
 use tokio::net::{TcpListener, TcpStream};
 #[cfg(unix)]
@@ -36,6 +37,30 @@ pub async fn agent_response_loop(socket_address: String, output: String) -> Anyh
     }
 }
 
+/// Serves the agent output on a named pipe, creating a new pipe instance for each client
+#[cfg(windows)]
+pub async fn agent_pipe_response_loop(pipe_name: String, output: String) -> AnyhowResult<()> {
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&pipe_name)?;
+    loop {
+        server.connect().await?;
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(&pipe_name)?);
+        tokio::spawn(agent_pipe_response(connected, output.clone()));
+    }
+}
+
+#[cfg(windows)]
+async fn agent_pipe_response(pipe: NamedPipeServer, output: String) -> AnyhowResult<()> {
+    let mut buffered_pipe = BufStream::new(pipe);
+    let mut buf = String::new();
+    buffered_pipe.read_line(&mut buf).await?;
+    buffered_pipe.write_all(output.as_bytes()).await?;
+    buffered_pipe.flush().await?;
+    buffered_pipe.into_inner().disconnect()?;
+    Ok(())
+}
+
 #[cfg(unix)]
 pub async fn agent_response(
     stream: UnixStream,
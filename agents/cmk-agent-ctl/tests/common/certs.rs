@@ -4,8 +4,10 @@
 
 use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
 use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
 use openssl::pkey::{PKey, PKeyRef, Private};
 use openssl::rsa::Rsa;
 use openssl::x509::extension::{
@@ -24,13 +26,36 @@ pub struct X509Certs {
     pub receiver_cert: Vec<u8>,
 }
 
+fn rsa_key() -> Result<PKey<Private>, ErrorStack> {
+    PKey::from_rsa(Rsa::generate(2048)?)
+}
+
+fn ec_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
 impl X509Certs {
     pub fn new(ca_name: &str, receiver_name: &str, controller_uuid: &str) -> X509Certs {
+        Self::with_keys(ca_name, receiver_name, controller_uuid, rsa_key)
+    }
+
+    /// With P-256 keys for controller and receiver instead of RSA
+    pub fn new_ec(ca_name: &str, receiver_name: &str, controller_uuid: &str) -> X509Certs {
+        Self::with_keys(ca_name, receiver_name, controller_uuid, ec_key)
+    }
+
+    fn with_keys(
+        ca_name: &str,
+        receiver_name: &str,
+        controller_uuid: &str,
+        key: fn() -> Result<PKey<Private>, ErrorStack>,
+    ) -> X509Certs {
         let (ca_cert, ca_key_pair) = mk_ca_cert(ca_name).unwrap();
         let (controller_cert, controller_key_pair) =
-            mk_ca_signed_cert(&ca_cert, &ca_key_pair, controller_uuid).unwrap();
+            mk_ca_signed_cert(&ca_cert, &ca_key_pair, controller_uuid, key().unwrap()).unwrap();
         let (receiver_cert, receiver_key_pair) =
-            mk_ca_signed_cert(&ca_cert, &ca_key_pair, receiver_name).unwrap();
+            mk_ca_signed_cert(&ca_cert, &ca_key_pair, receiver_name, key().unwrap()).unwrap();
 
         X509Certs {
             ca_cert: ca_cert.to_pem().unwrap(),
@@ -106,10 +131,8 @@ fn mk_ca_signed_cert(
     ca_cert: &X509Ref,
     ca_key_pair: &PKeyRef<Private>,
     cn: &str,
+    key_pair: PKey<Private>,
 ) -> Result<(X509, PKey<Private>), ErrorStack> {
-    let rsa = Rsa::generate(2048)?;
-    let key_pair = PKey::from_rsa(rsa)?;
-
     let req = mk_request(&key_pair, cn)?;
 
     let mut cert_builder = X509::builder()?;
@@ -6,6 +6,8 @@ use cmk_agent_ctl::{certs as lib_certs, configuration::config, site_spec, types}
 use std::{path::Path, str::FromStr};
 pub mod agent;
 pub mod certs;
+#[cfg(unix)]
+pub mod pull;
 use assert_cmd::Command;
 #[cfg(windows)]
 pub use is_elevated;
@@ -29,8 +31,15 @@ pub fn testing_registry(
                 private_key: String::from_utf8(certs.controller_private_key.clone()).unwrap(),
                 certificate: String::from_utf8(certs.controller_cert.clone()).unwrap(),
                 root_cert: String::from_utf8(certs.ca_cert.clone()).unwrap(),
+                pinned_fingerprint: None,
+                pinned_client_fingerprints: vec![],
+                labels: config::ConnectionLabels::new(),
+                agent_output_disabled: false,
+                min_tls_version: None,
             },
             receiver_port: 1234,
+            host_name: None,
+            path_prefix: None,
         },
     );
     registry
@@ -40,10 +49,18 @@ pub fn testing_pull_setup(
     path: &Path,
     port: u16,
     agent_channel: types::AgentChannel,
+) -> (String, config::PullConfig, certs::X509Certs) {
+    testing_pull_setup_with_certs(path, port, agent_channel, certs::X509Certs::new)
+}
+
+pub fn testing_pull_setup_with_certs(
+    path: &Path,
+    port: u16,
+    agent_channel: types::AgentChannel,
+    make_certs: fn(&str, &str, &str) -> certs::X509Certs,
 ) -> (String, config::PullConfig, certs::X509Certs) {
     let controller_uuid = uuid::Uuid::new_v4();
-    let x509_certs =
-        certs::X509Certs::new("Test CA", "Test receiver", &controller_uuid.to_string());
+    let x509_certs = make_certs("Test CA", "Test receiver", &controller_uuid.to_string());
     let registry = testing_registry(
         &path.join("registered_connections.json"),
         &x509_certs,
@@ -52,27 +69,83 @@ pub fn testing_pull_setup(
 
     (
         controller_uuid.to_string(),
-        testing_pull_config(port, agent_channel, registry),
+        testing_pull_config(path, port, agent_channel, registry),
         x509_certs,
     )
 }
 
 pub fn testing_pull_config(
+    path: &Path,
     port: u16,
     agent_channel: types::AgentChannel,
     registry: config::Registry,
 ) -> config::PullConfig {
     config::PullConfig {
         allowed_ip: vec![],
-        port,
+        allowed_ip_inline: vec![],
+        allowed_ip_file: None,
+        allowed_ip_configured: false,
+        allow_any: false,
+        denied_ip: vec![],
+        trusted_proxies: vec![],
+        ports: vec![port],
+        listen_address: None,
+        ports_overridden: false,
         max_connections: 3,
-        connection_timeout: 1,
+        max_connections_total: None,
+        on_overload: config::OverloadPolicy::Reject,
+        overload_queue: 16,
+        worker_threads: 1,
+        listen_backlog: 4096,
+        retry_bind: 0,
+        timeouts: config::Timeouts {
+            connect: 60,
+            handshake: 1,
+            idle: 1,
+            total: None,
+        },
+        site_connection_timeouts: std::collections::HashMap::new(),
+        max_output_bytes: 64 * 1024 * 1024,
+        max_request_bytes: 16 * 1024,
+        shutdown_grace_period: 10,
+        output_hooks: vec![],
+        output_hook_timeout: 30,
+        agent_output_marker: None,
+        on_agent_unavailable: config::AgentUnavailablePolicy::Fail,
+        cache_ttl: None,
+        metrics_listen: None,
+        health_listen: None,
+        pull_rate_limit: None,
+        pull_rate_limit_burst: 5,
+        pull_bandwidth_limit: None,
+        io_chunk_size: 64 * 1024,
         agent_channel,
+        site_agent_channels: std::collections::HashMap::new(),
         registry,
+        require_registry: false,
+        counters_path: path.join("pull_counters.json"),
+        connection_totals_path: path.join("connection_totals.json"),
+        connection_totals_interval: 300,
+        explain_rejections: false,
+        strict_startup: false,
+        access_log: None,
+        security_log: None,
+        pull_unix_socket: None,
+        pull_admin_socket: None,
+        tls_policy: lib_certs::TlsPolicy::default(),
+        config_path: None,
     }
 }
 
 pub fn testing_tls_client_connection(certs: X509Certs, address: &str) -> rustls::ClientConnection {
+    testing_tls_client_connection_with_versions(certs, address, rustls::DEFAULT_VERSIONS)
+}
+
+pub fn testing_tls_client_connection_with_versions(
+    certs: X509Certs,
+    address: &str,
+    versions: &[&'static rustls::SupportedProtocolVersion],
+) -> rustls::ClientConnection {
     let root_cert =
         lib_certs::rustls_certificate(&String::from_utf8(certs.ca_cert).unwrap()).unwrap();
     let client_cert =
@@ -88,7 +161,10 @@ pub fn testing_tls_client_connection(certs: X509Certs, address: &str) -> rustls:
 
     let client_config = std::sync::Arc::new(
         rustls::ClientConfig::builder()
-            .with_safe_defaults()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .unwrap()
             .with_root_certificates(root_cert_store)
             .with_single_cert(client_chain, private_key)
             .unwrap(),
@@ -120,6 +196,11 @@ pub fn setup_agent_socket_path(home_dir: &std::path::Path) -> String {
         .to_string()
 }
 
+#[cfg(windows)]
+pub fn setup_agent_pipe_name(prefix: &str) -> String {
+    format!(r"\\.\pipe\{}-{}", prefix, std::process::id())
+}
+
 pub fn controller_command() -> Command {
     Command::cargo_bin("cmk-agent-ctl").unwrap()
 }
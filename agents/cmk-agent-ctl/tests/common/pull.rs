@@ -0,0 +1,123 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! A pull server for tests which connect with TLS clients of their own, eg. to check which
+//! handshakes it accepts.
+
+use super::certs::X509Certs;
+use anyhow::{bail, Result as AnyhowResult};
+use cmk_agent_ctl::configuration::config;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+pub struct PullServer {
+    pub socket_addr: SocketAddr,
+    pub uuid: String,
+    pub certs: X509Certs,
+    agent_stream_thread: tokio::task::JoinHandle<AnyhowResult<()>>,
+    pull_thread: tokio::task::JoinHandle<AnyhowResult<()>>,
+    test_dir: tempfile::TempDir,
+}
+
+impl PullServer {
+    /// Serves the testing pull setup with a single connection on the given local port, after
+    /// configure adapted it. Returns once the server accepts connections.
+    pub async fn start(
+        port: u16,
+        prefix: &str,
+        configure: impl FnOnce(&mut config::PullConfig, &X509Certs) -> AnyhowResult<()>,
+    ) -> AnyhowResult<Self> {
+        let test_dir = super::setup_test_dir(prefix);
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        let agent_socket_address = super::setup_agent_socket_path(test_dir.path());
+        let (uuid, mut pull_config, certs) =
+            super::testing_pull_setup(test_dir.path(), port, agent_socket_address.as_str().into());
+        pull_config.security_log = Some(test_dir.path().join("security.log"));
+        configure(&mut pull_config, &certs)?;
+        let agent_stream_thread = tokio::spawn(super::agent::agent_response_loop(
+            agent_socket_address,
+            String::from("some test agent output"),
+        ));
+        let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+        let server = Self {
+            socket_addr,
+            uuid,
+            certs,
+            agent_stream_thread,
+            pull_thread,
+            test_dir,
+        };
+        server.wait_for_listener().await?;
+        Ok(server)
+    }
+
+    async fn wait_for_listener(&self) -> AnyhowResult<()> {
+        for _ in 0..100 {
+            if self.pull_thread.is_finished() {
+                bail!("The pull server stopped before accepting connections")
+            }
+            if tokio::net::TcpStream::connect(self.socket_addr)
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        }
+        bail!(
+            "The pull server does not accept connections on {}",
+            self.socket_addr
+        )
+    }
+
+    /// The agent output as received via the given client, or the error the handshake failed with
+    pub fn pull(
+        &self,
+        mut client_connection: rustls::ClientConnection,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut id_buf: [u8; 2] = [0; 2];
+        let mut message_buf: Vec<u8> = vec![];
+        let mut tcp_stream = std::net::TcpStream::connect(self.socket_addr)?;
+        tcp_stream.read_exact(&mut id_buf)?;
+        let mut tls_stream = rustls::Stream::new(&mut client_connection, &mut tcp_stream);
+        tls_stream
+            .read_to_end(&mut message_buf)
+            .map(|_| message_buf)
+    }
+
+    /// Waits for the security log to report a rejection for the given reason
+    pub async fn wait_for_rejection(&self, reason: &str) -> AnyhowResult<()> {
+        let security_log = self.test_dir.path().join("security.log");
+        for _ in 0..100 {
+            if std::fs::read_to_string(&security_log)
+                .unwrap_or_default()
+                .contains(reason)
+            {
+                return Ok(());
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        }
+        bail!(
+            "No rejection for '{}' in the security log:\n{}",
+            reason,
+            std::fs::read_to_string(&security_log).unwrap_or_default()
+        )
+    }
+
+    pub fn stop(self) -> AnyhowResult<()> {
+        self.agent_stream_thread.abort();
+        self.pull_thread.abort();
+        self.test_dir.close()?;
+        Ok(())
+    }
+}
+
+/// The TLS error a pull failed with, if any
+pub fn tls_error(received: &std::io::Result<Vec<u8>>) -> Option<&rustls::Error> {
+    received
+        .as_ref()
+        .err()?
+        .get_ref()?
+        .downcast_ref::<rustls::Error>()
+}
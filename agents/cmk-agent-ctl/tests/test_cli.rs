@@ -16,11 +16,15 @@ use predicates::prelude::predicate;
 use std::fs;
 use std::path::Path;
 
-const SUPPORTED_MODES: [&str; 12] = [
+const SUPPORTED_MODES: [&str; 24] = [
+    "capabilities",
     "daemon",
     "delete",
     "delete-all",
     "dump",
+    "dump-certs",
+    "export",
+    "healthcheck",
     "help",
     "import",
     "proxy-register",
@@ -28,13 +32,26 @@ const SUPPORTED_MODES: [&str; 12] = [
     "push",
     "register",
     "register-new",
+    "repair",
+    "self-test",
+    "show-config",
     "status",
+    "test-pull",
+    "trust-root",
+    "update-connection",
+    "validate",
+    "verify",
 ];
 
 lazy_static::lazy_static! {
     static ref REQUIRED_ARGUMENTS: std::collections::HashMap<&'static str, Vec<&'static str>> = {
         std::collections::HashMap::from([
             ("delete", vec!["some-connection"]),
+            ("delete-all", vec!["--force"]),
+            ("export", vec!["--public-only"]),
+            ("verify", vec!["some-connection"]),
+            ("trust-root", vec!["some-connection"]),
+            ("update-connection", vec!["some-connection", "--receiver-port", "8001"]),
             ("register", vec!["-s", "server", "-i", "site", "-U", "user", "-H", "host"]),
             ("proxy-register", vec!["-s", "server", "-i", "site", "-U", "user", "-H", "host"]),
             ("register-new", vec!["-s", "server", "-i", "site", "-U", "user"]),
@@ -61,10 +78,107 @@ fn test_help() {
     let stdout = std::str::from_utf8(&output.stdout).unwrap();
     assert!(stdout.contains("Checkmk agent controller"));
     assert!(test_supported_modes(String::from(stdout)));
+    assert!(stdout.contains("Exit codes:"));
     assert_eq!(&output.stderr, b"");
     output.assert().success();
 }
 
+#[test]
+fn test_usage_error() {
+    let err = common::controller_command()
+        .arg("status")
+        .arg("--no-such-option")
+        .unwrap_err();
+    let output = err.as_output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(output.stdout, b"");
+}
+
+#[test]
+fn test_password_stdin_requires_user() {
+    let err = common::controller_command()
+        .args(["register", "-s", "server", "-i", "site", "-H", "host"])
+        .arg("--password-stdin")
+        .unwrap_err();
+    let output = err.as_output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--user <USER>"));
+}
+
+#[test]
+fn test_update_connection_invalid_port() {
+    let err = common::controller_command()
+        .args(["update-connection", "server/site", "--receiver-port", "0"])
+        .unwrap_err();
+    let output = err.as_output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--receiver-port"));
+}
+
+#[test]
+fn test_pull_invalid_listen_backlog() {
+    let err = common::controller_command()
+        .args(["pull", "--listen-backlog", "0"])
+        .unwrap_err();
+    let output = err.as_output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--listen-backlog"));
+}
+
+#[test]
+fn test_status_interval_requires_watch() {
+    let err = common::controller_command()
+        .args(["status", "--interval", "5"])
+        .unwrap_err();
+    let output = err.as_output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--watch"));
+}
+
+#[test]
+fn test_delete_force_requires_batch() {
+    let err = common::controller_command()
+        .args(["delete", "server/site", "--force"])
+        .unwrap_err();
+    let output = err.as_output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--site-glob"));
+}
+
+#[test]
+fn test_delete_batch_requires_force() {
+    for args in [
+        vec!["delete", "--label", "env=staging"],
+        vec!["delete", "--site-glob", "staging-*/*"],
+    ] {
+        let err = common::controller_command().args(args).unwrap_err();
+        let output = err.as_output().unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--force"));
+    }
+    let err = common::controller_command()
+        .args(["delete", "server/site", "--label", "env=staging", "--force"])
+        .unwrap_err();
+    assert_eq!(err.as_output().unwrap().status.code(), Some(2));
+}
+
+#[test]
+fn test_delete_site_requires_all() {
+    for (args, missing) in [
+        (vec!["delete", "--site", "server/site"], "--all"),
+        (vec!["delete", "--all"], "--site"),
+    ] {
+        let err = common::controller_command().args(args).unwrap_err();
+        let output = err.as_output().unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(missing));
+    }
+    let err = common::controller_command()
+        .args(["delete", "server/site", "--site", "server/site", "--all"])
+        .unwrap_err();
+    assert_eq!(err.as_output().unwrap().status.code(), Some(2));
+}
+
 #[cfg(unix)]
 #[tokio::test(flavor = "multi_thread")]
 async fn test_dump() -> AnyhowResult<()> {
@@ -148,6 +262,26 @@ fn test_fail_socket_missing() {
     }
 }
 
+#[cfg(unix)]
+#[test]
+fn test_register_json_error() {
+    let output = common::controller_command()
+        .timeout(std::time::Duration::from_secs(1))
+        .env("DEBUG_HOME_DIR", "whatever")
+        .arg("register")
+        .args(REQUIRED_ARGUMENTS.get("register").unwrap())
+        .args(["--output-format", "json"])
+        .unwrap_err();
+    let output = output.as_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let error: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(error["error"]["category"], "failure");
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("Something seems wrong with the agent socket"));
+}
+
 fn write_legacy_registry(path: impl AsRef<Path>) {
     fs::write(
         path,
@@ -174,7 +308,8 @@ fn test_migration_is_always_triggered() {
     let path_registry = test_dir.path().join("registered_connections.json");
 
     for mode in SUPPORTED_MODES {
-        if mode == "help" {
+        // self-test and capabilities never touch the registry
+        if ["help", "self-test", "capabilities"].contains(&mode) {
             continue;
         }
         write_legacy_registry(&path_registry);
@@ -189,6 +324,124 @@ fn test_migration_is_always_triggered() {
     }
 }
 
+#[cfg(unix)]
+#[test]
+fn test_validate() {
+    let test_dir = common::setup_test_dir("cmk-agent-ctl_test_validate");
+    let validate = || {
+        let mut cmd = common::controller_command();
+        cmd.env("DEBUG_HOME_DIR", test_dir.path()).arg("validate");
+        cmd
+    };
+
+    validate()
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Configuration is valid."));
+
+    fs::write(test_dir.path().join("registered_connections.json"), "{").unwrap();
+    fs::write(
+        test_dir.path().join("cmk-agent-ctl.toml"),
+        "allowed_ip = [\"no-ip\"]",
+    )
+    .unwrap();
+    validate()
+        .assert()
+        .code(6)
+        .stdout(predicate::str::contains("registered_connections.json"))
+        .stdout(predicate::str::contains("'no-ip'"))
+        .stderr(predicate::str::contains("Found 2 problem(s)"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tmp_dir() {
+    let test_dir = common::setup_test_dir("cmk-agent-ctl_test_tmp_dir");
+    let tmp_dir = common::setup_test_dir("cmk-agent-ctl_test_tmp_dir_tmp");
+    let path_registry = test_dir.path().join("registered_connections.json");
+    write_legacy_registry(&path_registry);
+    // Migrating saves the registry
+    common::controller_command()
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .args(["validate", "--tmp-dir"])
+        .arg(tmp_dir.path())
+        .assert();
+    assert!(config::Registry::from_file(&path_registry).is_ok());
+    assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+
+    common::controller_command()
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .env("CMK_AGENT_CTL_TMP_DIR", tmp_dir.path().join("missing"))
+        .args(["validate", "--tmp-dir"])
+        .arg(tmp_dir.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("missing is not writable"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_self_test() {
+    let test_dir = common::setup_test_dir("cmk-agent-ctl_test_self_test");
+    let path_registry = test_dir.path().join("registered_connections.json");
+    write_legacy_registry(&path_registry);
+    let mut cmd = common::controller_command();
+    cmd.timeout(std::time::Duration::from_secs(10))
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .arg("self-test")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Self-test passed"));
+    // Neither migrated nor otherwise modified
+    assert!(config::Registry::from_file(&path_registry).is_err());
+    assert_eq!(fs::read_dir(test_dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_capabilities() {
+    let test_dir = common::setup_test_dir("cmk-agent-ctl_test_capabilities");
+    let path_registry = test_dir.path().join("registered_connections.json");
+    write_legacy_registry(&path_registry);
+    let output = common::controller_command()
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .arg("capabilities")
+        .unwrap();
+    let capabilities: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(capabilities["modes"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!("register")));
+    // Not even migrated
+    assert!(config::Registry::from_file(&path_registry).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_show_config() {
+    let test_dir = common::setup_test_dir("cmk-agent-ctl_test_show_config");
+    fs::write(
+        test_dir.path().join("cmk-agent-ctl.toml"),
+        "pull_port = 7556\nmax_connections = 5\ncache_ttl = 10",
+    )
+    .unwrap();
+    let output = common::controller_command()
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .env("CMK_AGENT_CTL_MAX_CONNECTIONS", "7")
+        .args([
+            "show-config",
+            "--output-format",
+            "json",
+            "--cache-ttl",
+            "20",
+        ])
+        .unwrap();
+    let shown: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(shown["pull_port"], serde_json::json!([7556]));
+    // The environment wins over the command line, which wins over the config file
+    assert_eq!(shown["max_connections"], 7);
+    assert_eq!(shown["cache_ttl"], 20);
+}
+
 fn build_status_command_with_log(
     test_dir: &tempfile::TempDir,
     with_log_file: bool,
@@ -225,3 +478,34 @@ fn test_log_to_file() {
         .unwrap()
         .contains("Mode status"));
 }
+
+#[cfg(unix)]
+fn daemon_listens(test_dir: &Path, mode: &str, port: u16) -> bool {
+    let mut daemon = std::process::Command::new(assert_cmd::cargo::cargo_bin("cmk-agent-ctl"))
+        .env("DEBUG_HOME_DIR", test_dir)
+        .args(["daemon", "--mode", mode, "--port", &port.to_string()])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    let listens = std::net::TcpStream::connect(("127.0.0.1", port)).is_ok();
+    // Still running, also without anything to do
+    assert!(daemon.try_wait().unwrap().is_none());
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+    listens
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_mode() {
+    let test_dir = common::setup_test_dir("cmk-agent-ctl_test_daemon_mode");
+    // Without any registry, the pull listener would wait for connections to be registered
+    config::Registry::new(&test_dir.path().join("registered_connections.json"))
+        .unwrap()
+        .save()
+        .unwrap();
+    assert!(!daemon_listens(test_dir.path(), "push", 9985));
+    assert!(daemon_listens(test_dir.path(), "pull", 9985));
+}
@@ -0,0 +1,71 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+// Test files are compiled to seperate crates, so there
+// may be some unused functions in the common module
+#![allow(dead_code)]
+mod common;
+
+#[cfg(unix)]
+use assert_cmd::prelude::OutputAssertExt;
+#[cfg(unix)]
+use cmk_agent_ctl::configuration::config;
+#[cfg(unix)]
+use predicates::prelude::*;
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_export_import() {
+    let source_dir = common::setup_test_dir("cmk-agent-ctl_test_export");
+    let controller_uuid = uuid::Uuid::new_v4();
+    let x509_certs =
+        common::certs::X509Certs::new("Test CA", "Test receiver", &controller_uuid.to_string());
+    common::testing_registry(
+        &source_dir.path().join("registered_connections.json"),
+        &x509_certs,
+        controller_uuid,
+    )
+    .save()
+    .unwrap();
+    let bundle_path = source_dir.path().join("bundle.json");
+
+    common::controller_command()
+        .env("DEBUG_HOME_DIR", source_dir.path())
+        .arg("export")
+        .arg("--include-secrets")
+        .arg(&bundle_path)
+        .unwrap()
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("private keys"));
+
+    let target_dir = common::setup_test_dir("cmk-agent-ctl_test_import");
+    tokio::spawn(common::agent::agent_response_loop(
+        common::setup_agent_socket_path(target_dir.path()),
+        String::from("some-agent-output"),
+    ));
+    common::controller_command()
+        .env("DEBUG_HOME_DIR", target_dir.path())
+        .arg("import")
+        .arg("--replace")
+        .arg(&bundle_path)
+        .unwrap()
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Imported 1 connection(s)"));
+
+    let registry =
+        config::Registry::from_file(&target_dir.path().join("registered_connections.json"))
+            .unwrap();
+    assert_eq!(
+        registry
+            .pull_connections()
+            .map(|connection| connection.uuid)
+            .collect::<Vec<uuid::Uuid>>(),
+        vec![controller_uuid]
+    );
+
+    source_dir.close().unwrap();
+    target_dir.close().unwrap();
+}
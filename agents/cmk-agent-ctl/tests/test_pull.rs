@@ -7,8 +7,13 @@
 #![allow(dead_code)]
 mod common;
 use anyhow::Result as AnyhowResult;
+use cmk_agent_ctl::configuration::config;
+#[cfg(unix)]
+use rustls::internal::msgs::enums::AlertDescription;
 use std::io::{Read, Write};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::str::FromStr;
 
 #[test]
 fn test_pull_inconsistent_cert() -> AnyhowResult<()> {
@@ -25,6 +30,7 @@ fn test_pull_inconsistent_cert() -> AnyhowResult<()> {
     );
 
     let error = cmk_agent_ctl::modes::pull::pull(common::testing_pull_config(
+        test_path,
         1234,
         "dummy".into(),
         registry,
@@ -195,6 +201,76 @@ async fn test_pull_tls_check_guards_ipv6() -> AnyhowResult<()> {
     _test_pull_tls_check_guards(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9981)).await
 }
 
+#[cfg(windows)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_tls_named_pipe() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_tls_named_pipe");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9975);
+    let test_agent_output = "some test agent output";
+    let pipe_name = common::setup_agent_pipe_name("cmk_agent_ctl_test_pull_tls_named_pipe");
+    let (uuid, pull_config, certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        pipe_name.as_str().into(),
+    );
+    let agent_pipe_thread = tokio::spawn(common::agent::agent_pipe_response_loop(
+        pipe_name,
+        test_agent_output.to_string(),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut message_buf: Vec<u8> = vec![];
+    let mut client_connection = common::testing_tls_client_connection(certs, &uuid);
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    assert_eq!(&id_buf, b"16");
+    let mut tls_stream = rustls::Stream::new(&mut client_connection, &mut tcp_stream);
+    tls_stream.read_to_end(&mut message_buf)?;
+    let mut compressed_agent_output = b"\x00\x00\x01".to_vec();
+    let mut zlib_enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    zlib_enc.write_all(test_agent_output.as_bytes())?;
+    compressed_agent_output.append(&mut zlib_enc.finish()?);
+    assert_eq!(message_buf, compressed_agent_output);
+
+    agent_pipe_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_test_pull() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_test_pull");
+    let test_agent_output = "some test agent output";
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    // The port is not used, test-pull brings its own listener
+    let (uuid, pull_config, _) =
+        common::testing_pull_setup(test_dir.path(), 1, agent_socket_address.as_str().into());
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        test_agent_output.to_string(),
+    ));
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    let fetched =
+        cmk_agent_ctl::modes::test_pull::fetch_agent_output(&pull_config, Some(&uuid)).await?;
+    assert_eq!(fetched.uuid.to_string(), uuid);
+    assert_eq!(fetched.agent_output, test_agent_output.as_bytes());
+    assert!(cmk_agent_ctl::modes::test_pull::fetch_agent_output(
+        &pull_config,
+        Some("some_server/other_site")
+    )
+    .await
+    .is_err());
+
+    agent_stream_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
 #[cfg(unix)]
 async fn _test_pull_legacy(socket_addr: SocketAddr) -> AnyhowResult<()> {
     let fixture: PullFixture = PullFixture::setup(socket_addr.port(), "test_pull_legacy", true)?;
@@ -247,3 +323,798 @@ async fn test_pull_legacy_ipv4() -> AnyhowResult<()> {
 async fn test_pull_legacy_ipv6() -> AnyhowResult<()> {
     _test_pull_legacy(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9991)).await
 }
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_reload_on_sighup() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_reload_on_sighup");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9995);
+    let (_uuid, mut pull_config, _certs) =
+        common::testing_pull_setup(test_dir.path(), socket_addr.port(), "dummy".into());
+    let registry = pull_config.registry.clone();
+    pull_config.registry = cmk_agent_ctl::configuration::config::Registry::new(registry.path())?;
+
+    // Nothing registered yet, so we are not supposed to listen
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    assert!(std::net::TcpStream::connect(socket_addr).is_err());
+
+    // Register and notify, we should start listening right away
+    registry.save()?;
+    nix::sys::signal::kill(nix::unistd::Pid::this(), nix::sys::signal::Signal::SIGHUP)?;
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    assert_eq!(&id_buf, b"16");
+
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_rebind_on_sighup() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_rebind_on_sighup");
+    let (first_port, second_port) = (9982, 9983);
+    let config_path = test_dir.path().join("cmk-agent-ctl.toml");
+    std::fs::write(&config_path, format!("pull_port = {}", first_port))?;
+    let (_uuid, pull_config, _certs) =
+        common::testing_pull_setup(test_dir.path(), first_port, "dummy".into());
+    // The registry is loaded again on reload
+    pull_config.registry.save()?;
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(
+        pull_config.reloadable(&config_path),
+    ));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    // Accepted before the reload, but only read afterwards
+    let mut in_flight = std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, first_port))?;
+
+    std::fs::write(
+        &config_path,
+        format!("pull_port = [{}, {}]", first_port, second_port),
+    )?;
+    nix::sys::signal::kill(nix::unistd::Pid::this(), nix::sys::signal::Signal::SIGHUP)?;
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    let mut id_buf: [u8; 2] = [0; 2];
+    in_flight.read_exact(&mut id_buf)?;
+    assert_eq!(&id_buf, b"16");
+    for port in [first_port, second_port] {
+        let mut tcp_stream = std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port))?;
+        tcp_stream.read_exact(&mut id_buf)?;
+        assert_eq!(&id_buf, b"16");
+    }
+
+    std::fs::write(&config_path, format!("pull_port = {}", second_port))?;
+    nix::sys::signal::kill(nix::unistd::Pid::this(), nix::sys::signal::Signal::SIGHUP)?;
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    assert!(std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, first_port)).is_err());
+    let mut tcp_stream = std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, second_port))?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    assert_eq!(&id_buf, b"16");
+
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_handshake_timeout() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_handshake_timeout");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9996);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (_uuid, mut pull_config, _certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    pull_config.timeouts.idle = 30;
+    pull_config.timeouts.handshake = 1;
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        String::from("some test agent output"),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // Never start the handshake, we are dropped long before the connection timeout
+    let mut message_buf: Vec<u8> = vec![];
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    let started = std::time::Instant::now();
+    tcp_stream.read_to_end(&mut message_buf)?;
+    assert_eq!(message_buf, b"16");
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+    agent_stream_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_max_request_bytes() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_max_request_bytes");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9984);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (_uuid, mut pull_config, _certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    pull_config.timeouts.handshake = 30;
+    pull_config.max_request_bytes = 1024;
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        String::from("some test agent output"),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // A ClientHello announcing 16 KiB, we are dropped long before the handshake timeout
+    let mut message_buf: Vec<u8> = vec![];
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    let mut client_hello = b"\x16\x03\x01\x40\x00\x01\x00\x3f\xfc".to_vec();
+    client_hello.resize(4096, 0);
+    let started = std::time::Instant::now();
+    let _ = tcp_stream.write_all(&client_hello);
+    let _ = tcp_stream.read_to_end(&mut message_buf);
+    assert_eq!(message_buf, b"16");
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+    agent_stream_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_proxy_protocol() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_proxy_protocol");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9973);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (_uuid, mut pull_config, _certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    pull_config.trusted_proxies = vec!["127.0.0.1/32".parse()?];
+    pull_config.denied_ip = vec!["10.0.0.2/32".parse()?];
+    pull_config.timeouts.handshake = 1;
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        String::from("some test agent output"),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let connect = |header: &[u8]| -> AnyhowResult<Vec<u8>> {
+        let mut message_buf: Vec<u8> = vec![];
+        let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+        tcp_stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+        tcp_stream.write_all(header)?;
+        // Never start the handshake, we only care about getting the protocol version
+        let _ = tcp_stream.read_to_end(&mut message_buf);
+        Ok(message_buf)
+    };
+    assert_eq!(
+        connect(b"PROXY TCP4 10.0.0.1 127.0.0.1 4242 9973\r\n")?,
+        b"16"
+    );
+    // The source from the header is the one we check against the denylist
+    assert!(connect(b"PROXY TCP4 10.0.0.2 127.0.0.1 4242 9973\r\n")?.is_empty());
+    // Trusted proxies have to send the header
+    assert!(connect(b"")?.is_empty());
+
+    agent_stream_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_close_notify() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_close_notify");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9974);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (uuid, mut pull_config, certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    // Aborting forwarding the agent output still closes the TLS session properly
+    pull_config.max_output_bytes = 10;
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        String::from("some test agent output"),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut message_buf: Vec<u8> = vec![];
+    let mut client_connection = common::testing_tls_client_connection(certs, &uuid);
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    let mut tls_stream = rustls::Stream::new(&mut client_connection, &mut tcp_stream);
+    tls_stream.read_to_end(&mut message_buf)?;
+    // Without close_notify, this would be an unexpected EOF
+    assert_eq!(client_connection.reader().read(&mut [0; 1])?, 0);
+
+    agent_stream_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_tls_ec_keys() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_tls_ec_keys");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9977);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (uuid, pull_config, certs) = common::testing_pull_setup_with_certs(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+        common::certs::X509Certs::new_ec,
+    );
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        String::from("some test agent output"),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut message_buf: Vec<u8> = vec![];
+    let mut client_connection = common::testing_tls_client_connection(certs, &uuid);
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    let mut tls_stream = rustls::Stream::new(&mut client_connection, &mut tcp_stream);
+    tls_stream.read_to_end(&mut message_buf)?;
+    // The header of the agent output, the rest is compressed
+    assert!(message_buf.starts_with(b"\x00\x00"));
+
+    agent_stream_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_access_log() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_access_log");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9976);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (uuid, mut pull_config, certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    let access_log = test_dir.path().join("access.jsonl");
+    pull_config.access_log = Some(access_log.clone());
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        String::from("some test agent output"),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut message_buf: Vec<u8> = vec![];
+    let mut client_connection = common::testing_tls_client_connection(certs, &uuid);
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    let mut tls_stream = rustls::Stream::new(&mut client_connection, &mut tcp_stream);
+    tls_stream.read_to_end(&mut message_buf)?;
+    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+    let records = std::fs::read_to_string(&access_log)?;
+    let records: Vec<serde_json::Value> = records
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["outcome"], "completed");
+    assert_eq!(records[0]["uuid"], uuid);
+    assert_eq!(records[0]["tls_version"], "TLSv1_3");
+    assert!(records[0]["peer"]
+        .as_str()
+        .unwrap()
+        .starts_with("127.0.0.1:"));
+    // The header and the compressed output, but not the TLS ID, which is sent before
+    assert!(records[0]["bytes_sent"].as_u64().unwrap() > 2);
+
+    agent_stream_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_multiple_ports() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_multiple_ports");
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (_uuid, mut pull_config, _certs) =
+        common::testing_pull_setup(test_dir.path(), 9997, agent_socket_address.as_str().into());
+    pull_config.ports = vec![9997, 9998];
+    let agent_stream_thread = tokio::spawn(common::agent::agent_response_loop(
+        agent_socket_address,
+        String::from("some test agent output"),
+    ));
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    for port in [9997, 9998] {
+        let mut id_buf: [u8; 2] = [0; 2];
+        let mut tcp_stream =
+            std::net::TcpStream::connect(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))?;
+        tcp_stream.read_exact(&mut id_buf)?;
+        assert_eq!(&id_buf, b"16");
+    }
+
+    agent_stream_thread.abort();
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_require_registry() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_require_registry");
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let registry =
+        config::Registry::from_file(&test_dir.path().join("registered_connections.json"))?;
+    let mut pull_config = common::testing_pull_config(
+        test_dir.path(),
+        9972,
+        agent_socket_address.as_str().into(),
+        registry,
+    );
+    pull_config.require_registry = true;
+    let err = cmk_agent_ctl::modes::pull::async_pull(pull_config)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("does not exist"));
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_empty_registry_listens() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_empty_registry_listens");
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let registry = config::Registry::new(&test_dir.path().join("registered_connections.json"))?;
+    registry.save()?;
+    let mut pull_config = common::testing_pull_config(
+        test_dir.path(),
+        9972,
+        agent_socket_address.as_str().into(),
+        config::Registry::from_file(registry.path())?,
+    );
+    pull_config.require_registry = true;
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // We announce TLS, but nobody is trusted, st. the handshake can't succeed
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut tcp_stream =
+        std::net::TcpStream::connect(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9972))?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    assert_eq!(&id_buf, b"16");
+
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn pin_client(pull_config: &mut config::PullConfig, fingerprint: String) -> AnyhowResult<()> {
+    pull_config
+        .registry
+        .get_mutable(&cmk_agent_ctl::site_spec::SiteID::from_str(
+            "some_server/some_site",
+        )?)
+        .unwrap()
+        .trust
+        .pinned_client_fingerprints = vec![fingerprint];
+    Ok(())
+}
+
+#[cfg(unix)]
+fn client_fingerprint(certs: &common::certs::X509Certs) -> AnyhowResult<String> {
+    cmk_agent_ctl::certs::fingerprint_sha256(
+        cmk_agent_ctl::certs::rustls_certificate(&String::from_utf8(certs.receiver_cert.clone())?)?
+            .as_ref(),
+    )
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_pinned_client() -> AnyhowResult<()> {
+    let server = common::pull::PullServer::start(
+        9978,
+        "cmk_agent_ctl_test_pull_pinned_client",
+        |pull_config, certs| pin_client(pull_config, client_fingerprint(certs)?),
+    )
+    .await?;
+    let received = server.pull(common::testing_tls_client_connection(
+        server.certs.clone(),
+        &server.uuid,
+    ))?;
+    assert!(received.starts_with(b"\x00\x00"));
+    server.stop()?;
+    // Signed by the root of the connection, but not pinned
+    let server = common::pull::PullServer::start(
+        9979,
+        "cmk_agent_ctl_test_pull_unpinned_client",
+        |pull_config, _| pin_client(pull_config, ["AB"; 32].join(":")),
+    )
+    .await?;
+    let received = server.pull(common::testing_tls_client_connection(
+        server.certs.clone(),
+        &server.uuid,
+    ));
+    // The checks of the server only fail after the handshake, it hangs up without an alert
+    assert!(received?.is_empty());
+    server
+        .wait_for_rejection("is not pinned for connection")
+        .await?;
+    server.stop()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_pinned_client_other_connection() -> AnyhowResult<()> {
+    // Another site with a CA of its own and no pins
+    let other_uuid = uuid::Uuid::new_v4();
+    let other_certs =
+        common::certs::X509Certs::new("Other CA", "Other receiver", &other_uuid.to_string());
+    let server = common::pull::PullServer::start(
+        9962,
+        "cmk_agent_ctl_test_pull_pinned_client_other",
+        |pull_config, certs| {
+            pin_client(pull_config, client_fingerprint(certs)?)?;
+            pull_config.registry.register_connection(
+                &config::ConnectionType::Pull,
+                &cmk_agent_ctl::site_spec::SiteID::from_str("other_server/other_site")?,
+                config::TrustedConnectionWithRemote {
+                    trust: config::TrustedConnection {
+                        uuid: other_uuid,
+                        private_key: String::from_utf8(other_certs.controller_private_key.clone())?,
+                        certificate: String::from_utf8(other_certs.controller_cert.clone())?,
+                        root_cert: String::from_utf8(other_certs.ca_cert.clone())?,
+                        pinned_fingerprint: None,
+                        pinned_client_fingerprints: vec![],
+                        labels: config::ConnectionLabels::new(),
+                        agent_output_disabled: false,
+                        min_tls_version: None,
+                    },
+                    receiver_port: 1234,
+                    host_name: None,
+                    path_prefix: None,
+                },
+            );
+            Ok(())
+        },
+    )
+    .await?;
+
+    // The pinned certificate, signed by the root of the first connection, selecting the other
+    let received = server.pull(common::testing_tls_client_connection(
+        common::certs::X509Certs {
+            ca_cert: other_certs.ca_cert.clone(),
+            ..server.certs.clone()
+        },
+        &other_uuid.to_string(),
+    ));
+    // The checks of the server only fail after the handshake, it hangs up without an alert
+    assert!(received?.is_empty());
+    server
+        .wait_for_rejection(&format!(
+            "Client certificate is not signed by the root of connection {}",
+            other_uuid
+        ))
+        .await?;
+    server.stop()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_min_tls_version() -> AnyhowResult<()> {
+    let server = common::pull::PullServer::start(
+        9986,
+        "cmk_agent_ctl_test_pull_min_tls_version",
+        |pull_config, _| {
+            pull_config
+                .registry
+                .get_mutable(&cmk_agent_ctl::site_spec::SiteID::from_str(
+                    "some_server/some_site",
+                )?)
+                .unwrap()
+                .trust
+                .min_tls_version = Some(cmk_agent_ctl::certs::TlsVersion::Tls13);
+            Ok(())
+        },
+    )
+    .await?;
+    let pull = |versions: &[&'static rustls::SupportedProtocolVersion]| {
+        server.pull(common::testing_tls_client_connection_with_versions(
+            server.certs.clone(),
+            &server.uuid,
+            versions,
+        ))
+    };
+    assert!(pull(&[&rustls::version::TLS13])?.starts_with(b"\x00\x00"));
+    // Acceptable to the global minimum, but not to the one of the connection
+    assert!(pull(&[&rustls::version::TLS12])?.is_empty());
+    server
+        .wait_for_rejection("requires at least TLS 1.3, but the peer negotiated TLS 1.2")
+        .await?;
+    server.stop()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn client_without_cert(
+    server: &common::pull::PullServer,
+) -> AnyhowResult<rustls::ClientConnection> {
+    let mut root_cert_store = rustls::RootCertStore::empty();
+    root_cert_store.add(&cmk_agent_ctl::certs::rustls_certificate(
+        &String::from_utf8(server.certs.ca_cert.clone())?,
+    )?)?;
+    Ok(rustls::ClientConnection::new(
+        std::sync::Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(root_cert_store)
+                .with_no_client_auth(),
+        ),
+        rustls::client::ServerName::try_from(server.uuid.as_str())?,
+    )?)
+}
+
+#[cfg(unix)]
+async fn pull_without_client_cert(
+    port: u16,
+    prefix: &str,
+    no_client_auth: bool,
+) -> AnyhowResult<std::io::Result<Vec<u8>>> {
+    let server = common::pull::PullServer::start(port, prefix, |pull_config, _| {
+        pull_config.tls_policy.no_client_auth = no_client_auth;
+        pull_config.allowed_ip = vec![config::IpEntry::from_str("127.0.0.1")?];
+        pull_config.allowed_ip_configured = true;
+        Ok(())
+    })
+    .await?;
+    let received = server.pull(client_without_cert(&server)?);
+    server.stop()?;
+    Ok(received)
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_no_client_auth() -> AnyhowResult<()> {
+    let received =
+        pull_without_client_cert(9987, "cmk_agent_ctl_test_pull_no_client_auth", true).await??;
+    assert!(received.starts_with(b"\x00\x00"));
+    // Client certificates are required by default
+    let received =
+        pull_without_client_cert(9988, "cmk_agent_ctl_test_pull_client_auth", false).await?;
+    assert_eq!(
+        common::pull::tls_error(&received),
+        Some(&rustls::Error::AlertReceived(
+            AlertDescription::CertificateRequired
+        ))
+    );
+    // Not at all without an IP allowlist
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_no_client_auth_no_allowlist");
+    let (_, mut pull_config, _) = common::testing_pull_setup(
+        test_dir.path(),
+        9963,
+        common::setup_agent_socket_path(test_dir.path())
+            .as_str()
+            .into(),
+    );
+    pull_config.tls_policy.no_client_auth = true;
+    assert!(cmk_agent_ctl::modes::pull::async_pull(pull_config)
+        .await
+        .unwrap_err()
+        .to_string()
+        .starts_with("Refusing to start without client authentication"));
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_admin_socket_cancel() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_admin_socket_cancel");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9989);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let admin_socket_path = test_dir.path().join("admin.sock");
+    let (_uuid, mut pull_config, _certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    pull_config.timeouts.handshake = 30;
+    pull_config.pull_admin_socket = Some(admin_socket_path.clone());
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // Never start the handshake, only the cancellation ends this connection in time
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    let mut protocol_version = [0; 2];
+    tcp_stream.read_exact(&mut protocol_version)?;
+    assert_eq!(&protocol_version, b"16");
+
+    let admin = |command: &str| -> AnyhowResult<String> {
+        let mut admin_stream = std::os::unix::net::UnixStream::connect(&admin_socket_path)?;
+        admin_stream.write_all(command.as_bytes())?;
+        admin_stream.shutdown(std::net::Shutdown::Write)?;
+        let mut response = String::new();
+        admin_stream.read_to_string(&mut response)?;
+        Ok(response)
+    };
+    let peer = tcp_stream.local_addr()?;
+    assert_eq!(admin("list\n")?, format!("1 {} - 0\nok\n", peer));
+    assert_eq!(admin("cancel 1\n")?, "ok\n");
+    let started = std::time::Instant::now();
+    let mut rest: Vec<u8> = vec![];
+    tcp_stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(admin("list\n")?, "ok\n");
+
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_security_log() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_security_log");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9992);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (_uuid, mut pull_config, _certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    let security_log = test_dir.path().join("security.log");
+    pull_config.security_log = Some(security_log.clone());
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    tcp_stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+    let _ = tcp_stream.read_to_end(&mut vec![]);
+    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+    let lines = std::fs::read_to_string(&security_log)?;
+    let lines: Vec<&str> = lines.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].contains(&format!(
+        " cmk-agent-ctl-security event=handshake src=127.0.0.1 port={} reason=\"TLS handshake (",
+        tcp_stream.local_addr()?.port()
+    )));
+
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_health_listen() -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_pull_health_listen");
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9993);
+    let health_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9994);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (_uuid, mut pull_config, _certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    pull_config.health_listen = Some(health_addr);
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let mut health_stream = std::net::TcpStream::connect(health_addr)?;
+    health_stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    health_stream.write_all(b"GET /health HTTP/1.1\r\nHost: lb\r\n\r\n")?;
+    let mut response = String::new();
+    health_stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nok\n"));
+
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+/// Opens one connection more than max_connections allows, and with a queue one more than fits
+/// into the queue
+#[cfg(unix)]
+async fn _test_pull_on_overload(
+    port: u16,
+    on_overload: config::OverloadPolicy,
+) -> AnyhowResult<()> {
+    let test_dir = common::setup_test_dir(&format!("cmk_agent_ctl_test_pull_on_overload_{}", port));
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    let agent_socket_address = common::setup_agent_socket_path(test_dir.path());
+    let (_uuid, mut pull_config, _certs) = common::testing_pull_setup(
+        test_dir.path(),
+        socket_addr.port(),
+        agent_socket_address.as_str().into(),
+    );
+    pull_config.max_connections = 1;
+    pull_config.on_overload = on_overload;
+    pull_config.overload_queue = 1;
+    pull_config.timeouts.handshake = 30;
+    let pull_thread = tokio::task::spawn(cmk_agent_ctl::modes::pull::async_pull(pull_config));
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let connect = || -> AnyhowResult<std::net::TcpStream> {
+        let tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+        tcp_stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        Ok(tcp_stream)
+    };
+    let rejected = |mut tcp_stream: std::net::TcpStream| -> AnyhowResult<bool> {
+        let mut rest: Vec<u8> = vec![];
+        tcp_stream.read_to_end(&mut rest)?;
+        Ok(rest.is_empty())
+    };
+    // Never start the handshake, st. the connection keeps its slot
+    let mut protocol_version = [0; 2];
+    let mut active = connect()?;
+    active.read_exact(&mut protocol_version)?;
+    assert_eq!(&protocol_version, b"16");
+    let mut over_limit = connect()?;
+    match on_overload {
+        config::OverloadPolicy::Reject => assert!(rejected(over_limit)?),
+        config::OverloadPolicy::Queue => {
+            // Held without a word, until the active connection is done
+            let err = over_limit.read_exact(&mut protocol_version).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+            assert!(rejected(connect()?)?);
+            drop(active);
+            over_limit.read_exact(&mut protocol_version)?;
+            assert_eq!(&protocol_version, b"16");
+        }
+    }
+
+    pull_thread.abort();
+    test_dir.close()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_on_overload_reject() -> AnyhowResult<()> {
+    _test_pull_on_overload(9960, config::OverloadPolicy::Reject).await
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_on_overload_queue() -> AnyhowResult<()> {
+    _test_pull_on_overload(9961, config::OverloadPolicy::Queue).await
+}
@@ -55,6 +55,23 @@ fn test_status_socket_down() {
         .success()
         .stdout(
             predicate::str::contains("No connections")
-                .and(predicate::str::contains("Agent socket: inoperational (!!)")),
+                .and(predicate::str::contains("Agent socket: inoperational (!!)"))
+                .and(predicate::str::contains("Agent channel: unreachable")),
         );
 }
+
+#[cfg(unix)]
+#[test]
+fn test_status_output_format_json() {
+    let mut cmd = common::controller_command();
+    let output = cmd
+        .env("DEBUG_HOME_DIR", "/hurz/barz")
+        .arg("status")
+        .arg("--output-format")
+        .arg("json")
+        .unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(status["format_version"], 1);
+    assert_eq!(status["agent_socket_operational"], false);
+    assert_eq!(status["connections"], serde_json::json!([]));
+}